@@ -0,0 +1,21 @@
+#![no_main]
+
+use std::panic;
+use std::process;
+
+use bitstream_tool::fuzz::RoundtripError;
+use bitstream_tool::fuzz::fuzz_roundtrip;
+use libfuzzer_sys::fuzz_target;
+
+// libfuzzer-sys aborts in its panic hook, before fuzz_roundtrip could catch the parser rejecting malformed
+// input. Nothing else panics in this process, so a quiet hook is installed once up front and real failures
+// abort here instead.
+fuzz_target!(init: panic::set_hook(Box::new(|_| {})), |data: &[u8]| {
+    match fuzz_roundtrip(data) {
+        Ok(()) | Err(RoundtripError::ParsePanic(_)) => (),
+        Err(err) => {
+            eprintln!("{:?}", err);
+            process::abort();
+        },
+    }
+});
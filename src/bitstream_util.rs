@@ -1,26 +1,116 @@
 use std::collections::VecDeque;
+use std::panic;
+use std::panic::AssertUnwindSafe;
 
+#[derive(Clone)]
 pub struct SyntaxField {
     pub name: String,
     pub val: i32,
+    // Line of the text form the element was read from, for pointing at it in errors
+    pub line: Option<usize>,
 }
 
+#[derive(Clone)]
 pub struct SyntaxNode {
     pub name: String,
     pub children: VecDeque<SyntaxElement>,
+    pub line: Option<usize>,
 }
 
+impl SyntaxNode {
+    pub fn new(name: &str) -> SyntaxNode {
+        SyntaxNode { name: name.to_string(), children: VecDeque::new(), line: None }
+    }
+
+    pub fn push_field(&mut self, name: &str, val: i32) {
+        self.children.push_back(SyntaxElement::Field(SyntaxField { name: name.to_string(), val, line: None }));
+    }
+
+    pub fn push_payload(&mut self, name: &str, data: Vec<u8>) {
+        self.children.push_back(SyntaxElement::Payload(SyntaxPayload { name: name.to_string(), data, text: false, line: None }));
+    }
+
+    pub fn get_field(&self, name: &str) -> Option<i32> {
+        self.children.iter().find_map(|x| match x {
+            SyntaxElement::Field(field) if field.name == name => Some(field.val),
+            _ => None,
+        })
+    }
+
+    pub fn set_field(&mut self, name: &str, val: i32) -> bool {
+        for child in self.children.iter_mut() {
+            if let SyntaxElement::Field(field) = child {
+                if field.name == name {
+                    field.val = val;
+                    return true;
+                }
+            }
+        }
+        false
+    }
+
+    pub fn get_node(&self, name: &str) -> Option<&SyntaxNode> {
+        self.children.iter().find_map(|x| match x {
+            SyntaxElement::Node(node) if node.name == name => Some(node),
+            _ => None,
+        })
+    }
+
+    pub fn get_node_mut(&mut self, name: &str) -> Option<&mut SyntaxNode> {
+        self.children.iter_mut().find_map(|x| match x {
+            SyntaxElement::Node(node) if node.name == name => Some(node),
+            _ => None,
+        })
+    }
+}
+
+#[derive(Clone)]
 pub struct SyntaxPayload {
     pub name: String,
     pub data: Vec<u8>,
+    // Shown as a quoted string instead of hex when every byte is printable ASCII
+    pub text: bool,
+    pub line: Option<usize>,
 }
 
+#[derive(Clone)]
 pub enum SyntaxElement {
     Field(SyntaxField),
     Node(SyntaxNode),
     Payload(SyntaxPayload),
 }
 
+impl SyntaxElement {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            SyntaxElement::Field(_) => "field",
+            SyntaxElement::Node(_) => "node",
+            SyntaxElement::Payload(_) => "payload",
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        match self {
+            SyntaxElement::Field(field) => &field.name,
+            SyntaxElement::Node(node) => &node.name,
+            SyntaxElement::Payload(payload) => &payload.name,
+        }
+    }
+
+    pub fn line(&self) -> Option<usize> {
+        match self {
+            SyntaxElement::Field(field) => field.line,
+            SyntaxElement::Node(node) => node.line,
+            SyntaxElement::Payload(payload) => payload.line,
+        }
+    }
+}
+
+// "line N: " for elements read from text, to prefix errors with
+pub fn location(line: Option<usize>) -> String {
+    line.map_or(String::new(), |x| format!("line {}: ", x))
+}
+
 impl ToString for SyntaxElement {
     fn to_string(&self) -> String {
         match self {
@@ -37,6 +127,9 @@ impl ToString for SyntaxElement {
                 }
                 format!("{}}}\n", ret)
             },
+            SyntaxElement::Payload(payload) if payload.text && payload.data.iter().all(|x| (0x20..0x7F).contains(x)) => {
+                format!("{}: '{}'\n", payload.name, String::from_utf8_lossy(&payload.data))
+            },
             SyntaxElement::Payload(payload) => {
                 format!("{}: \"{}\"\n", payload.name, payload.data.iter()
                     .map(|x| format!("{:02X}", x).to_string())
@@ -47,51 +140,162 @@ impl ToString for SyntaxElement {
     }
 }
 
-pub fn syntax_elements_from_string(mut rows: &mut VecDeque<String>) -> VecDeque<SyntaxElement> {
+// The canonical text form shows every payload as hex. Which payloads are shown as strings is a presentation
+// choice that may be extended, so golden files shouldn't depend on it.
+pub fn canonicalize(element: &mut SyntaxElement) {
+    match element {
+        SyntaxElement::Field(_) => (),
+        SyntaxElement::Node(node) => node.children.iter_mut().for_each(canonicalize),
+        SyntaxElement::Payload(payload) => payload.text = false,
+    }
+}
+
+pub fn syntax_elements_from_string(rows: &mut VecDeque<String>) -> VecDeque<SyntaxElement> {
+    let total = rows.len();
+    elements_from_rows(rows, total)
+}
+
+// Rows are numbered from the start of the text, total being how many there were
+fn elements_from_rows(rows: &mut VecDeque<String>, total: usize) -> VecDeque<SyntaxElement> {
     let mut ret: VecDeque<SyntaxElement> = VecDeque::new();
     loop {
         let Some(mut row) = rows.pop_front() else {
             break;
         };
+        let line_number = total - rows.len();
+        let line = Some(line_number);
         row = row.trim().to_string();
         if row == "}" {
             break;
         } else if row.ends_with(" {") {
             let name = row.replace(" {", "");
-            let children = syntax_elements_from_string(&mut rows);
-            ret.push_back(SyntaxElement::Node(SyntaxNode { name: name.to_string(), children: children }));
+            let children = elements_from_rows(rows, total);
+            ret.push_back(SyntaxElement::Node(SyntaxNode { name: name.to_string(), children, line }));
         } else if row.contains(":") {
             let (name, val) = row.split_at(row.find(":").unwrap());
-            if val.starts_with(": \"") && val.ends_with("\"") {
+            let val = val.strip_prefix(':').unwrap().trim_start();
+            if val.starts_with('"') && val.ends_with('"') && val.len() >= 2 {
                 let mut data: Vec<u8> = vec![];
-                for byte in val.strip_prefix(": \"").unwrap().strip_suffix("\"").unwrap().split(' ') {
-                    data.push(u8::from_str_radix(byte, 16).unwrap());
+                for byte in val[1..val.len() - 1].split(' ').filter(|x| !x.is_empty()) {
+                    data.push(u8::from_str_radix(byte, 16)
+                        .unwrap_or_else(|_| panic!("line {}: {} is not a hex byte in payload {}", line_number, byte, name)));
                 }
-                ret.push_back(SyntaxElement::Payload(SyntaxPayload { name: name.to_string(), data: data } ));
+                ret.push_back(SyntaxElement::Payload(SyntaxPayload { name: name.to_string(), data, text: false, line }));
+            } else if val.starts_with('\'') && val.ends_with('\'') && val.len() >= 2 {
+                let data = val.as_bytes()[1..val.len() - 1].to_vec();
+                ret.push_back(SyntaxElement::Payload(SyntaxPayload { name: name.to_string(), data, text: true, line }));
             } else {
-                let converted_val = i32::from_str_radix(val.strip_prefix(": ").unwrap(), 10).unwrap();
-                ret.push_back(SyntaxElement::Field(SyntaxField { name: name.to_string(), val: converted_val } ));
+                // A symbol after the value, as in "slice_type: 7 (I)", is only there to be read
+                let number = val.split_once(" (").map_or(val, |x| x.0);
+                let converted_val = number.parse()
+                    .unwrap_or_else(|_| panic!("line {}: expected a number for {}, found {}", line_number, name, val));
+                ret.push_back(SyntaxElement::Field(SyntaxField { name: name.to_string(), val: converted_val, line }));
             }
+        } else if !row.is_empty() {
+            panic!("line {}: expected a field, payload or node, found {}", line_number, row);
         }
     }
 
     ret
 }
 
+// Insert emulation prevention bytes so no 0x000000-0x000003 sequence appears in the payload.
+pub fn rbsp_to_ebsp(rbsp: &[u8]) -> Vec<u8> {
+    let mut ret: Vec<u8> = vec![];
+    let mut zero_count = 0;
+    for byte in rbsp {
+        if zero_count >= 2 && *byte <= 0x03 {
+            ret.push(0x03);
+            zero_count = 0;
+        }
+        ret.push(*byte);
+        zero_count = if *byte == 0x00 { zero_count + 1 } else { 0 };
+    }
+    // Trailing cabac_zero_words are each emitted as 0x000003
+    if zero_count >= 2 {
+        ret.push(0x03);
+    }
+
+    ret
+}
+
+// Strip the emulation prevention byte out of every 0x000003 sequence.
+pub fn ebsp_to_rbsp(ebsp: &[u8]) -> Vec<u8> {
+    let mut ret: Vec<u8> = vec![];
+    let mut zero_count = 0;
+    for byte in ebsp {
+        if zero_count >= 2 && *byte == 0x03 {
+            zero_count = 0;
+            continue;
+        }
+        ret.push(*byte);
+        zero_count = if *byte == 0x00 { zero_count + 1 } else { 0 };
+    }
+
+    ret
+}
+
+// Number of bytes an escaped buffer takes up to cover the first rbsp_len bytes of its RBSP
+pub fn ebsp_len(ebsp: &[u8], rbsp_len: usize) -> usize {
+    let mut zero_count = 0;
+    let mut rbsp_idx = 0;
+    for (idx, byte) in ebsp.iter().enumerate() {
+        if zero_count >= 2 && *byte == 0x03 {
+            zero_count = 0;
+            continue;
+        }
+        if rbsp_idx == rbsp_len {
+            return idx;
+        }
+        rbsp_idx += 1;
+        zero_count = if *byte == 0x00 { zero_count + 1 } else { 0 };
+    }
+
+    ebsp.len()
+}
+
 pub enum FieldType {
     Boolean,
     UnsignedInt,
     SignedInt,
     UnsignedExpGolomb,
     SignedExpGolomb,
+    // Run of 0xFF bytes plus a final byte, all summed, as used for SEI payloadType and payloadSize
+    FFCoded,
+    // te(v), with n the largest value: one inverted bit when that is 1, ue(v) otherwise
+    TruncatedExpGolomb,
+    // me(v), a ue(v) codeNum standing for the value at that index of the table
+    MappedExpGolomb(&'static [i32]),
+    // The number of zero bits before the next one bit, as level_prefix is coded
+    Unary,
+    // A code from a table of variable length codes, like the CAVLC coeff_token
+    Vlc(&'static [VlcCode]),
 }
 
+// (length in bits, code, value) of one entry in a table of variable length codes
+pub type VlcCode = (u8, u32, i32);
+
 pub trait BitstreamProcessor {
     fn field(&mut self, node: &mut SyntaxNode, name: &str, field_type: FieldType, n: u8) -> i32;
     fn subnode<A>(&mut self, node: &mut SyntaxNode, name: &str, cb: A) -> ()
         where A: FnMut(&mut SyntaxNode, &mut Self) -> ();
     fn payload(&mut self, node: &mut SyntaxNode, name: &str) -> ();
+    // A payload of exactly size bytes, rather than everything up to the end of the buffer
+    fn fixed_payload(&mut self, node: &mut SyntaxNode, name: &str, size: usize);
+    // A payload that is shown as a string when it is printable, for embedded text like encoder version strings
+    fn text_payload(&mut self, node: &mut SyntaxNode, name: &str) {
+        self.payload(node, name);
+    }
+    // A byte aligned subnode preceded by its size in bytes. The size is recomputed when writing.
+    fn sized_subnode<A>(&mut self, node: &mut SyntaxNode, size_name: &str, name: &str, cb: A)
+        where A: FnMut(&mut SyntaxNode, &mut Self);
+    // A value computed from the coded fields rather than coded itself, shown next to them. Writers skip it,
+    // whatever it was edited to, and it may be left out of a tree that is written.
+    fn derived(&mut self, node: &mut SyntaxNode, name: &str, val: i32) {
+        node.push_field(name, val);
+    }
     fn more_data(&mut self, node: &mut SyntaxNode) -> bool;
+    fn bit_position(&self) -> usize;
 }
 
 pub struct BitstreamReader<'a> {
@@ -143,6 +347,10 @@ impl BitstreamReader<'_> {
                     len += 1;
                     bit = self.read_bit()?;
                 }
+                // Values up to 2^31 - 2 fit in the 30 leading zero bits an i32 has room for
+                if len > 30 {
+                    panic!("Exp-Golomb code with {} leading zero bits is too long", len);
+                }
                 Some(((1 << len) | self.read(FieldType::UnsignedInt, len)?) - 1)
             },
             FieldType::SignedExpGolomb => {
@@ -153,26 +361,84 @@ impl BitstreamReader<'_> {
                     return Some(val / -2)
                 }
             },
+            FieldType::FFCoded => {
+                let mut ret = 0;
+                loop {
+                    let byte = self.read_bits(8, 0)?;
+                    ret += byte;
+                    if byte != 0xFF {
+                        return Some(ret);
+                    }
+                }
+            },
+            FieldType::TruncatedExpGolomb => {
+                if n > 1 {
+                    self.read(FieldType::UnsignedExpGolomb, 0)
+                } else {
+                    Some(1 - self.read_bit()?)
+                }
+            },
+            FieldType::MappedExpGolomb(table) => {
+                let code_num = self.read(FieldType::UnsignedExpGolomb, 0)?;
+                Some(*table.get(code_num as usize).unwrap_or_else(|| panic!("codeNum {} is out of range", code_num)))
+            },
+            FieldType::Unary => {
+                let mut ret = 0;
+                while self.read_bit()? == 0 {
+                    ret += 1;
+                }
+                Some(ret)
+            },
+            FieldType::Vlc(table) => {
+                let max_len = table.iter().map(|x| x.0).max().unwrap_or(0);
+                let mut code: u32 = 0;
+                for len in 1..=max_len {
+                    code = (code << 1) | self.read_bit()? as u32;
+                    if let Some(entry) = table.iter().find(|x| x.0 == len && x.1 == code) {
+                        return Some(entry.2);
+                    }
+                }
+                panic!("Invalid variable length code {:0width$b}", code, width = max_len as usize);
+            },
         }
     }
 
-    pub fn new(buffer: &[u8]) -> BitstreamReader {
+    pub fn new(buffer: &[u8]) -> BitstreamReader<'_> {
         BitstreamReader { buffer: buffer, bit_index: 0 }
     }
 }
 
+// The bytes a sized subnode spans. Reading and tracing reject a size running past the data alike.
+fn sized_subnode_bytes(bit_index: usize, len: usize, size_name: &str, size: usize, name: &str) -> (usize, usize) {
+    assert!(bit_index.is_multiple_of(8), "{} is not byte aligned", name);
+    let start_idx = bit_index / 8;
+    if start_idx + size > len {
+        panic!("{} of {} runs past the end of the bitstream", size_name, size);
+    }
+    (start_idx, start_idx + size)
+}
+
 impl BitstreamProcessor for BitstreamReader<'_> {
     fn field(&mut self, node: &mut SyntaxNode, name: &str, field_type: FieldType, n: u8) -> i32 {
-        let ret = self.read(field_type, n).expect(&format!("Bitstream ended unexpectedly while parsing {}", name));
-        node.children.push_back(SyntaxElement::Field(SyntaxField {name: name.to_string(), val: ret}));
+        let start = self.bit_index;
+        let Some(ret) = self.read(field_type, n) else {
+            // Leave the position at the field that couldn't be read, for callers salvaging a truncated NALU
+            self.bit_index = start;
+            panic!("Bitstream ended unexpectedly while parsing {}", name);
+        };
+        node.children.push_back(SyntaxElement::Field(SyntaxField { name: name.to_string(), val: ret, line: None }));
         ret
     }
 
+    // Subnodes are attached before they are filled in, so a panic partway through leaves what was read so
+    // far in the tree
     fn subnode<A>(&mut self, node: &mut SyntaxNode, name: &str, mut cb: A) -> ()
         where A: FnMut(&mut SyntaxNode, &mut Self) -> () {
-        let mut subnode = SyntaxNode {name: name.to_string(), children: VecDeque::new()};
-        cb(&mut subnode, self);
-        node.children.push_back(SyntaxElement::Node(subnode));
+        node.children.push_back(SyntaxElement::Node(SyntaxNode::new(name)));
+        let Some(SyntaxElement::Node(subnode)) = node.children.back_mut() else {
+            unreachable!();
+        };
+        cb(subnode, self);
     }
 
     fn payload(&mut self, node: &mut SyntaxNode, name: &str) -> () {
@@ -184,26 +450,160 @@ impl BitstreamProcessor for BitstreamReader<'_> {
         for i in (self.bit_index/8)..self.buffer.len() {
             payload.push(self.buffer[i]);
         }
-        node.children.push_back(SyntaxElement::Payload(SyntaxPayload {name: name.to_string(), data: payload}));
+        self.bit_index = self.buffer.len() * 8;
+        node.children.push_back(SyntaxElement::Payload(SyntaxPayload { name: name.to_string(), data: payload, text: false, line: None }));
     }
 
-    fn more_data(&mut self, node: &mut SyntaxNode) -> bool {
-        if self.bit_index/8 == self.buffer.len()-1 {
-            (self.buffer[self.buffer.len()-1] & ((1 << (8 - self.bit_index % 8)) - 1)).count_ones() != 1
+    fn fixed_payload(&mut self, node: &mut SyntaxNode, name: &str, size: usize) {
+        let mut payload: Vec<u8> = vec![];
+        for _ in 0..size {
+            payload.push(self.read(FieldType::UnsignedInt, 8).unwrap_or_else(|| panic!("Bitstream ended unexpectedly while parsing {}", name)) as u8);
+        }
+        node.children.push_back(SyntaxElement::Payload(SyntaxPayload { name: name.to_string(), data: payload, text: false, line: None }));
+    }
+
+    fn text_payload(&mut self, node: &mut SyntaxNode, name: &str) {
+        self.payload(node, name);
+        if let Some(SyntaxElement::Payload(payload)) = node.children.back_mut() {
+            payload.text = true;
+        }
+    }
+
+    fn sized_subnode<A>(&mut self, node: &mut SyntaxNode, size_name: &str, name: &str, mut cb: A)
+        where A: FnMut(&mut SyntaxNode, &mut Self) {
+        let size = self.field(node, size_name, FieldType::FFCoded, 0) as usize;
+        let (start_idx, end_idx) = sized_subnode_bytes(self.bit_index, self.buffer.len(), size_name, size, name);
+        let mut reader = BitstreamReader::new(&self.buffer[start_idx..end_idx]);
+        node.children.push_back(SyntaxElement::Node(SyntaxNode::new(name)));
+        let Some(SyntaxElement::Node(subnode)) = node.children.back_mut() else {
+            unreachable!();
+        };
+        // A parse error inside leaves this reader at the bit it was raised at, for salvaging to report
+        if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(|| cb(subnode, &mut reader))) {
+            self.bit_index = start_idx * 8 + reader.bit_index;
+            panic::resume_unwind(payload);
+        }
+        self.bit_index = end_idx * 8;
+    }
+
+    fn more_data(&mut self, _node: &mut SyntaxNode) -> bool {
+        if self.buffer.is_empty() {
+            false
+        } else if self.bit_index/8 == self.buffer.len()-1 {
+            (u32::from(self.buffer[self.buffer.len()-1]) & ((1 << (8 - self.bit_index % 8)) - 1)).count_ones() != 1
         } else if self.bit_index/8 < self.buffer.len()-1 {
             true
         } else {
             false
         }
     }
+
+    fn bit_position(&self) -> usize {
+        self.bit_index
+    }
+}
+
+// What a BitstreamTracer saw, with positions in bits from the start of the buffer it was given
+pub enum TraceEntry {
+    Field { position: usize, name: String, bits: String, value: i32 },
+    Payload { position: usize, name: String, data: Vec<u8> },
+}
+
+// A reader that also records the coded bits behind every field it reads, for trace style output.
+pub struct BitstreamTracer<'a> {
+    reader: BitstreamReader<'a>,
+    base: usize,
+    pub entries: Vec<TraceEntry>,
 }
 
+impl BitstreamTracer<'_> {
+    pub fn new(buffer: &[u8]) -> BitstreamTracer<'_> {
+        BitstreamTracer { reader: BitstreamReader::new(buffer), base: 0, entries: vec![] }
+    }
+}
+
+impl BitstreamProcessor for BitstreamTracer<'_> {
+    fn field(&mut self, node: &mut SyntaxNode, name: &str, field_type: FieldType, n: u8) -> i32 {
+        let start = self.reader.bit_index;
+        let value = self.reader.field(node, name, field_type, n);
+        let bits = (start..self.reader.bit_index)
+            .map(|x| if (self.reader.buffer[x / 8] << (x % 8)) & 0x80 != 0 { '1' } else { '0' })
+            .collect();
+        self.entries.push(TraceEntry::Field { position: self.base + start, name: name.to_string(), bits, value });
+        value
+    }
+
+    fn subnode<A>(&mut self, node: &mut SyntaxNode, name: &str, mut cb: A)
+        where A: FnMut(&mut SyntaxNode, &mut Self) {
+        let mut subnode = SyntaxNode::new(name);
+        cb(&mut subnode, self);
+        node.children.push_back(SyntaxElement::Node(subnode));
+    }
+
+    fn payload(&mut self, node: &mut SyntaxNode, name: &str) {
+        let position = self.base + self.reader.bit_index;
+        self.reader.payload(node, name);
+        if let Some(SyntaxElement::Payload(payload)) = node.children.back() {
+            self.entries.push(TraceEntry::Payload { position, name: name.to_string(), data: payload.data.clone() });
+        }
+    }
+
+    fn fixed_payload(&mut self, node: &mut SyntaxNode, name: &str, size: usize) {
+        let position = self.base + self.reader.bit_index;
+        self.reader.fixed_payload(node, name, size);
+        if let Some(SyntaxElement::Payload(payload)) = node.children.back() {
+            self.entries.push(TraceEntry::Payload { position, name: name.to_string(), data: payload.data.clone() });
+        }
+    }
+
+    fn text_payload(&mut self, node: &mut SyntaxNode, name: &str) {
+        self.payload(node, name);
+        if let Some(SyntaxElement::Payload(payload)) = node.children.back_mut() {
+            payload.text = true;
+        }
+    }
+
+    fn sized_subnode<A>(&mut self, node: &mut SyntaxNode, size_name: &str, name: &str, mut cb: A)
+        where A: FnMut(&mut SyntaxNode, &mut Self) {
+        let size = self.field(node, size_name, FieldType::FFCoded, 0) as usize;
+        let (start_idx, end_idx) = sized_subnode_bytes(self.reader.bit_index, self.reader.buffer.len(), size_name, size, name);
+        let mut tracer = BitstreamTracer::new(&self.reader.buffer[start_idx..end_idx]);
+        tracer.base = self.base + start_idx * 8;
+        let mut subnode = SyntaxNode::new(name);
+        cb(&mut subnode, &mut tracer);
+        node.children.push_back(SyntaxElement::Node(subnode));
+        self.entries.append(&mut tracer.entries);
+        self.reader.bit_index = end_idx * 8;
+    }
+
+    fn more_data(&mut self, node: &mut SyntaxNode) -> bool {
+        self.reader.more_data(node)
+    }
+
+    fn bit_position(&self) -> usize {
+        self.reader.bit_index
+    }
+}
+
+#[derive(Default)]
 pub struct BitstreamWriter {
     pub buffer: Vec<u8>,
     bit_index: usize,
+    // Write a fresh stop bit and alignment for "trailing_bits" payloads instead of copying them, so they
+    // stay valid when the fields in front of them change length
+    pub regenerate_trailing_bits: bool,
 }
 
 impl BitstreamWriter {
+    // Escape everything written since the byte at start_idx, once a NALU's RBSP is complete
+    pub fn insert_emulation_prevention(&mut self, start_idx: usize) {
+        assert!(self.bit_index.is_multiple_of(8), "NALU is not byte aligned");
+        let ebsp = rbsp_to_ebsp(&self.buffer[start_idx..]);
+        self.buffer.truncate(start_idx);
+        self.buffer.extend(ebsp);
+        self.bit_index = self.buffer.len() * 8;
+    }
+
     fn write_bit(&mut self, bit: bool) -> () {
         let byte_index = self.bit_index / 8;
         while byte_index >= self.buffer.len() {
@@ -231,6 +631,37 @@ impl BitstreamWriter {
                     self.write(FieldType::UnsignedExpGolomb, 0, -2 * val);
                 }
             },
+            FieldType::FFCoded => {
+                let mut val = val;
+                while val >= 0xFF {
+                    self.write(FieldType::UnsignedInt, 8, 0xFF);
+                    val -= 0xFF;
+                }
+                self.write(FieldType::UnsignedInt, 8, val);
+            },
+            FieldType::TruncatedExpGolomb => {
+                if n > 1 {
+                    self.write(FieldType::UnsignedExpGolomb, 0, val);
+                } else {
+                    self.write_bit(val == 0);
+                }
+            },
+            FieldType::MappedExpGolomb(table) => {
+                let code_num = table.iter().position(|x| *x == val).unwrap_or_else(|| panic!("Value {} has no codeNum", val));
+                self.write(FieldType::UnsignedExpGolomb, 0, code_num as i32);
+            },
+            FieldType::Unary => {
+                for _i in 0..val {
+                    self.write_bit(false);
+                }
+                self.write_bit(true);
+            },
+            FieldType::Vlc(table) => {
+                let Some(entry) = table.iter().find(|x| x.2 == val) else {
+                    panic!("Value {} has no variable length code", val);
+                };
+                self.write(FieldType::UnsignedInt, entry.0, entry.1 as i32);
+            },
             _ => {
                 // Signed and unsigned are handled the same
                 for i in 0..n {
@@ -241,34 +672,68 @@ impl BitstreamWriter {
     }
 
     pub fn new() -> BitstreamWriter {
-        BitstreamWriter { buffer: vec![], bit_index: 0 }
+        BitstreamWriter { buffer: vec![], bit_index: 0, regenerate_trailing_bits: false }
+    }
+}
+
+// The next child of a node being written, which the syntax says must be the given kind of element with the given
+// name. Anything else is an error pointing at the line of the text form it came from.
+fn expect_child(node: &mut SyntaxNode, kind: &str, name: &str) -> SyntaxElement {
+    let Some(child) = node.children.pop_front() else {
+        panic!("{}expected {} {} in {}, found the end of {}", location(node.line), kind, name, node.name, node.name);
+    };
+    if child.kind() != kind || child.name() != name {
+        panic!("{}expected {} {}, found {} {}", location(child.line()), kind, name, child.kind(), child.name());
+    }
+
+    child
+}
+
+// Children the syntax didn't read are left out of the bitstream
+pub fn warn_unread_children(node: &SyntaxNode) {
+    for child in &node.children {
+        eprintln!("Warning: {}unexpected {} {} at the end of {} is ignored", location(child.line()), child.kind(), child.name(), node.name);
     }
 }
 
 impl BitstreamProcessor for BitstreamWriter {
     fn field(&mut self, node: &mut SyntaxNode, name: &str, field_type: FieldType, n: u8) -> i32 {
-        let SyntaxElement::Field(child) = node.children.pop_front().expect(&format!("Expected {} but got nothing!", name)) else {
-            panic!("Invalid syntax element at {name}");
+        let SyntaxElement::Field(child) = expect_child(node, "field", name) else {
+            unreachable!();
         };
-        assert_eq!(child.name, name, "Expected {}, got {}", name, child.name);
+        if matches!(field_type, FieldType::UnsignedInt) && (child.val < 0 || (n < 31 && child.val >= 1 << n)) {
+            eprintln!("Warning: {} value {} does not fit in its {} bit field", name, child.val, n);
+        }
         self.write(field_type, n, child.val);
         child.val
     }
 
     fn subnode<A>(&mut self, node: &mut SyntaxNode, name: &str, mut cb: A) -> ()
         where A: FnMut(&mut SyntaxNode, &mut Self) -> () {
-        let SyntaxElement::Node(mut subnode) = node.children.pop_front().expect(&format!("Expected {} but got nothing!", name)) else {
-            panic!("Invalid syntax element at {name}");
+        let SyntaxElement::Node(mut subnode) = expect_child(node, "node", name) else {
+            unreachable!();
         };
-        assert_eq!(subnode.name, name, "Expected {}, got {}", name, subnode.name);
         cb(&mut subnode, self);
+        warn_unread_children(&subnode);
+    }
+
+    fn derived(&mut self, node: &mut SyntaxNode, name: &str, _val: i32) {
+        if matches!(node.children.front(), Some(SyntaxElement::Field(field)) if field.name == name) {
+            node.children.pop_front();
+        }
     }
 
     fn payload(&mut self, node: &mut SyntaxNode, name: &str) -> () {
-        let SyntaxElement::Payload(child) = node.children.pop_front().expect(&format!("Expected {} but got nothing!", name)) else {
-            panic!("Invalid syntax element at {name}");
+        let SyntaxElement::Payload(child) = expect_child(node, "payload", name) else {
+            unreachable!();
         };
-        assert_eq!(child.name, name, "Expected {}, got {}", name, child.name);
+        if self.regenerate_trailing_bits && name == "trailing_bits" {
+            self.write_bit(true);
+            while !self.bit_index.is_multiple_of(8) {
+                self.write_bit(false);
+            }
+            return;
+        }
         let start_idx = if self.bit_index % 8 != 0 && child.data.len() > 0 {
             self.write(FieldType::UnsignedInt,
                        (8 - (self.bit_index % 8)).try_into().unwrap(),
@@ -282,14 +747,57 @@ impl BitstreamProcessor for BitstreamWriter {
         }
     }
 
+    fn fixed_payload(&mut self, node: &mut SyntaxNode, name: &str, size: usize) {
+        let SyntaxElement::Payload(child) = expect_child(node, "payload", name) else {
+            unreachable!();
+        };
+        assert_eq!(child.data.len(), size, "{}{} must be exactly {} bytes", location(child.line), name, size);
+        for byte in child.data {
+            self.write(FieldType::UnsignedInt, 8, i32::from(byte));
+        }
+    }
+
+    fn sized_subnode<A>(&mut self, node: &mut SyntaxNode, size_name: &str, name: &str, mut cb: A)
+        where A: FnMut(&mut SyntaxNode, &mut Self) {
+        let SyntaxElement::Field(size) = expect_child(node, "field", size_name) else {
+            unreachable!();
+        };
+        let SyntaxElement::Node(mut subnode) = expect_child(node, "node", name) else {
+            unreachable!();
+        };
+        // Serialize the contents first so the size reflects any edits rather than the value in the text
+        let mut writer = BitstreamWriter::new();
+        cb(&mut subnode, &mut writer);
+        warn_unread_children(&subnode);
+        if !writer.bit_index.is_multiple_of(8) {
+            writer.write_bit(true);
+            while !writer.bit_index.is_multiple_of(8) {
+                writer.write_bit(false);
+            }
+        }
+        let actual_size = writer.buffer.len() as i32;
+        if actual_size != size.val {
+            eprintln!("Fixup: recomputing {} from {} to {}", size_name, size.val, actual_size);
+        }
+        self.write(FieldType::FFCoded, 0, actual_size);
+        for byte in writer.buffer {
+            self.write(FieldType::UnsignedInt, 8, i32::from(byte));
+        }
+    }
+
     fn more_data(&mut self, node: &mut SyntaxNode) -> bool {
         match node.children.len() {
             0 => false,
-            1 => match node.children[0] {
-                SyntaxElement::Payload(_) => false,
+            // The rbsp trailing bits are all that's left
+            1 => match &node.children[0] {
+                SyntaxElement::Payload(payload) => payload.name != "trailing_bits",
                 _ => true,
             },
             _ => true,
         }
     }
+
+    fn bit_position(&self) -> usize {
+        self.bit_index
+    }
 }
@@ -0,0 +1,314 @@
+use std::collections::VecDeque;
+
+use crate::bitstream_util::SyntaxElement;
+use crate::bitstream_util::SyntaxNode;
+use crate::h264_parser::parse_h264;
+
+// Big endian reads over a byte buffer. FLV and RTMP lay everything out in whole bytes.
+struct ByteCursor<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> ByteCursor<'a> {
+    fn new(data: &'a [u8]) -> ByteCursor<'a> {
+        ByteCursor { data, pos: 0 }
+    }
+
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        let ret = self.data.get(self.pos..self.pos + n)?;
+        self.pos += n;
+        Some(ret)
+    }
+
+    fn read(&mut self, n: usize) -> Option<u32> {
+        Some(self.take(n)?.iter().fold(0, |acc, x| (acc << 8) | u32::from(*x)))
+    }
+}
+
+pub struct AvcDecoderConfigurationRecord<'a> {
+    configuration_version: u32,
+    avc_profile_indication: u32,
+    profile_compatibility: u32,
+    avc_level_indication: u32,
+    length_size_minus_one: u32,
+    sps: Vec<&'a [u8]>,
+    pps: Vec<&'a [u8]>,
+}
+
+pub struct AvcVideoTag<'a> {
+    frame_type: u32,
+    codec_id: u32,
+    avc_packet_type: u32,
+    composition_time: i32,
+    configuration_record: Option<AvcDecoderConfigurationRecord<'a>>,
+    nalus: Vec<&'a [u8]>,
+}
+
+pub struct FlvTag<'a> {
+    tag_type: u32,
+    timestamp: u32,
+    stream_id: u32,
+    video: Option<AvcVideoTag<'a>>,
+}
+
+fn parse_configuration_record(data: &[u8]) -> Option<AvcDecoderConfigurationRecord<'_>> {
+    let mut cursor = ByteCursor::new(data);
+    let configuration_version = cursor.read(1)?;
+    let avc_profile_indication = cursor.read(1)?;
+    let profile_compatibility = cursor.read(1)?;
+    let avc_level_indication = cursor.read(1)?;
+    let length_size_minus_one = cursor.read(1)? & 0x03;
+    let mut sps: Vec<&[u8]> = vec![];
+    for _ in 0..(cursor.read(1)? & 0x1F) {
+        let len = cursor.read(2)? as usize;
+        sps.push(cursor.take(len)?);
+    }
+    let mut pps: Vec<&[u8]> = vec![];
+    for _ in 0..cursor.read(1)? {
+        let len = cursor.read(2)? as usize;
+        pps.push(cursor.take(len)?);
+    }
+    Some(AvcDecoderConfigurationRecord { configuration_version, avc_profile_indication, profile_compatibility,
+                                         avc_level_indication, length_size_minus_one, sps, pps })
+}
+
+// The body of an FLV video tag, which is also the payload of an RTMP video message. NALU packets are length
+// prefixed, with the prefix size taken from the last configuration record seen.
+pub fn parse_avc_video_tag<'a>(body: &'a [u8], nalu_length_size: &mut usize) -> Option<AvcVideoTag<'a>> {
+    let mut cursor = ByteCursor::new(body);
+    let flags = cursor.read(1)?;
+    let mut ret = AvcVideoTag { frame_type: flags >> 4, codec_id: flags & 0x0F, avc_packet_type: 0, composition_time: 0,
+                                configuration_record: None, nalus: vec![] };
+    // Only AVC (codec id 7) carries the packet type and composition time
+    if ret.codec_id != 7 {
+        return Some(ret);
+    }
+    ret.avc_packet_type = cursor.read(1)?;
+    // SI24
+    ret.composition_time = ((cursor.read(3)? << 8) as i32) >> 8;
+    match ret.avc_packet_type {
+        0 => {
+            let record = parse_configuration_record(&body[cursor.pos..])?;
+            *nalu_length_size = record.length_size_minus_one as usize + 1;
+            ret.configuration_record = Some(record);
+        },
+        1 => {
+            while cursor.pos < body.len() {
+                let len = cursor.read(*nalu_length_size)? as usize;
+                ret.nalus.push(cursor.take(len)?);
+            }
+        },
+        _ => (),
+    }
+    Some(ret)
+}
+
+fn read_tag_header(cursor: &mut ByteCursor) -> Option<(u32, usize, u32, u32)> {
+    let tag_type = cursor.read(1)?;
+    let data_size = cursor.read(3)? as usize;
+    let timestamp = cursor.read(3)?;
+    let timestamp_extended = cursor.read(1)?;
+    let stream_id = cursor.read(3)?;
+    Some((tag_type, data_size, (timestamp_extended << 24) | timestamp, stream_id))
+}
+
+pub fn parse_flv_tags(flv: &[u8]) -> Vec<FlvTag<'_>> {
+    let mut ret: Vec<FlvTag> = vec![];
+    if flv.len() < 9 || &flv[0..3] != b"FLV" {
+        panic!("Not an FLV file");
+    }
+    let header_size = u32::from_be_bytes(flv[5..9].try_into().unwrap()) as usize;
+    let mut cursor = ByteCursor::new(flv);
+    cursor.pos = header_size;
+    let mut nalu_length_size = 4;
+    // Every tag is preceded by the size of the one before it
+    while cursor.read(4).is_some() && cursor.pos < flv.len() {
+        let offset = cursor.pos;
+        let Some((tag_type, data_size, timestamp, stream_id)) = read_tag_header(&mut cursor) else {
+            eprintln!("Warning: FLV tag header at byte {} is truncated", offset);
+            break;
+        };
+        let Some(body) = cursor.take(data_size) else {
+            eprintln!("Warning: FLV tag at byte {} is truncated", offset);
+            break;
+        };
+        // Filtered (encrypted) tags have bit 5 of the tag type set, so they're left alone here
+        let video = if tag_type == 9 {
+            let video = parse_avc_video_tag(body, &mut nalu_length_size);
+            if video.is_none() {
+                eprintln!("Warning: malformed video tag at byte {}", offset);
+            }
+            video
+        } else {
+            None
+        };
+        ret.push(FlvTag { tag_type, timestamp, stream_id, video });
+    }
+
+    ret
+}
+
+// The H.264 NALUs carried by a tag, parameter sets from a configuration record included
+fn tag_nalus<'a>(tag: &FlvTag<'a>) -> Vec<&'a [u8]> {
+    let Some(video) = &tag.video else {
+        return vec![];
+    };
+    let mut ret: Vec<&[u8]> = vec![];
+    if let Some(record) = &video.configuration_record {
+        ret.extend(&record.sps);
+        ret.extend(&record.pps);
+    }
+    ret.extend(&video.nalus);
+    ret
+}
+
+// Rewrap the video of an FLV file as an Annex B stream, so every other mode can work on it.
+pub fn flv_to_annexb(flv: &[u8]) -> Vec<u8> {
+    let mut ret: Vec<u8> = vec![];
+    for tag in parse_flv_tags(flv) {
+        for nalu in tag_nalus(&tag).into_iter().filter(|x| !x.is_empty()) {
+            ret.extend([0x00, 0x00, 0x00, 0x01]);
+            ret.extend(nalu);
+        }
+    }
+    ret
+}
+
+fn push_nalus(node: &mut SyntaxNode, length_name: &str, nalus: &[&[u8]], parsed: &mut VecDeque<SyntaxElement>) {
+    for nalu in nalus {
+        node.push_field(length_name, nalu.len() as i32);
+        if nalu.is_empty() {
+            continue;
+        }
+        if let Some(parsed_nalu) = parsed.pop_front() {
+            node.children.push_back(parsed_nalu);
+        }
+    }
+}
+
+// The FLV tag structure with the H.264 NALUs inside every AVC video tag parsed in place.
+pub fn flv_syntax_tree(flv: &[u8]) -> Vec<SyntaxElement> {
+    let tags = parse_flv_tags(flv);
+    let mut parsed: VecDeque<SyntaxElement> = parse_h264(&flv_to_annexb(flv)).into();
+    let mut ret: Vec<SyntaxElement> = vec![];
+    for tag in &tags {
+        let mut node = SyntaxNode::new("flv_tag");
+        node.push_field("tag_type", tag.tag_type as i32);
+        node.push_field("timestamp", tag.timestamp as i32);
+        node.push_field("stream_id", tag.stream_id as i32);
+        if let Some(video) = &tag.video {
+            node.push_field("frame_type", video.frame_type as i32);
+            node.push_field("codec_id", video.codec_id as i32);
+            if video.codec_id == 7 {
+                node.push_field("avc_packet_type", video.avc_packet_type as i32);
+                node.push_field("composition_time", video.composition_time);
+            }
+            if let Some(record) = &video.configuration_record {
+                let mut record_node = SyntaxNode::new("avc_decoder_configuration_record");
+                record_node.push_field("configuration_version", record.configuration_version as i32);
+                record_node.push_field("avc_profile_indication", record.avc_profile_indication as i32);
+                record_node.push_field("profile_compatibility", record.profile_compatibility as i32);
+                record_node.push_field("avc_level_indication", record.avc_level_indication as i32);
+                record_node.push_field("length_size_minus_one", record.length_size_minus_one as i32);
+                record_node.push_field("num_of_sequence_parameter_sets", record.sps.len() as i32);
+                push_nalus(&mut record_node, "sequence_parameter_set_length", &record.sps, &mut parsed);
+                record_node.push_field("num_of_picture_parameter_sets", record.pps.len() as i32);
+                push_nalus(&mut record_node, "picture_parameter_set_length", &record.pps, &mut parsed);
+                node.children.push_back(SyntaxElement::Node(record_node));
+            }
+            push_nalus(&mut node, "nalu_length", &video.nalus, &mut parsed);
+        }
+        ret.push(SyntaxElement::Node(node));
+    }
+
+    ret
+}
+
+// Slices and slice data partitions make up the pictures a tag's sample carries (Table 7-1)
+fn carries_picture_data(nal_unit_type: u8) -> bool {
+    matches!(nal_unit_type, 1..=5 | 20 | 21)
+}
+
+// The tag-level properties a rewrite must leave alone so audio and video stay in sync: type, timestamp, stream,
+// frame type, packet type and composition time
+fn tag_timing(tag: &FlvTag) -> (u32, u32, u32, Option<(u32, u32, i32)>) {
+    let video = tag.video.as_ref().map(|x| (x.frame_type, x.avc_packet_type, x.composition_time));
+    (tag.tag_type, tag.timestamp, tag.stream_id, video)
+}
+
+// Drop the listed NALU types from the AVC NALU packets of an FLV file and write the file back. Every tag is kept,
+// emptied ones included, with its timestamp and composition time, so samples and their timing line up with the
+// other tracks as before; NALU types that carry picture data are refused since dropping them changes what a
+// sample holds. Tags other than AVC NALU packets are copied as is. The output is checked to hold the same tags
+// with the same timing and the kept NALUs byte for byte.
+pub fn strip_flv_nalus(flv: &[u8], drop_types: &[u8]) -> Vec<u8> {
+    if let Some(nal_unit_type) = drop_types.iter().find(|x| carries_picture_data(**x)) {
+        panic!("Dropping NALU type {} would change the pictures the FLV samples carry", nal_unit_type);
+    }
+    let input_tags = parse_flv_tags(flv);
+    let header_size = u32::from_be_bytes(flv[5..9].try_into().unwrap()) as usize;
+    let mut ret: Vec<u8> = flv[..header_size].to_vec();
+    let mut cursor = ByteCursor::new(flv);
+    cursor.pos = header_size;
+    let mut nalu_length_size = 4;
+    let mut kept: Vec<&[u8]> = vec![];
+    let mut dropped = 0;
+    let mut previous_tag_size = 0;
+    // Every tag is preceded by the size of the one before it, and a truncated tag is left out as when parsing
+    while cursor.read(4).is_some() && cursor.pos < flv.len() {
+        let offset = cursor.pos;
+        let (Some((tag_type, data_size, _, _)), Some(header)) = (read_tag_header(&mut cursor), flv.get(offset..offset + 11)) else {
+            break;
+        };
+        let Some(body) = cursor.take(data_size) else {
+            break;
+        };
+        let video = if tag_type == 9 { parse_avc_video_tag(body, &mut nalu_length_size) } else { None };
+        let new_body = match video {
+            Some(video) if video.codec_id == 7 && video.avc_packet_type == 1 => {
+                // Flags, packet type and composition time
+                let mut new_body = body[..5].to_vec();
+                for nalu in video.nalus {
+                    if nalu.first().is_some_and(|x| drop_types.contains(&(x & 0x1F))) {
+                        dropped += 1;
+                        continue;
+                    }
+                    new_body.extend(&(nalu.len() as u32).to_be_bytes()[4 - nalu_length_size..]);
+                    new_body.extend(nalu);
+                    kept.push(nalu);
+                }
+                new_body
+            },
+            Some(video) => {
+                kept.extend(video.nalus);
+                body.to_vec()
+            },
+            None => body.to_vec(),
+        };
+        ret.extend((previous_tag_size as u32).to_be_bytes());
+        ret.extend(&header[..1]);
+        ret.extend(&(new_body.len() as u32).to_be_bytes()[1..]);
+        ret.extend(&header[4..]);
+        ret.extend(&new_body);
+        previous_tag_size = header.len() + new_body.len();
+    }
+    ret.extend((previous_tag_size as u32).to_be_bytes());
+
+    let output_tags = parse_flv_tags(&ret);
+    if output_tags.len() != input_tags.len() {
+        panic!("Verification failed: the input has {} tags but the output {}", input_tags.len(), output_tags.len());
+    }
+    for (idx, (input, output)) in input_tags.iter().zip(&output_tags).enumerate() {
+        if tag_timing(input) != tag_timing(output) {
+            panic!("Verification failed: tag {} of the output has different timing from the input", idx);
+        }
+    }
+    let emitted: Vec<&[u8]> = output_tags.iter().flat_map(|x| x.video.iter().flat_map(|y| y.nalus.iter().copied())).collect();
+    if emitted != kept {
+        panic!("Verification failed: the NALUs of the output differ from the ones kept");
+    }
+    eprintln!("Verified {} tags kept with their timing and {} NALUs passed through bit-exact, {} dropped", output_tags.len(), kept.len(), dropped);
+
+    ret
+}
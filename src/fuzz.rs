@@ -0,0 +1,106 @@
+use std::any::Any;
+use std::collections::VecDeque;
+use std::panic;
+
+use crate::bitstream_util::SyntaxElement;
+use crate::bitstream_util::syntax_elements_from_string;
+use crate::h264_parser::SerializeOptions;
+use crate::h264_parser::parse_h264;
+use crate::h264_parser::serialize_h264_nalus;
+
+#[derive(Debug)]
+pub enum RoundtripError {
+    // Malformed input the parser rejected
+    ParsePanic(String),
+    // A panic the parser didn't raise on purpose, like an index out of bounds or an overflow
+    UnexpectedPanic(String),
+    // Serializing a tree the parser produced should never fail
+    SerializePanic(String),
+    // The serialized stream couldn't be parsed back
+    ReparsePanic(String),
+    // The parse of the serialized stream serialized to different bytes, so parse and serialize disagree
+    NotIdempotent { first_len: usize, second_len: usize },
+    // Parsing the same bytes twice printed different text
+    NotDeterministic,
+    // The printed text didn't read back into the same text, with the first differing line or the panic
+    TextNotStable(String),
+}
+
+pub fn panic_message(payload: &(dyn Any + Send)) -> String {
+    match payload.downcast_ref::<String>() {
+        Some(message) => message.clone(),
+        None => payload.downcast_ref::<&str>().map(|x| x.to_string()).unwrap_or("unknown panic".to_string()),
+    }
+}
+
+// How the language and standard library word the panics they raise for bugs. The parser rejects malformed
+// input with panics of its own, which never start like these.
+const UNEXPECTED_PANICS: [&str; 14] = ["index out of bounds", "attempt to ", "called `Option::unwrap()`",
+                                       "called `Result::unwrap()`", "range start index", "range end index",
+                                       "slice index", "byte index", "capacity overflow", "assertion", "internal error",
+                                       "not implemented", "not yet implemented", "unknown panic"];
+
+pub fn is_unexpected_panic(message: &str) -> bool {
+    UNEXPECTED_PANICS.iter().any(|x| message.starts_with(x))
+}
+
+fn parse_error(message: String) -> RoundtripError {
+    if is_unexpected_panic(&message) {
+        RoundtripError::UnexpectedPanic(message)
+    } else {
+        RoundtripError::ParsePanic(message)
+    }
+}
+
+fn roundtrip_once(bytes: &[u8]) -> Result<Vec<u8>, RoundtripError> {
+    let nalus = panic::catch_unwind(|| parse_h264(bytes)).map_err(|x| parse_error(panic_message(&*x)))?;
+    panic::catch_unwind(|| serialize_h264_nalus(VecDeque::from(nalus), &SerializeOptions::default()))
+        .map_err(|x| RoundtripError::SerializePanic(panic_message(&*x)))
+}
+
+fn roundtrip_twice(bytes: &[u8]) -> Result<(), RoundtripError> {
+    let first = roundtrip_once(bytes)?;
+    let second = match roundtrip_once(&first) {
+        Err(RoundtripError::ParsePanic(message)) => return Err(RoundtripError::ReparsePanic(message)),
+        x => x?,
+    };
+    if first != second {
+        return Err(RoundtripError::NotIdempotent { first_len: first.len(), second_len: second.len() });
+    }
+
+    check_text_form(&first)
+}
+
+fn print(nalus: &[SyntaxElement]) -> String {
+    nalus.iter().map(|x| x.to_string()).collect()
+}
+
+// Golden files of the text form rely on it depending only on the input bytes, and on reading it back
+// printing the same text.
+fn check_text_form(bytes: &[u8]) -> Result<(), RoundtripError> {
+    let text = panic::catch_unwind(|| print(&parse_h264(bytes))).map_err(|x| RoundtripError::ReparsePanic(panic_message(&*x)))?;
+    if print(&parse_h264(bytes)) != text {
+        return Err(RoundtripError::NotDeterministic);
+    }
+    let reread = panic::catch_unwind(|| {
+        let mut rows = text.split('\n').map(|x| x.to_string()).collect();
+        print(&Vec::from(syntax_elements_from_string(&mut rows)))
+    }).map_err(|x| RoundtripError::TextNotStable(panic_message(&*x)))?;
+    if let Some((line, _)) = text.lines().zip(reread.lines()).find(|(x, y)| x != y) {
+        return Err(RoundtripError::TextNotStable(line.to_string()));
+    }
+    if text.len() != reread.len() {
+        return Err(RoundtripError::TextNotStable("text length differs".to_string()));
+    }
+
+    Ok(())
+}
+
+// Tokenize, parse and serialize arbitrary bytes, then do it again on the output. The input itself needn't
+// survive (start codes and stray bytes are normalized), but the second pass must reproduce the first, and
+// the text form of the output must be deterministic and read back unchanged.
+// Panics are caught as they unwind, so the panic hook in effect still runs first. Fuzzers install one that
+// aborts, which the caller replaces once before fuzzing, as the fuzz target does.
+pub fn fuzz_roundtrip(bytes: &[u8]) -> Result<(), RoundtripError> {
+    roundtrip_twice(bytes)
+}
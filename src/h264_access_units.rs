@@ -0,0 +1,86 @@
+use std::ops::Range;
+
+use crate::bitstream_util::SyntaxElement;
+use crate::bitstream_util::SyntaxNode;
+use crate::h264_parser::get_slice_header;
+
+// The slice header values that tell two slices apart as belonging to different primary pictures (7.4.1.2.4)
+#[derive(PartialEq)]
+struct PictureKey {
+    frame_num: Option<i32>,
+    pic_parameter_set_id: Option<i32>,
+    field_pic_flag: Option<i32>,
+    bottom_field_flag: Option<i32>,
+    nal_ref_idc_zero: bool,
+    pic_order_cnt_lsb: Option<i32>,
+    delta_pic_order_cnt_bottom: Option<i32>,
+    delta_pic_order_cnt_0: Option<i32>,
+    delta_pic_order_cnt_1: Option<i32>,
+    idr_pic_flag: bool,
+    idr_pic_id: Option<i32>,
+}
+
+impl PictureKey {
+    fn new(nalu: &SyntaxNode, header: &SyntaxNode) -> PictureKey {
+        PictureKey { frame_num: header.get_field("frame_num"),
+                     pic_parameter_set_id: header.get_field("pic_parameter_set_id"),
+                     field_pic_flag: header.get_field("field_pic_flag"),
+                     bottom_field_flag: header.get_field("bottom_field_flag"),
+                     nal_ref_idc_zero: nalu.get_field("nal_ref_idc") == Some(0),
+                     pic_order_cnt_lsb: header.get_field("pic_order_cnt_lsb"),
+                     delta_pic_order_cnt_bottom: header.get_field("delta_pic_order_cnt_bottom"),
+                     delta_pic_order_cnt_0: header.get_field("delta_pic_order_cnt[0]"),
+                     delta_pic_order_cnt_1: header.get_field("delta_pic_order_cnt[1]"),
+                     idr_pic_flag: nalu.get_field("nal_unit_type") == Some(5),
+                     idr_pic_id: header.get_field("idr_pic_id"),
+        }
+    }
+}
+
+pub fn is_vcl_nalu(nal_unit_type: i32) -> bool {
+    (1..=5).contains(&nal_unit_type)
+}
+
+// NALU types that, following the last VCL NALU of a picture, begin the next access unit (7.4.1.2.3)
+fn starts_access_unit(nal_unit_type: i32) -> bool {
+    (6..=9).contains(&nal_unit_type) || (14..=18).contains(&nal_unit_type)
+}
+
+// Group a parsed stream into access units, returned as ranges of NALU indices. Auxiliary slices and MVC slice
+// extensions are not VCL NALUs of the primary picture and don't start an access unit, so they stay in the one
+// of the primary picture before them.
+pub fn split_access_units(nalus: &[SyntaxElement]) -> Vec<Range<usize>> {
+    let mut ret: Vec<Range<usize>> = vec![];
+    let mut start_idx = 0;
+    let mut seen_vcl = false;
+    let mut last_picture: Option<PictureKey> = None;
+    for (idx, element) in nalus.iter().enumerate() {
+        let SyntaxElement::Node(nalu) = element else {
+            continue;
+        };
+        let nal_unit_type = nalu.get_field("nal_unit_type").unwrap_or(0);
+        let mut new_access_unit = false;
+        if starts_access_unit(nal_unit_type) {
+            new_access_unit = seen_vcl;
+            seen_vcl = false;
+        } else if is_vcl_nalu(nal_unit_type) {
+            // Redundant coded pictures belong to the primary picture before them
+            let redundant = get_slice_header(nalu).and_then(|x| x.get_field("redundant_pic_cnt")).unwrap_or(0) > 0;
+            if let (Some(header), false) = (get_slice_header(nalu), redundant) {
+                let picture = PictureKey::new(nalu, header);
+                new_access_unit = seen_vcl && last_picture.as_ref() != Some(&picture);
+                last_picture = Some(picture);
+            }
+            seen_vcl = true;
+        }
+        if new_access_unit && idx != start_idx {
+            ret.push(start_idx..idx);
+            start_idx = idx;
+        }
+    }
+    if start_idx != nalus.len() {
+        ret.push(start_idx..nalus.len());
+    }
+
+    ret
+}
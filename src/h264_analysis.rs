@@ -0,0 +1,870 @@
+use std::collections::HashMap;
+use std::ops::Range;
+
+use crate::bitstream_util::SyntaxElement;
+use crate::bitstream_util::SyntaxNode;
+use crate::h264_access_units::split_access_units;
+use crate::h264_parser::get_slice_header;
+use crate::h264_parser::nal_unit_type_name;
+use crate::h264_parser::nalu_contents;
+use crate::h264_parser::tokenize_h264_nalus;
+use crate::h264_profiles::level_bitrate_limits;
+use crate::h264_profiles::level_max_mb_rate;
+use crate::h264_rewrite::nalu_chunks;
+use crate::h264_slice_groups::mb_to_slice_group_map;
+
+// SPS and PPS nodes by id as they appear in the stream, to resolve the ones a slice refers to
+#[derive(Default)]
+pub struct ParameterSets<'a> {
+    sps: HashMap<i32, &'a SyntaxNode>,
+    pps: HashMap<i32, &'a SyntaxNode>,
+}
+
+impl<'a> ParameterSets<'a> {
+    pub fn new() -> ParameterSets<'a> {
+        ParameterSets { sps: HashMap::new(), pps: HashMap::new() }
+    }
+
+    pub fn update(&mut self, nalu: &'a SyntaxNode) {
+        if let Some(sps) = nalu.get_node("sps") {
+            if let Some(id) = sps.get_field("seq_paramter_set_id") {
+                self.sps.insert(id, sps);
+            }
+        }
+        if let Some(pps) = nalu.get_node("pps") {
+            if let Some(id) = pps.get_field("pic_parameter_set_id") {
+                self.pps.insert(id, pps);
+            }
+        }
+    }
+
+    // (SPS, PPS) behind a slice header
+    pub fn for_slice(&self, header: &SyntaxNode) -> Option<(&'a SyntaxNode, &'a SyntaxNode)> {
+        let pps = *self.pps.get(&header.get_field("pic_parameter_set_id")?)?;
+        let sps = *self.sps.get(&pps.get_field("seq_parameter_set_id")?)?;
+        Some((sps, pps))
+    }
+}
+
+pub struct FrameStats {
+    pub frame_type: char,
+    pub bytes: usize,
+}
+
+// Frame rate signaled by an SPS's VUI timing_info, if any
+pub fn sps_frame_rate(sps: &SyntaxNode) -> Option<f64> {
+    let vui = sps.get_node("vui_parameters")?;
+    if vui.get_field("timing_info_present_flag")? == 0 {
+        return None;
+    }
+    let num_units_in_tick = vui.get_field("num_units_in_tick")? as u32;
+    let time_scale = vui.get_field("time_scale")? as u32;
+    (num_units_in_tick != 0).then(|| f64::from(time_scale) / (2.0 * f64::from(num_units_in_tick)))
+}
+
+// Displayed size of an SPS's frames, after cropping
+pub fn sps_resolution(sps: &SyntaxNode) -> (i32, i32) {
+    let field = |name: &str| sps.get_field(name).unwrap_or(0);
+    let frame_mbs_only_flag = sps.get_field("frame_mbs_only_flag").unwrap_or(1);
+    let width = (field("pic_width_in_mbs_minus1") + 1) * 16;
+    let height = (field("pic_height_in_mbs_minus1") + 1) * 16 * (2 - frame_mbs_only_flag);
+    // Crop offsets count chroma samples, CropUnitX/CropUnitY in 7.4.2.1.1
+    let chroma_array_type = if field("separate_color_plane_flag") != 0 { 0 } else { sps.get_field("chroma_format_idc").unwrap_or(1) };
+    let (crop_unit_x, crop_unit_y) = match chroma_array_type {
+        0 | 3 => (1, 2 - frame_mbs_only_flag),
+        2 => (2, 2 - frame_mbs_only_flag),
+        _ => (2, 2 * (2 - frame_mbs_only_flag)),
+    };
+    (width - crop_unit_x * (field("frame_crop_left_offset") + field("frame_crop_right_offset")),
+     height - crop_unit_y * (field("frame_crop_top_offset") + field("frame_crop_bottom_offset")))
+}
+
+pub fn stream_frame_rate(nalus: &[SyntaxElement]) -> Option<f64> {
+    nalus.iter().find_map(|x| match x {
+        SyntaxElement::Node(nalu) => nalu.get_node("sps").and_then(sps_frame_rate),
+        _ => None,
+    })
+}
+
+pub fn is_idr_access_unit(access_unit: &[SyntaxElement]) -> bool {
+    access_unit.iter().any(|x| match x {
+        SyntaxElement::Node(nalu) => nalu.get_field("nal_unit_type") == Some(5),
+        _ => false,
+    })
+}
+
+// Collapse the slice types of an access unit into the single letter it's usually described by
+pub fn access_unit_frame_type(access_unit: &[SyntaxElement]) -> Option<char> {
+    let mut ret: Option<char> = None;
+    for element in access_unit {
+        let SyntaxElement::Node(nalu) = element else {
+            continue;
+        };
+        let Some(slice_type) = get_slice_header(nalu).and_then(|x| x.get_field("slice_type")) else {
+            continue;
+        };
+        let frame_type = match slice_type % 5 {
+            1 => 'B',
+            0 | 3 => 'P',
+            _ => 'I',
+        };
+        ret = match (ret, frame_type) {
+            (Some('B'), _) | (_, 'B') => Some('B'),
+            (Some('P'), _) | (_, 'P') => Some('P'),
+            _ => Some('I'),
+        };
+    }
+
+    ret
+}
+
+// One letter per picture in decode order, with a '|' in front of every IDR after the first.
+pub fn frame_type_timeline(nalus: &[SyntaxElement]) -> String {
+    let mut ret: Vec<String> = vec![];
+    for access_unit in split_access_units(nalus) {
+        let access_unit = &nalus[access_unit];
+        let Some(frame_type) = access_unit_frame_type(access_unit) else {
+            continue;
+        };
+        if is_idr_access_unit(access_unit) && !ret.is_empty() {
+            ret.push("|".to_string());
+        }
+        ret.push(frame_type.to_string());
+    }
+
+    format!("{}\n", ret.join(" "))
+}
+
+// Per-picture type and coded size; nalus must be the parse of bitstream so NALUs line up one to one.
+pub fn frame_stats(bitstream: &[u8], nalus: &[SyntaxElement]) -> Vec<FrameStats> {
+    let nalu_sizes: Vec<usize> = tokenize_h264_nalus(bitstream).iter().map(|x| x.len()).collect();
+    let mut ret: Vec<FrameStats> = vec![];
+    for access_unit in split_access_units(nalus) {
+        let bytes = nalu_sizes[access_unit.clone()].iter().sum();
+        let access_unit = &nalus[access_unit];
+        let Some(frame_type) = access_unit_frame_type(access_unit) else {
+            continue;
+        };
+        ret.push(FrameStats { frame_type, bytes });
+    }
+
+    ret
+}
+
+// RawMbBits from 7.4.2.1.1: the size of an uncompressed macroblock, which bounds the CABAC bin count
+fn raw_mb_bits(sps: &SyntaxNode) -> i64 {
+    let field = |name: &str| i64::from(sps.get_field(name).unwrap_or(0));
+    let chroma_array_type = if field("separate_color_plane_flag") != 0 { 0 } else { i64::from(sps.get_field("chroma_format_idc").unwrap_or(1)) };
+    let (mb_width_c, mb_height_c) = match chroma_array_type {
+        0 => (0, 0),
+        1 => (8, 8),
+        2 => (8, 16),
+        _ => (16, 16),
+    };
+    256 * (8 + field("bit_depth_luma_minus8")) + 2 * mb_width_c * mb_height_c * (8 + field("bit_depth_chroma_minus8"))
+}
+
+// Estimate of the decoding work a stream needs: macroblocks per second against the level's MaxMBPS, slice data
+// rate by entropy coder, and for CABAC a bin rate taking the (32 / 3) bins per byte of the bound on bins per
+// picture, (32 / 3) * NumBytesInVclNALunits + (RawMbBits * PicSizeInMbs) / 32, along with the whole bound.
+// Field pictures take half a frame period. nalus must be the parse of bitstream.
+pub fn complexity_report(bitstream: &[u8], nalus: &[SyntaxElement], frame_rate: f64) -> String {
+    let nalu_sizes: Vec<usize> = tokenize_h264_nalus(bitstream).iter().map(|x| x.len()).collect();
+    let mut parameter_sets = ParameterSets::new();
+    let mut level_sps: Option<&SyntaxNode> = None;
+    let mut seconds = 0.0;
+    let mut macroblocks: i64 = 0;
+    let mut slice_bytes = [0usize; 2];
+    let mut total_bins = 0.0;
+    let mut total_bin_bound = 0.0;
+    // (bins per second, access unit) of the most demanding picture
+    let mut peak_bin_rate = (0.0, 0);
+    let mut pictures = 0;
+    for (access_unit_idx, access_unit) in split_access_units(nalus).into_iter().enumerate() {
+        let mut picture: Option<(&SyntaxNode, &SyntaxNode, &SyntaxNode)> = None;
+        let mut vcl_bytes = 0;
+        for (element, size) in nalus[access_unit.clone()].iter().zip(&nalu_sizes[access_unit]) {
+            let SyntaxElement::Node(nalu) = element else {
+                continue;
+            };
+            parameter_sets.update(nalu);
+            let Some(header) = get_slice_header(nalu) else {
+                continue;
+            };
+            let Some((sps, pps)) = parameter_sets.for_slice(header) else {
+                eprintln!("Warning: access unit {} refers to a missing parameter set, skipping it", access_unit_idx);
+                continue;
+            };
+            picture.get_or_insert((header, sps, pps));
+            vcl_bytes += size;
+            slice_bytes[pps.get_field("entropy_coding_mode_flag").unwrap_or(0) as usize] += size;
+        }
+        let Some((header, sps, pps)) = picture else {
+            continue;
+        };
+        level_sps.get_or_insert(sps);
+        let field_pic = header.get_field("field_pic_flag").unwrap_or(0) != 0;
+        let frame_height_in_mbs = (2 - sps.get_field("frame_mbs_only_flag").unwrap_or(1)) * (sps.get_field("pic_height_in_mbs_minus1").unwrap_or(0) + 1);
+        let pic_size_in_mbs = i64::from((sps.get_field("pic_width_in_mbs_minus1").unwrap_or(0) + 1) * frame_height_in_mbs / (1 + i32::from(field_pic)));
+        let duration = if field_pic { 0.5 / frame_rate } else { 1.0 / frame_rate };
+        seconds += duration;
+        macroblocks += pic_size_in_mbs;
+        pictures += 1;
+        if pps.get_field("entropy_coding_mode_flag").unwrap_or(0) != 0 {
+            let bins = 32.0 / 3.0 * vcl_bytes as f64;
+            total_bins += bins;
+            total_bin_bound += bins + (raw_mb_bits(sps) * pic_size_in_mbs) as f64 / 32.0;
+            if bins / duration > peak_bin_rate.0 {
+                peak_bin_rate = (bins / duration, access_unit_idx);
+            }
+        }
+    }
+    let Some(sps) = level_sps else {
+        panic!("No pictures in stream");
+    };
+
+    let mb_rate = macroblocks as f64 / seconds;
+    let mut ret = format!("{} pictures over {:.3} s at {} fps\n", pictures, seconds, frame_rate);
+    ret.push_str(&format!("macroblocks per second: {:.0}\n", mb_rate));
+    ret.push_str(&format!("CAVLC slice data: {:.1} kbit/s\n", slice_bytes[0] as f64 * 8.0 / seconds / 1000.0));
+    ret.push_str(&format!("CABAC slice data: {:.1} kbit/s\n", slice_bytes[1] as f64 * 8.0 / seconds / 1000.0));
+    if total_bins > 0.0 {
+        ret.push_str(&format!("CABAC bin rate: {:.1} kbin/s average, {:.1} kbin/s peak at access unit {}, at most {:.1} kbin/s average\n",
+                              total_bins / seconds / 1000.0, peak_bin_rate.0 / 1000.0, peak_bin_rate.1, total_bin_bound / seconds / 1000.0));
+    }
+    let level_idc = sps.get_field("level_idc").unwrap_or(0);
+    match level_max_mb_rate(sps) {
+        Some(max_mb_rate) => {
+            ret.push_str(&format!("level_idc {} allows {} macroblocks per second ({:.0}% used)\n", level_idc, max_mb_rate,
+                                  mb_rate * 100.0 / max_mb_rate as f64));
+            if mb_rate > max_mb_rate as f64 {
+                ret.push_str("Warning: macroblock rate exceeds the level's MaxMBPS\n");
+            }
+        },
+        None => ret.push_str(&format!("Warning: unknown level_idc {}, can't check limits\n", level_idc)),
+    }
+
+    ret
+}
+
+// Byte range of every access unit in the stream, from its first start code up to the next access unit, with
+// whether it's a sync sample (IDR) and the SPS and PPS ids its slices need, for building container sample
+// tables. Parameter sets that haven't appeared in the stream yet are starred. nalus must be the parse of
+// bitstream.
+pub fn access_unit_byte_ranges(bitstream: &[u8], nalus: &[SyntaxElement]) -> String {
+    let chunks: Vec<Range<usize>> = nalu_chunks(bitstream).into_iter()
+        .filter(|x| x.0.is_some())
+        .map(|x| x.1)
+        .collect();
+    let mut parameter_sets = ParameterSets::new();
+    let mut ret = "access_unit offset length nalus sync sps pps\n".to_string();
+    for (idx, access_unit) in split_access_units(nalus).into_iter().enumerate() {
+        let range = chunks[access_unit.start].start..chunks[access_unit.end - 1].end;
+        let mut sps_ids: Vec<String> = vec![];
+        let mut pps_ids: Vec<String> = vec![];
+        for element in &nalus[access_unit.clone()] {
+            let SyntaxElement::Node(nalu) = element else {
+                continue;
+            };
+            parameter_sets.update(nalu);
+            let Some(pps_id) = get_slice_header(nalu).and_then(|x| x.get_field("pic_parameter_set_id")) else {
+                continue;
+            };
+            let (sps_id, pps_id) = match parameter_sets.pps.get(&pps_id).and_then(|x| x.get_field("seq_parameter_set_id")) {
+                Some(sps_id) if parameter_sets.sps.contains_key(&sps_id) => (sps_id.to_string(), pps_id.to_string()),
+                Some(sps_id) => (format!("{}*", sps_id), pps_id.to_string()),
+                None => ("?".to_string(), format!("{}*", pps_id)),
+            };
+            if !sps_ids.contains(&sps_id) {
+                sps_ids.push(sps_id);
+            }
+            if !pps_ids.contains(&pps_id) {
+                pps_ids.push(pps_id);
+            }
+        }
+        ret.push_str(&format!("{} {} {} {} {} {} {}\n", idx, range.start, range.len(), access_unit.len(),
+                              if is_idr_access_unit(&nalus[access_unit]) { "yes" } else { "no" },
+                              if sps_ids.is_empty() { "-".to_string() } else { sps_ids.join(",") },
+                              if pps_ids.is_empty() { "-".to_string() } else { pps_ids.join(",") }));
+    }
+
+    ret
+}
+
+// Bits per frame plus the bitrate over the trailing one second window, in kbit/s
+fn bitrate_series(frames: &[FrameStats], frame_rate: f64) -> Vec<(u64, f64)> {
+    let window = (frame_rate.round() as usize).max(1);
+    let mut ret: Vec<(u64, f64)> = vec![];
+    let mut window_bits: u64 = 0;
+    for (idx, frame) in frames.iter().enumerate() {
+        let bits = frame.bytes as u64 * 8;
+        window_bits += bits;
+        if idx >= window {
+            window_bits -= frames[idx - window].bytes as u64 * 8;
+        }
+        let window_len = (idx + 1).min(window);
+        ret.push((bits, window_bits as f64 * frame_rate / window_len as f64 / 1000.0));
+    }
+
+    ret
+}
+
+// Whitespace separated columns ready for gnuplot: frame, type, bits, rolling kbit/s
+pub fn bitrate_gnuplot(frames: &[FrameStats], frame_rate: f64) -> String {
+    let mut ret = format!("# frame type bits rolling_kbps (window {} frames at {} fps)\n", frame_rate.round().max(1.0), frame_rate);
+    for (idx, (frame, (bits, kbps))) in frames.iter().zip(bitrate_series(frames, frame_rate)).enumerate() {
+        ret.push_str(&format!("{} {} {} {:.3}\n", idx, frame.frame_type, bits, kbps));
+    }
+
+    ret
+}
+
+// Bars of bits per frame (I red, P green, B blue) with the rolling bitrate drawn as a line on its own scale.
+pub fn bitrate_svg(frames: &[FrameStats], frame_rate: f64) -> String {
+    let (width, height, margin) = (1000.0, 400.0, 40.0);
+    let series = bitrate_series(frames, frame_rate);
+    let max_bits = series.iter().map(|x| x.0).max().unwrap_or(1).max(1) as f64;
+    let max_kbps = series.iter().map(|x| x.1).fold(1.0, f64::max);
+    let step = (width - 2.0 * margin) / frames.len().max(1) as f64;
+    let mut ret = format!("<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{}\" height=\"{}\">\n", width, height);
+    ret = format!("{}<rect width=\"100%\" height=\"100%\" fill=\"white\"/>\n", ret);
+    for (idx, (frame, (bits, _))) in frames.iter().zip(&series).enumerate() {
+        let bar_height = *bits as f64 / max_bits * (height - 2.0 * margin);
+        let color = match frame.frame_type {
+            'I' => "#d62728",
+            'P' => "#2ca02c",
+            _ => "#1f77b4",
+        };
+        ret.push_str(&format!("<rect x=\"{:.2}\" y=\"{:.2}\" width=\"{:.2}\" height=\"{:.2}\" fill=\"{}\"/>\n",
+                              margin + idx as f64 * step, height - margin - bar_height, step.max(1.0), bar_height, color));
+    }
+    let points: Vec<String> = series.iter().enumerate()
+        .map(|(idx, (_, kbps))| format!("{:.2},{:.2}", margin + (idx as f64 + 0.5) * step, height - margin - kbps / max_kbps * (height - 2.0 * margin)))
+        .collect();
+    ret = format!("{}<polyline points=\"{}\" fill=\"none\" stroke=\"black\" stroke-width=\"2\"/>\n", ret, points.join(" "));
+    ret = format!("{}<text x=\"{}\" y=\"{}\" font-size=\"14\">max frame {} bits, max rolling bitrate {:.1} kbit/s</text>\n",
+                  ret, margin, margin / 2.0, max_bits, max_kbps);
+    format!("{}</svg>\n", ret)
+}
+
+// Smallest buffer that can deliver every frame on time when filled at a constant bitrate, the same leaky bucket
+// as the HRD's CPB, in bits.
+fn required_buffer_size(frames: &[FrameStats], frame_rate: f64, bitrate: f64) -> f64 {
+    let mut backlog: f64 = 0.0;
+    let mut ret: f64 = 0.0;
+    for frame in frames {
+        backlog = (backlog - bitrate / frame_rate).max(0.0) + frame.bytes as f64 * 8.0;
+        ret = ret.max(backlog);
+    }
+
+    ret
+}
+
+// For streams without HRD parameters: the peak bitrate over any window of frames, and the buffer needed to
+// deliver the stream at the level's MaxBR, both checked against the limits of the SPS's level.
+pub fn peak_bitrate_report(frames: &[FrameStats], frame_rate: f64, window: usize, sps: &SyntaxNode) -> String {
+    if frames.is_empty() {
+        panic!("No frames in stream");
+    }
+    let window = window.clamp(1, frames.len());
+    let bits: Vec<u64> = frames.iter().map(|x| x.bytes as u64 * 8).collect();
+    // Earliest window with the most bits
+    let peak = bits.windows(window).enumerate()
+        .map(|(start, x)| (x.iter().sum::<u64>(), start))
+        .max_by(|a, b| a.0.cmp(&b.0).then(b.1.cmp(&a.1)))
+        .unwrap();
+    let seconds = frames.len() as f64 / frame_rate;
+    let total_bits = bits.iter().sum::<u64>();
+    let peak_kbps = peak.0 as f64 * frame_rate / window as f64 / 1000.0;
+    let mut ret = format!("{} frames at {} fps, average {:.1} kbit/s\n", frames.len(), frame_rate, total_bits as f64 / seconds / 1000.0);
+    ret.push_str(&format!("peak {:.1} kbit/s over frames {}..{} ({} frame window)\n", peak_kbps, peak.1, peak.1 + window - 1, window));
+    let level_idc = sps.get_field("level_idc").unwrap_or(0);
+    let Some((max_br, max_cpb)) = level_bitrate_limits(sps) else {
+        ret.push_str(&format!("Warning: unknown level_idc {}, can't check limits\n", level_idc));
+        return ret;
+    };
+    let buffer = required_buffer_size(frames, frame_rate, max_br as f64);
+    ret.push_str(&format!("level_idc {} allows {:.1} kbit/s and a {:.1} kbit CPB\n", level_idc, max_br as f64 / 1000.0, max_cpb as f64 / 1000.0));
+    ret.push_str(&format!("delivered at {:.1} kbit/s, the stream needs a {:.1} kbit buffer\n", max_br as f64 / 1000.0, buffer / 1000.0));
+    if peak_kbps * 1000.0 > max_br as f64 {
+        ret.push_str("Warning: peak bitrate exceeds the level's MaxBR\n");
+    }
+    if buffer > max_cpb as f64 {
+        ret.push_str("Warning: required buffer exceeds the level's MaxCPB\n");
+    }
+
+    ret
+}
+
+// (nal_unit_type, id) of an SPS or PPS, so a repeat can be matched against the copy it replaces
+pub fn parameter_set_key(nalu: &SyntaxNode) -> Option<(i32, i32)> {
+    match nalu.get_field("nal_unit_type")? {
+        7 => Some((7, nalu.get_node("sps")?.get_field("seq_paramter_set_id")?)),
+        8 => Some((8, nalu.get_node("pps")?.get_field("pic_parameter_set_id")?)),
+        _ => None,
+    }
+}
+
+// Count how often each SPS/PPS is resent unchanged versus how often its content actually changes.
+pub fn duplicate_parameter_set_report(nalus: &[SyntaxElement]) -> String {
+    let mut last_seen: HashMap<(i32, i32), String> = HashMap::new();
+    let mut counts: Vec<((i32, i32), usize, usize)> = vec![];
+    for element in nalus {
+        let SyntaxElement::Node(nalu) = element else {
+            continue;
+        };
+        let Some(key) = parameter_set_key(nalu) else {
+            continue;
+        };
+        let contents = nalu_contents(nalu);
+        let idx = match counts.iter().position(|x| x.0 == key) {
+            Some(idx) => idx,
+            None => {
+                counts.push((key, 0, 0));
+                counts.len() - 1
+            },
+        };
+        if last_seen.get(&key) == Some(&contents) {
+            counts[idx].2 += 1;
+        } else {
+            counts[idx].1 += 1;
+            last_seen.insert(key, contents);
+        }
+    }
+
+    let mut ret = "set id distinct_versions duplicate_copies\n".to_string();
+    for ((nal_unit_type, id), versions, duplicates) in counts {
+        ret.push_str(&format!("{} {} {} {}\n", if nal_unit_type == 7 { "SPS" } else { "PPS" }, id, versions, duplicates));
+    }
+
+    ret
+}
+
+fn extend_range<T: PartialOrd + Copy>(range: &mut Option<(T, T)>, val: T) {
+    *range = match *range {
+        Some((min, max)) => Some((if val < min { val } else { min }, if val > max { val } else { max })),
+        None => Some((val, val)),
+    };
+}
+
+fn describe_range<T: std::fmt::Display>(range: &Option<(T, T)>) -> String {
+    match range {
+        Some((min, max)) => format!("{}..{}", min, max),
+        None => "none".to_string(),
+    }
+}
+
+// Value ranges across every explicit pred_weight_table in a stream
+#[derive(Default)]
+struct WeightRanges {
+    luma_log2_weight_denom: Option<(i32, i32)>,
+    luma_weight: Option<(i32, i32)>,
+    luma_scale: Option<(f64, f64)>,
+    luma_offset: Option<(i32, i32)>,
+    chroma_weight: Option<(i32, i32)>,
+    chroma_offset: Option<(i32, i32)>,
+    out_of_range: usize,
+    default_only_tables: usize,
+}
+
+impl WeightRanges {
+    fn update(&mut self, table: &SyntaxNode) {
+        let luma_log2_weight_denom = table.get_field("luma_log2_weight_denom").unwrap_or(0);
+        extend_range(&mut self.luma_log2_weight_denom, luma_log2_weight_denom);
+        let mut any_weights = false;
+        for element in &table.children {
+            let SyntaxElement::Field(field) = element else {
+                continue;
+            };
+            // Flags are unindexed, the weights and offsets they enable carry the reference index
+            let Some((name, _)) = field.name.split_once('[') else {
+                continue;
+            };
+            let range = match name {
+                "luma_weight_l0" | "luma_weight_l1" => {
+                    extend_range(&mut self.luma_scale, f64::from(field.val) / f64::from(1 << luma_log2_weight_denom.clamp(0, 7)));
+                    &mut self.luma_weight
+                },
+                "luma_offset_l0" | "luma_offset_l1" => &mut self.luma_offset,
+                "chroma_weight_l0" | "chroma_weight_l1" => &mut self.chroma_weight,
+                "chroma_offset_l0" | "chroma_offset_l1" => &mut self.chroma_offset,
+                _ => continue,
+            };
+            extend_range(range, field.val);
+            any_weights = true;
+            // 7.4.3.2 limits weights and offsets to -128..127
+            if !(-128..=127).contains(&field.val) {
+                self.out_of_range += 1;
+            }
+        }
+        if !any_weights {
+            self.default_only_tables += 1;
+        }
+    }
+}
+
+// How P and B slices use weighted prediction and the spread of the explicit weights and offsets they send.
+// Wide luma scales or offsets on consecutive pictures are what shows up as brightness pumping.
+pub fn weighted_prediction_report(nalus: &[SyntaxElement]) -> String {
+    let mut parameter_sets = ParameterSets::new();
+    let (mut p_slices, mut p_explicit, mut b_slices, mut b_explicit, mut b_implicit) = (0, 0, 0, 0, 0);
+    let mut ranges = WeightRanges::default();
+    for element in nalus {
+        let SyntaxElement::Node(nalu) = element else {
+            continue;
+        };
+        parameter_sets.update(nalu);
+        let Some(header) = get_slice_header(nalu) else {
+            continue;
+        };
+        let table = header.get_node("pred_weight_table");
+        match header.get_field("slice_type").unwrap_or(2) % 5 {
+            0 | 3 => {
+                p_slices += 1;
+                p_explicit += usize::from(table.is_some());
+            },
+            1 => {
+                b_slices += 1;
+                b_explicit += usize::from(table.is_some());
+                let weighted_bipred_idc = parameter_sets.for_slice(header).and_then(|(_, pps)| pps.get_field("weighted_bipred_idc"));
+                b_implicit += usize::from(weighted_bipred_idc == Some(2));
+            },
+            _ => continue,
+        }
+        if let Some(table) = table {
+            ranges.update(table);
+        }
+    }
+
+    let mut ret = format!("P slices: {}, {} with explicit weights\n", p_slices, p_explicit);
+    ret.push_str(&format!("B slices: {}, {} with explicit weights, {} with implicit weights\n", b_slices, b_explicit, b_implicit));
+    if p_explicit + b_explicit == 0 {
+        return ret;
+    }
+    ret.push_str(&format!("luma_log2_weight_denom: {}\n", describe_range(&ranges.luma_log2_weight_denom)));
+    let luma_scale = ranges.luma_scale.map(|(min, max)| format!(" (scale {:.3}..{:.3})", min, max)).unwrap_or_default();
+    ret.push_str(&format!("luma weight: {}{}\n", describe_range(&ranges.luma_weight), luma_scale));
+    ret.push_str(&format!("luma offset: {}\n", describe_range(&ranges.luma_offset)));
+    ret.push_str(&format!("chroma weight: {}\n", describe_range(&ranges.chroma_weight)));
+    ret.push_str(&format!("chroma offset: {}\n", describe_range(&ranges.chroma_offset)));
+    if ranges.out_of_range > 0 {
+        ret.push_str(&format!("Warning: {} weights or offsets outside -128..127\n", ranges.out_of_range));
+    }
+    if ranges.default_only_tables > 0 {
+        ret.push_str(&format!("Warning: {} tables only signal default weights, weighted prediction could be turned off for them\n",
+                              ranges.default_only_tables));
+    }
+
+    ret
+}
+
+// Frame indices of the IDR pictures, along with the total number of frames
+pub fn idr_frame_indices(nalus: &[SyntaxElement]) -> (Vec<usize>, usize) {
+    let mut idr_frames: Vec<usize> = vec![];
+    let mut frames = 0;
+    for access_unit in split_access_units(nalus) {
+        let access_unit = &nalus[access_unit];
+        if access_unit_frame_type(access_unit).is_none() {
+            continue;
+        }
+        if is_idr_access_unit(access_unit) {
+            idr_frames.push(frames);
+        }
+        frames += 1;
+    }
+
+    (idr_frames, frames)
+}
+
+// Distances between IDR pictures in frames, and in seconds when the frame rate is known. The pictures after the
+// last IDR count against max_interval (seconds) too, since a player joining there waits just as long.
+pub fn idr_interval_report(nalus: &[SyntaxElement], frame_rate: Option<f64>, max_interval: Option<f64>) -> String {
+    let (idr_frames, frames) = idr_frame_indices(nalus);
+    let seconds = |frames: usize| frame_rate.map(|x| format!(" ({:.3} s)", frames as f64 / x)).unwrap_or_default();
+
+    let mut ret = format!("{} frames, {} IDR frames\n", frames, idr_frames.len());
+    if idr_frames.first().is_some_and(|x| *x != 0) {
+        ret.push_str(&format!("Warning: stream starts with {} frames before the first IDR\n", idr_frames[0]));
+    }
+    let intervals: Vec<usize> = idr_frames.windows(2).map(|x| x[1] - x[0]).collect();
+    for (idx, interval) in intervals.iter().enumerate() {
+        ret.push_str(&format!("IDR {} to {}: {} frames{}\n", idx, idx + 1, interval, seconds(*interval)));
+    }
+    if let (Some(min), Some(max)) = (intervals.iter().min(), intervals.iter().max()) {
+        let average = intervals.iter().sum::<usize>() as f64 / intervals.len() as f64;
+        let average_seconds = frame_rate.map(|x| format!(" ({:.3} s)", average / x)).unwrap_or_default();
+        ret.push_str(&format!("min {} frames{}, max {} frames{}, average {:.2} frames{}\n",
+                              min, seconds(*min), max, seconds(*max), average, average_seconds));
+    }
+    let tail = idr_frames.last().map(|x| frames - x);
+    if let Some(tail) = tail {
+        ret.push_str(&format!("{} frames from the last IDR to the end{}\n", tail, seconds(tail)));
+    }
+    if let (Some(max_interval), Some(frame_rate)) = (max_interval, frame_rate) {
+        let budget_frames = (max_interval * frame_rate).round() as usize;
+        let over: Vec<&usize> = intervals.iter().chain(&tail).filter(|x| **x > budget_frames).collect();
+        if !over.is_empty() {
+            ret.push_str(&format!("Warning: {} IDR intervals exceed the {} s budget ({} frames), longest {} frames{}\n",
+                                  over.len(), max_interval, budget_frames, over.iter().max().unwrap(), seconds(**over.iter().max().unwrap())));
+        }
+    }
+
+    ret
+}
+
+// (NALU offset within the access unit, first_mb_in_slice) of each primary coded slice, in decode order
+pub fn slice_starts(access_unit: &[SyntaxElement]) -> Vec<(usize, i32)> {
+    let mut ret: Vec<(usize, i32)> = vec![];
+    for (offset, element) in access_unit.iter().enumerate() {
+        let SyntaxElement::Node(nalu) = element else {
+            continue;
+        };
+        let Some(header) = get_slice_header(nalu) else {
+            continue;
+        };
+        if header.get_field("redundant_pic_cnt").unwrap_or(0) == 0 {
+            ret.push((offset, header.get_field("first_mb_in_slice").unwrap_or(0)));
+        }
+    }
+
+    ret
+}
+
+// Arbitrary slice order: a slice starts at or before the one decoded ahead of it in the same picture
+pub fn is_arbitrary_slice_order(slice_starts: &[(usize, i32)]) -> bool {
+    slice_starts.windows(2).any(|x| x[1].1 <= x[0].1)
+}
+
+// The slice order of every picture whose slices don't arrive in raster order, which only Baseline decoders
+// have to handle. Two slices starting at the same macroblock are flagged too, since no reordering fixes that.
+pub fn arbitrary_slice_order_report(nalus: &[SyntaxElement]) -> String {
+    let mut ret = "picture nalu first_mb_in_slice\n".to_string();
+    let mut warnings: Vec<String> = vec![];
+    let (mut pictures, mut aso_pictures) = (0, 0);
+    for (picture, access_unit) in split_access_units(nalus).into_iter().enumerate() {
+        let starts = slice_starts(&nalus[access_unit.clone()]);
+        if starts.is_empty() {
+            continue;
+        }
+        pictures += 1;
+        if !is_arbitrary_slice_order(&starts) {
+            continue;
+        }
+        aso_pictures += 1;
+        let first_mbs: Vec<String> = starts.iter().map(|x| x.1.to_string()).collect();
+        ret.push_str(&format!("{} {} {}\n", picture, access_unit.start + starts[0].0, first_mbs.join(",")));
+        let mut sorted: Vec<i32> = starts.iter().map(|x| x.1).collect();
+        sorted.sort();
+        if sorted.windows(2).any(|x| x[0] == x[1]) {
+            warnings.push(format!("picture {} has two slices starting at the same macroblock", picture));
+        }
+    }
+    ret.push_str(&format!("{} of {} pictures use arbitrary slice order\n", aso_pictures, pictures));
+    for warning in warnings {
+        ret.push_str(&format!("Warning: {}\n", warning));
+    }
+
+    ret
+}
+
+// Problems with where the slices of one picture start. Slice ends are only known after parsing the macroblocks,
+// so a slice that stops early or runs into the next one can't be seen here.
+fn slice_coverage_problems(access_unit: &[SyntaxElement], sps: &SyntaxNode, pps: &SyntaxNode, header: &SyntaxNode) -> Vec<String> {
+    let mut ret: Vec<String> = vec![];
+    let field_pic = header.get_field("field_pic_flag").unwrap_or(0) != 0;
+    let mbaff = sps.get_field("mb_adaptive_frame_field_flag").unwrap_or(0) != 0 && !field_pic;
+    let slice_group_map = mb_to_slice_group_map(sps, pps, header);
+    let pic_size_in_mbs = slice_group_map.iter().map(|x| x.len()).sum::<usize>() as i32;
+    let mut starts: Vec<i32> = slice_starts(access_unit).iter().map(|x| x.1 * (1 + i32::from(mbaff))).collect();
+    starts.sort();
+    for pair in starts.windows(2).filter(|x| x[0] == x[1]) {
+        ret.push(format!("two slices start at macroblock {}, overlapping", pair[0]));
+    }
+    if let Some(start) = starts.iter().find(|x| **x >= pic_size_in_mbs) {
+        ret.push(format!("slice starts at macroblock {}, past the {} macroblocks of the picture", start, pic_size_in_mbs));
+    }
+    // Every slice group is coded by its own slices, so its first macroblock must start one. MBAFF addresses
+    // macroblocks pair by pair rather than in raster order.
+    let mut group_starts: Vec<(i32, i32)> = vec![];
+    for (y, row) in slice_group_map.iter().enumerate() {
+        for (x, group) in row.iter().enumerate() {
+            let (x, y, width) = (x as i32, y as i32, row.len() as i32);
+            let mb = if mbaff { 2 * ((y / 2) * width + x) + y % 2 } else { y * width + x };
+            match group_starts.iter_mut().find(|x| x.0 == *group) {
+                Some(start) => start.1 = start.1.min(mb),
+                None => group_starts.push((*group, mb)),
+            }
+        }
+    }
+    for (group, mb) in group_starts {
+        if starts.contains(&mb) {
+            continue;
+        }
+        if pps.get_field("num_slice_groups_minus1").unwrap_or(0) == 0 {
+            ret.push(format!("no slice starts at macroblock {}, the start of the picture", mb));
+        } else {
+            ret.push(format!("no slice starts at macroblock {}, the start of slice group {}", mb, group));
+        }
+    }
+
+    ret
+}
+
+// Check the slices of each picture start where they have to for every macroblock to be covered exactly once:
+// one slice at the start of the picture (or of each slice group), no two at the same macroblock, none past the
+// end of the picture.
+pub fn slice_coverage_report(nalus: &[SyntaxElement]) -> String {
+    let mut parameter_sets = ParameterSets::new();
+    let mut ret = String::new();
+    let (mut pictures, mut slices, mut bad_pictures) = (0, 0, 0);
+    let mut slice_counts: Option<(usize, usize)> = None;
+    for (picture, access_unit) in split_access_units(nalus).into_iter().enumerate() {
+        let mut first_slice: Option<&SyntaxNode> = None;
+        for element in &nalus[access_unit.clone()] {
+            let SyntaxElement::Node(nalu) = element else {
+                continue;
+            };
+            parameter_sets.update(nalu);
+            if first_slice.is_none() {
+                first_slice = get_slice_header(nalu);
+            }
+        }
+        let Some(header) = first_slice else {
+            continue;
+        };
+        let Some((sps, pps)) = parameter_sets.for_slice(header) else {
+            eprintln!("Warning: picture {} refers to a missing parameter set", picture);
+            continue;
+        };
+        let access_unit = &nalus[access_unit];
+        let slice_count = slice_starts(access_unit).len();
+        pictures += 1;
+        slices += slice_count;
+        extend_range(&mut slice_counts, slice_count);
+        let problems = slice_coverage_problems(access_unit, sps, pps, header);
+        if !problems.is_empty() {
+            bad_pictures += 1;
+        }
+        for problem in problems {
+            ret.push_str(&format!("picture {}: {}\n", picture, problem));
+        }
+    }
+    ret.push_str(&format!("{} pictures, {} slices, {} slices per picture\n", pictures, slices, describe_range(&slice_counts)));
+    if bad_pictures > 0 {
+        ret.push_str(&format!("Warning: {} pictures have slices that don't cover every macroblock exactly once\n", bad_pictures));
+    }
+
+    ret
+}
+
+// Check SliceQPY = 26 + pic_init_qp_minus26 + slice_qp_delta stays within -QpBdOffsetY..51 (7.4.3), and QSY of
+// SP/SI slices within 0..51. Out of range QPs aren't rejected by every decoder, some silently clip or wrap them.
+pub fn slice_qp_report(nalus: &[SyntaxElement]) -> String {
+    let mut parameter_sets = ParameterSets::new();
+    let mut ret = String::new();
+    let mut qp_range: Option<(i32, i32)> = None;
+    let (mut slices, mut violations) = (0, 0);
+    for (idx, element) in nalus.iter().enumerate() {
+        let SyntaxElement::Node(nalu) = element else {
+            continue;
+        };
+        parameter_sets.update(nalu);
+        let Some(header) = get_slice_header(nalu) else {
+            continue;
+        };
+        let Some((sps, pps)) = parameter_sets.for_slice(header) else {
+            eprintln!("Warning: NALU {} refers to a missing parameter set", idx);
+            continue;
+        };
+        slices += 1;
+        let qp_bd_offset = 6 * sps.get_field("bit_depth_luma_minus8").unwrap_or(0);
+        let qp = 26 + pps.get_field("pic_init_qp_minus26").unwrap_or(0) + header.get_field("slice_qp_delta").unwrap_or(0);
+        extend_range(&mut qp_range, qp);
+        if qp < -qp_bd_offset || qp > 51 {
+            ret.push_str(&format!("NALU {}: SliceQPY {} is outside {}..51\n", idx, qp, -qp_bd_offset));
+            violations += 1;
+        }
+        if let Some(slice_qs_delta) = header.get_field("slice_qs_delta") {
+            let qs = 26 + pps.get_field("pic_init_qs_minus26").unwrap_or(0) + slice_qs_delta;
+            if !(0..=51).contains(&qs) {
+                ret.push_str(&format!("NALU {}: QSY {} is outside 0..51\n", idx, qs));
+                violations += 1;
+            }
+        }
+    }
+    ret.push_str(&format!("{} slices, SliceQPY {}\n", slices, describe_range(&qp_range)));
+    if violations > 0 {
+        ret.push_str(&format!("Warning: {} slice QPs out of range\n", violations));
+    }
+
+    ret
+}
+
+// The value the spec fixes for a reserved, forbidden or marker field, by the name the parser gives it
+fn fixed_field_value(name: &str) -> Option<i32> {
+    match name {
+        "forbidden_zero_bit" | "nuh_reserved_zero_bit" | "reserved_zero_2bits" | "sei_nesting_zero_bit" => Some(0),
+        "reserved_one_bit" | "bit_equal_to_one" => Some(1),
+        "reserved_three_2bits" => Some(3),
+        // cc_data(): five marker bits before each construct, eight after the last
+        "marker_bits" => Some(0xFF),
+        _ if name.starts_with("marker_bits[") => Some(0x1F),
+        _ => None,
+    }
+}
+
+// Every fixed field of a node and its subnodes as (name, value, expected value), in bitstream order
+fn fixed_fields(node: &SyntaxNode, ret: &mut Vec<(String, i32, i32)>) {
+    for child in &node.children {
+        match child {
+            SyntaxElement::Field(field) => {
+                if let Some(expected) = fixed_field_value(&field.name) {
+                    ret.push((field.name.clone(), field.val, expected));
+                }
+            },
+            SyntaxElement::Node(subnode) => fixed_fields(subnode, ret),
+            SyntaxElement::Payload(_) => (),
+        }
+    }
+}
+
+// Check forbidden, reserved and marker bits hold the values the spec fixes. A wrong one rarely comes from the
+// field itself; it usually means the encoder wrote something before it with the wrong number of bits.
+pub fn reserved_bits_report(nalus: &[SyntaxElement]) -> String {
+    let mut ret = String::new();
+    // (name, times seen, times wrong) in order of first appearance
+    let mut counts: Vec<(String, usize, usize)> = vec![];
+    for (idx, element) in nalus.iter().enumerate() {
+        let SyntaxElement::Node(nalu) = element else {
+            continue;
+        };
+        let mut fields: Vec<(String, i32, i32)> = vec![];
+        fixed_fields(nalu, &mut fields);
+        for (name, val, expected) in fields {
+            let base_name = name.split('[').next().unwrap_or(&name).to_string();
+            let idx_in_counts = match counts.iter().position(|x| x.0 == base_name) {
+                Some(x) => x,
+                None => {
+                    counts.push((base_name, 0, 0));
+                    counts.len() - 1
+                },
+            };
+            counts[idx_in_counts].1 += 1;
+            if val != expected {
+                counts[idx_in_counts].2 += 1;
+                let nal_unit_type = nalu.get_field("nal_unit_type").unwrap_or(0);
+                ret.push_str(&format!("NALU {} ({}): {} is {}, expected {}\n", idx, nal_unit_type_name(nal_unit_type), name, val, expected));
+            }
+        }
+    }
+    let mut violations = 0;
+    for (name, seen, wrong) in counts {
+        ret.push_str(&format!("{}: {} checked, {} wrong\n", name, seen, wrong));
+        violations += wrong;
+    }
+    if violations > 0 {
+        ret.push_str(&format!("Warning: {} reserved or forbidden bits have the wrong value, check the encoder for bit offset bugs\n", violations));
+    }
+
+    ret
+}
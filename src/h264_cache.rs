@@ -0,0 +1,68 @@
+use std::collections::VecDeque;
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+
+use crate::bitstream_util::SyntaxElement;
+use crate::bitstream_util::syntax_elements_from_string;
+use crate::h264_slice_data::SliceDataParams;
+
+// FNV-1a, which unlike the standard library's hasher is guaranteed to give the same keys on every build
+fn fnv1a(mut hash: u64, data: &[u8]) -> u64 {
+    for byte in data {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x0000_0100_0000_01B3);
+    }
+
+    hash
+}
+
+// On-disk cache of the macroblocks of deeply parsed slices, one text file per slice. A slice's key covers
+// its NALU's bytes and everything its slice_data() parse depends on, so an edit anywhere else in the stream
+// leaves it cached, while a parameter set change reparses the slices coded against it.
+pub struct SliceDataCache {
+    dir: PathBuf,
+    nalu_hash: u64,
+    pub hits: usize,
+    pub misses: usize,
+}
+
+impl SliceDataCache {
+    pub fn new(dir: &Path) -> SliceDataCache {
+        fs::create_dir_all(dir).expect("Cannot create directory");
+        SliceDataCache { dir: dir.to_path_buf(), nalu_hash: 0, hits: 0, misses: 0 }
+    }
+
+    // Called with the RBSP of every NALU before it's parsed
+    pub fn begin_nalu(&mut self, rbsp: &[u8]) {
+        self.nalu_hash = fnv1a(0xCBF2_9CE4_8422_2325, rbsp);
+    }
+
+    fn path(&self, params: &SliceDataParams, bit_offset: usize) -> PathBuf {
+        let mut hash = fnv1a(self.nalu_hash, env!("CARGO_PKG_VERSION").as_bytes());
+        hash = fnv1a(hash, format!("{:?}", params).as_bytes());
+        hash = fnv1a(hash, &bit_offset.to_le_bytes());
+        self.dir.join(format!("{:016x}.txt", hash))
+    }
+
+    // The slice_data node and trailing_bits cached for the current NALU's slice
+    pub fn get(&mut self, params: &SliceDataParams, bit_offset: usize) -> Option<VecDeque<SyntaxElement>> {
+        let Ok(text) = fs::read_to_string(self.path(params, bit_offset)) else {
+            self.misses += 1;
+            return None;
+        };
+        self.hits += 1;
+        let mut rows = text.split('\n').map(|x| x.to_string()).collect();
+        Some(syntax_elements_from_string(&mut rows))
+    }
+
+    pub fn put(&self, params: &SliceDataParams, bit_offset: usize, elements: &[SyntaxElement]) {
+        let path = self.path(params, bit_offset);
+        // Written under another name first, so an interrupted run can't leave half a file behind
+        let partial = path.with_extension("partial");
+        let text: String = elements.iter().map(|x| x.to_string()).collect();
+        if let Err(err) = fs::write(&partial, text).and_then(|_| fs::rename(&partial, &path)) {
+            eprintln!("Warning: cannot write {}: {}", path.display(), err);
+        }
+    }
+}
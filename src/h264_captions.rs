@@ -0,0 +1,56 @@
+use crate::bitstream_util::SyntaxElement;
+use crate::bitstream_util::SyntaxNode;
+use crate::h264_access_units::split_access_units;
+use crate::h264_poc::compute_picture_order;
+
+// The cc_data() of every ATSC A/53 user_data_registered_itu_t_t35 SEI message in a NALU
+fn cc_data_nodes(nalu: &SyntaxNode) -> Vec<&SyntaxNode> {
+    let Some(sei) = nalu.get_node("sei") else {
+        return vec![];
+    };
+    sei.children.iter().filter_map(|x| match x {
+        SyntaxElement::Node(message) if message.get_field("payloadType") == Some(4) => {
+            message.get_node("sei_payload").and_then(|y| y.get_node("cc_data"))
+        },
+        _ => None,
+    }).collect()
+}
+
+// Each construct as it is coded: marker_bits, cc_valid and cc_type packed into the first byte, then
+// cc_data_1 and cc_data_2
+fn cc_triplets(cc_data: &SyntaxNode) -> Vec<[u8; 3]> {
+    let field = |name: String| cc_data.get_field(&name).unwrap_or(0) as u8;
+    (0..cc_data.get_field("cc_count").unwrap_or(0)).map(|i| {
+        [(field(format!("marker_bits[{}]", i)) << 3) | (field(format!("cc_valid[{}]", i)) << 2) | field(format!("cc_type[{}]", i)),
+         field(format!("cc_data_1[{}]", i)),
+         field(format!("cc_data_2[{}]", i))]
+    }).collect()
+}
+
+// Caption data is coded in decode order but belongs to pictures in output order, so the triplets
+// are gathered per access unit and emitted by picture order count.
+pub fn extract_captions(nalus: &[SyntaxElement]) -> Vec<[u8; 3]> {
+    let access_units = split_access_units(nalus);
+    let mut epoch = 0;
+    let mut pictures: Vec<(i32, i32, usize)> = vec![];
+    for picture in compute_picture_order(nalus) {
+        if picture.resets_order {
+            epoch += 1;
+        }
+        pictures.push((epoch, picture.pic_order_cnt, picture.access_unit));
+    }
+    pictures.sort();
+
+    let mut ret: Vec<[u8; 3]> = vec![];
+    for (_, _, access_unit) in pictures {
+        for element in &nalus[access_units[access_unit].clone()] {
+            if let SyntaxElement::Node(nalu) = element {
+                for cc_data in cc_data_nodes(nalu) {
+                    ret.append(&mut cc_triplets(cc_data));
+                }
+            }
+        }
+    }
+
+    ret
+}
@@ -0,0 +1,254 @@
+use crate::bitstream_util::SyntaxElement;
+use crate::bitstream_util::SyntaxNode;
+use crate::h264_access_units::split_access_units;
+use crate::h264_parser::nalu_contents;
+
+// Table E-3
+pub fn colour_primaries_name(val: i32) -> Option<&'static str> {
+    match val {
+        1 => Some("BT.709"),
+        2 => Some("unspecified"),
+        4 => Some("BT.470 System M"),
+        5 => Some("BT.601 625"),
+        6 => Some("BT.601 525"),
+        7 => Some("SMPTE 240M"),
+        8 => Some("generic film"),
+        9 => Some("BT.2020"),
+        10 => Some("SMPTE ST 428-1"),
+        11 => Some("DCI-P3"),
+        12 => Some("Display P3"),
+        22 => Some("EBU Tech 3213"),
+        _ => None,
+    }
+}
+
+// Table E-4
+pub fn transfer_characteristics_name(val: i32) -> Option<&'static str> {
+    match val {
+        1 => Some("BT.709"),
+        2 => Some("unspecified"),
+        4 => Some("gamma 2.2"),
+        5 => Some("gamma 2.8"),
+        6 => Some("BT.601"),
+        7 => Some("SMPTE 240M"),
+        8 => Some("linear"),
+        9 => Some("log 100:1"),
+        10 => Some("log 316:1"),
+        11 => Some("IEC 61966-2-4"),
+        12 => Some("BT.1361"),
+        13 => Some("sRGB"),
+        14 => Some("BT.2020 10 bit"),
+        15 => Some("BT.2020 12 bit"),
+        16 => Some("PQ"),
+        17 => Some("SMPTE ST 428-1"),
+        18 => Some("HLG"),
+        _ => None,
+    }
+}
+
+// Table E-5
+pub fn matrix_coefficients_name(val: i32) -> Option<&'static str> {
+    match val {
+        0 => Some("identity (GBR)"),
+        1 => Some("BT.709"),
+        2 => Some("unspecified"),
+        4 => Some("FCC"),
+        5 => Some("BT.601 625"),
+        6 => Some("BT.601 525"),
+        7 => Some("SMPTE 240M"),
+        8 => Some("YCgCo"),
+        9 => Some("BT.2020 non-constant luminance"),
+        10 => Some("BT.2020 constant luminance"),
+        11 => Some("SMPTE ST 2085"),
+        12 => Some("chromaticity derived non-constant luminance"),
+        13 => Some("chromaticity derived constant luminance"),
+        14 => Some("ICtCp"),
+        _ => None,
+    }
+}
+
+// Broad colour space families, for spotting primaries and matrix taken from different standards
+#[derive(PartialEq)]
+enum ColorFamily {
+    Bt601,
+    Bt709,
+    Bt2020,
+}
+
+fn primaries_family(val: i32) -> Option<ColorFamily> {
+    match val {
+        1 => Some(ColorFamily::Bt709),
+        5 | 6 => Some(ColorFamily::Bt601),
+        9 => Some(ColorFamily::Bt2020),
+        _ => None,
+    }
+}
+
+fn matrix_family(val: i32) -> Option<ColorFamily> {
+    match val {
+        1 => Some(ColorFamily::Bt709),
+        5 | 6 => Some(ColorFamily::Bt601),
+        9 | 10 => Some(ColorFamily::Bt2020),
+        _ => None,
+    }
+}
+
+fn describe(val: i32, name: Option<&str>) -> String {
+    format!("{} ({})", name.unwrap_or("reserved"), val)
+}
+
+// Warnings about colour descriptions that are invalid, or valid but likely to be displayed wrong.
+pub fn check_color_description(sps: &SyntaxNode, vui: &SyntaxNode) -> Vec<String> {
+    let mut ret: Vec<String> = vec![];
+    let height = (sps.get_field("pic_height_in_mbs_minus1").unwrap_or(0) + 1) * 16 * (2 - sps.get_field("frame_mbs_only_flag").unwrap_or(1));
+    if vui.get_field("colour_description_present_flag").unwrap_or(0) == 0 {
+        ret.push(format!("No colour description, players will guess BT.{} from the {} line height",
+                         if height >= 720 { "709" } else { "601" }, height));
+        return ret;
+    }
+    let primaries = vui.get_field("colour_primaries").unwrap_or(2);
+    let transfer = vui.get_field("transfer_characteristics").unwrap_or(2);
+    let matrix = vui.get_field("matrix_coefficients").unwrap_or(2);
+    if colour_primaries_name(primaries).is_none() {
+        ret.push(format!("colour_primaries {} is reserved", primaries));
+    }
+    if transfer_characteristics_name(transfer).is_none() {
+        ret.push(format!("transfer_characteristics {} is reserved", transfer));
+    }
+    if matrix_coefficients_name(matrix).is_none() {
+        ret.push(format!("matrix_coefficients {} is reserved", matrix));
+    }
+    if let (Some(primaries_family), Some(matrix_family)) = (primaries_family(primaries), matrix_family(matrix)) {
+        if primaries_family != matrix_family {
+            ret.push(format!("{} matrix with {} primaries, colours will shift on players that honor the matrix",
+                             describe(matrix, matrix_coefficients_name(matrix)), describe(primaries, colour_primaries_name(primaries))));
+        }
+    }
+    if matches!(transfer, 14 | 15 | 16 | 18) && primaries_family(primaries) != Some(ColorFamily::Bt2020) {
+        ret.push(format!("{} transfer is meant for BT.2020 primaries, not {}",
+                         describe(transfer, transfer_characteristics_name(transfer)), describe(primaries, colour_primaries_name(primaries))));
+    }
+    if matrix == 0 && sps.get_field("chroma_format_idc").unwrap_or(1) != 3 {
+        ret.push("matrix_coefficients 0 (GBR) requires 4:4:4 chroma".to_string());
+    }
+    if matrix_family(matrix) == Some(ColorFamily::Bt601) && height >= 720 {
+        ret.push(format!("BT.601 matrix on {} line content, which players often decode as BT.709 regardless", height));
+    }
+    if vui.get_field("video_full_range_flag").unwrap_or(0) != 0 {
+        ret.push("Full range signaled, players that ignore video_full_range_flag will show it washed out".to_string());
+    }
+
+    ret
+}
+
+// Colour description of every distinct SPS, followed by warnings about suspicious combinations.
+pub fn color_description_report(nalus: &[SyntaxElement]) -> String {
+    let mut ret = "".to_string();
+    let mut seen: Vec<String> = vec![];
+    for (idx, element) in nalus.iter().enumerate() {
+        let SyntaxElement::Node(nalu) = element else {
+            continue;
+        };
+        let Some(sps) = nalu.get_node("sps") else {
+            continue;
+        };
+        let contents = nalu_contents(nalu);
+        if seen.contains(&contents) {
+            continue;
+        }
+        seen.push(contents);
+        let sps_id = sps.get_field("seq_paramter_set_id").unwrap_or(0);
+        let Some(vui) = sps.get_node("vui_parameters") else {
+            let reason = if sps.get_field("vui_parameters_present_flag").unwrap_or(0) == 0 { "no VUI" } else { "VUI not parsed" };
+            ret.push_str(&format!("SPS {} (NALU {}): {}\n", sps_id, idx, reason));
+            continue;
+        };
+        if vui.get_field("colour_description_present_flag").unwrap_or(0) != 0 {
+            let primaries = vui.get_field("colour_primaries").unwrap_or(2);
+            let transfer = vui.get_field("transfer_characteristics").unwrap_or(2);
+            let matrix = vui.get_field("matrix_coefficients").unwrap_or(2);
+            ret.push_str(&format!("SPS {} (NALU {}): primaries {}, transfer {}, matrix {}, {} range\n", sps_id, idx,
+                                  describe(primaries, colour_primaries_name(primaries)),
+                                  describe(transfer, transfer_characteristics_name(transfer)),
+                                  describe(matrix, matrix_coefficients_name(matrix)),
+                                  if vui.get_field("video_full_range_flag").unwrap_or(0) != 0 { "full" } else { "limited" }));
+        } else {
+            ret.push_str(&format!("SPS {} (NALU {}): no colour description\n", sps_id, idx));
+        }
+        for warning in check_color_description(sps, vui) {
+            ret.push_str(&format!("  Warning: {}\n", warning));
+        }
+    }
+
+    ret
+}
+
+// Whether a user_data_registered_itu_t_t35 payload carries SMPTE ST 2094-40 (HDR10+) metadata
+fn is_hdr10_plus(payload: &SyntaxNode) -> bool {
+    payload.get_field("itu_t_t35_country_code") == Some(0xB5) && payload.get_field("itu_t_t35_provider_code") == Some(0x3C) &&
+        payload.get_field("itu_t_t35_provider_oriented_code") == Some(1) && payload.get_field("application_identifier") == Some(4)
+}
+
+// The HDR metadata SEI messages of every access unit in decode order: static mastering display and content
+// light level, the alternative transfer characteristics, and HDR10+ dynamic metadata, which should be in every
+// frame once it appears.
+pub fn hdr_metadata_report(nalus: &[SyntaxElement]) -> String {
+    let mut ret = "".to_string();
+    let (mut mastering_display, mut content_light_level, mut alternative_transfer, mut hdr10_plus) = (0, 0, 0, 0);
+    let mut missing_hdr10_plus: Vec<usize> = vec![];
+    for (idx, access_unit) in split_access_units(nalus).into_iter().enumerate() {
+        let mut found: Vec<String> = vec![];
+        for element in &nalus[access_unit] {
+            let SyntaxElement::Node(nalu) = element else {
+                continue;
+            };
+            let Some(sei) = nalu.get_node("sei") else {
+                continue;
+            };
+            for message in &sei.children {
+                let SyntaxElement::Node(message) = message else {
+                    continue;
+                };
+                let Some(payload) = message.get_node("sei_payload") else {
+                    continue;
+                };
+                match message.get_field("payloadType") {
+                    Some(137) => {
+                        mastering_display += 1;
+                        found.push("mastering display colour volume".to_string());
+                    },
+                    Some(144) => {
+                        content_light_level += 1;
+                        found.push(format!("content light level (MaxCLL {}, MaxFALL {})",
+                                           payload.get_field("max_content_light_level").unwrap_or(0),
+                                           payload.get_field("max_pic_average_light_level").unwrap_or(0)));
+                    },
+                    Some(147) => {
+                        alternative_transfer += 1;
+                        let transfer = payload.get_field("preferred_transfer_characteristics").unwrap_or(2);
+                        found.push(format!("alternative transfer {}", describe(transfer, transfer_characteristics_name(transfer))));
+                    },
+                    Some(4) if is_hdr10_plus(payload) => {
+                        hdr10_plus += 1;
+                        found.push(format!("HDR10+ (application_version {})", payload.get_field("application_version").unwrap_or(0)));
+                    },
+                    _ => (),
+                }
+            }
+        }
+        if hdr10_plus > 0 && !found.iter().any(|x| x.starts_with("HDR10+")) {
+            missing_hdr10_plus.push(idx);
+        }
+        if !found.is_empty() {
+            ret.push_str(&format!("access unit {}: {}\n", idx, found.join(", ")));
+        }
+    }
+    ret.push_str(&format!("mastering_display_colour_volume: {}\ncontent_light_level_info: {}\nalternative_transfer_characteristics: {}\nhdr10_plus: {}\n",
+                          mastering_display, content_light_level, alternative_transfer, hdr10_plus));
+    if !missing_hdr10_plus.is_empty() {
+        let missing: Vec<String> = missing_hdr10_plus.iter().map(|x| x.to_string()).collect();
+        ret.push_str(&format!("Warning: no HDR10+ metadata in access units {} after it first appears\n", missing.join(", ")));
+    }
+
+    ret
+}
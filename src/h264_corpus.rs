@@ -0,0 +1,213 @@
+use std::collections::BTreeMap;
+
+use crate::bitstream_util::SyntaxElement;
+use crate::bitstream_util::SyntaxNode;
+use crate::h264_access_units::split_access_units;
+use crate::h264_analysis::idr_frame_indices;
+use crate::h264_analysis::is_arbitrary_slice_order;
+use crate::h264_analysis::slice_starts;
+use crate::h264_analysis::sps_resolution;
+use crate::h264_parser::get_slice_header;
+
+// What one stream of a corpus uses, for aggregating over many of them
+pub struct StreamSummary {
+    pub name: String,
+    pub profiles: Vec<i32>,
+    pub levels: Vec<i32>,
+    pub resolutions: Vec<(i32, i32)>,
+    pub frames: usize,
+    // Frames between consecutive IDRs
+    pub gop_lengths: Vec<usize>,
+    pub features: Vec<&'static str>,
+}
+
+fn push_unique<T: PartialEq>(list: &mut Vec<T>, val: T) {
+    if !list.contains(&val) {
+        list.push(val);
+    }
+}
+
+// Every feature stream_features can report, in matrix column order
+pub const FEATURES: [&str; 15] = ["cabac", "8x8_transform", "weighted_prediction", "b_slices", "sp_si_slices", "interlace", "mbaff",
+                                  "field_pictures", "multiple_slices", "aso", "fmo", "redundant_pictures", "data_partitioning",
+                                  "long_term_refs", "scaling_matrices"];
+
+fn parameter_set_features(nalu: &SyntaxNode, features: &mut Vec<&'static str>) {
+    if let Some(sps) = nalu.get_node("sps") {
+        if sps.get_field("frame_mbs_only_flag").unwrap_or(1) == 0 {
+            push_unique(features, "interlace");
+        }
+        if sps.get_field("mb_adaptive_frame_field_flag").unwrap_or(0) != 0 {
+            push_unique(features, "mbaff");
+        }
+        if sps.get_field("seq_scaling_matrix_present_flag").unwrap_or(0) != 0 {
+            push_unique(features, "scaling_matrices");
+        }
+    }
+    if let Some(pps) = nalu.get_node("pps") {
+        if pps.get_field("entropy_coding_mode_flag").unwrap_or(0) != 0 {
+            push_unique(features, "cabac");
+        }
+        if pps.get_field("transform_8x8_mode_flag").unwrap_or(0) != 0 {
+            push_unique(features, "8x8_transform");
+        }
+        if pps.get_field("pic_scaling_matrix_present_flag").unwrap_or(0) != 0 {
+            push_unique(features, "scaling_matrices");
+        }
+        if pps.get_field("weighted_bipred_idc").unwrap_or(0) == 2 {
+            push_unique(features, "weighted_prediction");
+        }
+        if pps.get_field("num_slice_groups_minus1").unwrap_or(0) > 0 {
+            push_unique(features, "fmo");
+        }
+        if pps.get_field("redundant_pic_cnt_present_flag").unwrap_or(0) != 0 {
+            push_unique(features, "redundant_pictures");
+        }
+    }
+}
+
+fn slice_header_features(header: &SyntaxNode, features: &mut Vec<&'static str>) {
+    match header.get_field("slice_type").unwrap_or(2) % 5 {
+        1 => push_unique(features, "b_slices"),
+        3 | 4 => push_unique(features, "sp_si_slices"),
+        _ => (),
+    }
+    if header.get_field("field_pic_flag").unwrap_or(0) != 0 {
+        push_unique(features, "field_pictures");
+    }
+    if header.get_node("pred_weight_table").is_some() {
+        push_unique(features, "weighted_prediction");
+    }
+    if let Some(marking) = header.get_node("dec_ref_pic_marking") {
+        if marking.get_field("long_term_reference_flag").unwrap_or(0) != 0 || marking.get_field("long_term_frame_idx").is_some() {
+            push_unique(features, "long_term_refs");
+        }
+    }
+}
+
+// Coding tools a stream turns on anywhere, by name
+pub fn stream_features(nalus: &[SyntaxElement]) -> Vec<&'static str> {
+    let mut ret: Vec<&'static str> = vec![];
+    for access_unit in split_access_units(nalus) {
+        let access_unit = &nalus[access_unit];
+        for element in access_unit {
+            let SyntaxElement::Node(nalu) = element else {
+                continue;
+            };
+            parameter_set_features(nalu, &mut ret);
+            if let Some(header) = get_slice_header(nalu) {
+                slice_header_features(header, &mut ret);
+            }
+            if (2..=4).contains(&nalu.get_field("nal_unit_type").unwrap_or(0)) {
+                push_unique(&mut ret, "data_partitioning");
+            }
+        }
+        let starts = slice_starts(access_unit);
+        if starts.len() > 1 {
+            push_unique(&mut ret, "multiple_slices");
+        }
+        if is_arbitrary_slice_order(&starts) {
+            push_unique(&mut ret, "aso");
+        }
+    }
+
+    ret
+}
+
+pub fn summarize_stream(name: &str, nalus: &[SyntaxElement]) -> StreamSummary {
+    let mut ret = StreamSummary { name: name.to_string(), profiles: vec![], levels: vec![], resolutions: vec![], frames: 0,
+                                  gop_lengths: vec![], features: stream_features(nalus) };
+    for element in nalus {
+        let SyntaxElement::Node(nalu) = element else {
+            continue;
+        };
+        if let Some(sps) = nalu.get_node("sps") {
+            push_unique(&mut ret.profiles, sps.get_field("profile_idc").unwrap_or(0));
+            push_unique(&mut ret.levels, sps.get_field("level_idc").unwrap_or(0));
+            push_unique(&mut ret.resolutions, sps_resolution(sps));
+        }
+    }
+    let (idr_frames, frames) = idr_frame_indices(nalus);
+    ret.frames = frames;
+    ret.gop_lengths = idr_frames.windows(2).map(|x| x[1] - x[0]).collect();
+
+    ret
+}
+
+fn count_lines<K: Ord, D: std::fmt::Display>(title: &str, counts: BTreeMap<K, usize>, describe: impl Fn(&K) -> D) -> String {
+    let mut ret = format!("{}:\n", title);
+    for (key, count) in counts {
+        ret.push_str(&format!("  {}: {} streams\n", describe(&key), count));
+    }
+    ret
+}
+
+// Aggregate a corpus into how many streams cover each profile, level, resolution and coding tool, plus the
+// spread of GOP lengths, to show which decoder paths a test corpus exercises at all.
+pub fn corpus_report(summaries: &[StreamSummary]) -> String {
+    let mut profiles: BTreeMap<i32, usize> = BTreeMap::new();
+    let mut levels: BTreeMap<i32, usize> = BTreeMap::new();
+    let mut resolutions: BTreeMap<(i32, i32), usize> = BTreeMap::new();
+    let mut features: BTreeMap<&'static str, usize> = BTreeMap::new();
+    let mut gop_lengths: Vec<usize> = vec![];
+    for summary in summaries {
+        for profile in &summary.profiles {
+            *profiles.entry(*profile).or_default() += 1;
+        }
+        for level in &summary.levels {
+            *levels.entry(*level).or_default() += 1;
+        }
+        for resolution in &summary.resolutions {
+            *resolutions.entry(*resolution).or_default() += 1;
+        }
+        for feature in &summary.features {
+            *features.entry(feature).or_default() += 1;
+        }
+        gop_lengths.extend(&summary.gop_lengths);
+    }
+
+    let mut ret = format!("streams: {}\nframes: {}\n", summaries.len(), summaries.iter().map(|x| x.frames).sum::<usize>());
+    ret.push_str(&count_lines("profile_idc", profiles, |x| *x));
+    ret.push_str(&count_lines("level_idc", levels, |x| *x));
+    ret.push_str(&count_lines("resolution", resolutions, |(width, height)| format!("{}x{}", width, height)));
+    match (gop_lengths.iter().min(), gop_lengths.iter().max()) {
+        (Some(min), Some(max)) => ret.push_str(&format!("gop_length: min {}, max {}, average {:.2} frames over {} GOPs\n", min, max,
+                                                        gop_lengths.iter().sum::<usize>() as f64 / gop_lengths.len() as f64, gop_lengths.len())),
+        _ => ret.push_str("gop_length: no stream has two IDRs\n"),
+    }
+    ret.push_str(&count_lines("features", features, |x| *x));
+    let plain: Vec<&str> = summaries.iter().filter(|x| x.features.is_empty()).map(|x| x.name.as_str()).collect();
+    if !plain.is_empty() {
+        ret.push_str(&format!("streams using none of the listed features: {}\n", plain.join(", ")));
+    }
+
+    ret
+}
+
+// One row per stream and one column per feature, "x" where the stream uses it, to find a stream that covers a given
+// decoder path. Columns are headed by their index, with the key listed above the table.
+pub fn feature_matrix(summaries: &[StreamSummary]) -> String {
+    let mut ret = String::new();
+    for (idx, feature) in FEATURES.iter().enumerate() {
+        ret.push_str(&format!("{:>2}: {}\n", idx, feature));
+    }
+    let name_width = summaries.iter().map(|x| x.name.len()).max().unwrap_or(0).max("stream".len());
+    ret.push_str(&format!("{:<width$}", "stream", width = name_width));
+    for idx in 0..FEATURES.len() {
+        ret.push_str(&format!(" {:>2}", idx));
+    }
+    ret.push('\n');
+    for summary in summaries {
+        ret.push_str(&format!("{:<width$}", summary.name, width = name_width));
+        for feature in FEATURES {
+            ret.push_str(if summary.features.contains(&feature) { "  x" } else { "  ." });
+        }
+        ret.push('\n');
+    }
+    let unused: Vec<&str> = FEATURES.iter().filter(|x| !summaries.iter().any(|y| y.features.contains(x))).copied().collect();
+    if !unused.is_empty() {
+        ret.push_str(&format!("No stream uses: {}\n", unused.join(", ")));
+    }
+
+    ret
+}
@@ -0,0 +1,222 @@
+use std::collections::HashMap;
+
+use crate::bitstream_util::location;
+use crate::bitstream_util::SyntaxElement;
+use crate::bitstream_util::SyntaxNode;
+use crate::h264_parser::get_slice_header;
+use crate::h264_parser::nal_unit_type_name;
+use crate::h264_parser::nalu_contents;
+use crate::h264_parser::without_byte_stream_fields;
+
+// Differences that are tolerated when comparing re-encodes which should be functionally equivalent
+#[derive(Default)]
+pub struct DiffTolerance {
+    pub ignore_sei: bool,
+    pub ignore_filler: bool,
+    // frame_num may be numbered differently as long as it advances on the same pictures on both sides
+    pub ignore_frame_num: bool,
+    // Compare payloads by name only, e.g. for slice data an encoder is free to change
+    pub ignore_payloads: bool,
+    // Fields whose values aren't compared, by name
+    pub ignore_fields: Vec<String>,
+}
+
+// NALU indices of the SPS and PPS a NALU was parsed against
+#[derive(Clone, Copy, Default)]
+struct Provenance {
+    sps: Option<usize>,
+    pps: Option<usize>,
+}
+
+fn nalu_node(nalus: &[SyntaxElement], idx: usize) -> Option<&SyntaxNode> {
+    match nalus.get(idx)? {
+        SyntaxElement::Node(nalu) => Some(nalu),
+        _ => None,
+    }
+}
+
+// Resolve the parameter sets behind every PPS and slice by the ids they refer to.
+fn parameter_set_provenance(nalus: &[SyntaxElement]) -> Vec<Provenance> {
+    let mut sps_by_id: HashMap<i32, usize> = HashMap::new();
+    let mut pps_by_id: HashMap<i32, usize> = HashMap::new();
+    let mut ret: Vec<Provenance> = vec![];
+    for (idx, element) in nalus.iter().enumerate() {
+        let mut provenance = Provenance::default();
+        if let SyntaxElement::Node(nalu) = element {
+            if let Some(id) = nalu.get_node("sps").and_then(|x| x.get_field("seq_paramter_set_id")) {
+                sps_by_id.insert(id, idx);
+            }
+            if let Some(pps) = nalu.get_node("pps") {
+                provenance.sps = pps.get_field("seq_parameter_set_id").and_then(|x| sps_by_id.get(&x).copied());
+                if let Some(id) = pps.get_field("pic_parameter_set_id") {
+                    pps_by_id.insert(id, idx);
+                }
+            }
+            if let Some(header) = get_slice_header(nalu) {
+                provenance.pps = header.get_field("pic_parameter_set_id").and_then(|x| pps_by_id.get(&x).copied());
+                provenance.sps = provenance.pps.and_then(|x| ret[x].sps);
+            }
+        }
+        ret.push(provenance);
+    }
+
+    ret
+}
+
+fn element_name(element: &SyntaxElement) -> &str {
+    match element {
+        SyntaxElement::Field(field) => &field.name,
+        SyntaxElement::Node(node) => &node.name,
+        SyntaxElement::Payload(payload) => &payload.name,
+    }
+}
+
+// Walk two nodes in step, noting every differing value. Returns false once the syntax itself diverges, since
+// nothing after that point lines up anymore.
+// Values read from a text form are prefixed with the line of b they came from.
+fn diff_nodes(a: &SyntaxNode, b: &SyntaxNode, path: &str, ignored: &[&str], ignore_payloads: bool, diffs: &mut Vec<String>) -> bool {
+    for (x, y) in a.children.iter().zip(&b.children) {
+        match (x, y) {
+            (SyntaxElement::Field(x), SyntaxElement::Field(y)) if x.name == y.name => {
+                if x.val != y.val && !ignored.contains(&x.name.as_str()) {
+                    diffs.push(format!("{}{}{}: {} != {}", location(y.line), path, x.name, x.val, y.val));
+                }
+            },
+            (SyntaxElement::Node(x), SyntaxElement::Node(y)) if x.name == y.name => {
+                if !diff_nodes(x, y, &format!("{}{}/", path, x.name), ignored, ignore_payloads, diffs) {
+                    return false;
+                }
+            },
+            (SyntaxElement::Payload(x), SyntaxElement::Payload(y)) if x.name == y.name => {
+                if x.data != y.data && !ignore_payloads {
+                    let offset = x.data.iter().zip(&y.data).position(|(x, y)| x != y).unwrap_or(x.data.len().min(y.data.len()));
+                    diffs.push(format!("{}{}{}: {} bytes != {} bytes, first difference at byte {}", location(y.line), path, x.name, x.data.len(), y.data.len(), offset));
+                }
+            },
+            _ => {
+                diffs.push(format!("{}{}: syntax diverges, {} != {}", location(y.line()), path, element_name(x), element_name(y)));
+                return false;
+            },
+        }
+    }
+    if a.children.len() != b.children.len() {
+        diffs.push(format!("{}{}: {} elements != {} elements", location(b.line), path, a.children.len(), b.children.len()));
+        return false;
+    }
+
+    true
+}
+
+fn describe_provenance(nalus: &[SyntaxElement], provenance: Provenance) -> String {
+    let sps = provenance.sps.and_then(|x| Some((x, nalu_node(nalus, x)?.get_node("sps")?.get_field("seq_paramter_set_id")?)));
+    let pps = provenance.pps.and_then(|x| Some((x, nalu_node(nalus, x)?.get_node("pps")?.get_field("pic_parameter_set_id")?)));
+    let mut ret: Vec<String> = vec![];
+    match sps {
+        Some((idx, id)) => ret.push(format!("SPS {} from NALU {}", id, idx)),
+        None => ret.push("no SPS".to_string()),
+    }
+    if let Some((idx, id)) = pps {
+        ret.push(format!("PPS {} from NALU {}", id, idx));
+    }
+
+    ret.join(", ")
+}
+
+// Name the parameter sets that parsed differently on the two sides, which explains differences in a NALU
+// that come from its SPS/PPS rather than from the NALU itself.
+fn differing_parameter_sets(a: &[SyntaxElement], a_provenance: Provenance, b: &[SyntaxElement], b_provenance: Provenance) -> Vec<&'static str> {
+    let contents = |nalus: &[SyntaxElement], idx: Option<usize>| idx.and_then(|x| nalu_node(nalus, x)).map(nalu_contents);
+    let mut ret: Vec<&'static str> = vec![];
+    if contents(a, a_provenance.sps) != contents(b, b_provenance.sps) {
+        ret.push("SPS");
+    }
+    if contents(a, a_provenance.pps) != contents(b, b_provenance.pps) {
+        ret.push("PPS");
+    }
+
+    ret
+}
+
+// Indices of the NALUs that take part in the comparison
+fn compared_nalus(nalus: &[SyntaxElement], tolerance: &DiffTolerance) -> Vec<usize> {
+    (0..nalus.len()).filter(|x| {
+        let nal_unit_type = nalu_node(nalus, *x).and_then(|y| y.get_field("nal_unit_type"));
+        let ignored = (tolerance.ignore_sei && nal_unit_type == Some(6)) || (tolerance.ignore_filler && nal_unit_type == Some(12));
+        !ignored
+    }).collect()
+}
+
+// Compare two parsed streams NALU by NALU. Each NALU with differences is listed along with the parameter
+// sets each side parsed it against.
+pub fn diff_report(a: &[SyntaxElement], b: &[SyntaxElement], tolerance: &DiffTolerance) -> String {
+    diff_streams(a, b, tolerance).0
+}
+
+// The diff report, and whether the streams are structurally equal
+pub fn diff_streams(a: &[SyntaxElement], b: &[SyntaxElement], tolerance: &DiffTolerance) -> (String, bool) {
+    let a_provenance = parameter_set_provenance(a);
+    let b_provenance = parameter_set_provenance(b);
+    let a_compared = compared_nalus(a, tolerance);
+    let b_compared = compared_nalus(b, tolerance);
+    let mut ignored: Vec<&str> = tolerance.ignore_fields.iter().map(|x| x.as_str()).collect();
+    if tolerance.ignore_frame_num {
+        ignored.push("frame_num");
+    }
+    let mut prev_frame_nums: Option<(i32, i32)> = None;
+    let mut ret = "".to_string();
+    let mut differing_nalus = 0;
+    for (&a_idx, &b_idx) in a_compared.iter().zip(&b_compared) {
+        let (Some(a_nalu), Some(b_nalu)) = (nalu_node(a, a_idx), nalu_node(b, b_idx)) else {
+            continue;
+        };
+        let mut diffs: Vec<String> = vec![];
+        // How a NALU was framed in the byte stream isn't part of its syntax
+        diff_nodes(&without_byte_stream_fields(a_nalu), &without_byte_stream_fields(b_nalu), "", &ignored, tolerance.ignore_payloads, &mut diffs);
+        if tolerance.ignore_frame_num {
+            let frame_num = |x: &SyntaxNode| get_slice_header(x).and_then(|y| y.get_field("frame_num"));
+            if let (Some(a_frame_num), Some(b_frame_num)) = (frame_num(a_nalu), frame_num(b_nalu)) {
+                if let Some((a_prev, b_prev)) = prev_frame_nums {
+                    if (a_frame_num == a_prev) != (b_frame_num == b_prev) {
+                        diffs.push(format!("frame_num: {} -> {} != {} -> {}, renumbering does not line up", a_prev, a_frame_num, b_prev, b_frame_num));
+                    }
+                }
+                prev_frame_nums = Some((a_frame_num, b_frame_num));
+            }
+        }
+        if diffs.is_empty() {
+            continue;
+        }
+        differing_nalus += 1;
+        let nal_unit_type = a_nalu.get_field("nal_unit_type").unwrap_or(0);
+        if a_idx == b_idx {
+            ret.push_str(&format!("NALU {} ({}):\n", a_idx, nal_unit_type_name(nal_unit_type)));
+        } else {
+            ret.push_str(&format!("NALU {} in a, {} in b ({}):\n", a_idx, b_idx, nal_unit_type_name(nal_unit_type)));
+        }
+        for diff in diffs {
+            ret.push_str(&format!("  {}\n", diff));
+        }
+        let (a_provenance, b_provenance) = (a_provenance[a_idx], b_provenance[b_idx]);
+        if a_provenance.sps.is_some() || a_provenance.pps.is_some() || b_provenance.sps.is_some() || b_provenance.pps.is_some() {
+            ret.push_str(&format!("  a parsed with {}\n", describe_provenance(a, a_provenance)));
+            ret.push_str(&format!("  b parsed with {}\n", describe_provenance(b, b_provenance)));
+            let differing = differing_parameter_sets(a, a_provenance, b, b_provenance);
+            if !differing.is_empty() {
+                ret.push_str(&format!("  active {} {} between a and b\n", differing.join(" and "), if differing.len() == 1 { "differs" } else { "differ" }));
+            }
+        }
+    }
+    if a_compared.len() != b_compared.len() {
+        ret.push_str(&format!("a has {} NALUs, b has {}\n", a_compared.len(), b_compared.len()));
+    }
+    if a_compared.len() != a.len() || b_compared.len() != b.len() {
+        ret.push_str(&format!("ignored {} NALUs in a and {} in b\n", a.len() - a_compared.len(), b.len() - b_compared.len()));
+    }
+    ret.push_str(&format!("{} of {} compared NALUs differ\n", differing_nalus, a_compared.len().min(b_compared.len())));
+    let equal = differing_nalus == 0 && a_compared.len() == b_compared.len();
+    if equal {
+        ret.push_str("streams are structurally equal\n");
+    }
+
+    (ret, equal)
+}
@@ -0,0 +1,374 @@
+use std::collections::VecDeque;
+
+use crate::bitstream_util::SyntaxElement;
+use crate::bitstream_util::SyntaxNode;
+use crate::h264_parser::SerializeOptions;
+use crate::h264_parser::pack_slice_payload;
+use crate::h264_parser::serialize_h264_nalus;
+use crate::h264_parser::serialize_h264_nalus_separately;
+use crate::h264_profiles::LEVEL_MAX_FRAME_SIZES;
+use crate::h264_profiles::is_high_profile;
+use crate::json::JsonValue;
+use crate::json::parse_json;
+
+// An I_16x16_2_0_0 macroblock: DC prediction, no coded coefficients, so every sample decodes to 128.
+// mb_type ue(3), intra_chroma_pred_mode ue(0), mb_qp_delta se(0), and a coeff_token with nC 0 for the
+// empty Intra16x16DCLevel block.
+const GREY_MACROBLOCK: u8 = 0b0010_0111;
+
+// pic_order_cnt_lsb length of generated streams with B frames, enough for anchors up to 64 frames apart
+const POC_LSB_BITS: i32 = 8;
+
+// Smallest level whose MaxFS fits the picture, in total and along each side (A.3.1 item h)
+fn minimum_level(width_mbs: i32, height_mbs: i32) -> i32 {
+    LEVEL_MAX_FRAME_SIZES.iter()
+        .find(|(_, max_fs)| width_mbs * height_mbs <= *max_fs && width_mbs.max(height_mbs) * width_mbs.max(height_mbs) <= 8 * max_fs)
+        .map(|x| x.0)
+        .unwrap_or(62)
+}
+
+fn new_nalu(nal_ref_idc: i32, nal_unit_type: i32, body: SyntaxNode) -> SyntaxElement {
+    let mut nalu = SyntaxNode::new("nalu");
+    nalu.push_field("forbidden_zero_bit", 0);
+    nalu.push_field("nal_ref_idc", nal_ref_idc);
+    nalu.push_field("nal_unit_type", nal_unit_type);
+    nalu.children.push_back(SyntaxElement::Node(body));
+    SyntaxElement::Node(nalu)
+}
+
+// With reorders, output order differs from decode order: POC type 0 carries it and B frames get two references
+fn skeleton_sps(width: i32, height: i32, profile_idc: i32, level_idc: i32, reorders: bool) -> SyntaxNode {
+    let (width_mbs, height_mbs) = ((width + 15) / 16, (height + 15) / 16);
+    let mut sps = SyntaxNode::new("sps");
+    sps.push_field("profile_idc", profile_idc);
+    // Baseline is signaled as constrained baseline so Main and High decoders accept it too
+    sps.push_field("constraint_set0_flag", i32::from(profile_idc == 66));
+    sps.push_field("constraint_set1_flag", i32::from(profile_idc == 66 || profile_idc == 77));
+    for name in ["constraint_set2_flag", "constraint_set3_flag", "constraint_set4_flag", "constraint_set5_flag"] {
+        sps.push_field(name, 0);
+    }
+    sps.push_field("reserved_zero_2bits", 0);
+    sps.push_field("level_idc", level_idc);
+    sps.push_field("seq_paramter_set_id", 0);
+    if is_high_profile(profile_idc) {
+        sps.push_field("chroma_format_idc", 1);
+        sps.push_field("bit_depth_luma_minus8", 0);
+        sps.push_field("bit_depth_chroma_minus8", 0);
+        sps.push_field("qpprime_y_zero_transform_bypass_flag", 0);
+        sps.push_field("seq_scaling_matrix_present_flag", 0);
+    }
+    sps.push_field("log2_max_frame_num_minus4", 0);
+    if reorders {
+        sps.push_field("pic_order_cnt_type", 0);
+        sps.push_field("log2_max_pic_order_cnt_lsb_minus4", POC_LSB_BITS - 4);
+        sps.push_field("max_num_ref_frames", 2);
+    } else {
+        // Picture order follows decode order, so slices need no POC fields
+        sps.push_field("pic_order_cnt_type", 2);
+        sps.push_field("max_num_ref_frames", 1);
+    }
+    sps.push_field("gaps_in_frame_num_value_allowed_flag", 0);
+    sps.push_field("pic_width_in_mbs_minus1", width_mbs - 1);
+    sps.push_field("pic_height_in_mbs_minus1", height_mbs - 1);
+    sps.push_field("frame_mbs_only_flag", 1);
+    sps.push_field("direct_8x8_inference_flag", 1);
+    // Crop units are two samples in each direction for 4:2:0 frames
+    let (crop_right, crop_bottom) = ((width_mbs * 16 - width) / 2, (height_mbs * 16 - height) / 2);
+    sps.push_field("frame_cropping_flag", i32::from(crop_right != 0 || crop_bottom != 0));
+    if crop_right != 0 || crop_bottom != 0 {
+        sps.push_field("frame_crop_left_offset", 0);
+        sps.push_field("frame_crop_right_offset", crop_right);
+        sps.push_field("frame_crop_top_offset", 0);
+        sps.push_field("frame_crop_bottom_offset", crop_bottom);
+    }
+    sps.push_field("vui_parameters_present_flag", 0);
+    sps.push_payload("trailing_bits", vec![0x80]);
+    sps
+}
+
+fn skeleton_pps() -> SyntaxNode {
+    let mut pps = SyntaxNode::new("pps");
+    for name in ["pic_parameter_set_id", "seq_parameter_set_id", "entropy_coding_mode_flag", "bottom_field_pic_order_in_frame_present_flag",
+                 "num_slice_groups_minus1", "num_ref_idx_l0_default_active_minus1", "num_ref_idx_l1_default_active_minus1",
+                 "weighted_pred_flag", "weighted_bipred_idc", "pic_init_qp_minus26", "pic_init_qs_minus26", "chroma_qp_index_offset"] {
+        pps.push_field(name, 0);
+    }
+    pps.push_field("deblocking_filter_control_present_flag", 1);
+    pps.push_field("constrained_intra_pred_flag", 0);
+    pps.push_field("redundant_pic_cnt_present_flag", 0);
+    pps.push_payload("trailing_bits", vec![0x80]);
+    pps
+}
+
+fn skeleton_idr_slice(slice_payload: Vec<u8>) -> SyntaxNode {
+    let mut header = SyntaxNode::new("slice_header");
+    header.push_field("first_mb_in_slice", 0);
+    header.push_field("slice_type", 7);
+    header.push_field("pic_parameter_set_id", 0);
+    header.push_field("frame_num", 0);
+    header.push_field("idr_pic_id", 0);
+    header.children.push_back(SyntaxElement::Node(SyntaxNode::new("ref_pic_list_modification")));
+    let mut dec_ref_pic_marking = SyntaxNode::new("dec_ref_pic_marking");
+    dec_ref_pic_marking.push_field("no_output_of_prior_pics_flag", 0);
+    dec_ref_pic_marking.push_field("long_term_reference_flag", 0);
+    header.children.push_back(SyntaxElement::Node(dec_ref_pic_marking));
+    header.push_field("slice_qp_delta", 0);
+    header.push_field("disable_deblocking_filter_idc", 1);
+    let mut slice = SyntaxNode::new("slice");
+    slice.children.push_back(SyntaxElement::Node(header));
+    slice.push_payload("slice_payload", slice_payload);
+    slice
+}
+
+// Grey macroblocks plus rbsp_slice_trailing_bits
+fn grey_slice_data(macroblocks: i32, bit_offset: usize) -> Vec<bool> {
+    let mut ret: Vec<bool> = vec![];
+    for _ in 0..macroblocks {
+        ret.extend((0..8).map(|x| (GREY_MACROBLOCK << x) & 0x80 != 0));
+    }
+    ret.push(true);
+    while !(bit_offset + ret.len()).is_multiple_of(8) {
+        ret.push(false);
+    }
+
+    ret
+}
+
+// A minimal stream that decodes to one mid grey frame: SPS, PPS and a CAVLC IDR slice of empty macroblocks.
+pub fn generate_skeleton(width: i32, height: i32, profile_idc: i32, level_idc: Option<i32>) -> Vec<u8> {
+    if width <= 0 || height <= 0 || width % 2 != 0 || height % 2 != 0 {
+        panic!("Width and height must be positive and even for 4:2:0");
+    }
+    let (width_mbs, height_mbs) = ((width + 15) / 16, (height + 15) / 16);
+    let level_idc = level_idc.unwrap_or_else(|| minimum_level(width_mbs, height_mbs));
+    let nalus = |slice_payload: Vec<u8>| VecDeque::from([new_nalu(3, 7, skeleton_sps(width, height, profile_idc, level_idc, false)),
+                                                          new_nalu(3, 8, skeleton_pps()),
+                                                          new_nalu(3, 5, skeleton_idr_slice(slice_payload))]);
+    // Serialize once to learn where the slice data starts, since the header isn't byte aligned
+    let bit_offset = serialize_h264_nalus_separately(nalus(vec![]))[2].1.as_ref().unwrap().bit_offset;
+    let slice_payload = pack_slice_payload(&grey_slice_data(width_mbs * height_mbs, bit_offset), bit_offset);
+    serialize_h264_nalus(nalus(slice_payload), &SerializeOptions { regenerate_trailing_bits: true, ..Default::default() })
+}
+
+// What generate_from_description builds, read from a JSON object by parse_stream_description
+pub struct StreamDescription {
+    pub width: i32,
+    pub height: i32,
+    pub frames: usize,
+    // Frame types in display order, e.g. "IBBP", repeated until frames are coded. Each repetition starts
+    // with an IDR.
+    pub gop: String,
+    // Slice QP of I, P and B frames
+    pub qp: [i32; 3],
+    pub profile_idc: i32,
+    pub level_idc: Option<i32>,
+    pub slices_per_frame: i32,
+    // Dummy slice data bytes after each slice header
+    pub payload_bytes: usize,
+}
+
+pub fn parse_stream_description(text: &str) -> StreamDescription {
+    let json = parse_json(text);
+    let number = |key: &str, default: Option<f64>| -> f64 {
+        match json.get(key) {
+            Some(val) => val.as_f64().unwrap_or_else(|| panic!("{} must be a number", key)),
+            None => default.unwrap_or_else(|| panic!("Description has no {}", key)),
+        }
+    };
+    let gop = match json.get("gop") {
+        Some(val) => val.as_str().expect("gop must be a string").to_string(),
+        None => "I".to_string(),
+    };
+    let qp = match json.get("qp") {
+        Some(JsonValue::Object(_)) => {
+            let qp = json.get("qp").unwrap();
+            ["I", "P", "B"].map(|x| qp.get(x).map(|y| y.as_f64().unwrap_or_else(|| panic!("qp {} must be a number", x)) as i32).unwrap_or(26))
+        },
+        Some(val) => [val.as_f64().expect("qp must be a number or an object") as i32; 3],
+        None => [26; 3],
+    };
+    StreamDescription {
+        width: number("width", None) as i32,
+        height: number("height", None) as i32,
+        frames: number("frames", Some(1.0)) as usize,
+        // B frames need Main profile or above
+        profile_idc: number("profile_idc", Some(if gop.contains('B') { 77.0 } else { 66.0 })) as i32,
+        level_idc: json.get("level_idc").map(|x| x.as_f64().expect("level_idc must be a number") as i32),
+        slices_per_frame: number("slices_per_frame", Some(1.0)) as i32,
+        payload_bytes: number("payload_bytes", Some(16.0)) as usize,
+        gop,
+        qp,
+    }
+}
+
+struct CodedFrame {
+    frame_type: char,
+    idr: bool,
+    frame_num: i32,
+    idr_pic_id: i32,
+    // Display index since the last IDR
+    display_offset: usize,
+}
+
+// Frames in decode order. B frames are coded after the anchor that follows them, so those with no anchor
+// before the next IDR or the end of the stream become P frames.
+fn coding_order(description: &StreamDescription) -> Vec<CodedFrame> {
+    let gop: Vec<char> = description.gop.chars().collect();
+    if gop.first() != Some(&'I') || gop.iter().any(|x| !"IPB".contains(*x)) {
+        panic!("gop must be made of I, P and B and start with I");
+    }
+    let mut types: Vec<char> = (0..description.frames).map(|x| gop[x % gop.len()]).collect();
+    let mut anchor_follows = false;
+    for (i, frame_type) in types.iter_mut().enumerate().rev() {
+        if *frame_type == 'B' && !anchor_follows {
+            *frame_type = 'P';
+        }
+        if *frame_type != 'B' {
+            anchor_follows = i % gop.len() != 0;
+        }
+    }
+
+    let mut display_order: Vec<usize> = vec![];
+    let mut pending: Vec<usize> = vec![];
+    for (i, frame_type) in types.iter().enumerate() {
+        if *frame_type == 'B' {
+            pending.push(i);
+        } else {
+            display_order.push(i);
+            display_order.append(&mut pending);
+        }
+    }
+
+    let mut ret: Vec<CodedFrame> = vec![];
+    let (mut idr_display_index, mut idr_count, mut prev_ref_frame_num) = (0, 0, 0);
+    for i in display_order {
+        let idr = i % gop.len() == 0;
+        if idr {
+            idr_display_index = i;
+            idr_count += 1;
+        }
+        // frame_num counts reference frames since the IDR (7.4.3), wrapping at MaxFrameNum 16
+        let frame_num = if idr { 0 } else { (prev_ref_frame_num + 1) % 16 };
+        if types[i] != 'B' {
+            prev_ref_frame_num = frame_num;
+        }
+        ret.push(CodedFrame { frame_type: types[i], idr, frame_num, idr_pic_id: (idr_count - 1) % 2, display_offset: i - idr_display_index });
+    }
+
+    ret
+}
+
+fn description_slice(frame: &CodedFrame, first_mb_in_slice: i32, reorders: bool, qp: i32, slice_payload: Vec<u8>) -> SyntaxNode {
+    let mut header = SyntaxNode::new("slice_header");
+    header.push_field("first_mb_in_slice", first_mb_in_slice);
+    // The types that promise every slice of the picture is the same type
+    header.push_field("slice_type", match frame.frame_type { 'P' => 5, 'B' => 6, _ => 7 });
+    header.push_field("pic_parameter_set_id", 0);
+    header.push_field("frame_num", frame.frame_num);
+    if frame.idr {
+        header.push_field("idr_pic_id", frame.idr_pic_id);
+    }
+    if reorders {
+        header.push_field("pic_order_cnt_lsb", (2 * frame.display_offset as i32) % (1 << POC_LSB_BITS));
+    }
+    let mut ref_pic_list_modification = SyntaxNode::new("ref_pic_list_modification");
+    match frame.frame_type {
+        'P' => {
+            header.push_field("num_ref_idx_active_override_flag", 0);
+            ref_pic_list_modification.push_field("ref_pic_list_modification_flag_l0", 0);
+        },
+        'B' => {
+            header.push_field("direct_spatial_mv_pred_flag", 1);
+            header.push_field("num_ref_idx_active_override_flag", 1);
+            header.push_field("num_ref_idx_l0_active_minus1", 0);
+            header.push_field("num_ref_idx_l1_active_minus1", 0);
+            ref_pic_list_modification.push_field("ref_pic_list_modification_flag_l0", 0);
+            ref_pic_list_modification.push_field("ref_pic_list_modification_flag_l1", 0);
+        },
+        _ => (),
+    }
+    header.children.push_back(SyntaxElement::Node(ref_pic_list_modification));
+    // B frames aren't used for reference, so they carry no marking
+    if frame.frame_type != 'B' {
+        let mut dec_ref_pic_marking = SyntaxNode::new("dec_ref_pic_marking");
+        if frame.idr {
+            dec_ref_pic_marking.push_field("no_output_of_prior_pics_flag", 0);
+            dec_ref_pic_marking.push_field("long_term_reference_flag", 0);
+        } else {
+            dec_ref_pic_marking.push_field("adaptive_ref_pic_marking_mode_flag", 0);
+        }
+        header.children.push_back(SyntaxElement::Node(dec_ref_pic_marking));
+    }
+    // pic_init_qp_minus26 is 0
+    header.push_field("slice_qp_delta", qp - 26);
+    header.push_field("disable_deblocking_filter_idc", 1);
+    let mut slice = SyntaxNode::new("slice");
+    slice.children.push_back(SyntaxElement::Node(header));
+    slice.push_payload("slice_payload", slice_payload);
+    slice
+}
+
+// Arbitrary slice data bytes plus rbsp_slice_trailing_bits. Only the headers are meant to be valid.
+fn dummy_slice_data(payload_bytes: usize, bit_offset: usize) -> Vec<bool> {
+    let mut ret: Vec<bool> = vec![];
+    for _ in 0..payload_bytes {
+        ret.extend((0..8).map(|x| (0xA5u8 << x) & 0x80 != 0));
+    }
+    ret.push(true);
+    while !(bit_offset + ret.len()).is_multiple_of(8) {
+        ret.push(false);
+    }
+
+    ret
+}
+
+// A headers-only stream following a description: SPS and PPS before every IDR, then slice headers carrying
+// the GOP structure and QPs with dummy slice data.
+pub fn generate_from_description(description: &StreamDescription) -> Vec<u8> {
+    let (width, height) = (description.width, description.height);
+    if width <= 0 || height <= 0 || width % 2 != 0 || height % 2 != 0 {
+        panic!("Width and height must be positive and even for 4:2:0");
+    }
+    if description.qp.iter().any(|x| !(0..=51).contains(x)) {
+        panic!("QPs must be in the range 0..=51");
+    }
+    let frames = coding_order(description);
+    let reorders = frames.iter().any(|x| x.frame_type == 'B');
+    if reorders && description.profile_idc == 66 {
+        panic!("B frames need Main profile or above");
+    }
+    if description.gop.split(['I', 'P']).any(|x| x.len() >= 1 << (POC_LSB_BITS - 2)) {
+        panic!("Too many consecutive B frames");
+    }
+    let (width_mbs, height_mbs) = ((width + 15) / 16, (height + 15) / 16);
+    let slices = description.slices_per_frame.clamp(1, width_mbs * height_mbs);
+    let level_idc = description.level_idc.unwrap_or_else(|| minimum_level(width_mbs, height_mbs));
+
+    let nalus = |slice_payloads: &[Vec<u8>]| {
+        let mut ret: VecDeque<SyntaxElement> = VecDeque::new();
+        let mut payloads = slice_payloads.iter();
+        for frame in &frames {
+            if frame.idr {
+                ret.push_back(new_nalu(3, 7, skeleton_sps(width, height, description.profile_idc, level_idc, reorders)));
+                ret.push_back(new_nalu(3, 8, skeleton_pps()));
+            }
+            let (nal_ref_idc, qp) = match frame.frame_type {
+                'P' => (2, description.qp[1]),
+                'B' => (0, description.qp[2]),
+                _ => (if frame.idr { 3 } else { 2 }, description.qp[0]),
+            };
+            for i in 0..slices {
+                let first_mb_in_slice = i * width_mbs * height_mbs / slices;
+                let slice = description_slice(frame, first_mb_in_slice, reorders, qp, payloads.next().cloned().unwrap_or_default());
+                ret.push_back(new_nalu(nal_ref_idc, if frame.idr { 5 } else { 1 }, slice));
+            }
+        }
+        ret
+    };
+    // Serialize once to learn where each slice's data starts
+    let slice_payloads: Vec<Vec<u8>> = serialize_h264_nalus_separately(nalus(&[])).iter()
+        .filter_map(|x| x.1.as_ref())
+        .map(|x| pack_slice_payload(&dummy_slice_data(description.payload_bytes, x.bit_offset), x.bit_offset))
+        .collect();
+    serialize_h264_nalus(nalus(&slice_payloads), &SerializeOptions { regenerate_trailing_bits: true, ..Default::default() })
+}
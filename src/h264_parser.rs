@@ -1,63 +1,243 @@
+use std::collections::HashMap;
+use std::collections::HashSet;
 use std::collections::VecDeque;
+use std::panic;
+use std::panic::AssertUnwindSafe;
+use std::path::Path;
+use std::path::PathBuf;
 
 use crate::bitstream_util::SyntaxField;
 use crate::bitstream_util::SyntaxNode;
 use crate::bitstream_util::SyntaxElement;
 use crate::bitstream_util::BitstreamReader;
+use crate::bitstream_util::BitstreamTracer;
 use crate::bitstream_util::BitstreamWriter;
 use crate::bitstream_util::FieldType;
+use crate::bitstream_util::TraceEntry;
 use crate::bitstream_util::BitstreamProcessor;
+use crate::bitstream_util::canonicalize;
+use crate::bitstream_util::ebsp_to_rbsp;
+use crate::bitstream_util::location;
+use crate::bitstream_util::warn_unread_children;
 use crate::bitstream_util::syntax_elements_from_string;
+use crate::fuzz::is_unexpected_panic;
+use crate::fuzz::panic_message;
+use crate::h264_access_units::split_access_units;
+use crate::h264_cache::SliceDataCache;
+use crate::h264_slice_data::SliceDataParams;
+use crate::h264_slice_data::process_slice_data;
+use crate::nal_header::H264HeaderExtension;
+use crate::nal_header::NalHeader;
+use crate::nal_header::NalHeaderFormat;
+use crate::nal_header::peek_nal_unit_type;
+use crate::nal_header::process_nal_unit_header;
 
-struct H264State {
+// Values of an SPS that the NALUs parsed after it depend on
+#[derive(Clone)]
+struct SpsState {
     chroma_format_idc: i32,
     separate_color_plane_flag: bool,
+    bit_depth_luma_minus8: i32,
+    bit_depth_chroma_minus8: i32,
     frame_mbs_only_flag: bool,
+    mb_adaptive_frame_field_flag: bool,
+    direct_8x8_inference_flag: bool,
     pic_order_cnt_type: i32,
-    bottom_field_pic_order_in_frame_present_flag: bool,
     delta_pic_order_always_zero_flag: bool,
+    log2_max_frame_num_minus4: i32,
+    log2_max_pic_order_cnt_lsb_minus4: i32,
+    pic_width_in_mbs_minus1: i32,
+    pic_height_in_map_units_minus1: i32,
+    nal_hrd_parameters_present_flag: bool,
+    vcl_hrd_parameters_present_flag: bool,
+    nal_cpb_cnt_minus1: i32,
+    vcl_cpb_cnt_minus1: i32,
+    initial_cpb_removal_delay_length_minus1: i32,
+    cpb_removal_delay_length_minus1: i32,
+    dpb_output_delay_length_minus1: i32,
+    time_offset_length: i32,
+    pic_struct_present_flag: bool,
+    // num_units_in_tick and time_scale of the VUI timing_info
+    timing_info: Option<(u32, u32)>,
+}
+
+impl SpsState {
+    // ChromaArrayType (7.4.2.1.1)
+    fn chroma_array_type(&self) -> i32 {
+        if self.separate_color_plane_flag { 0 } else { self.chroma_format_idc }
+    }
+}
+
+impl Default for SpsState {
+    fn default() -> SpsState {
+        SpsState { chroma_format_idc: 1,
+                   separate_color_plane_flag: false,
+                   bit_depth_luma_minus8: 0,
+                   bit_depth_chroma_minus8: 0,
+                   frame_mbs_only_flag: false,
+                   mb_adaptive_frame_field_flag: false,
+                   direct_8x8_inference_flag: false,
+                   pic_order_cnt_type: 0,
+                   delta_pic_order_always_zero_flag: false,
+                   log2_max_frame_num_minus4: 0,
+                   log2_max_pic_order_cnt_lsb_minus4: 0,
+                   pic_width_in_mbs_minus1: 0,
+                   pic_height_in_map_units_minus1: 0,
+                   nal_hrd_parameters_present_flag: false,
+                   vcl_hrd_parameters_present_flag: false,
+                   nal_cpb_cnt_minus1: 0,
+                   vcl_cpb_cnt_minus1: 0,
+                   initial_cpb_removal_delay_length_minus1: 23,
+                   cpb_removal_delay_length_minus1: 23,
+                   dpb_output_delay_length_minus1: 23,
+                   time_offset_length: 24,
+                   pic_struct_present_flag: false,
+                   timing_info: None,
+        }
+    }
+}
+
+// Values of a PPS that slices referring to it depend on
+#[derive(Clone, Default)]
+struct PpsState {
+    seq_parameter_set_id: i32,
+    bottom_field_pic_order_in_frame_present_flag: bool,
     redundant_pic_cnt_present_flag: bool,
+    num_ref_idx_l0_default_active_minus1: i32,
+    num_ref_idx_l1_default_active_minus1: i32,
     weighted_pred_flag: bool,
     weighted_bipred_idc: i32,
     entropy_coding_mode_flag: bool,
+    transform_8x8_mode_flag: bool,
     deblocking_filter_control_present_flag: bool,
     num_slice_groups_minus1: i32,
     slice_group_map_type: i32,
-    log2_max_frame_num_minus4: i32,
-    log2_max_pic_order_cnt_lsb_minus4: i32,
-    num_ref_idx_l0_active_minus1: i32,
-    num_ref_idx_l1_active_minus1: i32,
     pic_size_in_map_units_minus1: i32,
     slice_group_change_rate_minus1: i32,
 }
 
+// Caps on counts read from the bitstream that drive parse loops or size allocations, so crafted input fails
+// with a panic instead of looping or allocating without bound. The defaults are the largest values a
+// conforming stream can have.
+#[derive(Clone)]
+pub struct ParseLimits {
+    // num_ref_frames_in_pic_order_cnt_cycle, at most 255 (7.4.2.1.1)
+    pub max_pic_order_cnt_cycle: i32,
+    // num_slice_groups_minus1 + 1, at most 8 (7.4.2.2)
+    pub max_slice_groups: i32,
+    // Macroblocks in a frame and PicSizeInMapUnits of a PPS, at most MaxFS of level 6.2 (Table A-1)
+    pub max_pic_size_in_mbs: i32,
+    // num_ref_idx_l0_active_minus1 + 1 and num_ref_idx_l1_active_minus1 + 1, at most 32 (7.4.3)
+    pub max_ref_idx_active: i32,
+    // Operations in one ref_pic_list_modification list or one dec_ref_pic_marking. The spec doesn't bound the
+    // latter, but real encoders stay far below this.
+    pub max_modification_operations: i32,
+    // coded_data_bit_depth and target_bit_depth of a tone mapping SEI, at most 14 and 16 (D.2.26)
+    pub max_tone_map_coded_data_bit_depth: i32,
+    pub max_tone_map_target_bit_depth: i32,
+    // bit_depth_aux_minus8 of an SPS extension, at most 4 (7.4.2.1.2)
+    pub max_bit_depth_aux_minus8: i32,
+}
+
+impl Default for ParseLimits {
+    fn default() -> ParseLimits {
+        ParseLimits { max_pic_order_cnt_cycle: 255,
+                      max_slice_groups: 8,
+                      max_pic_size_in_mbs: 139264,
+                      max_ref_idx_active: 32,
+                      max_modification_operations: 64,
+                      max_tone_map_coded_data_bit_depth: 14,
+                      max_tone_map_target_bit_depth: 16,
+                      max_bit_depth_aux_minus8: 4,
+        }
+    }
+}
+
+fn check_limit(name: &str, val: i64, limit: i32) {
+    if val > i64::from(limit) {
+        panic!("{} of {} exceeds the parse limit of {}", name, val, limit);
+    }
+}
+
+// For values below which fields sized by them would be 0 bits long, so a loop over them never reads anything
+fn check_minimum(name: &str, val: i64, minimum: i32) {
+    if val < i64::from(minimum) {
+        panic!("{} of {} is below the minimum of {}", name, val, minimum);
+    }
+}
+
+struct H264State {
+    // The parameter sets in effect: those a slice refers to, and before any slice the last ones parsed
+    sps: SpsState,
+    pps: PpsState,
+    sps_by_id: HashMap<i32, SpsState>,
+    // Subset SPSs have ids of their own, used by the MVC slices of other views
+    subset_sps_by_id: HashMap<i32, SpsState>,
+    pps_by_id: HashMap<i32, PpsState>,
+    slice_data_bit_offset: usize,
+    // IdrPicFlag of the last primary coded picture, which its auxiliary coded picture shares
+    primary_idr_pic_flag: bool,
+    // Parse slice_data() macroblock by macroblock where supported, instead of keeping it as slice_payload
+    deep: bool,
+    cache: Option<SliceDataCache>,
+    limits: ParseLimits,
+    // Keep a NALU that ends before its syntax does, up to the first field that couldn't be read
+    salvage: bool,
+    // Warnings raised by the NALU being parsed, when they are collected for the caller instead of printed
+    warnings: Option<Vec<String>>,
+}
+
 impl H264State {
     fn new() -> H264State {
-        H264State { chroma_format_idc: 1,
-                    separate_color_plane_flag: false,
-                    frame_mbs_only_flag: false,
-                    pic_order_cnt_type: 0,
-                    bottom_field_pic_order_in_frame_present_flag: false,
-                    delta_pic_order_always_zero_flag: false,
-                    redundant_pic_cnt_present_flag: false,
-                    weighted_pred_flag: false,
-                    weighted_bipred_idc: 0,
-                    entropy_coding_mode_flag: false,
-                    deblocking_filter_control_present_flag: false,
-                    num_slice_groups_minus1: 0,
-                    slice_group_map_type: 0,
-                    log2_max_frame_num_minus4: 0,
-                    log2_max_pic_order_cnt_lsb_minus4: 0,
-                    num_ref_idx_l0_active_minus1: 0,
-                    num_ref_idx_l1_active_minus1: 0,
-                    pic_size_in_map_units_minus1: 0,
-                    slice_group_change_rate_minus1: 0,
-        }
-    }
-}
-
-#[derive(PartialEq)]
-enum SliceType {
+        H264State { sps: SpsState::default(),
+                    pps: PpsState::default(),
+                    sps_by_id: HashMap::new(),
+                    subset_sps_by_id: HashMap::new(),
+                    pps_by_id: HashMap::new(),
+                    slice_data_bit_offset: 0,
+                    primary_idr_pic_flag: false,
+                    deep: false,
+                    cache: None,
+                    limits: ParseLimits::default(),
+                    salvage: false,
+                    warnings: None,
+        }
+    }
+
+    fn warn(&mut self, message: String) {
+        match &mut self.warnings {
+            Some(warnings) => warnings.push(message),
+            None => eprintln!("Warning: {}", message),
+        }
+    }
+
+    // Make the PPS a slice refers to, and the SPS behind it, the ones in effect. Unknown ids leave the
+    // last parsed sets in effect. Slice extensions take their SPS from the subset SPSs.
+    fn activate(&mut self, pic_parameter_set_id: i32, subset: bool) {
+        let Some(pps) = self.pps_by_id.get(&pic_parameter_set_id) else {
+            self.warn(format!("slice refers to pic_parameter_set_id {} which has no preceding PPS", pic_parameter_set_id));
+            return;
+        };
+        self.pps = pps.clone();
+        if !subset {
+            self.activate_sps(self.pps.seq_parameter_set_id);
+        } else if let Some(sps) = self.subset_sps_by_id.get(&self.pps.seq_parameter_set_id) {
+            self.sps = sps.clone();
+        } else {
+            self.warn(format!("seq_parameter_set_id {} has no preceding subset SPS", self.pps.seq_parameter_set_id));
+        }
+    }
+
+    fn activate_sps(&mut self, seq_parameter_set_id: i32) {
+        match self.sps_by_id.get(&seq_parameter_set_id) {
+            Some(sps) => self.sps = sps.clone(),
+            None => self.warn(format!("seq_parameter_set_id {} has no preceding SPS", seq_parameter_set_id)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SliceType {
     P,
     B,
     I,
@@ -65,6 +245,56 @@ enum SliceType {
     SI,
 }
 
+// Variables the slice header syntax after slice_type depends on, from the parameter sets the slice activates and
+// the header fields read so far (7.4.3)
+struct SliceState {
+    slice_type: SliceType,
+    idr_pic_flag: bool,
+    // Zero for auxiliary coded pictures, which are monochrome whatever the SPS says (7.4.2.1.2)
+    chroma_array_type: i32,
+    field_pic_flag: bool,
+    // The PPS defaults until num_ref_idx_active_override_flag replaces them
+    num_ref_idx_l0_active_minus1: i32,
+    num_ref_idx_l1_active_minus1: i32,
+}
+
+impl SliceState {
+    fn new(state: &H264State, slice_type: SliceType, header: &NalHeader) -> SliceState {
+        SliceState { slice_type,
+                     idr_pic_flag: header.idr_pic_flag,
+                     chroma_array_type: if header.nal_unit_type == 19 { 0 } else { state.sps.chroma_array_type() },
+                     field_pic_flag: false,
+                     num_ref_idx_l0_active_minus1: state.pps.num_ref_idx_l0_default_active_minus1,
+                     num_ref_idx_l1_active_minus1: state.pps.num_ref_idx_l1_default_active_minus1,
+        }
+    }
+}
+
+pub fn nal_unit_type_name(nal_unit_type: i32) -> &'static str {
+    match nal_unit_type {
+        1 => "non-IDR slice",
+        2 => "slice data partition A",
+        3 => "slice data partition B",
+        4 => "slice data partition C",
+        5 => "IDR slice",
+        6 => "SEI",
+        7 => "SPS",
+        8 => "PPS",
+        9 => "access unit delimiter",
+        10 => "end of sequence",
+        11 => "end of stream",
+        12 => "filler data",
+        13 => "SPS extension",
+        14 => "prefix NAL unit",
+        15 => "subset SPS",
+        16 => "depth parameter set",
+        19 => "auxiliary slice",
+        20 => "slice extension",
+        21 => "depth view slice extension",
+        _ => "reserved",
+    }
+}
+
 fn int_to_slice_type(x: i32) -> SliceType {
     match x % 5 {
         0 => SliceType::P,
@@ -76,27 +306,31 @@ fn int_to_slice_type(x: i32) -> SliceType {
     }
 }
 
-fn tokenize_h264_bitstream(bitstream: &Vec<u8>) -> Vec<BitstreamReader> {
-    let mut ret: Vec<BitstreamReader> = vec![];
-    let mut start_idx = 0;
-    let mut curr_idx = 0;
+// The NALUs of an Annex B stream, each with its trailing zero bytes. Zero bytes ahead of the first start code are
+// leading_zero_8bits (B.1.2) rather than a NALU.
+pub fn tokenize_h264_nalus(bitstream: &[u8]) -> Vec<&[u8]> {
+    let mut ret: Vec<&[u8]> = vec![];
+    let leading_zeros = bitstream.iter().position(|x| *x != 0x00).unwrap_or(bitstream.len());
+    let leading_zeros = if leading_zeros >= 3 && bitstream.get(leading_zeros) == Some(&0x01) { leading_zeros - 3 } else { 0 };
+    let mut start_idx = leading_zeros;
+    let mut curr_idx = leading_zeros;
     while curr_idx < bitstream.len() {
-        if curr_idx < bitstream.len() - 4 &&
+        if curr_idx + 4 <= bitstream.len() &&
             bitstream[curr_idx] == 0x00 &&
             bitstream[curr_idx+1] == 0x00 &&
             bitstream[curr_idx+2] == 0x00 &&
             bitstream[curr_idx+3] == 0x01 {
             if curr_idx != start_idx {
-                ret.push(BitstreamReader::new(&bitstream[start_idx..curr_idx]));
+                ret.push(&bitstream[start_idx..curr_idx]);
             }
             curr_idx += 4;
             start_idx = curr_idx;
-        } else if curr_idx < bitstream.len() - 3 &&
+        } else if curr_idx + 3 <= bitstream.len() &&
             bitstream[curr_idx] == 0x00 &&
             bitstream[curr_idx+1] == 0x00 &&
             bitstream[curr_idx+2] == 0x01 {
             if curr_idx != start_idx {
-                ret.push(BitstreamReader::new(&bitstream[start_idx..curr_idx]));
+                ret.push(&bitstream[start_idx..curr_idx]);
             }
             curr_idx += 3;
             start_idx = curr_idx;
@@ -105,28 +339,242 @@ fn tokenize_h264_bitstream(bitstream: &Vec<u8>) -> Vec<BitstreamReader> {
         }
     }
     if curr_idx != start_idx {
-        ret.push(BitstreamReader::new(&bitstream[start_idx..curr_idx]));
+        ret.push(&bitstream[start_idx..curr_idx]);
+    }
+
+    ret
+}
+
+// Emulation prevention only applies past the NAL unit header, which the SVC and MVC NALU types extend by
+// three bytes and 3D-AVC slices by two (7.3.1)
+pub fn nal_unit_header_bytes(nalu: &[u8]) -> usize {
+    match peek_nal_unit_type(nalu, NalHeaderFormat::H264) {
+        Some(21) if nalu.get(1).is_some_and(|x| x & 0x80 != 0) => 3.min(nalu.len()),
+        Some(14 | 20 | 21) => 4.min(nalu.len()),
+        _ => NalHeaderFormat::H264.header_bytes().min(nalu.len()),
+    }
+}
+
+// Strip the emulation prevention bytes out of a NALU as it appears in the stream
+pub fn nalu_to_rbsp(nalu: &[u8]) -> Vec<u8> {
+    let header_bytes = nal_unit_header_bytes(nalu);
+    let mut ret = nalu[..header_bytes].to_vec();
+    ret.extend(ebsp_to_rbsp(&nalu[header_bytes..]));
+    ret
+}
+
+// A NALU can't end in a zero byte (7.4.1), so zero bytes at the end of a token are trailing_zero_8bits of the
+// byte stream (B.1.2) rather than part of the NALU. Trailing cabac_zero_words end in 0x03 and stay in the NALU.
+fn split_trailing_zeros(nalu: &[u8]) -> (&[u8], usize) {
+    let len = nalu.iter().rposition(|x| *x != 0x00).map_or(0, |x| x + 1);
+    (&nalu[..len], nalu.len() - len)
+}
+
+// A NALU's RBSP with the byte stream syntax around it (B.1.1)
+struct ByteStreamNalu {
+    rbsp: Vec<u8>,
+    leading_zeros: usize,
+    // Whether the start code has a zero_byte, making it 4 bytes long
+    zero_byte: bool,
+    trailing_zeros: usize,
+}
+
+fn tokenize_h264_bitstream(bitstream: &[u8]) -> Vec<ByteStreamNalu> {
+    let mut ret: Vec<ByteStreamNalu> = vec![];
+    for (idx, token) in tokenize_h264_nalus(bitstream).into_iter().enumerate() {
+        let offset = token.as_ptr() as usize - bitstream.as_ptr() as usize;
+        let zero_byte = offset >= 4 && bitstream[offset - 4..offset] == [0x00, 0x00, 0x00, 0x01];
+        let start_code = if zero_byte { 4 } else { 3 };
+        // Bytes ahead of a first NALU without a start code are part of it
+        let leading_zeros = if idx == 0 { offset.saturating_sub(start_code) } else { 0 };
+        let (nalu, trailing_zeros) = split_trailing_zeros(token);
+        ret.push(ByteStreamNalu { rbsp: nalu_to_rbsp(nalu), leading_zeros, zero_byte: zero_byte || offset == 0, trailing_zeros });
+    }
+
+    ret
+}
+
+// Noted ahead of a NALU's syntax: leading_zero_8bits before the first NALU, and a start code without a zero_byte,
+// which can be written back while 4 byte start codes stay the default
+fn push_start_code(root: &mut SyntaxNode, nalu: &ByteStreamNalu) {
+    if nalu.leading_zeros > 0 {
+        root.push_field("leading_zero_8bits", nalu.leading_zeros as i32);
+    }
+    if !nalu.zero_byte {
+        root.push_field("start_code_length", 3);
     }
+}
+
+fn push_trailing_zeros(root: &mut SyntaxNode, nalu: &ByteStreamNalu) {
+    if nalu.trailing_zeros > 0 {
+        root.push_field("trailing_zero_8bits", nalu.trailing_zeros as i32);
+    }
+}
+
+// Fields that aren't part of the NALU but of the byte stream around it
+pub const BYTE_STREAM_FIELDS: [&str; 3] = ["leading_zero_8bits", "start_code_length", "trailing_zero_8bits"];
 
+// A NALU without its byte stream fields, for comparing NALUs regardless of how they were framed
+pub fn without_byte_stream_fields(nalu: &SyntaxNode) -> SyntaxNode {
+    let mut ret = nalu.clone();
+    ret.children.retain(|x| !matches!(x, SyntaxElement::Field(field) if BYTE_STREAM_FIELDS.contains(&field.name.as_str())));
     ret
 }
 
-fn process_scaling_list<A>(node: &mut SyntaxNode, bitstream: &mut A, scaling_list_size: usize) -> ()
+// The text form of a NALU without its byte stream fields, the same for every copy of a NALU however it was framed
+pub fn nalu_contents(nalu: &SyntaxNode) -> String {
+    SyntaxElement::Node(without_byte_stream_fields(nalu)).to_string()
+}
+
+// Pop a byte stream field from the front of a NALU, for the writer to emit around the NALU
+fn take_byte_stream_field(nalu: &mut SyntaxNode, name: &str) -> Option<i32> {
+    match nalu.children.front() {
+        Some(SyntaxElement::Field(field)) if field.name == name => {
+            let val = field.val;
+            nalu.children.pop_front();
+            Some(val)
+        },
+        _ => None,
+    }
+}
+
+fn take_trailing_zeros(nalu: &mut SyntaxNode) -> usize {
+    take_byte_stream_field(nalu, "trailing_zero_8bits").unwrap_or(0).max(0) as usize
+}
+
+// Table 7-3 and 7-4, in the zig-zag order scaling lists are coded in
+const DEFAULT_4X4_INTRA: [i32; 16] = [6, 13, 13, 20, 20, 20, 28, 28, 28, 28, 32, 32, 32, 37, 37, 42];
+const DEFAULT_4X4_INTER: [i32; 16] = [10, 14, 14, 20, 20, 20, 24, 24, 24, 24, 27, 27, 27, 30, 30, 34];
+const DEFAULT_8X8_INTRA: [i32; 64] = [
+    6, 10, 10, 13, 11, 13, 16, 16, 16, 16, 18, 18, 18, 18, 18, 23, 23, 23, 23, 23, 23, 25, 25, 25, 25, 25, 25, 25, 27, 27, 27, 27,
+    27, 27, 27, 27, 29, 29, 29, 29, 29, 29, 29, 31, 31, 31, 31, 31, 31, 33, 33, 33, 33, 33, 36, 36, 36, 36, 38, 38, 38, 40, 40, 42,
+];
+const DEFAULT_8X8_INTER: [i32; 64] = [
+    9, 13, 13, 15, 13, 15, 17, 17, 17, 17, 19, 19, 19, 19, 19, 21, 21, 21, 21, 21, 21, 22, 22, 22, 22, 22, 22, 22, 24, 24, 24, 24,
+    24, 24, 24, 24, 25, 25, 25, 25, 25, 25, 25, 27, 27, 27, 27, 27, 27, 28, 28, 28, 28, 28, 30, 30, 30, 30, 32, 32, 32, 33, 33, 35,
+];
+
+// 7.3.2.1.1.1, for list i of the SPS or PPS. The reconstructed list follows the delta_scale fields as derived
+// ScalingList4x4[i][j] or ScalingList8x8[i - 6][j] values, the default list when useDefaultScalingMatrixFlag is set.
+fn process_scaling_list<A>(node: &mut SyntaxNode, bitstream: &mut A, i: usize)
     where A: BitstreamProcessor {
+    let scaling_list_size = if i < 6 { 16 } else { 64 };
+    let mut scaling_list: Vec<i32> = vec![];
+    let mut use_default_scaling_matrix_flag = false;
     let mut last_scale = 8;
     let mut next_scale = 8;
-    for i in 0..scaling_list_size {
+    for j in 0..scaling_list_size {
         if next_scale != 0 {
             let delta_scale = bitstream.field(node, "delta_scale", FieldType::SignedExpGolomb, 0);
             next_scale = (last_scale + delta_scale + 256) % 256;
+            use_default_scaling_matrix_flag = j == 0 && next_scale == 0;
         }
         let curr_scale = if next_scale == 0 { last_scale } else { next_scale };
+        scaling_list.push(curr_scale);
         last_scale = curr_scale;
     }
+    bitstream.derived(node, "useDefaultScalingMatrixFlag", use_default_scaling_matrix_flag as i32);
+    let (name, default): (String, &[i32]) = match i {
+        0..=2 => (format!("ScalingList4x4[{}]", i), &DEFAULT_4X4_INTRA),
+        3..=5 => (format!("ScalingList4x4[{}]", i), &DEFAULT_4X4_INTER),
+        _ => (format!("ScalingList8x8[{}]", i - 6), if i.is_multiple_of(2) { &DEFAULT_8X8_INTRA } else { &DEFAULT_8X8_INTER }),
+    };
+    if use_default_scaling_matrix_flag {
+        scaling_list = default.to_vec();
+    }
+    for (j, val) in scaling_list.iter().enumerate() {
+        bitstream.derived(node, &format!("{}[{}]", name, j), *val);
+    }
+}
+
+// Returns cpb_cnt_minus1
+fn process_hrd_parameters<A>(node: &mut SyntaxNode, bitstream: &mut A, state: &mut H264State) -> i32
+    where A: BitstreamProcessor {
+    let cpb_cnt_minus1 = bitstream.field(node, "cpb_cnt_minus1", FieldType::UnsignedExpGolomb, 0);
+    bitstream.field(node, "bit_rate_scale", FieldType::UnsignedInt, 4);
+    bitstream.field(node, "cpb_size_scale", FieldType::UnsignedInt, 4);
+    for i in 0..=cpb_cnt_minus1 {
+        bitstream.field(node, &format!("bit_rate_value_minus1[{}]", i), FieldType::UnsignedExpGolomb, 0);
+        bitstream.field(node, &format!("cpb_size_value_minus1[{}]", i), FieldType::UnsignedExpGolomb, 0);
+        bitstream.field(node, &format!("cbr_flag[{}]", i), FieldType::Boolean, 1);
+    }
+    state.sps.initial_cpb_removal_delay_length_minus1 = bitstream.field(node, "initial_cpb_removal_delay_length_minus1", FieldType::UnsignedInt, 5);
+    state.sps.cpb_removal_delay_length_minus1 = bitstream.field(node, "cpb_removal_delay_length_minus1", FieldType::UnsignedInt, 5);
+    state.sps.dpb_output_delay_length_minus1 = bitstream.field(node, "dpb_output_delay_length_minus1", FieldType::UnsignedInt, 5);
+    state.sps.time_offset_length = bitstream.field(node, "time_offset_length", FieldType::UnsignedInt, 5);
+    cpb_cnt_minus1
 }
 
-fn process_sps<A>(node: &mut SyntaxNode, bitstream: &mut A, state: &mut H264State) -> ()
+fn process_vui_parameters<A>(node: &mut SyntaxNode, bitstream: &mut A, state: &mut H264State)
     where A: BitstreamProcessor {
+    let aspect_ratio_info_present_flag = bitstream.field(node, "aspect_ratio_info_present_flag", FieldType::Boolean, 1);
+    if aspect_ratio_info_present_flag != 0 {
+        let aspect_ratio_idc = bitstream.field(node, "aspect_ratio_idc", FieldType::UnsignedInt, 8);
+        // Extended_SAR
+        if aspect_ratio_idc == 255 {
+            bitstream.field(node, "sar_width", FieldType::UnsignedInt, 16);
+            bitstream.field(node, "sar_height", FieldType::UnsignedInt, 16);
+        }
+    }
+    let overscan_info_present_flag = bitstream.field(node, "overscan_info_present_flag", FieldType::Boolean, 1);
+    if overscan_info_present_flag != 0 {
+        bitstream.field(node, "overscan_appropriate_flag", FieldType::Boolean, 1);
+    }
+    let video_signal_type_present_flag = bitstream.field(node, "video_signal_type_present_flag", FieldType::Boolean, 1);
+    if video_signal_type_present_flag != 0 {
+        bitstream.field(node, "video_format", FieldType::UnsignedInt, 3);
+        bitstream.field(node, "video_full_range_flag", FieldType::Boolean, 1);
+        let colour_description_present_flag = bitstream.field(node, "colour_description_present_flag", FieldType::Boolean, 1);
+        if colour_description_present_flag != 0 {
+            bitstream.field(node, "colour_primaries", FieldType::UnsignedInt, 8);
+            bitstream.field(node, "transfer_characteristics", FieldType::UnsignedInt, 8);
+            bitstream.field(node, "matrix_coefficients", FieldType::UnsignedInt, 8);
+        }
+    }
+    let chroma_loc_info_present_flag = bitstream.field(node, "chroma_loc_info_present_flag", FieldType::Boolean, 1);
+    if chroma_loc_info_present_flag != 0 {
+        bitstream.field(node, "chroma_sample_loc_type_top_field", FieldType::UnsignedExpGolomb, 0);
+        bitstream.field(node, "chroma_sample_loc_type_bottom_field", FieldType::UnsignedExpGolomb, 0);
+    }
+    let timing_info_present_flag = bitstream.field(node, "timing_info_present_flag", FieldType::Boolean, 1);
+    if timing_info_present_flag != 0 {
+        let num_units_in_tick = bitstream.field(node, "num_units_in_tick", FieldType::UnsignedInt, 32);
+        let time_scale = bitstream.field(node, "time_scale", FieldType::UnsignedInt, 32);
+        state.sps.timing_info = Some((num_units_in_tick as u32, time_scale as u32));
+        bitstream.field(node, "fixed_frame_rate_flag", FieldType::Boolean, 1);
+    }
+    state.sps.nal_hrd_parameters_present_flag = bitstream.field(node, "nal_hrd_parameters_present_flag", FieldType::Boolean, 1) != 0;
+    if state.sps.nal_hrd_parameters_present_flag {
+        let mut cpb_cnt_minus1 = 0;
+        bitstream.subnode(node, "nal_hrd_parameters", |x, y| cpb_cnt_minus1 = process_hrd_parameters(x, y, state));
+        state.sps.nal_cpb_cnt_minus1 = cpb_cnt_minus1;
+    }
+    state.sps.vcl_hrd_parameters_present_flag = bitstream.field(node, "vcl_hrd_parameters_present_flag", FieldType::Boolean, 1) != 0;
+    if state.sps.vcl_hrd_parameters_present_flag {
+        let mut cpb_cnt_minus1 = 0;
+        bitstream.subnode(node, "vcl_hrd_parameters", |x, y| cpb_cnt_minus1 = process_hrd_parameters(x, y, state));
+        state.sps.vcl_cpb_cnt_minus1 = cpb_cnt_minus1;
+    }
+    if state.sps.nal_hrd_parameters_present_flag || state.sps.vcl_hrd_parameters_present_flag {
+        bitstream.field(node, "low_delay_hrd_flag", FieldType::Boolean, 1);
+    }
+    state.sps.pic_struct_present_flag = bitstream.field(node, "pic_struct_present_flag", FieldType::Boolean, 1) != 0;
+    let bitstream_restriction_flag = bitstream.field(node, "bitstream_restriction_flag", FieldType::Boolean, 1);
+    if bitstream_restriction_flag != 0 {
+        bitstream.field(node, "motion_vectors_over_pic_boundaries_flag", FieldType::Boolean, 1);
+        bitstream.field(node, "max_bytes_per_pic_denom", FieldType::UnsignedExpGolomb, 0);
+        bitstream.field(node, "max_bits_per_mb_denom", FieldType::UnsignedExpGolomb, 0);
+        bitstream.field(node, "log2_max_mv_length_horizontal", FieldType::UnsignedExpGolomb, 0);
+        bitstream.field(node, "log2_max_mv_length_vertical", FieldType::UnsignedExpGolomb, 0);
+        bitstream.field(node, "max_num_reorder_frames", FieldType::UnsignedExpGolomb, 0);
+        bitstream.field(node, "max_dec_frame_buffering", FieldType::UnsignedExpGolomb, 0);
+    }
+}
+
+// seq_parameter_set_data(), shared by the SPS and subset SPS. Returns (profile_idc, seq_parameter_set_id).
+fn process_seq_parameter_set_data<A>(node: &mut SyntaxNode, bitstream: &mut A, state: &mut H264State) -> (i32, i32)
+    where A: BitstreamProcessor {
+    state.sps = SpsState::default();
     let profile_idc = bitstream.field(node, "profile_idc", FieldType::UnsignedInt, 8);
     bitstream.field(node, "constraint_set0_flag", FieldType::Boolean, 1);
     bitstream.field(node, "constraint_set1_flag", FieldType::Boolean, 1);
@@ -136,7 +584,7 @@ fn process_sps<A>(node: &mut SyntaxNode, bitstream: &mut A, state: &mut H264Stat
     bitstream.field(node, "constraint_set5_flag", FieldType::Boolean, 1);
     bitstream.field(node, "reserved_zero_2bits", FieldType::UnsignedInt, 2);
     bitstream.field(node, "level_idc", FieldType::UnsignedInt, 8);
-    bitstream.field(node, "seq_paramter_set_id", FieldType::UnsignedExpGolomb, 0);
+    let seq_parameter_set_id = bitstream.field(node, "seq_paramter_set_id", FieldType::UnsignedExpGolomb, 0);
     if profile_idc == 100 ||
        profile_idc == 110 ||
        profile_idc == 122 ||
@@ -151,12 +599,12 @@ fn process_sps<A>(node: &mut SyntaxNode, bitstream: &mut A, state: &mut H264Stat
        profile_idc == 134 ||
        profile_idc == 135 {
            let chroma_format_idc = bitstream.field(node, "chroma_format_idc", FieldType::UnsignedExpGolomb, 0);
-           state.chroma_format_idc = chroma_format_idc;
+           state.sps.chroma_format_idc = chroma_format_idc;
            if chroma_format_idc == 3 {
-               state.separate_color_plane_flag = bitstream.field(node, "separate_color_plane_flag", FieldType::Boolean, 1) != 0;
+               state.sps.separate_color_plane_flag = bitstream.field(node, "separate_color_plane_flag", FieldType::Boolean, 1) != 0;
            }
-           bitstream.field(node, "bit_depth_luma_minus8", FieldType::UnsignedExpGolomb, 0);
-           bitstream.field(node, "bit_depth_chroma_minus8", FieldType::UnsignedExpGolomb, 0);
+           state.sps.bit_depth_luma_minus8 = bitstream.field(node, "bit_depth_luma_minus8", FieldType::UnsignedExpGolomb, 0);
+           state.sps.bit_depth_chroma_minus8 = bitstream.field(node, "bit_depth_chroma_minus8", FieldType::UnsignedExpGolomb, 0);
            bitstream.field(node, "qpprime_y_zero_transform_bypass_flag", FieldType::Boolean, 1);
            let seq_scaling_matrix_present_flag = bitstream.field(node, "seq_scaling_matrix_present_flag", FieldType::Boolean, 1);
            if seq_scaling_matrix_present_flag != 0 {
@@ -164,60 +612,318 @@ fn process_sps<A>(node: &mut SyntaxNode, bitstream: &mut A, state: &mut H264Stat
                    let scale_list_present = bitstream.field(node, &format!("seq_scaling_list_present_flag[{}]", i), FieldType::Boolean, 1) != 0;
                    if scale_list_present {
                        if i < 6 {
-                           bitstream.subnode(node, "scaling_list4x4", |x, y| process_scaling_list(x, y, 16));
+                           bitstream.subnode(node, "scaling_list4x4", |x, y| process_scaling_list(x, y, i as usize));
                        } else {
-                           bitstream.subnode(node, "scaling_list8x8", |x, y| process_scaling_list(x, y, 64));
+                           bitstream.subnode(node, "scaling_list8x8", |x, y| process_scaling_list(x, y, i as usize));
                        }
                    }
                }
            }
     }
-    state.log2_max_frame_num_minus4 = bitstream.field(node, "log2_max_frame_num_minus4", FieldType::UnsignedExpGolomb, 0);
+    state.sps.log2_max_frame_num_minus4 = bitstream.field(node, "log2_max_frame_num_minus4", FieldType::UnsignedExpGolomb, 0);
     let pic_order_cnt_type = bitstream.field(node, "pic_order_cnt_type", FieldType::UnsignedExpGolomb, 0);
-    state.pic_order_cnt_type = pic_order_cnt_type;
+    state.sps.pic_order_cnt_type = pic_order_cnt_type;
     if pic_order_cnt_type == 0 {
-        state.log2_max_pic_order_cnt_lsb_minus4 = bitstream.field(node, "log2_max_pic_order_cnt_lsb_minus4", FieldType::UnsignedExpGolomb, 0);
+        state.sps.log2_max_pic_order_cnt_lsb_minus4 = bitstream.field(node, "log2_max_pic_order_cnt_lsb_minus4", FieldType::UnsignedExpGolomb, 0);
     } else if pic_order_cnt_type == 1 {
-        state.delta_pic_order_always_zero_flag = bitstream.field(node, "delta_pic_order_always_zero_flag", FieldType::Boolean, 1) != 0;
+        state.sps.delta_pic_order_always_zero_flag = bitstream.field(node, "delta_pic_order_always_zero_flag", FieldType::Boolean, 1) != 0;
         bitstream.field(node, "offset_for_non_ref_pic", FieldType::SignedExpGolomb, 0);
         bitstream.field(node, "offset_for_top_to_bottom_field", FieldType::SignedExpGolomb, 0);
         let num_ref_frames_in_pic_order_cnt_cycle = bitstream.field(node, "num_ref_frames_in_pic_order_cnt_cycle", FieldType::UnsignedExpGolomb, 0);
+        check_limit("num_ref_frames_in_pic_order_cnt_cycle", i64::from(num_ref_frames_in_pic_order_cnt_cycle), state.limits.max_pic_order_cnt_cycle);
         for i in 0..num_ref_frames_in_pic_order_cnt_cycle {
             bitstream.field(node, &format!("offset_for_ref_frame[{}]", i), FieldType::SignedExpGolomb, 0);
         }
     }
     bitstream.field(node, "max_num_ref_frames", FieldType::UnsignedExpGolomb, 0);
     bitstream.field(node, "gaps_in_frame_num_value_allowed_flag", FieldType::Boolean, 1);
-    bitstream.field(node, "pic_width_in_mbs_minus1", FieldType::UnsignedExpGolomb, 0);
-    bitstream.field(node, "pic_height_in_mbs_minus1", FieldType::UnsignedExpGolomb, 0);
+    state.sps.pic_width_in_mbs_minus1 = bitstream.field(node, "pic_width_in_mbs_minus1", FieldType::UnsignedExpGolomb, 0);
+    state.sps.pic_height_in_map_units_minus1 = bitstream.field(node, "pic_height_in_mbs_minus1", FieldType::UnsignedExpGolomb, 0);
     let frame_mbs_only_flag = bitstream.field(node, "frame_mbs_only_flag", FieldType::Boolean, 1);
-    state.frame_mbs_only_flag = frame_mbs_only_flag != 0;
+    state.sps.frame_mbs_only_flag = frame_mbs_only_flag != 0;
+    // FrameSizeInMbs (7-17), in 64 bits since the coded dimensions can overflow 32
+    let frame_height_in_mbs = (2 - i64::from(frame_mbs_only_flag)) * (i64::from(state.sps.pic_height_in_map_units_minus1) + 1);
+    check_limit("FrameSizeInMbs", (i64::from(state.sps.pic_width_in_mbs_minus1) + 1) * frame_height_in_mbs, state.limits.max_pic_size_in_mbs);
     if frame_mbs_only_flag == 0 {
-        bitstream.field(node, "mb_adaptive_frame_field_flag", FieldType::Boolean, 1);
+        state.sps.mb_adaptive_frame_field_flag = bitstream.field(node, "mb_adaptive_frame_field_flag", FieldType::Boolean, 1) != 0;
     }
-    bitstream.field(node, "direct_8x8_inference_flag", FieldType::Boolean, 1);
+    state.sps.direct_8x8_inference_flag = bitstream.field(node, "direct_8x8_inference_flag", FieldType::Boolean, 1) != 0;
     let frame_cropping_flag = bitstream.field(node, "frame_cropping_flag", FieldType::Boolean, 1);
+    // Left, right, top and bottom
+    let mut crop_offsets = [0; 4];
     if frame_cropping_flag != 0 {
-        bitstream.field(node, "frame_crop_left_offset", FieldType::UnsignedExpGolomb, 0);
-        bitstream.field(node, "frame_crop_right_offset", FieldType::UnsignedExpGolomb, 0);
-        bitstream.field(node, "frame_crop_top_offset", FieldType::UnsignedExpGolomb, 0);
-        bitstream.field(node, "frame_crop_bottom_offset", FieldType::UnsignedExpGolomb, 0);
+        crop_offsets[0] = bitstream.field(node, "frame_crop_left_offset", FieldType::UnsignedExpGolomb, 0);
+        crop_offsets[1] = bitstream.field(node, "frame_crop_right_offset", FieldType::UnsignedExpGolomb, 0);
+        crop_offsets[2] = bitstream.field(node, "frame_crop_top_offset", FieldType::UnsignedExpGolomb, 0);
+        crop_offsets[3] = bitstream.field(node, "frame_crop_bottom_offset", FieldType::UnsignedExpGolomb, 0);
     }
     let vui_params = bitstream.field(node, "vui_parameters_present_flag", FieldType::Boolean, 1);
-    bitstream.payload(node, if vui_params != 0 { "unparsed_vui_params" } else { "trailing_bits" });
+    if vui_params != 0 {
+        bitstream.subnode(node, "vui_parameters", |x, y| process_vui_parameters(x, y, state));
+    }
+    process_sps_summary(node, bitstream, &state.sps, crop_offsets);
+    (profile_idc, seq_parameter_set_id)
 }
 
-fn process_pps<A>(node: &mut SyntaxNode, bitstream: &mut A, state: &mut H264State) -> ()
+fn gcd(a: u64, b: u64) -> u64 {
+    if b == 0 { a } else { gcd(b, a % b) }
+}
+
+// One side of the frame after cropping (7-19 to 7-22), in i64 since the offsets can be any Exp-Golomb value. The
+// cropping window must keep at least one sample (7.4.2.1.1).
+fn cropped_size(name: &str, size: i32, crop_unit: i32, offset_start: i32, offset_end: i32) -> i32 {
+    let cropped = i64::from(size) - i64::from(crop_unit) * (i64::from(offset_start) + i64::from(offset_end));
+    if cropped < 1 {
+        panic!("{} of {} leaves nothing of the {} samples of the frame", name, cropped, size);
+    }
+    cropped as i32
+}
+
+// The picture format an SPS describes, worked out from its coded fields: ChromaArrayType and the chroma
+// subsampling (Table 6-1), bit depths, the coded size and the size after cropping (7-19 to 7-22), and the frame
+// rate as a reduced fraction when VUI timing is present
+fn process_sps_summary<A>(node: &mut SyntaxNode, bitstream: &mut A, sps: &SpsState, crop_offsets: [i32; 4])
+    where A: BitstreamProcessor {
+    let chroma_array_type = sps.chroma_array_type();
+    let (sub_width_c, sub_height_c) = match sps.chroma_format_idc {
+        1 => (2, 2),
+        2 => (2, 1),
+        _ => (1, 1),
+    };
+    let frame_mbs_only = i32::from(sps.frame_mbs_only_flag);
+    let (crop_unit_x, crop_unit_y) = if chroma_array_type == 0 {
+        (1, 2 - frame_mbs_only)
+    } else {
+        (sub_width_c, sub_height_c * (2 - frame_mbs_only))
+    };
+    let width = (sps.pic_width_in_mbs_minus1 + 1) * 16;
+    let height = (sps.pic_height_in_map_units_minus1 + 1) * (2 - frame_mbs_only) * 16;
+    bitstream.derived(node, "ChromaArrayType", chroma_array_type);
+    if chroma_array_type != 0 {
+        bitstream.derived(node, "SubWidthC", sub_width_c);
+        bitstream.derived(node, "SubHeightC", sub_height_c);
+    }
+    bitstream.derived(node, "BitDepthY", sps.bit_depth_luma_minus8 + 8);
+    if chroma_array_type != 0 {
+        bitstream.derived(node, "BitDepthC", sps.bit_depth_chroma_minus8 + 8);
+    }
+    bitstream.derived(node, "PicWidthInSamplesL", width);
+    bitstream.derived(node, "FrameHeightInSamplesL", height);
+    bitstream.derived(node, "CroppedWidth", cropped_size("CroppedWidth", width, crop_unit_x, crop_offsets[0], crop_offsets[1]));
+    bitstream.derived(node, "CroppedHeight", cropped_size("CroppedHeight", height, crop_unit_y, crop_offsets[2], crop_offsets[3]));
+    // time_scale / (2 * num_units_in_tick) (E.2.1)
+    if let Some((num_units_in_tick, time_scale)) = sps.timing_info.filter(|x| x.0 != 0 && x.1 != 0) {
+        let (num, den) = (u64::from(time_scale), 2 * u64::from(num_units_in_tick));
+        let gcd = gcd(num, den);
+        if let (Ok(num), Ok(den)) = (i32::try_from(num / gcd), i32::try_from(den / gcd)) {
+            bitstream.derived(node, "FrameRateNumerator", num);
+            bitstream.derived(node, "FrameRateDenominator", den);
+        }
+    }
+}
+
+fn process_sps<A>(node: &mut SyntaxNode, bitstream: &mut A, state: &mut H264State)
+    where A: BitstreamProcessor {
+    let (_, seq_parameter_set_id) = process_seq_parameter_set_data(node, bitstream, state);
+    bitstream.payload(node, "trailing_bits");
+    state.sps_by_id.insert(seq_parameter_set_id, state.sps.clone());
+}
+
+// G.7.3.2.1.4
+fn process_seq_parameter_set_svc_extension<A>(node: &mut SyntaxNode, bitstream: &mut A, state: &H264State)
+    where A: BitstreamProcessor {
+    let chroma_array_type = state.sps.chroma_array_type();
+    bitstream.field(node, "inter_layer_deblocking_filter_control_present_flag", FieldType::Boolean, 1);
+    let extended_spatial_scalability_idc = bitstream.field(node, "extended_spatial_scalability_idc", FieldType::UnsignedInt, 2);
+    if chroma_array_type == 1 || chroma_array_type == 2 {
+        bitstream.field(node, "chroma_phase_x_plus1_flag", FieldType::Boolean, 1);
+    }
+    if chroma_array_type == 1 {
+        bitstream.field(node, "chroma_phase_y_plus1", FieldType::UnsignedInt, 2);
+    }
+    if extended_spatial_scalability_idc == 1 {
+        if chroma_array_type > 0 {
+            bitstream.field(node, "seq_ref_layer_chroma_phase_x_plus1_flag", FieldType::Boolean, 1);
+            bitstream.field(node, "seq_ref_layer_chroma_phase_y_plus1", FieldType::UnsignedInt, 2);
+        }
+        bitstream.field(node, "seq_scaled_ref_layer_left_offset", FieldType::SignedExpGolomb, 0);
+        bitstream.field(node, "seq_scaled_ref_layer_top_offset", FieldType::SignedExpGolomb, 0);
+        bitstream.field(node, "seq_scaled_ref_layer_right_offset", FieldType::SignedExpGolomb, 0);
+        bitstream.field(node, "seq_scaled_ref_layer_bottom_offset", FieldType::SignedExpGolomb, 0);
+    }
+    if bitstream.field(node, "seq_tcoeff_level_prediction_flag", FieldType::Boolean, 1) != 0 {
+        bitstream.field(node, "adaptive_tcoeff_level_prediction_flag", FieldType::Boolean, 1);
+    }
+    bitstream.field(node, "slice_header_restriction_flag", FieldType::Boolean, 1);
+}
+
+// The timing and HRD fields every entry of the SVC and MVC VUI extensions carries. The prefix is vui_ext or
+// vui_mvc.
+fn process_vui_extension_timing<A>(node: &mut SyntaxNode, bitstream: &mut A, state: &mut H264State, prefix: &str, i: i32)
+    where A: BitstreamProcessor {
+    if bitstream.field(node, &format!("{}_timing_info_present_flag[{}]", prefix, i), FieldType::Boolean, 1) != 0 {
+        bitstream.field(node, &format!("{}_num_units_in_tick[{}]", prefix, i), FieldType::UnsignedInt, 32);
+        bitstream.field(node, &format!("{}_time_scale[{}]", prefix, i), FieldType::UnsignedInt, 32);
+        bitstream.field(node, &format!("{}_fixed_frame_rate_flag[{}]", prefix, i), FieldType::Boolean, 1);
+    }
+    let mut hrd_present = false;
+    for hrd in ["nal", "vcl"] {
+        if bitstream.field(node, &format!("{}_{}_hrd_parameters_present_flag[{}]", prefix, hrd, i), FieldType::Boolean, 1) != 0 {
+            bitstream.subnode(node, &format!("{}_hrd_parameters", hrd), |x, y| { process_hrd_parameters(x, y, state); });
+            hrd_present = true;
+        }
+    }
+    if hrd_present {
+        bitstream.field(node, &format!("{}_low_delay_hrd_flag[{}]", prefix, i), FieldType::Boolean, 1);
+    }
+    bitstream.field(node, &format!("{}_pic_struct_present_flag[{}]", prefix, i), FieldType::Boolean, 1);
+}
+
+// G.14.1
+fn process_svc_vui_parameters_extension<A>(node: &mut SyntaxNode, bitstream: &mut A, state: &mut H264State)
+    where A: BitstreamProcessor {
+    let vui_ext_num_entries_minus1 = bitstream.field(node, "vui_ext_num_entries_minus1", FieldType::UnsignedExpGolomb, 0);
+    for i in 0..=vui_ext_num_entries_minus1 {
+        bitstream.field(node, &format!("vui_ext_dependency_id[{}]", i), FieldType::UnsignedInt, 3);
+        bitstream.field(node, &format!("vui_ext_quality_id[{}]", i), FieldType::UnsignedInt, 4);
+        bitstream.field(node, &format!("vui_ext_temporal_id[{}]", i), FieldType::UnsignedInt, 3);
+        process_vui_extension_timing(node, bitstream, state, "vui_ext", i);
+    }
+}
+
+// H.7.3.2.1.4
+fn process_seq_parameter_set_mvc_extension<A>(node: &mut SyntaxNode, bitstream: &mut A, state: &H264State, profile_idc: i32)
+    where A: BitstreamProcessor {
+    let num_views_minus1 = bitstream.field(node, "num_views_minus1", FieldType::UnsignedExpGolomb, 0);
+    for i in 0..=num_views_minus1 {
+        bitstream.field(node, &format!("view_id[{}]", i), FieldType::UnsignedExpGolomb, 0);
+    }
+    for anchor in ["anchor", "non_anchor"] {
+        for i in 1..=num_views_minus1 {
+            for list in ["l0", "l1"] {
+                let num_refs = bitstream.field(node, &format!("num_{}_refs_{}[{}]", anchor, list, i), FieldType::UnsignedExpGolomb, 0);
+                for j in 0..num_refs {
+                    bitstream.field(node, &format!("{}_ref_{}[{}][{}]", anchor, list, i, j), FieldType::UnsignedExpGolomb, 0);
+                }
+            }
+        }
+    }
+    let num_level_values_signalled_minus1 = bitstream.field(node, "num_level_values_signalled_minus1", FieldType::UnsignedExpGolomb, 0);
+    for i in 0..=num_level_values_signalled_minus1 {
+        bitstream.field(node, &format!("level_idc[{}]", i), FieldType::UnsignedInt, 8);
+        let num_applicable_ops_minus1 = bitstream.field(node, &format!("num_applicable_ops_minus1[{}]", i), FieldType::UnsignedExpGolomb, 0);
+        for j in 0..=num_applicable_ops_minus1 {
+            bitstream.field(node, &format!("applicable_op_temporal_id[{}][{}]", i, j), FieldType::UnsignedInt, 3);
+            let num_target_views_minus1 = bitstream.field(node, &format!("applicable_op_num_target_views_minus1[{}][{}]", i, j), FieldType::UnsignedExpGolomb, 0);
+            for k in 0..=num_target_views_minus1 {
+                bitstream.field(node, &format!("applicable_op_target_view_id[{}][{}][{}]", i, j, k), FieldType::UnsignedExpGolomb, 0);
+            }
+            bitstream.field(node, &format!("applicable_op_num_views_minus1[{}][{}]", i, j), FieldType::UnsignedExpGolomb, 0);
+        }
+    }
+    // Multiview frame compatible (MFC) high profile
+    if profile_idc == 134 {
+        let mfc_format_idc = bitstream.field(node, "mfc_format_idc", FieldType::UnsignedInt, 6);
+        if (mfc_format_idc == 0 || mfc_format_idc == 1) &&
+           bitstream.field(node, "default_grid_position_flag", FieldType::Boolean, 1) == 0 {
+            bitstream.field(node, "view0_grid_position_x", FieldType::UnsignedInt, 4);
+            bitstream.field(node, "view0_grid_position_y", FieldType::UnsignedInt, 4);
+            bitstream.field(node, "view1_grid_position_x", FieldType::UnsignedInt, 4);
+            bitstream.field(node, "view1_grid_position_y", FieldType::UnsignedInt, 4);
+        }
+        bitstream.field(node, "rpu_filter_enabled_flag", FieldType::Boolean, 1);
+        if !state.sps.frame_mbs_only_flag {
+            bitstream.field(node, "rpu_field_processing_flag", FieldType::Boolean, 1);
+        }
+    }
+}
+
+// H.14.1
+fn process_mvc_vui_parameters_extension<A>(node: &mut SyntaxNode, bitstream: &mut A, state: &mut H264State)
+    where A: BitstreamProcessor {
+    let vui_mvc_num_ops_minus1 = bitstream.field(node, "vui_mvc_num_ops_minus1", FieldType::UnsignedExpGolomb, 0);
+    for i in 0..=vui_mvc_num_ops_minus1 {
+        bitstream.field(node, &format!("vui_mvc_temporal_id[{}]", i), FieldType::UnsignedInt, 3);
+        let num_target_output_views_minus1 = bitstream.field(node, &format!("vui_mvc_num_target_output_views_minus1[{}]", i), FieldType::UnsignedExpGolomb, 0);
+        for j in 0..=num_target_output_views_minus1 {
+            bitstream.field(node, &format!("vui_mvc_view_id[{}][{}]", i, j), FieldType::UnsignedExpGolomb, 0);
+        }
+        process_vui_extension_timing(node, bitstream, state, "vui_mvc", i);
+    }
+}
+
+// 7.3.2.1.3. Subset SPSs only apply to the SVC and MVC layers, so the SPS in effect for the base layer is left
+// alone. The extensions don't affect how slices are parsed, so the subset SPS is stored as soon as its
+// seq_parameter_set_data() is read.
+fn process_subset_sps<A>(node: &mut SyntaxNode, bitstream: &mut A, state: &mut H264State)
+    where A: BitstreamProcessor {
+    let active_sps = state.sps.clone();
+    let (profile_idc, seq_parameter_set_id) = process_seq_parameter_set_data(node, bitstream, state);
+    state.subset_sps_by_id.insert(seq_parameter_set_id, state.sps.clone());
+    if profile_idc == 83 || profile_idc == 86 {
+        bitstream.subnode(node, "seq_parameter_set_svc_extension", |x, y| process_seq_parameter_set_svc_extension(x, y, state));
+        if bitstream.field(node, "svc_vui_parameters_present_flag", FieldType::Boolean, 1) != 0 {
+            bitstream.subnode(node, "svc_vui_parameters_extension", |x, y| process_svc_vui_parameters_extension(x, y, state));
+        }
+    } else if profile_idc == 118 || profile_idc == 128 || profile_idc == 134 {
+        bitstream.field(node, "bit_equal_to_one", FieldType::Boolean, 1);
+        bitstream.subnode(node, "seq_parameter_set_mvc_extension", |x, y| process_seq_parameter_set_mvc_extension(x, y, state, profile_idc));
+        if bitstream.field(node, "mvc_vui_parameters_present_flag", FieldType::Boolean, 1) != 0 {
+            bitstream.subnode(node, "mvc_vui_parameters_extension", |x, y| process_mvc_vui_parameters_extension(x, y, state));
+        }
+    } else if profile_idc == 135 || profile_idc == 138 || profile_idc == 139 {
+        // The MVCD and 3D-AVC extensions aren't broken down; they and the trailing bits stay hex
+        bitstream.payload(node, "seq_parameter_set_extension_data");
+        state.sps = active_sps;
+        return;
+    }
+    // The extension data, when present, runs up to and includes the trailing bits
+    if bitstream.field(node, "additional_extension2_flag", FieldType::Boolean, 1) != 0 {
+        bitstream.payload(node, "additional_extension2_data");
+    } else {
+        bitstream.payload(node, "trailing_bits");
+    }
+    state.sps = active_sps;
+}
+
+// 7.3.2.1.2, the alpha (auxiliary) plane format of the SPS with the same id
+fn process_sps_extension<A>(node: &mut SyntaxNode, bitstream: &mut A, limits: &ParseLimits)
     where A: BitstreamProcessor {
-    bitstream.field(node, "pic_parameter_set_id", FieldType::UnsignedExpGolomb, 0);
     bitstream.field(node, "seq_parameter_set_id", FieldType::UnsignedExpGolomb, 0);
-    state.entropy_coding_mode_flag = bitstream.field(node, "entropy_coding_mode_flag", FieldType::Boolean, 1) != 0;
-    state.bottom_field_pic_order_in_frame_present_flag = bitstream.field(node, "bottom_field_pic_order_in_frame_present_flag", FieldType::Boolean, 1) != 0;
+    let aux_format_idc = bitstream.field(node, "aux_format_idc", FieldType::UnsignedExpGolomb, 0);
+    if aux_format_idc != 0 {
+        let bit_depth_aux_minus8 = bitstream.field(node, "bit_depth_aux_minus8", FieldType::UnsignedExpGolomb, 0);
+        check_minimum("bit_depth_aux_minus8", i64::from(bit_depth_aux_minus8), 0);
+        check_limit("bit_depth_aux_minus8", i64::from(bit_depth_aux_minus8), limits.max_bit_depth_aux_minus8);
+        bitstream.field(node, "alpha_incr_flag", FieldType::Boolean, 1);
+        // Both are bit_depth_aux_minus8 + 9 bits long
+        let alpha_value_bits = (bit_depth_aux_minus8 + 9) as u8;
+        bitstream.field(node, "alpha_opaque_value", FieldType::UnsignedInt, alpha_value_bits);
+        bitstream.field(node, "alpha_transparent_value", FieldType::UnsignedInt, alpha_value_bits);
+    }
+    // Reserved, with anything after it to be ignored, so it's kept up to and including the trailing bits
+    if bitstream.field(node, "additional_extension_flag", FieldType::Boolean, 1) != 0 {
+        bitstream.payload(node, "additional_extension_data");
+    } else {
+        bitstream.payload(node, "trailing_bits");
+    }
+}
+
+fn process_pps<A>(node: &mut SyntaxNode, bitstream: &mut A, state: &mut H264State) -> ()
+    where A: BitstreamProcessor {
+    state.pps = PpsState::default();
+    let pic_parameter_set_id = bitstream.field(node, "pic_parameter_set_id", FieldType::UnsignedExpGolomb, 0);
+    state.pps.seq_parameter_set_id = bitstream.field(node, "seq_parameter_set_id", FieldType::UnsignedExpGolomb, 0);
+    // The scaling lists depend on the SPS this PPS refers to, not the last one parsed
+    let chroma_format_idc = state.sps_by_id.get(&state.pps.seq_parameter_set_id).unwrap_or(&state.sps).chroma_format_idc;
+    state.pps.entropy_coding_mode_flag = bitstream.field(node, "entropy_coding_mode_flag", FieldType::Boolean, 1) != 0;
+    state.pps.bottom_field_pic_order_in_frame_present_flag = bitstream.field(node, "bottom_field_pic_order_in_frame_present_flag", FieldType::Boolean, 1) != 0;
     let num_slice_groups_minus1 = bitstream.field(node, "num_slice_groups_minus1", FieldType::UnsignedExpGolomb, 0);
-    state.num_slice_groups_minus1 = num_slice_groups_minus1;
+    check_limit("num_slice_groups_minus1 + 1", i64::from(num_slice_groups_minus1) + 1, state.limits.max_slice_groups);
+    state.pps.num_slice_groups_minus1 = num_slice_groups_minus1;
     if num_slice_groups_minus1 > 0 {
         let slice_group_map_type = bitstream.field(node, "slice_group_map_type", FieldType::UnsignedExpGolomb, 0);
-        state.slice_group_map_type = slice_group_map_type;
+        state.pps.slice_group_map_type = slice_group_map_type;
         if slice_group_map_type == 0 {
             for i in 0..(num_slice_groups_minus1+1) {
                 bitstream.field(node, &format!("run_length_minus1[{}]", i), FieldType::UnsignedExpGolomb, 0);
@@ -229,36 +935,38 @@ fn process_pps<A>(node: &mut SyntaxNode, bitstream: &mut A, state: &mut H264Stat
             }
         } else if slice_group_map_type >= 3 && slice_group_map_type <= 5 {
             bitstream.field(node, "slice_group_change_direction_flag", FieldType::Boolean, 1);
-            state.slice_group_change_rate_minus1 = bitstream.field(node, "slice_group_change_rate_minus1", FieldType::UnsignedExpGolomb, 0);
+            state.pps.slice_group_change_rate_minus1 = bitstream.field(node, "slice_group_change_rate_minus1", FieldType::UnsignedExpGolomb, 0);
         } else if slice_group_map_type == 6 {
             let pic_size_in_map_units_minus1 = bitstream.field(node, "pic_size_in_map_units_minus1", FieldType::UnsignedExpGolomb, 0);
-            state.pic_size_in_map_units_minus1 = pic_size_in_map_units_minus1;
+            check_limit("pic_size_in_map_units_minus1 + 1", i64::from(pic_size_in_map_units_minus1) + 1, state.limits.max_pic_size_in_mbs);
+            state.pps.pic_size_in_map_units_minus1 = pic_size_in_map_units_minus1;
             for i in 0..(pic_size_in_map_units_minus1+1) {
                 bitstream.field(node, &format!("slice_group_id[{}]", i), FieldType::UnsignedInt, f64::from(num_slice_groups_minus1+1).log2().ceil() as u8);
             }
         }
     }
-    bitstream.field(node, "num_ref_idx_l0_default_active_minus1", FieldType::UnsignedExpGolomb, 0);
-    bitstream.field(node, "num_ref_idx_l1_default_active_minus1", FieldType::UnsignedExpGolomb, 0);
-    state.weighted_pred_flag = bitstream.field(node, "weighted_pred_flag", FieldType::Boolean, 1) != 0;
-    state.weighted_bipred_idc = bitstream.field(node, "weighted_bipred_idc", FieldType::UnsignedInt, 2);
+    state.pps.num_ref_idx_l0_default_active_minus1 = bitstream.field(node, "num_ref_idx_l0_default_active_minus1", FieldType::UnsignedExpGolomb, 0);
+    state.pps.num_ref_idx_l1_default_active_minus1 = bitstream.field(node, "num_ref_idx_l1_default_active_minus1", FieldType::UnsignedExpGolomb, 0);
+    state.pps.weighted_pred_flag = bitstream.field(node, "weighted_pred_flag", FieldType::Boolean, 1) != 0;
+    state.pps.weighted_bipred_idc = bitstream.field(node, "weighted_bipred_idc", FieldType::UnsignedInt, 2);
     bitstream.field(node, "pic_init_qp_minus26", FieldType::SignedExpGolomb, 0);
     bitstream.field(node, "pic_init_qs_minus26", FieldType::SignedExpGolomb, 0);
     bitstream.field(node, "chroma_qp_index_offset", FieldType::SignedExpGolomb, 0);
-    state.deblocking_filter_control_present_flag = bitstream.field(node, "deblocking_filter_control_present_flag", FieldType::Boolean, 1) != 0;
+    state.pps.deblocking_filter_control_present_flag = bitstream.field(node, "deblocking_filter_control_present_flag", FieldType::Boolean, 1) != 0;
     bitstream.field(node, "constrained_intra_pred_flag", FieldType::Boolean, 1);
-    state.redundant_pic_cnt_present_flag = bitstream.field(node, "redundant_pic_cnt_present_flag", FieldType::Boolean, 1) != 0;
+    state.pps.redundant_pic_cnt_present_flag = bitstream.field(node, "redundant_pic_cnt_present_flag", FieldType::Boolean, 1) != 0;
     if bitstream.more_data(node) {
         let transform_8x8_mode_flag = bitstream.field(node, "transform_8x8_mode_flag", FieldType::Boolean, 1);
+        state.pps.transform_8x8_mode_flag = transform_8x8_mode_flag != 0;
         let pic_scaling_matrix_present_flag = bitstream.field(node, "pic_scaling_matrix_present_flag", FieldType::Boolean, 1);
         if pic_scaling_matrix_present_flag != 0 {
-            for i in 0..(6 + transform_8x8_mode_flag * (if state.chroma_format_idc != 3 { 2 } else { 6 })) {
+            for i in 0..(6 + transform_8x8_mode_flag * (if chroma_format_idc != 3 { 2 } else { 6 })) {
                 let scale_list_present = bitstream.field(node, &format!("pic_scaling_list_present_flag[{}]", i), FieldType::Boolean, 1);
                 if scale_list_present != 0 {
                     if i < 6 {
-                        bitstream.subnode(node, "scaling_list4x4", |x, y| process_scaling_list(x, y, 16));
+                        bitstream.subnode(node, "scaling_list4x4", |x, y| process_scaling_list(x, y, i as usize));
                     } else {
-                        bitstream.subnode(node, "scaling_list8x8", |x, y| process_scaling_list(x, y, 64));
+                        bitstream.subnode(node, "scaling_list8x8", |x, y| process_scaling_list(x, y, i as usize));
                     }
                 }
             }
@@ -266,6 +974,7 @@ fn process_pps<A>(node: &mut SyntaxNode, bitstream: &mut A, state: &mut H264Stat
         bitstream.field(node, "second_chroma_qp_index_offset", FieldType::SignedExpGolomb, 0);
     }
     bitstream.payload(node, "trailing_bits");
+    state.pps_by_id.insert(pic_parameter_set_id, state.pps.clone());
 }
 
 fn process_filler<A>(node: &mut SyntaxNode, bitstream: &mut A) -> ()
@@ -273,12 +982,423 @@ fn process_filler<A>(node: &mut SyntaxNode, bitstream: &mut A) -> ()
     bitstream.payload(node, "filler_data");
 }
 
-fn process_ref_pic_list_modification<A>(node: &mut SyntaxNode, bitstream: &mut A, slice_type: &SliceType) -> ()
+// 7.3.2.4
+fn process_access_unit_delimiter<A>(node: &mut SyntaxNode, bitstream: &mut A)
+    where A: BitstreamProcessor {
+    bitstream.field(node, "primary_pic_type", FieldType::UnsignedInt, 3);
+    bitstream.payload(node, "trailing_bits");
+}
+
+fn process_mastering_display_colour_volume<A>(node: &mut SyntaxNode, bitstream: &mut A)
+    where A: BitstreamProcessor {
+    for c in 0..3 {
+        bitstream.field(node, &format!("display_primaries_x[{}]", c), FieldType::UnsignedInt, 16);
+        bitstream.field(node, &format!("display_primaries_y[{}]", c), FieldType::UnsignedInt, 16);
+    }
+    bitstream.field(node, "white_point_x", FieldType::UnsignedInt, 16);
+    bitstream.field(node, "white_point_y", FieldType::UnsignedInt, 16);
+    bitstream.field(node, "max_display_mastering_luminance", FieldType::UnsignedInt, 32);
+    bitstream.field(node, "min_display_mastering_luminance", FieldType::UnsignedInt, 32);
+}
+
+fn process_buffering_period<A>(node: &mut SyntaxNode, bitstream: &mut A, state: &mut H264State)
+    where A: BitstreamProcessor {
+    // A buffering period activates the SPS it names (D.2.2), so picture timing in the same access unit
+    // is parsed against it as well
+    let seq_parameter_set_id = bitstream.field(node, "seq_parameter_set_id", FieldType::UnsignedExpGolomb, 0);
+    state.activate_sps(seq_parameter_set_id);
+    let sps = &state.sps;
+    let delay_length = (sps.initial_cpb_removal_delay_length_minus1 + 1) as u8;
+    for (present, cpb_cnt_minus1, prefix) in [(sps.nal_hrd_parameters_present_flag, sps.nal_cpb_cnt_minus1, "nal"),
+                                              (sps.vcl_hrd_parameters_present_flag, sps.vcl_cpb_cnt_minus1, "vcl")] {
+        if !present {
+            continue;
+        }
+        for i in 0..=cpb_cnt_minus1 {
+            bitstream.field(node, &format!("{}_initial_cpb_removal_delay[{}]", prefix, i), FieldType::UnsignedInt, delay_length);
+            bitstream.field(node, &format!("{}_initial_cpb_removal_delay_offset[{}]", prefix, i), FieldType::UnsignedInt, delay_length);
+        }
+    }
+}
+
+fn process_clock_timestamp<A>(node: &mut SyntaxNode, bitstream: &mut A, state: &H264State, i: i32)
+    where A: BitstreamProcessor {
+    bitstream.field(node, &format!("ct_type[{}]", i), FieldType::UnsignedInt, 2);
+    bitstream.field(node, &format!("nuit_field_based_flag[{}]", i), FieldType::Boolean, 1);
+    bitstream.field(node, &format!("counting_type[{}]", i), FieldType::UnsignedInt, 5);
+    let full_timestamp_flag = bitstream.field(node, &format!("full_timestamp_flag[{}]", i), FieldType::Boolean, 1);
+    bitstream.field(node, &format!("discontinuity_flag[{}]", i), FieldType::Boolean, 1);
+    bitstream.field(node, &format!("cnt_dropped_flag[{}]", i), FieldType::Boolean, 1);
+    bitstream.field(node, &format!("n_frames[{}]", i), FieldType::UnsignedInt, 8);
+    if full_timestamp_flag != 0 {
+        bitstream.field(node, &format!("seconds_value[{}]", i), FieldType::UnsignedInt, 6);
+        bitstream.field(node, &format!("minutes_value[{}]", i), FieldType::UnsignedInt, 6);
+        bitstream.field(node, &format!("hours_value[{}]", i), FieldType::UnsignedInt, 5);
+    } else if bitstream.field(node, &format!("seconds_flag[{}]", i), FieldType::Boolean, 1) != 0 {
+        bitstream.field(node, &format!("seconds_value[{}]", i), FieldType::UnsignedInt, 6);
+        if bitstream.field(node, &format!("minutes_flag[{}]", i), FieldType::Boolean, 1) != 0 {
+            bitstream.field(node, &format!("minutes_value[{}]", i), FieldType::UnsignedInt, 6);
+            if bitstream.field(node, &format!("hours_flag[{}]", i), FieldType::Boolean, 1) != 0 {
+                bitstream.field(node, &format!("hours_value[{}]", i), FieldType::UnsignedInt, 5);
+            }
+        }
+    }
+    if state.sps.time_offset_length > 0 {
+        bitstream.field(node, &format!("time_offset[{}]", i), FieldType::SignedInt, state.sps.time_offset_length as u8);
+    }
+}
+
+fn process_pic_timing<A>(node: &mut SyntaxNode, bitstream: &mut A, state: &H264State)
     where A: BitstreamProcessor {
-    if *slice_type != SliceType::I && *slice_type != SliceType::SI {
+    // CpbDpbDelaysPresentFlag
+    if state.sps.nal_hrd_parameters_present_flag || state.sps.vcl_hrd_parameters_present_flag {
+        bitstream.field(node, "cpb_removal_delay", FieldType::UnsignedInt, (state.sps.cpb_removal_delay_length_minus1 + 1) as u8);
+        bitstream.field(node, "dpb_output_delay", FieldType::UnsignedInt, (state.sps.dpb_output_delay_length_minus1 + 1) as u8);
+    }
+    if !state.sps.pic_struct_present_flag {
+        return;
+    }
+    let pic_struct = bitstream.field(node, "pic_struct", FieldType::UnsignedInt, 4);
+    // NumClockTS from Table D-1
+    let num_clock_ts = match pic_struct {
+        0..=2 => 1,
+        3 | 4 | 7 => 2,
+        5 | 6 | 8 => 3,
+        _ => 0,
+    };
+    for i in 0..num_clock_ts {
+        if bitstream.field(node, &format!("clock_timestamp_flag[{}]", i), FieldType::Boolean, 1) != 0 {
+            process_clock_timestamp(node, bitstream, state, i);
+        }
+    }
+}
+
+fn process_pan_scan_rect<A>(node: &mut SyntaxNode, bitstream: &mut A)
+    where A: BitstreamProcessor {
+    bitstream.field(node, "pan_scan_rect_id", FieldType::UnsignedExpGolomb, 0);
+    let pan_scan_rect_cancel_flag = bitstream.field(node, "pan_scan_rect_cancel_flag", FieldType::Boolean, 1);
+    if pan_scan_rect_cancel_flag == 0 {
+        let pan_scan_cnt_minus1 = bitstream.field(node, "pan_scan_cnt_minus1", FieldType::UnsignedExpGolomb, 0);
+        for i in 0..=pan_scan_cnt_minus1 {
+            bitstream.field(node, &format!("pan_scan_rect_left_offset[{}]", i), FieldType::SignedExpGolomb, 0);
+            bitstream.field(node, &format!("pan_scan_rect_right_offset[{}]", i), FieldType::SignedExpGolomb, 0);
+            bitstream.field(node, &format!("pan_scan_rect_top_offset[{}]", i), FieldType::SignedExpGolomb, 0);
+            bitstream.field(node, &format!("pan_scan_rect_bottom_offset[{}]", i), FieldType::SignedExpGolomb, 0);
+        }
+        bitstream.field(node, "pan_scan_rect_repetition_period", FieldType::UnsignedExpGolomb, 0);
+    }
+}
+
+fn process_user_data_unregistered<A>(node: &mut SyntaxNode, bitstream: &mut A)
+    where A: BitstreamProcessor {
+    bitstream.fixed_payload(node, "uuid_iso_iec_11578", 16);
+    bitstream.text_payload(node, "user_data_payload_byte");
+}
+
+// ATSC A/53 caption data: cc_count triplets of CEA-608 byte pairs or CEA-708 DTVCC packet bytes
+fn process_cc_data<A>(node: &mut SyntaxNode, bitstream: &mut A)
+    where A: BitstreamProcessor {
+    bitstream.field(node, "process_em_data_flag", FieldType::Boolean, 1);
+    bitstream.field(node, "process_cc_data_flag", FieldType::Boolean, 1);
+    bitstream.field(node, "additional_data_flag", FieldType::Boolean, 1);
+    let cc_count = bitstream.field(node, "cc_count", FieldType::UnsignedInt, 5);
+    bitstream.field(node, "em_data", FieldType::UnsignedInt, 8);
+    for i in 0..cc_count {
+        bitstream.field(node, &format!("marker_bits[{}]", i), FieldType::UnsignedInt, 5);
+        bitstream.field(node, &format!("cc_valid[{}]", i), FieldType::Boolean, 1);
+        bitstream.field(node, &format!("cc_type[{}]", i), FieldType::UnsignedInt, 2);
+        bitstream.field(node, &format!("cc_data_1[{}]", i), FieldType::UnsignedInt, 8);
+        bitstream.field(node, &format!("cc_data_2[{}]", i), FieldType::UnsignedInt, 8);
+    }
+    bitstream.field(node, "marker_bits", FieldType::UnsignedInt, 8);
+}
+
+// Only ATSC A/53 user data (country 0xB5, provider 0x0031, "GA94") is decoded further; any other
+// registered payload is kept as bytes.
+fn process_user_data_registered_itu_t_t35<A>(node: &mut SyntaxNode, bitstream: &mut A)
+    where A: BitstreamProcessor {
+    let itu_t_t35_country_code = bitstream.field(node, "itu_t_t35_country_code", FieldType::UnsignedInt, 8);
+    if itu_t_t35_country_code == 0xFF {
+        bitstream.field(node, "itu_t_t35_country_code_extension_byte", FieldType::UnsignedInt, 8);
+    }
+    if itu_t_t35_country_code != 0xB5 {
+        bitstream.payload(node, "itu_t_t35_payload_byte");
+        return;
+    }
+    let itu_t_t35_provider_code = bitstream.field(node, "itu_t_t35_provider_code", FieldType::UnsignedInt, 16);
+    // SMPTE ST 2094-40 (HDR10+) dynamic metadata, told apart by its header. The metadata itself stays hex.
+    if itu_t_t35_provider_code == 0x3C {
+        let itu_t_t35_provider_oriented_code = bitstream.field(node, "itu_t_t35_provider_oriented_code", FieldType::UnsignedInt, 16);
+        if itu_t_t35_provider_oriented_code == 1 {
+            bitstream.field(node, "application_identifier", FieldType::UnsignedInt, 8);
+            bitstream.field(node, "application_version", FieldType::UnsignedInt, 8);
+        }
+        bitstream.payload(node, "itu_t_t35_payload_byte");
+        return;
+    }
+    if itu_t_t35_provider_code != 0x31 {
+        bitstream.payload(node, "itu_t_t35_payload_byte");
+        return;
+    }
+    let user_identifier = bitstream.field(node, "user_identifier", FieldType::UnsignedInt, 32);
+    if user_identifier != 0x47413934 {
+        bitstream.payload(node, "itu_t_t35_payload_byte");
+        return;
+    }
+    let user_data_type_code = bitstream.field(node, "user_data_type_code", FieldType::UnsignedInt, 8);
+    if user_data_type_code != 3 {
+        bitstream.payload(node, "itu_t_t35_payload_byte");
+        return;
+    }
+    bitstream.subnode(node, "cc_data", process_cc_data);
+}
+
+fn process_recovery_point<A>(node: &mut SyntaxNode, bitstream: &mut A)
+    where A: BitstreamProcessor {
+    bitstream.field(node, "recovery_frame_cnt", FieldType::UnsignedExpGolomb, 0);
+    bitstream.field(node, "exact_match_flag", FieldType::Boolean, 1);
+    bitstream.field(node, "broken_link_flag", FieldType::Boolean, 1);
+    bitstream.field(node, "changing_slice_group_idc", FieldType::UnsignedInt, 2);
+}
+
+fn process_film_grain_characteristics<A>(node: &mut SyntaxNode, bitstream: &mut A)
+    where A: BitstreamProcessor {
+    let film_grain_characteristics_cancel_flag = bitstream.field(node, "film_grain_characteristics_cancel_flag", FieldType::Boolean, 1);
+    if film_grain_characteristics_cancel_flag != 0 {
+        return;
+    }
+    bitstream.field(node, "model_id", FieldType::UnsignedInt, 2);
+    let separate_colour_description_present_flag = bitstream.field(node, "separate_colour_description_present_flag", FieldType::Boolean, 1);
+    if separate_colour_description_present_flag != 0 {
+        bitstream.field(node, "film_grain_bit_depth_luma_minus8", FieldType::UnsignedInt, 3);
+        bitstream.field(node, "film_grain_bit_depth_chroma_minus8", FieldType::UnsignedInt, 3);
+        bitstream.field(node, "film_grain_full_range_flag", FieldType::Boolean, 1);
+        bitstream.field(node, "film_grain_colour_primaries", FieldType::UnsignedInt, 8);
+        bitstream.field(node, "film_grain_transfer_characteristics", FieldType::UnsignedInt, 8);
+        bitstream.field(node, "film_grain_matrix_coefficients", FieldType::UnsignedInt, 8);
+    }
+    bitstream.field(node, "blending_mode_id", FieldType::UnsignedInt, 2);
+    bitstream.field(node, "log2_scale_factor", FieldType::UnsignedInt, 4);
+    let mut comp_model_present_flag = [0; 3];
+    for (c, flag) in comp_model_present_flag.iter_mut().enumerate() {
+        *flag = bitstream.field(node, &format!("comp_model_present_flag[{}]", c), FieldType::Boolean, 1);
+    }
+    for c in (0..3).filter(|x| comp_model_present_flag[*x] != 0) {
+        let num_intensity_intervals_minus1 = bitstream.field(node, &format!("num_intensity_intervals_minus1[{}]", c), FieldType::UnsignedInt, 8);
+        let num_model_values_minus1 = bitstream.field(node, &format!("num_model_values_minus1[{}]", c), FieldType::UnsignedInt, 3);
+        for i in 0..=num_intensity_intervals_minus1 {
+            bitstream.field(node, &format!("intensity_interval_lower_bound[{}][{}]", c, i), FieldType::UnsignedInt, 8);
+            bitstream.field(node, &format!("intensity_interval_upper_bound[{}][{}]", c, i), FieldType::UnsignedInt, 8);
+            for j in 0..=num_model_values_minus1 {
+                bitstream.field(node, &format!("comp_model_value[{}][{}][{}]", c, i, j), FieldType::SignedExpGolomb, 0);
+            }
+        }
+    }
+    bitstream.field(node, "film_grain_characteristics_repetition_period", FieldType::UnsignedExpGolomb, 0);
+}
+
+fn process_tone_mapping_info<A>(node: &mut SyntaxNode, bitstream: &mut A, limits: &ParseLimits)
+    where A: BitstreamProcessor {
+    bitstream.field(node, "tone_map_id", FieldType::UnsignedExpGolomb, 0);
+    let tone_map_cancel_flag = bitstream.field(node, "tone_map_cancel_flag", FieldType::Boolean, 1);
+    if tone_map_cancel_flag != 0 {
+        return;
+    }
+    bitstream.field(node, "tone_map_repetition_period", FieldType::UnsignedExpGolomb, 0);
+    let coded_data_bit_depth = bitstream.field(node, "coded_data_bit_depth", FieldType::UnsignedInt, 8);
+    let target_bit_depth = bitstream.field(node, "target_bit_depth", FieldType::UnsignedInt, 8);
+    // Both size the table and pivot fields below (D.2.26)
+    check_minimum("coded_data_bit_depth", i64::from(coded_data_bit_depth), 8);
+    check_limit("coded_data_bit_depth", i64::from(coded_data_bit_depth), limits.max_tone_map_coded_data_bit_depth);
+    check_minimum("target_bit_depth", i64::from(target_bit_depth), 1);
+    check_limit("target_bit_depth", i64::from(target_bit_depth), limits.max_tone_map_target_bit_depth);
+    let tone_map_model_id = bitstream.field(node, "tone_map_model_id", FieldType::UnsignedExpGolomb, 0);
+    match tone_map_model_id {
+        // Linear with clipping
+        0 => {
+            bitstream.field(node, "min_value", FieldType::UnsignedInt, 32);
+            bitstream.field(node, "max_value", FieldType::UnsignedInt, 32);
+        },
+        1 => {
+            bitstream.field(node, "sigmoid_midpoint", FieldType::UnsignedInt, 32);
+            bitstream.field(node, "sigmoid_width", FieldType::UnsignedInt, 32);
+        },
+        // User defined table, one entry per target value
+        2 => {
+            let size = (((coded_data_bit_depth + 7) >> 3) << 3) as u8;
+            for i in 0..(1 << target_bit_depth) {
+                bitstream.field(node, &format!("start_of_coded_interval[{}]", i), FieldType::UnsignedInt, size);
+            }
+        },
+        // Piece-wise linear
+        3 => {
+            let num_pivots = bitstream.field(node, "num_pivots", FieldType::UnsignedInt, 16);
+            let coded_size = (((coded_data_bit_depth + 7) >> 3) << 3) as u8;
+            let target_size = (((target_bit_depth + 7) >> 3) << 3) as u8;
+            for i in 0..num_pivots {
+                bitstream.field(node, &format!("coded_pivot_value[{}]", i), FieldType::UnsignedInt, coded_size);
+                bitstream.field(node, &format!("target_pivot_value[{}]", i), FieldType::UnsignedInt, target_size);
+            }
+        },
+        // Camera exposure
+        4 => {
+            if bitstream.field(node, "camera_iso_speed_idc", FieldType::UnsignedInt, 8) == 255 {
+                bitstream.field(node, "camera_iso_speed_value", FieldType::UnsignedInt, 32);
+            }
+            if bitstream.field(node, "exposure_index_idc", FieldType::UnsignedInt, 8) == 255 {
+                bitstream.field(node, "exposure_index_value", FieldType::UnsignedInt, 32);
+            }
+            bitstream.field(node, "exposure_compensation_value_sign_flag", FieldType::Boolean, 1);
+            bitstream.field(node, "exposure_compensation_value_numerator", FieldType::UnsignedInt, 16);
+            bitstream.field(node, "exposure_compensation_value_denom_idc", FieldType::UnsignedInt, 16);
+            bitstream.field(node, "ref_screen_luminance_white", FieldType::UnsignedInt, 32);
+            bitstream.field(node, "extended_range_white_level", FieldType::UnsignedInt, 32);
+            bitstream.field(node, "nominal_black_level_code_value", FieldType::UnsignedInt, 16);
+            bitstream.field(node, "nominal_white_level_code_value", FieldType::UnsignedInt, 16);
+            bitstream.field(node, "extended_white_level_code_value", FieldType::UnsignedInt, 16);
+        },
+        // Reserved models are left to reserved_payload_extension_data
+        _ => (),
+    }
+}
+
+fn process_alternative_transfer_characteristics<A>(node: &mut SyntaxNode, bitstream: &mut A)
+    where A: BitstreamProcessor {
+    bitstream.field(node, "preferred_transfer_characteristics", FieldType::UnsignedInt, 8);
+}
+
+fn process_content_light_level_info<A>(node: &mut SyntaxNode, bitstream: &mut A)
+    where A: BitstreamProcessor {
+    bitstream.field(node, "max_content_light_level", FieldType::UnsignedInt, 16);
+    bitstream.field(node, "max_pic_average_light_level", FieldType::UnsignedInt, 16);
+}
+
+// D.1.29 and ISO/IEC 23001-11: complexity metrics for the coming period, or the quality lost to power saving.
+// Other green_metadata_type values stay in reserved_payload_extension_data.
+fn process_green_metadata<A>(node: &mut SyntaxNode, bitstream: &mut A)
+    where A: BitstreamProcessor {
+    let green_metadata_type = bitstream.field(node, "green_metadata_type", FieldType::UnsignedInt, 8);
+    if green_metadata_type == 0 {
+        let period_type = bitstream.field(node, "period_type", FieldType::UnsignedInt, 8);
+        if period_type == 2 {
+            bitstream.field(node, "num_seconds", FieldType::UnsignedInt, 16);
+        } else if period_type == 3 {
+            bitstream.field(node, "num_pictures", FieldType::UnsignedInt, 16);
+        }
+        bitstream.field(node, "percent_non_zero_macroblocks", FieldType::UnsignedInt, 8);
+        bitstream.field(node, "percent_intra_coded_macroblocks", FieldType::UnsignedInt, 8);
+        bitstream.field(node, "percent_six_tap_filtering", FieldType::UnsignedInt, 8);
+        bitstream.field(node, "percent_alpha_point_deblocking_instance", FieldType::UnsignedInt, 8);
+    } else if green_metadata_type == 1 {
+        bitstream.field(node, "xsd_metric_type", FieldType::UnsignedInt, 8);
+        bitstream.field(node, "xsd_metric_value", FieldType::UnsignedInt, 16);
+    }
+}
+
+fn process_sei_nesting_zero_bits<A>(node: &mut SyntaxNode, bitstream: &mut A)
+    where A: BitstreamProcessor {
+    while !bitstream.bit_position().is_multiple_of(8) {
+        bitstream.field(node, "sei_nesting_zero_bit", FieldType::Boolean, 1);
+    }
+}
+
+// SVC (G.13.1.4): the nested messages apply to the listed layer representations
+fn process_scalable_nesting<A>(node: &mut SyntaxNode, bitstream: &mut A, state: &mut H264State)
+    where A: BitstreamProcessor {
+    let all_layer_representations_in_au_flag = bitstream.field(node, "all_layer_representations_in_au_flag", FieldType::Boolean, 1);
+    if all_layer_representations_in_au_flag == 0 {
+        let num_layer_representations_minus1 = bitstream.field(node, "num_layer_representations_minus1", FieldType::UnsignedExpGolomb, 0);
+        for i in 0..=num_layer_representations_minus1 {
+            bitstream.field(node, &format!("sei_dependency_id[{}]", i), FieldType::UnsignedInt, 3);
+            bitstream.field(node, &format!("sei_quality_id[{}]", i), FieldType::UnsignedInt, 4);
+        }
+        bitstream.field(node, "sei_temporal_id", FieldType::UnsignedInt, 3);
+    }
+    process_sei_nesting_zero_bits(node, bitstream);
+    loop {
+        bitstream.subnode(node, "sei_message", |x, y| process_sei_message(x, y, state));
+        if !bitstream.more_data(node) {
+            break;
+        }
+    }
+}
+
+// MVC (H.13.1.4): the nested message applies to the listed view components or operation point
+fn process_mvc_scalable_nesting<A>(node: &mut SyntaxNode, bitstream: &mut A, state: &mut H264State)
+    where A: BitstreamProcessor {
+    let operation_point_flag = bitstream.field(node, "operation_point_flag", FieldType::Boolean, 1);
+    if operation_point_flag == 0 {
+        let all_view_components_in_au_flag = bitstream.field(node, "all_view_components_in_au_flag", FieldType::Boolean, 1);
+        if all_view_components_in_au_flag == 0 {
+            let num_view_components_minus1 = bitstream.field(node, "num_view_components_minus1", FieldType::UnsignedExpGolomb, 0);
+            for i in 0..=num_view_components_minus1 {
+                bitstream.field(node, &format!("sei_view_id[{}]", i), FieldType::UnsignedInt, 10);
+            }
+        }
+    } else {
+        let num_view_components_op_minus1 = bitstream.field(node, "num_view_components_op_minus1", FieldType::UnsignedExpGolomb, 0);
+        for i in 0..=num_view_components_op_minus1 {
+            bitstream.field(node, &format!("sei_op_view_id[{}]", i), FieldType::UnsignedInt, 10);
+        }
+        bitstream.field(node, "sei_op_temporal_id", FieldType::UnsignedInt, 3);
+    }
+    process_sei_nesting_zero_bits(node, bitstream);
+    bitstream.subnode(node, "sei_message", |x, y| process_sei_message(x, y, state));
+}
+
+// Dispatch on payloadType (Annex D). Unknown payloads are kept as hex, and whatever a known payload leaves
+// unread besides its alignment bits is kept too, so every message round trips exactly.
+fn process_sei_payload<A>(node: &mut SyntaxNode, bitstream: &mut A, state: &mut H264State, payload_type: i32)
+    where A: BitstreamProcessor {
+    match payload_type {
+        0 => process_buffering_period(node, bitstream, state),
+        1 => process_pic_timing(node, bitstream, state),
+        2 => process_pan_scan_rect(node, bitstream),
+        4 => process_user_data_registered_itu_t_t35(node, bitstream),
+        5 => process_user_data_unregistered(node, bitstream),
+        6 => process_recovery_point(node, bitstream),
+        19 => process_film_grain_characteristics(node, bitstream),
+        23 => process_tone_mapping_info(node, bitstream, &state.limits),
+        30 => process_scalable_nesting(node, bitstream, state),
+        37 => process_mvc_scalable_nesting(node, bitstream, state),
+        56 => process_green_metadata(node, bitstream),
+        137 => process_mastering_display_colour_volume(node, bitstream),
+        144 => process_content_light_level_info(node, bitstream),
+        147 => process_alternative_transfer_characteristics(node, bitstream),
+        _ => {
+            bitstream.payload(node, "payload_data");
+            return;
+        },
+    }
+    if bitstream.more_data(node) {
+        bitstream.payload(node, "reserved_payload_extension_data");
+    }
+}
+
+fn process_sei_message<A>(node: &mut SyntaxNode, bitstream: &mut A, state: &mut H264State)
+    where A: BitstreamProcessor {
+    let payload_type = bitstream.field(node, "payloadType", FieldType::FFCoded, 0);
+    bitstream.sized_subnode(node, "payloadSize", "sei_payload", |x, y| process_sei_payload(x, y, state, payload_type));
+}
+
+fn process_sei<A>(node: &mut SyntaxNode, bitstream: &mut A, state: &mut H264State)
+    where A: BitstreamProcessor {
+    loop {
+        bitstream.subnode(node, "sei_message", |x, y| process_sei_message(x, y, state));
+        if !bitstream.more_data(node) {
+            break;
+        }
+    }
+    bitstream.payload(node, "trailing_bits");
+}
+
+fn process_ref_pic_list_modification<A>(node: &mut SyntaxNode, bitstream: &mut A, slice: &SliceState, limits: &ParseLimits)
+    where A: BitstreamProcessor {
+    if slice.slice_type != SliceType::I && slice.slice_type != SliceType::SI {
         let ref_pic_list_modification_flag_l0 = bitstream.field(node, "ref_pic_list_modification_flag_l0", FieldType::Boolean, 1) != 0;
         if ref_pic_list_modification_flag_l0 {
-            loop {
+            for operations in 0.. {
+                check_limit("ref_pic_list_modification_l0 operations", operations, limits.max_modification_operations);
                 let modification_of_pic_nums_idc = bitstream.field(node, "modification_of_pic_nums_idc", FieldType::UnsignedExpGolomb, 0);
                 match modification_of_pic_nums_idc {
                     0 | 1 => bitstream.field(node, "abs_diff_pic_num_minus1", FieldType::UnsignedExpGolomb, 0),
@@ -289,10 +1409,11 @@ fn process_ref_pic_list_modification<A>(node: &mut SyntaxNode, bitstream: &mut A
             }
         }
     }
-    if *slice_type == SliceType::B {
+    if slice.slice_type == SliceType::B {
         let ref_pic_list_modification_flag_l1 = bitstream.field(node, "ref_pic_list_modification_flag_l1", FieldType::Boolean, 1) != 0;
         if ref_pic_list_modification_flag_l1 {
-            loop {
+            for operations in 0.. {
+                check_limit("ref_pic_list_modification_l1 operations", operations, limits.max_modification_operations);
                 let modification_of_pic_nums_idc = bitstream.field(node, "modification_of_pic_nums_idc", FieldType::UnsignedExpGolomb, 0);
                 match modification_of_pic_nums_idc {
                     0 | 1 => bitstream.field(node, "abs_diff_pic_num_minus1", FieldType::UnsignedExpGolomb, 0),
@@ -305,14 +1426,14 @@ fn process_ref_pic_list_modification<A>(node: &mut SyntaxNode, bitstream: &mut A
     }
 }
 
-fn process_pred_weight_table<A>(node: &mut SyntaxNode, bitstream: &mut A, state: &mut H264State, slice_type: &SliceType) -> ()
+fn process_pred_weight_table<A>(node: &mut SyntaxNode, bitstream: &mut A, slice: &SliceState)
     where A: BitstreamProcessor {
     bitstream.field(node, "luma_log2_weight_denom", FieldType::UnsignedExpGolomb, 0);
-    let chroma_array_type = if state.separate_color_plane_flag { 0 } else { state.chroma_format_idc };
+    let chroma_array_type = slice.chroma_array_type;
     if chroma_array_type != 0 {
         bitstream.field(node, "chroma_log2_weight_denom", FieldType::UnsignedExpGolomb, 0);
     }
-    for i in 0..(state.num_ref_idx_l0_active_minus1+1) {
+    for i in 0..(slice.num_ref_idx_l0_active_minus1+1) {
         let luma_weight_l0_flag = bitstream.field(node, "luma_weight_l0_flag", FieldType::Boolean, 1) != 0;
         if luma_weight_l0_flag {
             bitstream.field(node, &format!("luma_weight_l0[{}]", i), FieldType::SignedExpGolomb, 0);
@@ -328,8 +1449,8 @@ fn process_pred_weight_table<A>(node: &mut SyntaxNode, bitstream: &mut A, state:
             }
         }
     }
-    if *slice_type != SliceType::B {
-        for i in 0..(state.num_ref_idx_l1_active_minus1+1) {
+    if slice.slice_type == SliceType::B {
+        for i in 0..(slice.num_ref_idx_l1_active_minus1+1) {
             let luma_weight_l1_flag = bitstream.field(node, "luma_weight_l1_flag", FieldType::Boolean, 1) != 0;
             if luma_weight_l1_flag {
                 bitstream.field(node, &format!("luma_weight_l1[{}]", i), FieldType::SignedExpGolomb, 0);
@@ -348,15 +1469,16 @@ fn process_pred_weight_table<A>(node: &mut SyntaxNode, bitstream: &mut A, state:
     }
 }
 
-fn process_dec_ref_pic_marking<A>(node: &mut SyntaxNode, bitstream: &mut A, idr_pic_flag: bool) -> ()
+fn process_dec_ref_pic_marking<A>(node: &mut SyntaxNode, bitstream: &mut A, slice: &SliceState, limits: &ParseLimits)
     where A: BitstreamProcessor {
-    if idr_pic_flag {
+    if slice.idr_pic_flag {
         bitstream.field(node, "no_output_of_prior_pics_flag", FieldType::Boolean, 1);
         bitstream.field(node, "long_term_reference_flag", FieldType::Boolean, 1);
     } else {
         let adaptive_ref_pic_marking_mode_flag = bitstream.field(node, "adaptive_ref_pic_marking_mode_flag", FieldType::Boolean, 1) != 0;
         if adaptive_ref_pic_marking_mode_flag {
-            loop {
+            for operations in 0.. {
+                check_limit("memory_management_control_operation count", operations, limits.max_modification_operations);
                 let memory_management_control_operation = bitstream.field(node, "memory_management_control_operation", FieldType::UnsignedExpGolomb, 0);
                 if memory_management_control_operation == 0 {
                     break;
@@ -380,38 +1502,43 @@ fn process_dec_ref_pic_marking<A>(node: &mut SyntaxNode, bitstream: &mut A, idr_
     }
 }
 
-fn process_slice_header<A>(node: &mut SyntaxNode, bitstream: &mut A, state: &mut H264State, nalu_type: i32, nal_ref_idc: i32) -> ()
+// Returns what the slice data that follows depends on
+fn process_slice_header<A>(node: &mut SyntaxNode, bitstream: &mut A, state: &mut H264State, header: &NalHeader) -> SliceDataParams
     where A: BitstreamProcessor {
-    bitstream.field(node, "first_mb_in_slice", FieldType::UnsignedExpGolomb, 0);
+    let extension = header.nal_unit_type == 20 || header.nal_unit_type == 21;
+    let first_mb_in_slice = bitstream.field(node, "first_mb_in_slice", FieldType::UnsignedExpGolomb, 0);
     let slice_type = int_to_slice_type(bitstream.field(node, "slice_type", FieldType::UnsignedExpGolomb, 0));
-    bitstream.field(node, "pic_parameter_set_id", FieldType::UnsignedExpGolomb, 0);
-    if state.separate_color_plane_flag {
+    let pic_parameter_set_id = bitstream.field(node, "pic_parameter_set_id", FieldType::UnsignedExpGolomb, 0);
+    state.activate(pic_parameter_set_id, extension);
+    let mut slice = SliceState::new(state, slice_type, header);
+    if state.sps.separate_color_plane_flag {
         bitstream.field(node, "color_plane_id", FieldType::UnsignedInt, 2);
     }
-    let frame_num_size = state.log2_max_frame_num_minus4 + 4;
+    let frame_num_size = state.sps.log2_max_frame_num_minus4 + 4;
     bitstream.field(node, "frame_num", FieldType::UnsignedInt, frame_num_size.try_into().unwrap());
-    let mut field_pic_flag = false;
-    if !state.frame_mbs_only_flag {
-        field_pic_flag = bitstream.field(node, "field_pic_flag", FieldType::Boolean, 1) != 0;
-        if field_pic_flag {
+    if !state.sps.frame_mbs_only_flag {
+        slice.field_pic_flag = bitstream.field(node, "field_pic_flag", FieldType::Boolean, 1) != 0;
+        if slice.field_pic_flag {
             bitstream.field(node, "bottom_field_flag", FieldType::Boolean, 1);
         }
     }
-    let idr_pic_flag = nalu_type == 5;
-    if idr_pic_flag {
+    if slice.idr_pic_flag {
         bitstream.field(node, "idr_pic_id", FieldType::UnsignedExpGolomb, 0);
     }
-    if state.pic_order_cnt_type == 0 {
-        let pic_order_cnt_lsb_size = state.log2_max_pic_order_cnt_lsb_minus4 + 4;
+    if state.sps.pic_order_cnt_type == 0 {
+        let pic_order_cnt_lsb_size = state.sps.log2_max_pic_order_cnt_lsb_minus4 + 4;
         bitstream.field(node, "pic_order_cnt_lsb", FieldType::UnsignedInt, pic_order_cnt_lsb_size.try_into().unwrap());
-        if state.bottom_field_pic_order_in_frame_present_flag && !field_pic_flag {
+        if state.pps.bottom_field_pic_order_in_frame_present_flag && !slice.field_pic_flag {
             bitstream.field(node, "delta_pic_order_cnt_bottom", FieldType::SignedExpGolomb, 0);
         }
     }
-    if state.pic_order_cnt_type == 1 && !state.delta_pic_order_always_zero_flag {
-        bitstream.field(node, "delta_pic_order_cnt", FieldType::SignedExpGolomb, 0);
+    if state.sps.pic_order_cnt_type == 1 && !state.sps.delta_pic_order_always_zero_flag {
+        bitstream.field(node, "delta_pic_order_cnt[0]", FieldType::SignedExpGolomb, 0);
+        if state.pps.bottom_field_pic_order_in_frame_present_flag && !slice.field_pic_flag {
+            bitstream.field(node, "delta_pic_order_cnt[1]", FieldType::SignedExpGolomb, 0);
+        }
     }
-    if state.redundant_pic_cnt_present_flag {
+    if state.pps.redundant_pic_cnt_present_flag {
         bitstream.field(node, "redundant_pic_cnt", FieldType::UnsignedExpGolomb, 0);
     }
     if slice_type == SliceType::B {
@@ -423,22 +1550,24 @@ fn process_slice_header<A>(node: &mut SyntaxNode, bitstream: &mut A, state: &mut
        slice_type == SliceType::B {
         let num_ref_idx_active_override_flag = bitstream.field(node, "num_ref_idx_active_override_flag", FieldType::Boolean, 1) != 0;
         if num_ref_idx_active_override_flag {
-            bitstream.field(node, "num_ref_idx_l0_active_minus1", FieldType::UnsignedExpGolomb, 0);
-        }
-        if slice_type == SliceType::B {
-            bitstream.field(node, "num_ref_idx_l1_active_minus1", FieldType::UnsignedExpGolomb, 0);
+            slice.num_ref_idx_l0_active_minus1 = bitstream.field(node, "num_ref_idx_l0_active_minus1", FieldType::UnsignedExpGolomb, 0);
+            if slice_type == SliceType::B {
+                slice.num_ref_idx_l1_active_minus1 = bitstream.field(node, "num_ref_idx_l1_active_minus1", FieldType::UnsignedExpGolomb, 0);
+            }
         }
     }
-    bitstream.subnode(node, if (nalu_type == 20 || nalu_type == 21) { "ref_pic_list_mvc_modification" } else { "ref_pic_list_modification" },
-                      |x, y| process_ref_pic_list_modification(x, y, &slice_type));
-    if (state.weighted_pred_flag && (slice_type == SliceType::P || slice_type == SliceType::SP)) ||
-       (state.weighted_bipred_idc == 1 && slice_type == SliceType::B) {
-        bitstream.subnode(node, "pred_weight_table", |x, y| process_pred_weight_table(x, y, state, &slice_type));
+    check_limit("num_ref_idx_l0_active_minus1 + 1", i64::from(slice.num_ref_idx_l0_active_minus1) + 1, state.limits.max_ref_idx_active);
+    check_limit("num_ref_idx_l1_active_minus1 + 1", i64::from(slice.num_ref_idx_l1_active_minus1) + 1, state.limits.max_ref_idx_active);
+    bitstream.subnode(node, if extension { "ref_pic_list_mvc_modification" } else { "ref_pic_list_modification" },
+                      |x, y| process_ref_pic_list_modification(x, y, &slice, &state.limits));
+    if (state.pps.weighted_pred_flag && (slice_type == SliceType::P || slice_type == SliceType::SP)) ||
+       (state.pps.weighted_bipred_idc == 1 && slice_type == SliceType::B) {
+        bitstream.subnode(node, "pred_weight_table", |x, y| process_pred_weight_table(x, y, &slice));
     }
-    if nal_ref_idc != 0 {
-        bitstream.subnode(node, "dec_ref_pic_marking", |x, y| process_dec_ref_pic_marking(x, y, idr_pic_flag));
+    if header.nal_ref_idc != 0 {
+        bitstream.subnode(node, "dec_ref_pic_marking", |x, y| process_dec_ref_pic_marking(x, y, &slice, &state.limits));
     }
-    if state.entropy_coding_mode_flag && slice_type != SliceType::I && slice_type != SliceType::SI {
+    if state.pps.entropy_coding_mode_flag && slice_type != SliceType::I && slice_type != SliceType::SI {
         bitstream.field(node, "cabac_init_idc", FieldType::UnsignedExpGolomb, 0);
     }
     bitstream.field(node, "slice_qp_delta", FieldType::SignedExpGolomb, 0);
@@ -448,69 +1577,577 @@ fn process_slice_header<A>(node: &mut SyntaxNode, bitstream: &mut A, state: &mut
         }
         bitstream.field(node, "slice_qs_delta", FieldType::SignedExpGolomb, 0);
     }
-    if state.deblocking_filter_control_present_flag {
+    if state.pps.deblocking_filter_control_present_flag {
         let disable_deblocking_filter_idc = bitstream.field(node, "disable_deblocking_filter_idc", FieldType::UnsignedExpGolomb, 0);
         if disable_deblocking_filter_idc != 1 {
             bitstream.field(node, "slice_alpha_c0_offset_div2", FieldType::SignedExpGolomb, 0);
             bitstream.field(node, "slice_beta_offset_div2", FieldType::SignedExpGolomb, 0);
         }
     }
-    if state.num_slice_groups_minus1 > 0 && state.slice_group_map_type >= 3 && state.slice_group_map_type <= 5 {
-        let slice_group_change_cycle_size = f64::from((state.pic_size_in_map_units_minus1 + 1) / (state.slice_group_change_rate_minus1 + 1) + 1).log2().ceil() as u8;
+    if state.pps.num_slice_groups_minus1 > 0 && state.pps.slice_group_map_type >= 3 && state.pps.slice_group_map_type <= 5 {
+        // Ceil(Log2(PicSizeInMapUnits / SliceGroupChangeRate + 1)), sized from the SPS picture dimensions
+        let pic_size_in_map_units = (state.sps.pic_width_in_mbs_minus1 + 1) * (state.sps.pic_height_in_map_units_minus1 + 1);
+        let slice_group_change_cycle_size = (f64::from(pic_size_in_map_units) / f64::from(state.pps.slice_group_change_rate_minus1 + 1) + 1.0).log2().ceil() as u8;
         bitstream.field(node, "slice_group_change_cycle", FieldType::UnsignedInt, slice_group_change_cycle_size);
     }
+
+    let pic_width_in_mbs = state.sps.pic_width_in_mbs_minus1 + 1;
+    let frame_height_in_mbs = (state.sps.pic_height_in_map_units_minus1 + 1) * if state.sps.frame_mbs_only_flag { 1 } else { 2 };
+    // MbaffFrameFlag (7-25) and PicHeightInMbs (7-26)
+    let mbaff_frame_flag = state.sps.mb_adaptive_frame_field_flag && !slice.field_pic_flag;
+    let pic_height_in_mbs = frame_height_in_mbs / if slice.field_pic_flag { 2 } else { 1 };
+    bitstream.derived(node, "MbaffFrameFlag", mbaff_frame_flag as i32);
+    bitstream.derived(node, "PicHeightInMbs", pic_height_in_mbs);
+    SliceDataParams { slice_type,
+                      first_mb_in_slice,
+                      mbaff_frame_flag,
+                      pic_width_in_mbs,
+                      pic_size_in_mbs: pic_width_in_mbs * pic_height_in_mbs,
+                      chroma_array_type: slice.chroma_array_type,
+                      bit_depth_luma: state.sps.bit_depth_luma_minus8 + 8,
+                      bit_depth_chroma: state.sps.bit_depth_chroma_minus8 + 8,
+                      transform_8x8_mode_flag: state.pps.transform_8x8_mode_flag,
+                      direct_8x8_inference_flag: state.sps.direct_8x8_inference_flag,
+                      num_ref_idx_l0_active_minus1: slice.num_ref_idx_l0_active_minus1,
+                      num_ref_idx_l1_active_minus1: slice.num_ref_idx_l1_active_minus1,
+    }
+}
+
+fn process_slice<A>(node: &mut SyntaxNode, bitstream: &mut A, state: &mut H264State, header: &NalHeader)
+    where A: BitstreamProcessor {
+    if header.nal_unit_type != 19 {
+        state.primary_idr_pic_flag = header.idr_pic_flag;
+    }
+    let mut params: Option<SliceDataParams> = None;
+    bitstream.subnode(node, "slice_header", |x, y| params = Some(process_slice_header(x, y, state, header)));
+    let params = params.unwrap();
+    // Partition A (7.3.2.9.1) names its slice, so partitions B and C can refer to it
+    if header.nal_unit_type == 2 {
+        bitstream.field(node, "slice_id", FieldType::UnsignedExpGolomb, 0);
+    }
+    state.slice_data_bit_offset = bitstream.bit_position();
+    // Macroblocks are only parsed for CAVLC slices without data partitioning, slice groups or MBAFF. A tree
+    // that has slice_data is written back from it, whichever way it was parsed.
+    let supported = !state.pps.entropy_coding_mode_flag && state.pps.num_slice_groups_minus1 == 0 && !params.mbaff_frame_flag &&
+        params.slice_type != SliceType::SI && (header.nal_unit_type == 1 || header.nal_unit_type == 5);
+    if node.get_node("slice_data").is_some() || (state.deep && supported) {
+        // A cached parse is taken as is, nothing after slice_data() being read
+        let offset = state.slice_data_bit_offset;
+        if let Some(cached) = state.cache.as_mut().and_then(|x| x.get(&params, offset)) {
+            node.children.extend(cached);
+            return;
+        }
+        bitstream.subnode(node, "slice_data", |x, y| process_slice_data(x, y, &params));
+        bitstream.payload(node, "trailing_bits");
+        if let Some(cache) = &state.cache {
+            let len = node.children.len();
+            cache.put(&params, offset, &node.children.make_contiguous()[len - 2..]);
+        }
+    } else {
+        bitstream.payload(node, "slice_payload");
+    }
 }
 
-fn process_slice<A>(node: &mut SyntaxNode, bitstream: &mut A, state: &mut H264State, nalu_type: i32, nalu_ref_idc: i32) -> ()
+// Partitions B and C (7.3.2.9.2, 7.3.2.9.3) have no slice header of their own. They carry the residual data of
+// the slice partition A with the same slice_id introduced, so its parameter sets are still the ones in effect.
+fn process_slice_data_partition<A>(node: &mut SyntaxNode, bitstream: &mut A, state: &mut H264State)
     where A: BitstreamProcessor {
-    bitstream.subnode(node, "slice_header", |x, y| process_slice_header(x, y, state, nalu_type, nalu_ref_idc));
+    bitstream.field(node, "slice_id", FieldType::UnsignedExpGolomb, 0);
+    if state.sps.separate_color_plane_flag {
+        bitstream.field(node, "color_plane_id", FieldType::UnsignedInt, 2);
+    }
+    if state.pps.redundant_pic_cnt_present_flag {
+        bitstream.field(node, "redundant_pic_cnt", FieldType::UnsignedExpGolomb, 0);
+    }
+    state.slice_data_bit_offset = bitstream.bit_position();
     bitstream.payload(node, "slice_payload");
 }
 
 fn process_nalu<A>(node: &mut SyntaxNode, bitstream: &mut A, state: &mut H264State) -> ()
     where A: BitstreamProcessor {
-    bitstream.field(node, "forbidden_zero_bit", FieldType::Boolean, 1);
-    let nalu_ref_idc = bitstream.field(node, "nal_ref_idc", FieldType::UnsignedInt, 2);
-    let nalu_type = bitstream.field(node, "nal_unit_type", FieldType::UnsignedInt, 5);
-    match nalu_type {
-        1 | 2 | 3 | 4 | 5 => bitstream.subnode(node, "slice", |x, y| process_slice(x, y, state, nalu_type, nalu_ref_idc)),
+    let header = process_nal_unit_header(node, bitstream, NalHeaderFormat::H264);
+    match header.nal_unit_type {
+        1 | 2 | 5 => bitstream.subnode(node, "slice", |x, y| process_slice(x, y, state, &header)),
+        // Kept apart from slice, like slice_extension, so analysis modes only see primary coded pictures. An
+        // auxiliary coded picture is an IDR picture when its primary coded picture is.
+        19 => {
+            let header = NalHeader { idr_pic_flag: state.primary_idr_pic_flag, ..header };
+            bitstream.subnode(node, "aux_slice", |x, y| process_slice(x, y, state, &header))
+        },
+        3 => bitstream.subnode(node, "slice_data_partition_b", |x, y| process_slice_data_partition(x, y, state)),
+        4 => bitstream.subnode(node, "slice_data_partition_c", |x, y| process_slice_data_partition(x, y, state)),
+        // Slices of the non-base MVC views are coded like any other (H.7.3.2.13). The SVC and 3D-AVC slice
+        // headers aren't decoded.
+        20 | 21 if header.h264_extension == H264HeaderExtension::Mvc => {
+            bitstream.subnode(node, "slice_extension", |x, y| process_slice(x, y, state, &header))
+        },
+        6 => bitstream.subnode(node, "sei", |x, y| process_sei(x, y, state)),
         7 => bitstream.subnode(node, "sps", |x, y| process_sps(x, y, state)),
         8 => bitstream.subnode(node, "pps", |x, y| process_pps(x, y, state)),
+        9 => bitstream.subnode(node, "access_unit_delimiter", process_access_unit_delimiter),
+        13 => bitstream.subnode(node, "sps_extension", |x, y| process_sps_extension(x, y, &state.limits)),
+        15 => bitstream.subnode(node, "subset_sps", |x, y| process_subset_sps(x, y, state)),
+        // 7.3.2.5 and 7.3.2.6, both RBSPs are empty
+        10 => bitstream.subnode(node, "end_of_seq", |_, _| ()),
+        11 => bitstream.subnode(node, "end_of_stream", |_, _| ()),
         12 => bitstream.subnode(node, "filler_nalu", process_filler),
         _ => bitstream.subnode(node, "unparsed_nalu", process_filler),
     };
 }
 
-pub fn parse_h264<'a>(bitstream: &Vec<u8>) -> Vec<SyntaxElement> {
+pub fn parse_h264(bitstream: &[u8]) -> Vec<SyntaxElement> {
+    parse_h264_with_state(bitstream, H264State::new())
+}
+
+// Like parse_h264, with other caps on the counts read from the bitstream, e.g. tighter ones for untrusted input
+pub fn parse_h264_with_limits(bitstream: &[u8], limits: ParseLimits) -> Vec<SyntaxElement> {
+    let mut state = H264State::new();
+    state.limits = limits;
+    parse_h264_with_state(bitstream, state)
+}
+
+// Like parse_h264, but the slice data of CAVLC slices is parsed into a slice_data node of macroblocks,
+// followed by trailing_bits, instead of a slice_payload
+pub fn parse_h264_deep(bitstream: &[u8]) -> Vec<SyntaxElement> {
+    let mut state = H264State::new();
+    state.deep = true;
+    parse_h264_with_state(bitstream, state)
+}
+
+// Like parse_h264_deep, but the macroblocks of each slice are looked up in a cache directory first and stored
+// there when parsed, so reparsing a slightly edited stream only parses the slices that changed
+pub fn parse_h264_cached(bitstream: &[u8], cache_dir: &Path) -> Vec<SyntaxElement> {
+    let mut state = H264State::new();
+    state.deep = true;
+    state.cache = Some(SliceDataCache::new(cache_dir));
+    parse_h264_with_state(bitstream, state)
+}
+
+fn parse_h264_with_state(bitstream: &[u8], state: H264State) -> Vec<SyntaxElement> {
     let mut ret: Vec<SyntaxElement> = vec![];
-    let mut compressed_nalus = tokenize_h264_bitstream(bitstream);
+    parse_h264_nalus(bitstream, state, &mut |nalu, _| ret.push(nalu));
+    ret
+}
+
+// Like parse_h264, but a NALU that ends before its syntax does keeps the fields read so far, followed by a
+// truncated_at field with the bit offset in its RBSP of the first field that couldn't be read.
+pub fn parse_h264_salvaging(bitstream: &[u8]) -> Vec<SyntaxElement> {
+    let mut state = H264State::new();
+    state.salvage = true;
+    parse_h264_with_state(bitstream, state)
+}
+
+// How parse_h264_streaming parses, matching the parse_h264 variants
+#[derive(Default)]
+pub struct ParseOptions {
+    pub deep: bool,
+    pub salvage: bool,
+    pub cache_dir: Option<PathBuf>,
+}
+
+// Hand each NALU to on_nalu as soon as it is parsed, together with the warnings parsing it raised, instead of
+// printing them. Output can then be written while the rest of the stream is still being parsed.
+pub fn parse_h264_streaming(bitstream: &[u8], options: &ParseOptions, on_nalu: &mut dyn FnMut(SyntaxElement, Vec<String>)) {
+    let mut state = H264State::new();
+    state.deep = options.deep || options.cache_dir.is_some();
+    state.salvage = options.salvage;
+    state.cache = options.cache_dir.as_deref().map(SliceDataCache::new);
+    state.warnings = Some(vec![]);
+    parse_h264_nalus(bitstream, state, on_nalu);
+}
+
+// When salvaging, the panic a NALU raises is caught as it unwinds, leaving the panic hook as it is. Only the
+// parser's own errors are salvaged; a panic from a bug in it goes on unwinding.
+fn parse_h264_nalus(bitstream: &[u8], mut state: H264State, on_nalu: &mut dyn FnMut(SyntaxElement, Vec<String>)) {
+    for (idx, nalu) in tokenize_h264_bitstream(bitstream).iter().enumerate() {
+        if let Some(cache) = &mut state.cache {
+            cache.begin_nalu(&nalu.rbsp);
+        }
+        let mut root = SyntaxNode::new("nalu");
+        push_start_code(&mut root, nalu);
+        let mut reader = BitstreamReader::new(&nalu.rbsp);
+        if !state.salvage {
+            process_nalu(&mut root, &mut reader, &mut state);
+            push_trailing_zeros(&mut root, nalu);
+        } else if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(|| process_nalu(&mut root, &mut reader, &mut state))) {
+            if is_unexpected_panic(&panic_message(&*payload)) {
+                panic::resume_unwind(payload);
+            }
+            state.warn(format!("NALU {} is truncated at bit {}", idx, reader.bit_position()));
+            root.push_field("truncated_at", reader.bit_position() as i32);
+        } else {
+            push_trailing_zeros(&mut root, nalu);
+        }
+        let warnings = state.warnings.as_mut().map(std::mem::take).unwrap_or_default();
+        on_nalu(SyntaxElement::Node(root), warnings);
+    }
+    if let Some(cache) = &state.cache {
+        eprintln!("Slice data cache: {} hits, {} misses", cache.hits, cache.misses);
+    }
+}
+
+// Parse every NALU, also returning the coded bits and bit position of each field NALU by NALU. Positions
+// are within the RBSP, after emulation prevention bytes are removed.
+pub fn trace_h264(bitstream: &[u8]) -> (Vec<SyntaxElement>, Vec<Vec<TraceEntry>>) {
+    let mut nalus: Vec<SyntaxElement> = vec![];
+    let mut traces: Vec<Vec<TraceEntry>> = vec![];
     let mut state = H264State::new();
+    for nalu in tokenize_h264_bitstream(bitstream) {
+        let mut root = SyntaxNode::new("nalu");
+        push_start_code(&mut root, &nalu);
+        let mut tracer = BitstreamTracer::new(&nalu.rbsp);
+        process_nalu(&mut root, &mut tracer, &mut state);
+        push_trailing_zeros(&mut root, &nalu);
+        nalus.push(SyntaxElement::Node(root));
+        traces.push(tracer.entries);
+    }
+
+    (nalus, traces)
+}
+
+// Where slice_data() begins within the RBSP of a slice NALU, for edits that must leave the slice data bytes alone
+pub struct SliceDataStart {
+    pub bit_offset: usize,
+    pub cabac: bool,
+}
+
+fn slice_data_start(state: &H264State) -> SliceDataStart {
+    SliceDataStart { bit_offset: state.slice_data_bit_offset, cabac: state.pps.entropy_coding_mode_flag }
+}
+
+// slice_payload holds the bits up to the first byte boundary in the low bits of its first byte, then whole bytes
+pub fn unpack_slice_payload(payload: &[u8], bit_offset: usize) -> Vec<bool> {
+    let partial = (8 - bit_offset % 8) % 8;
+    let mut ret: Vec<bool> = vec![];
+    for (idx, byte) in payload.iter().enumerate() {
+        let bits = if idx == 0 && partial != 0 { partial } else { 8 };
+        ret.extend((0..bits).rev().map(|x| (byte >> x) & 1 != 0));
+    }
+
+    ret
+}
+
+pub fn pack_slice_payload(bits: &[bool], bit_offset: usize) -> Vec<u8> {
+    let partial = ((8 - bit_offset % 8) % 8).min(bits.len());
+    let mut ret: Vec<u8> = vec![];
+    if partial != 0 {
+        ret.push(bits[..partial].iter().fold(0, |acc, x| (acc << 1) | u8::from(*x)));
+    }
+    for byte in bits[partial..].chunks(8) {
+        ret.push(byte.iter().fold(0, |acc, x| (acc << 1) | u8::from(*x)) << (8 - byte.len()));
+    }
+
+    ret
+}
+
+// Every NALU carrying slice data: the slices, whichever picture they belong to, and data partitions B and C
+fn has_slice_data(nalu: &SyntaxNode) -> bool {
+    get_any_slice_header(nalu).is_some() || nalu.get_node("slice_data_partition_b").is_some() ||
+        nalu.get_node("slice_data_partition_c").is_some()
+}
 
-    for mut reader in &mut compressed_nalus {
-        let mut root = SyntaxNode {name: "nalu".to_string(), children: VecDeque::new()};
-        process_nalu(&mut root, reader, &mut state);
-        ret.push(SyntaxElement::Node(root));
+pub fn parse_h264_slice_data_starts(bitstream: &[u8]) -> Vec<Option<SliceDataStart>> {
+    let mut ret: Vec<Option<SliceDataStart>> = vec![];
+    let mut state = H264State::new();
+    for nalu in tokenize_h264_bitstream(bitstream) {
+        let mut root = SyntaxNode::new("nalu");
+        process_nalu(&mut root, &mut BitstreamReader::new(&nalu.rbsp), &mut state);
+        ret.push(has_slice_data(&root).then(|| slice_data_start(&state)));
     }
 
     ret
 }
 
-pub fn serialize_h264(human_readable: String) -> Vec<u8> {
+// Which NALUs get a 4 byte start code (a zero_byte before 00 00 01) rather than a 3 byte one
+#[derive(Clone, Copy, Default, PartialEq)]
+pub enum StartCodePolicy {
+    // The length noted when the stream was parsed, 4 bytes where none was
+    #[default]
+    Preserve,
+    AllFour,
+    AllThree,
+    // 4 bytes for SPS, PPS and the first NALU of each access unit, where Annex B requires a zero_byte,
+    // and 3 bytes elsewhere
+    AccessUnit,
+}
+
+#[derive(Default)]
+pub struct SerializeOptions {
+    pub fixup_dependent_fields: bool,
+    pub regenerate_trailing_bits: bool,
+    pub start_codes: StartCodePolicy,
+}
+
+fn bits_needed(val: i32) -> i32 {
+    32 - val.max(0).leading_zeros() as i32
+}
+
+fn fixup_log2_field(sps: &mut SyntaxNode, name: &str, bits: i32) {
+    let Some(log2_minus4) = sps.get_field(name) else {
+        return;
+    };
+    if bits <= log2_minus4 + 4 {
+        return;
+    }
+    if bits > 16 {
+        eprintln!("Warning: {} cannot be raised past 12 to fit a {} bit value", name, bits);
+        return;
+    }
+    eprintln!("Fixup: raising {} from {} to {}", name, log2_minus4, bits - 4);
+    sps.set_field(name, bits - 4);
+}
+
+// Widen the SPS fields that size frame_num and pic_order_cnt_lsb so edited slice values still fit. Each slice is
+// attributed to the SPS it activates through its pic_parameter_set_id, as the most recent SPS and PPS with those
+// ids preceding it, the same way parsing resolves them (7.4.1.2.1). Slices of non-base MVC views activate a
+// subset SPS instead.
+fn fixup_dependent_fields(nalus: &mut VecDeque<SyntaxElement>) {
+    let mut required_bits: Vec<(usize, i32, i32)> = vec![];
+    let mut sps_by_id: HashMap<i32, usize> = HashMap::new();
+    let mut subset_sps_by_id: HashMap<i32, usize> = HashMap::new();
+    let mut pps_by_id: HashMap<i32, i32> = HashMap::new();
+    for (idx, element) in nalus.iter().enumerate() {
+        let SyntaxElement::Node(nalu) = element else {
+            continue;
+        };
+        let nal_unit_type = nalu.get_field("nal_unit_type");
+        match nal_unit_type {
+            Some(7) | Some(15) => {
+                let (name, ids) = if nal_unit_type == Some(7) { ("sps", &mut sps_by_id) } else { ("subset_sps", &mut subset_sps_by_id) };
+                let sps_id = nalu.get_node(name).and_then(|x| x.get_field("seq_paramter_set_id")).unwrap_or(0);
+                ids.insert(sps_id, required_bits.len());
+                required_bits.push((idx, 0, 0));
+            },
+            Some(8) => {
+                let Some(pps) = nalu.get_node("pps") else {
+                    continue;
+                };
+                let pps_id = pps.get_field("pic_parameter_set_id").unwrap_or(0);
+                pps_by_id.insert(pps_id, pps.get_field("seq_parameter_set_id").unwrap_or(0));
+            },
+            Some(1..=5) | Some(19..=21) => {
+                let Some(header) = get_any_slice_header(nalu) else {
+                    continue;
+                };
+                let ids = if nalu.get_node("slice_extension").is_some() { &subset_sps_by_id } else { &sps_by_id };
+                let pps_id = header.get_field("pic_parameter_set_id").unwrap_or(0);
+                let Some(sps) = pps_by_id.get(&pps_id).and_then(|x| ids.get(x)) else {
+                    eprintln!("Warning: slice in NALU {} has no preceding SPS for its PPS, cannot fix up its field widths", idx);
+                    continue;
+                };
+                let entry = &mut required_bits[*sps];
+                entry.1 = entry.1.max(bits_needed(header.get_field("frame_num").unwrap_or(0)));
+                entry.2 = entry.2.max(bits_needed(header.get_field("pic_order_cnt_lsb").unwrap_or(0)));
+            },
+            _ => (),
+        }
+    }
+    for (idx, frame_num_bits, pic_order_cnt_lsb_bits) in required_bits {
+        let SyntaxElement::Node(nalu) = &mut nalus[idx] else {
+            continue;
+        };
+        let name = if nalu.get_field("nal_unit_type") == Some(7) { "sps" } else { "subset_sps" };
+        let Some(sps) = nalu.get_node_mut(name) else {
+            continue;
+        };
+        fixup_log2_field(sps, "log2_max_frame_num_minus4", frame_num_bits);
+        fixup_log2_field(sps, "log2_max_pic_order_cnt_lsb_minus4", pic_order_cnt_lsb_bits);
+    }
+}
+
+// Warn about slices referring to a PPS, or PPSs referring to an SPS, that isn't encoded ahead of them.
+fn check_references(nalus: &VecDeque<SyntaxElement>) {
+    let mut sps_ids: HashSet<i32> = HashSet::new();
+    let mut pps_ids: HashSet<i32> = HashSet::new();
+    for (idx, element) in nalus.iter().enumerate() {
+        let SyntaxElement::Node(nalu) = element else {
+            continue;
+        };
+        match nalu.get_field("nal_unit_type") {
+            Some(7) => {
+                if let Some(sps_id) = nalu.get_node("sps").and_then(|x| x.get_field("seq_paramter_set_id")) {
+                    sps_ids.insert(sps_id);
+                }
+            },
+            Some(8) => {
+                let Some(pps) = nalu.get_node("pps") else {
+                    continue;
+                };
+                if let Some(sps_id) = pps.get_field("seq_parameter_set_id") {
+                    if !sps_ids.contains(&sps_id) {
+                        eprintln!("Warning: PPS in NALU {} refers to seq_parameter_set_id {} which has no preceding SPS", idx, sps_id);
+                    }
+                }
+                if let Some(pps_id) = pps.get_field("pic_parameter_set_id") {
+                    pps_ids.insert(pps_id);
+                }
+            },
+            Some(1..=5) => {
+                let pps_id = get_slice_header(nalu).and_then(|x| x.get_field("pic_parameter_set_id"));
+                if let Some(pps_id) = pps_id {
+                    if !pps_ids.contains(&pps_id) {
+                        eprintln!("Warning: slice in NALU {} refers to pic_parameter_set_id {} which has no preceding PPS", idx, pps_id);
+                    }
+                }
+            },
+            _ => (),
+        }
+    }
+}
+
+pub fn parse_h264_nalu(nalu: &[u8]) -> SyntaxNode {
+    let mut root = SyntaxNode::new("nalu");
+    process_nalu(&mut root, &mut BitstreamReader::new(&nalu_to_rbsp(nalu)), &mut H264State::new());
+    root
+}
+
+// The header of a slice of a primary or redundant coded picture, data partition A among them. Auxiliary slices
+// and slices of non-base MVC views are left out on purpose: they belong to the access unit of the primary
+// picture they follow (7.4.1.2.3, H.7.4.1.2.3) without being pictures of their own, so splitting access units
+// and the analyses of primary pictures must not count them. get_any_slice_header returns theirs too.
+pub fn get_slice_header(nalu: &SyntaxNode) -> Option<&SyntaxNode> {
+    nalu.get_node("slice").and_then(|x| x.get_node("slice_header"))
+}
+
+// The nodes that start with a slice_header: slices of the primary coded picture, data partition A among them,
+// auxiliary coded slices and slices of non-base MVC views.
+pub const SLICE_HEADER_NODES: [&str; 3] = ["slice", "aux_slice", "slice_extension"];
+
+pub fn get_any_slice_header(nalu: &SyntaxNode) -> Option<&SyntaxNode> {
+    SLICE_HEADER_NODES.iter().find_map(|x| nalu.get_node(x)).and_then(|x| x.get_node("slice_header"))
+}
+
+pub fn serialize_h264(human_readable: String, options: &SerializeOptions) -> Vec<u8> {
     let mut rows: VecDeque<String> = VecDeque::from_iter(human_readable.split('\n').map(|x| x.to_string()));
-    let mut nalus: VecDeque<SyntaxElement> = syntax_elements_from_string(&mut rows);
+    serialize_h264_nalus(syntax_elements_from_string(&mut rows), options)
+}
+
+fn expect_nalu(element: SyntaxElement) -> SyntaxNode {
+    match element {
+        SyntaxElement::Node(nalu) => nalu,
+        other => panic!("{}expected node nalu, found {} {}", location(other.line()), other.kind(), other.name()),
+    }
+}
+
+pub fn serialize_h264_nalus(mut nalus: VecDeque<SyntaxElement>, options: &SerializeOptions) -> Vec<u8> {
+    // Salvaged NALUs don't hold enough syntax to be written back
+    nalus.retain(|x| match x {
+        SyntaxElement::Node(nalu) if nalu.get_field("truncated_at").is_some() => {
+            eprintln!("Warning: dropping NALU truncated at bit {}", nalu.get_field("truncated_at").unwrap());
+            false
+        },
+        _ => true,
+    });
+    if options.fixup_dependent_fields {
+        fixup_dependent_fields(&mut nalus);
+    }
+    check_references(&nalus);
     let mut writer: BitstreamWriter = BitstreamWriter::new();
+    writer.regenerate_trailing_bits = options.regenerate_trailing_bits;
     let mut state = H264State::new();
+    let access_unit_starts: HashSet<usize> = split_access_units(nalus.make_contiguous()).iter().map(|x| x.start).collect();
 
-    while nalus.len() > 0 {
-        writer.write(FieldType::UnsignedInt, 8, 0x00);
+    for idx in 0..nalus.len() {
+        let mut nalu = expect_nalu(nalus.pop_front().unwrap());
+        let leading_zeros = take_byte_stream_field(&mut nalu, "leading_zero_8bits").unwrap_or(0);
+        let start_code_length = take_byte_stream_field(&mut nalu, "start_code_length");
+        for _ in 0..leading_zeros {
+            writer.write(FieldType::UnsignedInt, 8, 0x00);
+        }
+        let zero_byte = match options.start_codes {
+            StartCodePolicy::Preserve => match start_code_length {
+                None | Some(4) => true,
+                Some(3) => false,
+                Some(len) => panic!("{}start_code_length must be 3 or 4, found {}", location(nalu.line), len),
+            },
+            StartCodePolicy::AllFour => true,
+            StartCodePolicy::AllThree => false,
+            StartCodePolicy::AccessUnit => {
+                access_unit_starts.contains(&idx) || matches!(nalu.get_field("nal_unit_type"), Some(7) | Some(8))
+            },
+        };
+        if zero_byte {
+            writer.write(FieldType::UnsignedInt, 8, 0x00);
+        }
         writer.write(FieldType::UnsignedInt, 8, 0x00);
         writer.write(FieldType::UnsignedInt, 8, 0x00);
         writer.write(FieldType::UnsignedInt, 8, 0x01);
-        let SyntaxElement::Node(mut nalu) = nalus.pop_front().unwrap() else {
-            panic!("Invalid syntax element!");
-        };
+        let start_idx = writer.buffer.len();
         process_nalu(&mut nalu, &mut writer, &mut state);
+        let zeros = take_trailing_zeros(&mut nalu);
+        warn_unread_children(&nalu);
+        writer.insert_emulation_prevention(start_idx + nal_unit_header_bytes(&writer.buffer[start_idx..]));
+        for _ in 0..zeros {
+            writer.write(FieldType::UnsignedInt, 8, 0x00);
+        }
     }
 
     writer.buffer
 }
+
+// Serialize every NALU into its own buffer, without start codes or emulation prevention, noting where each
+// slice's data begins.
+pub fn serialize_h264_nalus_separately(nalus: VecDeque<SyntaxElement>) -> Vec<(Vec<u8>, Option<SliceDataStart>)> {
+    check_references(&nalus);
+    let mut ret: Vec<(Vec<u8>, Option<SliceDataStart>)> = vec![];
+    let mut state = H264State::new();
+    for element in nalus {
+        let mut nalu = expect_nalu(element);
+        take_byte_stream_field(&mut nalu, "leading_zero_8bits");
+        take_byte_stream_field(&mut nalu, "start_code_length");
+        let is_slice = has_slice_data(&nalu);
+        let mut writer = BitstreamWriter::new();
+        process_nalu(&mut nalu, &mut writer, &mut state);
+        // Without start codes there's no byte stream for trailing_zero_8bits to be part of
+        take_trailing_zeros(&mut nalu);
+        warn_unread_children(&nalu);
+        ret.push((writer.buffer, is_slice.then(|| slice_data_start(&state))));
+    }
+
+    ret
+}
+
+// Serialize only the NALUs that differ from the original parse they line up with one to one, each with
+// emulation prevention but no start code. Every NALU still goes through the writer for the state it sets.
+// Once a parameter set changes, every NALU after it is re-encoded, as they may be coded against it.
+pub fn serialize_changed_h264_nalus(mut nalus: VecDeque<SyntaxElement>, original: &[SyntaxElement],
+                                    options: &SerializeOptions) -> Vec<Option<Vec<u8>>> {
+    if nalus.len() != original.len() {
+        panic!("The edit has {} NALUs but the original stream has {}", nalus.len(), original.len());
+    }
+    if options.fixup_dependent_fields {
+        fixup_dependent_fields(&mut nalus);
+    }
+    check_references(&nalus);
+    let mut ret: Vec<Option<Vec<u8>>> = vec![];
+    let mut state = H264State::new();
+    let mut reencode_rest = false;
+    for (element, original) in nalus.into_iter().zip(original) {
+        let (mut edited_text, mut original_text) = (element.clone(), original.clone());
+        canonicalize(&mut edited_text);
+        canonicalize(&mut original_text);
+        let changed = reencode_rest || edited_text.to_string() != original_text.to_string();
+        let mut nalu = expect_nalu(element);
+        take_byte_stream_field(&mut nalu, "leading_zero_8bits");
+        take_byte_stream_field(&mut nalu, "start_code_length");
+        if changed && matches!(nalu.get_field("nal_unit_type"), Some(7 | 8 | 13 | 15)) {
+            reencode_rest = true;
+        }
+        let mut writer = BitstreamWriter::new();
+        writer.regenerate_trailing_bits = options.regenerate_trailing_bits;
+        process_nalu(&mut nalu, &mut writer, &mut state);
+        let zeros = take_trailing_zeros(&mut nalu);
+        warn_unread_children(&nalu);
+        if changed {
+            writer.insert_emulation_prevention(nal_unit_header_bytes(&writer.buffer));
+            for _ in 0..zeros {
+                writer.write(FieldType::UnsignedInt, 8, 0x00);
+            }
+            ret.push(Some(writer.buffer));
+        } else {
+            ret.push(None);
+        }
+    }
+
+    ret
+}
@@ -0,0 +1,334 @@
+use crate::bitstream_util::SyntaxElement;
+use crate::bitstream_util::SyntaxNode;
+use crate::h264_access_units::split_access_units;
+use crate::h264_analysis::ParameterSets;
+use crate::h264_parser::get_slice_header;
+
+pub struct PictureOrder {
+    pub access_unit: usize,
+    // PicOrderCnt() of the picture: the lower of the two for a frame, the field's own for a field
+    pub pic_order_cnt: i32,
+    // None for the parity a field picture doesn't have
+    pub top_field_order_cnt: Option<i32>,
+    pub bottom_field_order_cnt: Option<i32>,
+    // IDR and memory_management_control_operation 5 pictures restart output order
+    pub resets_order: bool,
+}
+
+// Values carried from one picture to the next by the decoding process in 8.2.1
+struct PocState {
+    prev_pic_order_cnt_msb: i32,
+    prev_pic_order_cnt_lsb: i32,
+    prev_frame_num_offset: i32,
+    prev_frame_num: i32,
+}
+
+pub fn has_mmco5(header: &SyntaxNode) -> bool {
+    header.get_node("dec_ref_pic_marking").is_some_and(|x| x.children.iter().any(|y| match y {
+        SyntaxElement::Field(field) => field.name == "memory_management_control_operation" && field.val == 5,
+        _ => false,
+    }))
+}
+
+fn frame_num_offset(state: &PocState, idr: bool, frame_num: i32, max_frame_num: i32) -> i32 {
+    if idr {
+        0
+    } else if state.prev_frame_num > frame_num {
+        state.prev_frame_num_offset + max_frame_num
+    } else {
+        state.prev_frame_num_offset
+    }
+}
+
+fn field(node: &SyntaxNode, name: &str) -> i32 {
+    node.get_field(name).unwrap_or(0)
+}
+
+// Derive TopFieldOrderCnt/BottomFieldOrderCnt for the primary picture of every access unit (8.2.1).
+pub fn compute_picture_order(nalus: &[SyntaxElement]) -> Vec<PictureOrder> {
+    let mut parameter_sets = ParameterSets::new();
+    let mut state = PocState { prev_pic_order_cnt_msb: 0, prev_pic_order_cnt_lsb: 0, prev_frame_num_offset: 0, prev_frame_num: 0 };
+    let mut ret: Vec<PictureOrder> = vec![];
+    for (access_unit_idx, access_unit) in split_access_units(nalus).into_iter().enumerate() {
+        let mut picture: Option<(&SyntaxNode, &SyntaxNode)> = None;
+        for element in &nalus[access_unit] {
+            let SyntaxElement::Node(nalu) = element else {
+                continue;
+            };
+            parameter_sets.update(nalu);
+            if picture.is_none() {
+                picture = get_slice_header(nalu).map(|x| (nalu, x));
+            }
+        }
+        let Some((nalu, header)) = picture else {
+            continue;
+        };
+        let Some((sps, _)) = parameter_sets.for_slice(header) else {
+            eprintln!("Warning: access unit {} refers to a missing parameter set, skipping it", access_unit_idx);
+            continue;
+        };
+
+        let idr = nalu.get_field("nal_unit_type") == Some(5);
+        let reference = field(nalu, "nal_ref_idc") != 0;
+        let mmco5 = has_mmco5(header);
+        let field_pic = field(header, "field_pic_flag") != 0;
+        let bottom_field = field(header, "bottom_field_flag") != 0;
+        let frame_num = field(header, "frame_num");
+        let max_frame_num = 1 << (field(sps, "log2_max_frame_num_minus4") + 4);
+        let (top, bottom) = match field(sps, "pic_order_cnt_type") {
+            0 => {
+                if idr {
+                    state.prev_pic_order_cnt_msb = 0;
+                    state.prev_pic_order_cnt_lsb = 0;
+                }
+                let max_pic_order_cnt_lsb = 1 << (field(sps, "log2_max_pic_order_cnt_lsb_minus4") + 4);
+                let lsb = field(header, "pic_order_cnt_lsb");
+                let msb = if lsb < state.prev_pic_order_cnt_lsb && state.prev_pic_order_cnt_lsb - lsb >= max_pic_order_cnt_lsb / 2 {
+                    state.prev_pic_order_cnt_msb + max_pic_order_cnt_lsb
+                } else if lsb > state.prev_pic_order_cnt_lsb && lsb - state.prev_pic_order_cnt_lsb > max_pic_order_cnt_lsb / 2 {
+                    state.prev_pic_order_cnt_msb - max_pic_order_cnt_lsb
+                } else {
+                    state.prev_pic_order_cnt_msb
+                };
+                if reference {
+                    state.prev_pic_order_cnt_msb = msb;
+                    state.prev_pic_order_cnt_lsb = lsb;
+                }
+                let top = msb + lsb;
+                if !field_pic {
+                    (top, top + field(header, "delta_pic_order_cnt_bottom"))
+                } else {
+                    (top, top)
+                }
+            },
+            1 => {
+                let frame_num_offset = frame_num_offset(&state, idr, frame_num, max_frame_num);
+                let num_ref_frames_in_pic_order_cnt_cycle = field(sps, "num_ref_frames_in_pic_order_cnt_cycle");
+                let offsets: Vec<i32> = (0..num_ref_frames_in_pic_order_cnt_cycle)
+                    .map(|i| field(sps, &format!("offset_for_ref_frame[{}]", i)))
+                    .collect();
+                let mut abs_frame_num = if num_ref_frames_in_pic_order_cnt_cycle != 0 { frame_num_offset + frame_num } else { 0 };
+                if !reference && abs_frame_num > 0 {
+                    abs_frame_num -= 1;
+                }
+                let mut expected_pic_order_cnt = 0;
+                if abs_frame_num > 0 {
+                    let pic_order_cnt_cycle_cnt = (abs_frame_num - 1) / num_ref_frames_in_pic_order_cnt_cycle;
+                    let frame_num_in_pic_order_cnt_cycle = (abs_frame_num - 1) % num_ref_frames_in_pic_order_cnt_cycle;
+                    expected_pic_order_cnt = pic_order_cnt_cycle_cnt * offsets.iter().sum::<i32>() +
+                        offsets[..=frame_num_in_pic_order_cnt_cycle as usize].iter().sum::<i32>();
+                }
+                if !reference {
+                    expected_pic_order_cnt += field(sps, "offset_for_non_ref_pic");
+                }
+                state.prev_frame_num_offset = frame_num_offset;
+                let offset_for_top_to_bottom_field = field(sps, "offset_for_top_to_bottom_field");
+                let delta_pic_order_cnt_0 = field(header, "delta_pic_order_cnt[0]");
+                if !field_pic {
+                    let top = expected_pic_order_cnt + delta_pic_order_cnt_0;
+                    (top, top + offset_for_top_to_bottom_field + field(header, "delta_pic_order_cnt[1]"))
+                } else if !bottom_field {
+                    (expected_pic_order_cnt + delta_pic_order_cnt_0, expected_pic_order_cnt + delta_pic_order_cnt_0)
+                } else {
+                    let bottom = expected_pic_order_cnt + offset_for_top_to_bottom_field + delta_pic_order_cnt_0;
+                    (bottom, bottom)
+                }
+            },
+            _ => {
+                let frame_num_offset = frame_num_offset(&state, idr, frame_num, max_frame_num);
+                let temp_pic_order_cnt = if idr {
+                    0
+                } else if !reference {
+                    2 * (frame_num_offset + frame_num) - 1
+                } else {
+                    2 * (frame_num_offset + frame_num)
+                };
+                state.prev_frame_num_offset = frame_num_offset;
+                (temp_pic_order_cnt, temp_pic_order_cnt)
+            },
+        };
+        let pic_order_cnt = if !field_pic { top.min(bottom) } else { top };
+        state.prev_frame_num = frame_num;
+
+        ret.push(PictureOrder { access_unit: access_unit_idx,
+                                pic_order_cnt,
+                                top_field_order_cnt: if !field_pic || !bottom_field { Some(top) } else { None },
+                                bottom_field_order_cnt: if !field_pic || bottom_field { Some(bottom) } else { None },
+                                resets_order: idr || mmco5 });
+
+        // After memory_management_control_operation 5 the picture is treated as having POC 0 going forward
+        if mmco5 {
+            state.prev_frame_num_offset = 0;
+            state.prev_frame_num = 0;
+            if reference {
+                state.prev_pic_order_cnt_msb = 0;
+                state.prev_pic_order_cnt_lsb = if field_pic && bottom_field { 0 } else { top - pic_order_cnt };
+            }
+        }
+    }
+
+    ret
+}
+
+// For every picture, count the pictures decoded before it that are output after it. The maximum is the
+// num_reorder_frames a decoder needs; zero means the stream can be decoded and output with no delay.
+pub fn reorder_report(nalus: &[SyntaxElement]) -> String {
+    let pictures = compute_picture_order(nalus);
+    let mut max_depth = 0;
+    let mut reordered_pictures = 0;
+    let mut epoch_start = 0;
+    let mut ret = "".to_string();
+    for (idx, picture) in pictures.iter().enumerate() {
+        if picture.resets_order {
+            epoch_start = idx;
+        }
+        let depth = pictures[epoch_start..idx].iter()
+            .filter(|x| x.pic_order_cnt > picture.pic_order_cnt)
+            .count();
+        if depth > 0 {
+            reordered_pictures += 1;
+            ret.push_str(&format!("access unit {} (POC {}) is output before {} earlier decoded picture(s)\n",
+                                  picture.access_unit, picture.pic_order_cnt, depth));
+        }
+        max_depth = max_depth.max(depth);
+    }
+
+    format!("{}pictures: {}\nreordered_pictures: {}\nmax_reorder_depth: {}\nlow_delay: {}\n",
+            ret, pictures.len(), reordered_pictures, max_depth, if max_depth == 0 { "yes" } else { "no" })
+}
+
+// TopFieldOrderCnt and BottomFieldOrderCnt of every primary picture in decode order, with its rank in output
+// order among the pictures since the last IDR or memory_management_control_operation 5 picture. Two frames or
+// same parity fields sharing an order count within that span can't be output in a defined order (8.2.1).
+pub fn picture_order_report(nalus: &[SyntaxElement]) -> String {
+    let pictures = compute_picture_order(nalus);
+    let mut ret = "access_unit\tstructure\tTopFieldOrderCnt\tBottomFieldOrderCnt\tPicOrderCnt\toutput\n".to_string();
+    let mut duplicates = 0;
+    let mut epoch_start = 0;
+    for (idx, picture) in pictures.iter().enumerate() {
+        if picture.resets_order {
+            epoch_start = idx;
+        }
+        let epoch_end = pictures[idx + 1..].iter().position(|x| x.resets_order).map_or(pictures.len(), |x| idx + 1 + x);
+        let epoch = &pictures[epoch_start..epoch_end];
+        let output = epoch.iter().filter(|x| x.pic_order_cnt < picture.pic_order_cnt).count();
+        let structure = match (picture.top_field_order_cnt, picture.bottom_field_order_cnt) {
+            (Some(_), Some(_)) => "frame",
+            (Some(_), None) => "top",
+            _ => "bottom",
+        };
+        let show = |x: Option<i32>| x.map_or("-".to_string(), |x| x.to_string());
+        ret.push_str(&format!("{}\t{}\t{}\t{}\t{}\t{}\n", picture.access_unit, structure, show(picture.top_field_order_cnt),
+                              show(picture.bottom_field_order_cnt), picture.pic_order_cnt, output));
+        let earlier = &pictures[epoch_start..idx];
+        let clash = earlier.iter().find(|x| (picture.top_field_order_cnt.is_some() && x.top_field_order_cnt == picture.top_field_order_cnt) ||
+                                            (picture.bottom_field_order_cnt.is_some() && x.bottom_field_order_cnt == picture.bottom_field_order_cnt));
+        if let Some(clash) = clash {
+            ret.push_str(&format!("Warning: access unit {} has the same order count as access unit {}\n", picture.access_unit, clash.access_unit));
+            duplicates += 1;
+        }
+    }
+    ret.push_str(&format!("pictures: {}\nduplicate_order_counts: {}\n", pictures.len(), duplicates));
+
+    ret
+}
+
+struct PictureStructure {
+    access_unit: usize,
+    frame_num: i32,
+    // None for a frame, otherwise bottom_field_flag
+    bottom_field: Option<bool>,
+    mbaff_frame_flag: bool,
+    pic_height_in_mbs: i32,
+    paired_with: Option<usize>,
+}
+
+// The frame or field structure of every primary picture, with the access unit each field pairs with. Two fields
+// pair into a frame when they're consecutive in decode order, of opposite parity, share frame_num, and are both
+// reference or both non-reference fields, the second not IDR and the first without
+// memory_management_control_operation 5 (3.30, 3.91).
+pub fn field_structure_report(nalus: &[SyntaxElement]) -> String {
+    let mut parameter_sets = ParameterSets::new();
+    let mut pictures: Vec<PictureStructure> = vec![];
+    // Index into pictures of the last picture if it's a field still waiting for its pair, with its reference
+    // flag and whether it can take a second field
+    let mut unpaired: Option<(usize, bool, bool)> = None;
+    for (access_unit_idx, access_unit) in split_access_units(nalus).into_iter().enumerate() {
+        let mut picture: Option<(&SyntaxNode, &SyntaxNode)> = None;
+        for element in &nalus[access_unit] {
+            let SyntaxElement::Node(nalu) = element else {
+                continue;
+            };
+            parameter_sets.update(nalu);
+            if picture.is_none() {
+                picture = get_slice_header(nalu).map(|x| (nalu, x));
+            }
+        }
+        let Some((nalu, header)) = picture else {
+            continue;
+        };
+        let Some((sps, _)) = parameter_sets.for_slice(header) else {
+            eprintln!("Warning: access unit {} refers to a missing parameter set, skipping it", access_unit_idx);
+            unpaired = None;
+            continue;
+        };
+
+        let field_pic = field(header, "field_pic_flag") != 0;
+        let bottom_field = field(header, "bottom_field_flag") != 0;
+        let frame_num = field(header, "frame_num");
+        let reference = field(nalu, "nal_ref_idc") != 0;
+        let idr = nalu.get_field("nal_unit_type") == Some(5);
+        let frame_height_in_mbs = (field(sps, "pic_height_in_mbs_minus1") + 1) * (2 - field(sps, "frame_mbs_only_flag"));
+        let mut structure = PictureStructure { access_unit: access_unit_idx,
+                                               frame_num,
+                                               bottom_field: if field_pic { Some(bottom_field) } else { None },
+                                               mbaff_frame_flag: !field_pic && field(sps, "mb_adaptive_frame_field_flag") != 0,
+                                               pic_height_in_mbs: frame_height_in_mbs / if field_pic { 2 } else { 1 },
+                                               paired_with: None };
+        let first = unpaired.take().filter(|(idx, first_reference, pairable)| {
+            let first = &pictures[*idx];
+            field_pic && *pairable && !idr && *first_reference == reference && first.frame_num == frame_num &&
+                first.bottom_field == Some(!bottom_field)
+        });
+        if let Some((idx, _, _)) = first {
+            pictures[idx].paired_with = Some(access_unit_idx);
+            structure.paired_with = Some(pictures[idx].access_unit);
+        } else if field_pic {
+            unpaired = Some((pictures.len(), reference, !has_mmco5(header)));
+        }
+        pictures.push(structure);
+    }
+
+    let mut ret = "".to_string();
+    let (mut frames, mut mbaff_frames, mut field_pairs, mut unpaired_fields) = (0, 0, 0, 0);
+    for picture in &pictures {
+        let description = match picture.bottom_field {
+            None if picture.mbaff_frame_flag => {
+                mbaff_frames += 1;
+                "MBAFF frame".to_string()
+            },
+            None => {
+                frames += 1;
+                "frame".to_string()
+            },
+            Some(bottom_field) => {
+                let parity = if bottom_field { "bottom field" } else { "top field" };
+                match picture.paired_with {
+                    Some(idx) => {
+                        field_pairs += 1;
+                        format!("{}, paired with access unit {}", parity, idx)
+                    },
+                    None => {
+                        unpaired_fields += 1;
+                        format!("{}, unpaired", parity)
+                    },
+                }
+            },
+        };
+        ret.push_str(&format!("access unit {} (frame_num {}, PicHeightInMbs {}): {}\n",
+                              picture.access_unit, picture.frame_num, picture.pic_height_in_mbs, description));
+    }
+
+    format!("{}frames: {}\nmbaff_frames: {}\nfield_pairs: {}\nunpaired_fields: {}\n",
+            ret, frames, mbaff_frames, field_pairs / 2, unpaired_fields)
+}
@@ -0,0 +1,121 @@
+use std::collections::HashMap;
+
+use crate::bitstream_util::BitstreamReader;
+use crate::bitstream_util::FieldType;
+use crate::bitstream_util::ebsp_to_rbsp;
+use crate::h264_analysis::sps_frame_rate;
+use crate::h264_parser::parse_h264_nalu;
+use crate::h264_parser::tokenize_h264_nalus;
+use crate::nal_header::NalHeaderFormat;
+use crate::nal_header::peek_nal_unit_type;
+
+struct ProbeSps {
+    log2_max_frame_num: u8,
+    frame_mbs_only_flag: bool,
+    separate_color_plane_flag: bool,
+    frame_rate: Option<f64>,
+}
+
+struct ProbeSlice {
+    first_mb_in_slice: i32,
+    frame_num: i32,
+    field_pic_flag: bool,
+}
+
+fn probe_sps(nalu: &[u8]) -> Option<(i32, ProbeSps)> {
+    let root = parse_h264_nalu(nalu);
+    let sps = root.get_node("sps")?;
+    let frame_rate = sps_frame_rate(sps);
+    Some((sps.get_field("seq_paramter_set_id")?,
+          ProbeSps { log2_max_frame_num: (sps.get_field("log2_max_frame_num_minus4")? + 4).try_into().ok()?,
+                     frame_mbs_only_flag: sps.get_field("frame_mbs_only_flag")? != 0,
+                     separate_color_plane_flag: sps.get_field("separate_color_plane_flag").unwrap_or(0) != 0,
+                     frame_rate }))
+}
+
+// The fields probed sit well within the first bytes of a NALU, so only those are unescaped
+fn probed_rbsp(nalu: &[u8]) -> Vec<u8> {
+    ebsp_to_rbsp(&nalu[1..nalu.len().min(32)])
+}
+
+// Only the two ids at the front of the PPS are needed to find the SPS behind a slice
+fn probe_pps(nalu: &[u8]) -> Option<(i32, i32)> {
+    let rbsp = probed_rbsp(nalu);
+    let mut reader = BitstreamReader::new(&rbsp);
+    let pic_parameter_set_id = reader.read(FieldType::UnsignedExpGolomb, 0)?;
+    let seq_parameter_set_id = reader.read(FieldType::UnsignedExpGolomb, 0)?;
+    Some((pic_parameter_set_id, seq_parameter_set_id))
+}
+
+fn probe_slice(nalu: &[u8], sps_map: &HashMap<i32, ProbeSps>, pps_map: &HashMap<i32, i32>) -> Option<ProbeSlice> {
+    let rbsp = probed_rbsp(nalu);
+    let mut reader = BitstreamReader::new(&rbsp);
+    let first_mb_in_slice = reader.read(FieldType::UnsignedExpGolomb, 0)?;
+    reader.read(FieldType::UnsignedExpGolomb, 0)?;
+    let pic_parameter_set_id = reader.read(FieldType::UnsignedExpGolomb, 0)?;
+    let sps = sps_map.get(pps_map.get(&pic_parameter_set_id)?)?;
+    if sps.separate_color_plane_flag {
+        reader.read(FieldType::UnsignedInt, 2)?;
+    }
+    let frame_num = reader.read(FieldType::UnsignedInt, sps.log2_max_frame_num)?;
+    let field_pic_flag = !sps.frame_mbs_only_flag && reader.read(FieldType::Boolean, 1)? != 0;
+    Some(ProbeSlice { first_mb_in_slice, frame_num, field_pic_flag })
+}
+
+// Count frames and IDRs from NALU headers and the first few slice header fields, skipping everything else.
+pub fn probe_h264(bitstream: &[u8], frame_rate_override: Option<f64>) -> String {
+    let mut sps_map: HashMap<i32, ProbeSps> = HashMap::new();
+    let mut pps_map: HashMap<i32, i32> = HashMap::new();
+    let mut frame_count = 0;
+    let mut idr_count = 0;
+    let mut unparsed_slices = 0;
+    let mut frame_rate: Option<f64> = None;
+    // frame_num of a field still waiting for its second field
+    let mut unpaired_field: Option<i32> = None;
+    for nalu in tokenize_h264_nalus(bitstream) {
+        let Some(nal_unit_type) = peek_nal_unit_type(nalu, NalHeaderFormat::H264) else {
+            continue;
+        };
+        match nal_unit_type {
+            7 => {
+                if let Some((sps_id, sps)) = probe_sps(nalu) {
+                    frame_rate = frame_rate.or(sps.frame_rate);
+                    sps_map.insert(sps_id, sps);
+                }
+            },
+            8 => {
+                if let Some((pps_id, sps_id)) = probe_pps(nalu) {
+                    pps_map.insert(pps_id, sps_id);
+                }
+            },
+            1 | 2 | 5 => {
+                let Some(slice) = probe_slice(nalu, &sps_map, &pps_map) else {
+                    unparsed_slices += 1;
+                    continue;
+                };
+                if slice.first_mb_in_slice != 0 {
+                    continue;
+                }
+                if slice.field_pic_flag && unpaired_field == Some(slice.frame_num) {
+                    unpaired_field = None;
+                    continue;
+                }
+                unpaired_field = if slice.field_pic_flag { Some(slice.frame_num) } else { None };
+                frame_count += 1;
+                if nal_unit_type == 5 {
+                    idr_count += 1;
+                }
+            },
+            _ => (),
+        }
+    }
+
+    let mut ret = format!("frames: {}\nidr_frames: {}\n", frame_count, idr_count);
+    if unparsed_slices > 0 {
+        ret = format!("{}unparsed_slices: {}\n", ret, unparsed_slices);
+    }
+    match frame_rate_override.or(frame_rate) {
+        Some(fps) => format!("{}frame_rate: {:.3}\nduration: {:.3}s\n", ret, fps, f64::from(frame_count) / fps),
+        None => format!("{}duration: unknown (no VUI timing, pass --fps=<rate>)\n", ret),
+    }
+}
@@ -0,0 +1,316 @@
+use crate::bitstream_util::SyntaxElement;
+use crate::bitstream_util::SyntaxNode;
+use crate::h264_corpus::stream_features;
+use crate::h264_parser::nalu_contents;
+
+// The coding tools a profile rules out (A.2), by the feature names stream_features reports. Bit depth and
+// chroma format limits are checked separately, since they are SPS values rather than tools.
+pub struct ProfileConstraints {
+    pub name: &'static str,
+    pub forbidden: &'static [&'static str],
+}
+
+pub const BASELINE: ProfileConstraints = ProfileConstraints {
+    name: "Baseline",
+    forbidden: &["cabac", "b_slices", "sp_si_slices", "interlace", "mbaff", "field_pictures", "weighted_prediction",
+                 "data_partitioning", "8x8_transform", "scaling_matrices"],
+};
+
+pub const MAIN: ProfileConstraints = ProfileConstraints {
+    name: "Main",
+    forbidden: &["sp_si_slices", "fmo", "aso", "redundant_pictures", "data_partitioning", "8x8_transform", "scaling_matrices"],
+};
+
+pub const EXTENDED: ProfileConstraints = ProfileConstraints {
+    name: "Extended",
+    forbidden: &["cabac", "8x8_transform", "scaling_matrices"],
+};
+
+pub const HIGH: ProfileConstraints = ProfileConstraints {
+    name: "High",
+    forbidden: &["sp_si_slices", "fmo", "aso", "redundant_pictures", "data_partitioning"],
+};
+
+// constraint_set4_flag in Main, Extended and the High profiles
+pub const FRAME_MBS_ONLY: ProfileConstraints = ProfileConstraints {
+    name: "frame_mbs_only",
+    forbidden: &["interlace", "mbaff", "field_pictures"],
+};
+
+// constraint_set5_flag in Main, Extended and High
+pub const NO_B_SLICES: ProfileConstraints = ProfileConstraints {
+    name: "no B slices",
+    forbidden: &["b_slices"],
+};
+
+// (level_idc, MaxBR in 1000 bit/s, MaxCPB in 1000 bits) from Table A-1, with level 1b as level_idc 9
+const LEVEL_BITRATE_LIMITS: [(i32, i64, i64); 20] = [(10, 64, 175), (9, 128, 350), (11, 192, 500), (12, 384, 1000), (13, 768, 2000),
+                                                    (20, 2000, 2000), (21, 4000, 4000), (22, 4000, 4000), (30, 10000, 10000),
+                                                    (31, 14000, 14000), (32, 20000, 20000), (40, 20000, 25000), (41, 50000, 62500),
+                                                    (42, 50000, 62500), (50, 135000, 135000), (51, 240000, 240000), (52, 240000, 240000),
+                                                    (60, 240000, 240000), (61, 480000, 480000), (62, 800000, 800000)];
+
+// (level_idc, MaxFS in macroblocks) from Table A-1. Level 1b shares level 1's limit.
+pub const LEVEL_MAX_FRAME_SIZES: [(i32, i32); 19] = [(10, 99), (11, 396), (12, 396), (13, 396), (20, 396), (21, 792), (22, 1620),
+                                                      (30, 1620), (31, 3600), (32, 5120), (40, 8192), (41, 8192), (42, 8704),
+                                                      (50, 22080), (51, 36864), (52, 36864), (60, 139264), (61, 139264), (62, 139264)];
+
+// (level_idc, MaxMBPS in macroblocks per second) from Table A-1. Level 1b shares level 1's limit.
+const LEVEL_MAX_MB_RATES: [(i32, i64); 19] = [(10, 1485), (11, 3000), (12, 6000), (13, 11880), (20, 11880), (21, 19800),
+                                              (22, 20250), (30, 40500), (31, 108000), (32, 216000), (40, 245760), (41, 245760),
+                                              (42, 522240), (50, 589824), (51, 983040), (52, 2073600), (60, 4177920),
+                                              (61, 8355840), (62, 16711680)];
+
+// Level 1b is level_idc 11 with constraint_set3_flag in Baseline, Main and Extended, and level_idc 9 elsewhere
+pub fn is_level_1b(sps: &SyntaxNode) -> bool {
+    let level_idc = sps.get_field("level_idc").unwrap_or(0);
+    level_idc == 9 || (level_idc == 11 && sps.get_field("constraint_set3_flag").unwrap_or(0) != 0 &&
+                       [66, 77, 88].contains(&sps.get_field("profile_idc").unwrap_or(0)))
+}
+
+// cpbBrNalFactor from Table A-2, scaling the level's MaxBR and MaxCPB to NAL HRD bits
+pub fn cpb_br_nal_factor(profile_idc: i32) -> i64 {
+    match profile_idc {
+        100 => 1500,
+        110 => 3600,
+        122 | 244 | 44 => 4800,
+        _ => 1200,
+    }
+}
+
+// cpbBrVclFactor from Table A-2, for VCL HRD parameters
+pub fn cpb_br_vcl_factor(profile_idc: i32) -> i64 {
+    match profile_idc {
+        100 => 1250,
+        110 => 3000,
+        122 | 244 | 44 => 4000,
+        _ => 1000,
+    }
+}
+
+// (MaxBR, MaxCPB) of an SPS's level in NAL HRD bits per second and bits, None for an unknown level
+pub fn level_bitrate_limits(sps: &SyntaxNode) -> Option<(i64, i64)> {
+    let level_idc = if is_level_1b(sps) { 9 } else { sps.get_field("level_idc")? };
+    let factor = cpb_br_nal_factor(sps.get_field("profile_idc").unwrap_or(0));
+    LEVEL_BITRATE_LIMITS.iter()
+        .find(|x| x.0 == level_idc)
+        .map(|(_, max_br, max_cpb)| (max_br * factor, max_cpb * factor))
+}
+
+// MaxFS of an SPS's level, None for an unknown level
+pub fn level_max_frame_size(sps: &SyntaxNode) -> Option<i32> {
+    let level_idc = if is_level_1b(sps) { 10 } else { sps.get_field("level_idc")? };
+    LEVEL_MAX_FRAME_SIZES.iter().find(|x| x.0 == level_idc).map(|x| x.1)
+}
+
+// MaxMBPS of an SPS's level, None for an unknown level
+pub fn level_max_mb_rate(sps: &SyntaxNode) -> Option<i64> {
+    let level_idc = if is_level_1b(sps) { 10 } else { sps.get_field("level_idc")? };
+    LEVEL_MAX_MB_RATES.iter().find(|x| x.0 == level_idc).map(|x| x.1)
+}
+
+// Profiles whose SPS carries chroma_format_idc, bit depths and scaling matrices
+pub fn is_high_profile(profile_idc: i32) -> bool {
+    [100, 110, 122, 244, 44, 83, 86, 118, 128, 138, 139, 134, 135].contains(&profile_idc)
+}
+
+pub fn profile_name(profile_idc: i32) -> &'static str {
+    match profile_idc {
+        66 => "Baseline",
+        77 => "Main",
+        88 => "Extended",
+        100 => "High",
+        110 => "High 10",
+        122 => "High 4:2:2",
+        244 => "High 4:4:4 Predictive",
+        44 => "CAVLC 4:4:4 Intra",
+        83 => "Scalable Baseline",
+        86 => "Scalable High",
+        118 => "Multiview High",
+        128 => "Stereo High",
+        _ => "unknown",
+    }
+}
+
+// Every set of constraints an SPS signals, with what signals it
+pub fn signaled_constraints(sps: &SyntaxNode) -> Vec<(String, &'static ProfileConstraints)> {
+    let profile_idc = sps.get_field("profile_idc").unwrap_or(0);
+    let flag = |i: i32| sps.get_field(&format!("constraint_set{}_flag", i)).unwrap_or(0) != 0;
+    let mut ret: Vec<(String, &'static ProfileConstraints)> = vec![];
+    let by_profile = format!("profile_idc {}", profile_idc);
+    match profile_idc {
+        66 => ret.push((by_profile, &BASELINE)),
+        77 => ret.push((by_profile, &MAIN)),
+        88 => ret.push((by_profile, &EXTENDED)),
+        100 | 110 | 122 | 244 | 44 => ret.push((by_profile, &HIGH)),
+        _ => (),
+    }
+    for (i, constraints) in [(0, &BASELINE), (1, &MAIN), (2, &EXTENDED)] {
+        if flag(i) {
+            ret.push((format!("constraint_set{}_flag", i), constraints));
+        }
+    }
+    if flag(4) && [77, 88, 100, 110, 122, 244].contains(&profile_idc) {
+        ret.push(("constraint_set4_flag".to_string(), &FRAME_MBS_ONLY));
+    }
+    if flag(5) && [77, 88, 100].contains(&profile_idc) {
+        ret.push(("constraint_set5_flag".to_string(), &NO_B_SLICES));
+    }
+
+    ret
+}
+
+// Chroma format and bit depth limits of the signaled profile
+fn sample_format_violations(sps: &SyntaxNode) -> Vec<String> {
+    let profile_idc = sps.get_field("profile_idc").unwrap_or(0);
+    let chroma_format_idc = sps.get_field("chroma_format_idc").unwrap_or(1);
+    let bit_depth = 8 + sps.get_field("bit_depth_luma_minus8").unwrap_or(0).max(sps.get_field("bit_depth_chroma_minus8").unwrap_or(0));
+    let (max_chroma_format_idc, max_bit_depth) = match profile_idc {
+        100 => (1, 8),
+        110 => (1, 10),
+        122 => (2, 10),
+        244 | 44 => (3, 14),
+        _ => (1, 8),
+    };
+    let mut ret: Vec<String> = vec![];
+    if chroma_format_idc > max_chroma_format_idc {
+        ret.push(format!("chroma_format_idc {} is not allowed by profile_idc {}", chroma_format_idc, profile_idc));
+    }
+    if bit_depth > max_bit_depth {
+        ret.push(format!("{} bit samples are not allowed by profile_idc {}", bit_depth, profile_idc));
+    }
+    if sps.get_field("qpprime_y_zero_transform_bypass_flag").unwrap_or(0) != 0 && profile_idc != 244 {
+        ret.push(format!("qpprime_y_zero_transform_bypass_flag is not allowed by profile_idc {}", profile_idc));
+    }
+
+    ret
+}
+
+// Baseline, Main and Extended only allow 8 bit 4:2:0
+fn is_8_bit_420(sps: &SyntaxNode) -> bool {
+    sps.get_field("chroma_format_idc").unwrap_or(1) == 1 &&
+        sps.get_field("bit_depth_luma_minus8").unwrap_or(0) == 0 &&
+        sps.get_field("bit_depth_chroma_minus8").unwrap_or(0) == 0 &&
+        sps.get_field("qpprime_y_zero_transform_bypass_flag").unwrap_or(0) == 0
+}
+
+// constraint_set0..5_flag as the stream actually allows them. constraint_set3_flag is kept as signaled, since
+// it means level 1b or an intra profile rather than a tool restriction.
+pub fn consistent_constraint_flags(sps: &SyntaxNode, features: &[&'static str]) -> [i32; 6] {
+    let profile_idc = sps.get_field("profile_idc").unwrap_or(0);
+    let satisfies = |constraints: &ProfileConstraints| i32::from(!constraints.forbidden.iter().any(|x| features.contains(x)));
+    let mut ret = [0; 6];
+    ret[3] = sps.get_field("constraint_set3_flag").unwrap_or(0);
+    for (i, constraints) in [(0, &BASELINE), (1, &MAIN), (2, &EXTENDED)] {
+        ret[i] = satisfies(constraints) * i32::from(is_8_bit_420(sps));
+    }
+    // Other profiles reserve constraint_set4_flag and constraint_set5_flag
+    ret[4] = if [77, 88, 100, 110, 122, 244].contains(&profile_idc) { satisfies(&FRAME_MBS_ONLY) } else { 0 };
+    ret[5] = if [77, 88, 100].contains(&profile_idc) { satisfies(&NO_B_SLICES) } else { 0 };
+
+    ret
+}
+
+// Features the stream uses that an SPS's profile_idc or constraint_set flags rule out. Features are collected
+// across the whole stream, so with several SPSs a violation may come from pictures using another one.
+pub fn profile_violations(sps: &SyntaxNode, features: &[&'static str]) -> Vec<String> {
+    let mut ret = sample_format_violations(sps);
+    let signaled = signaled_constraints(sps);
+    for feature in features {
+        let ruled_out_by: Vec<String> = signaled.iter()
+            .filter(|x| x.1.forbidden.contains(feature))
+            .map(|(signaled_by, constraints)| format!("{} ({})", signaled_by, constraints.name))
+            .collect();
+        if !ruled_out_by.is_empty() {
+            ret.push(format!("uses {}, ruled out by {}", feature, ruled_out_by.join(" and ")));
+        }
+    }
+
+    ret
+}
+
+pub fn profile_compliance_report(nalus: &[SyntaxElement]) -> String {
+    let features = stream_features(nalus);
+    let mut ret = format!("features used: {}\n", if features.is_empty() { "none".to_string() } else { features.join(", ") });
+    let mut seen: Vec<String> = vec![];
+    let mut violations = 0;
+    for element in nalus {
+        let SyntaxElement::Node(nalu) = element else {
+            continue;
+        };
+        let Some(sps) = nalu.get_node("sps") else {
+            continue;
+        };
+        // Repeated copies of the same SPS are only checked once
+        let contents = nalu_contents(nalu);
+        if seen.contains(&contents) {
+            continue;
+        }
+        seen.push(contents);
+        let profile_idc = sps.get_field("profile_idc").unwrap_or(0);
+        let flags: Vec<String> = (0..6).filter(|x| sps.get_field(&format!("constraint_set{}_flag", x)).unwrap_or(0) != 0)
+            .map(|x| x.to_string())
+            .collect();
+        ret.push_str(&format!("SPS {}: profile_idc {} ({}), constraint_set flags [{}], level_idc {}\n", sps.get_field("seq_paramter_set_id").unwrap_or(0),
+                              profile_idc, profile_name(profile_idc), flags.join(","), sps.get_field("level_idc").unwrap_or(0)));
+        for violation in profile_violations(sps, &features) {
+            ret.push_str(&format!("Warning: {}\n", violation));
+            violations += 1;
+        }
+    }
+    ret.push_str(&format!("{} profile violations\n", violations));
+
+    ret
+}
+
+// RFC 6381 codecs parameter for an SPS or subset SPS: the sample entry name, then profile_idc, the byte
+// holding constraint_set0..5_flag and reserved_zero_2bits, and level_idc in hex. in_band selects the
+// ISO/IEC 14496-15 sample entry that allows parameter sets in the samples themselves (avc3 rather than avc1).
+pub fn codec_string(sps: &SyntaxNode, subset: bool, in_band: bool) -> String {
+    let profile_idc = sps.get_field("profile_idc").unwrap_or(0);
+    let entry = match (subset && [83, 86].contains(&profile_idc), subset, in_band) {
+        (true, _, false) => "svc1",
+        (true, _, true) => "svc2",
+        (false, true, false) => "mvc1",
+        (false, true, true) => "mvc3",
+        (false, false, false) => "avc1",
+        (false, false, true) => "avc3",
+    };
+    let constraints = (0..6).fold(0, |acc, x| acc | (sps.get_field(&format!("constraint_set{}_flag", x)).unwrap_or(0) << (7 - x)))
+        | sps.get_field("reserved_zero_2bits").unwrap_or(0);
+
+    format!("{}.{:02X}{:02X}{:02X}", entry, profile_idc, constraints, sps.get_field("level_idc").unwrap_or(0))
+}
+
+// Codec strings of every distinct SPS and subset SPS, followed by the combined CODECS value for a manifest
+pub fn codec_string_report(nalus: &[SyntaxElement], in_band: bool) -> String {
+    let mut ret = String::new();
+    let mut codecs: Vec<String> = vec![];
+    for element in nalus {
+        let SyntaxElement::Node(nalu) = element else {
+            continue;
+        };
+        let (sps, subset) = match (nalu.get_node("sps"), nalu.get_node("subset_sps")) {
+            (Some(sps), _) => (sps, false),
+            (None, Some(sps)) => (sps, true),
+            (None, None) => continue,
+        };
+        let codec = codec_string(sps, subset, in_band);
+        let line = format!("{} {}: {} ({}, level_idc {})\n", if subset { "subset SPS" } else { "SPS" },
+                           sps.get_field("seq_paramter_set_id").unwrap_or(0), codec,
+                           profile_name(sps.get_field("profile_idc").unwrap_or(0)), sps.get_field("level_idc").unwrap_or(0));
+        // Repeated parameter sets only show up once
+        if !ret.contains(&line) {
+            ret.push_str(&line);
+        }
+        if !codecs.contains(&codec) {
+            codecs.push(codec);
+        }
+    }
+    if codecs.is_empty() {
+        eprintln!("Warning: no SPS found");
+    }
+    ret.push_str(&format!("CODECS=\"{}\"\n", codecs.join(",")));
+
+    ret
+}
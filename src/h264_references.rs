@@ -0,0 +1,618 @@
+use std::collections::HashMap;
+use std::ops::Range;
+
+use crate::bitstream_util::SyntaxElement;
+use crate::bitstream_util::SyntaxNode;
+use crate::h264_access_units::split_access_units;
+use crate::h264_analysis::ParameterSets;
+use crate::h264_analysis::access_unit_frame_type;
+use crate::h264_parser::get_slice_header;
+use crate::h264_poc::compute_picture_order;
+use crate::h264_poc::has_mmco5;
+use crate::h264_rewrite::nalu_chunks;
+
+// The reordering commands of one list, condensed: "-n"/"+n" move the short-term picture n pic nums away from
+// the prediction to the front, "LTn" a long-term picture, "view-n"/"view+n" an inter-view reference.
+#[derive(Default)]
+struct ListModification {
+    present: bool,
+    operations: Vec<String>,
+    long_term: bool,
+}
+
+fn list_modifications(node: &SyntaxNode) -> [ListModification; 2] {
+    let mut ret: [ListModification; 2] = Default::default();
+    let mut list = 0;
+    let mut idc = 3;
+    for element in &node.children {
+        let SyntaxElement::Field(field) = element else {
+            continue;
+        };
+        match field.name.as_str() {
+            "ref_pic_list_modification_flag_l0" => {
+                list = 0;
+                ret[0].present = field.val != 0;
+            },
+            "ref_pic_list_modification_flag_l1" => {
+                list = 1;
+                ret[1].present = field.val != 0;
+            },
+            "modification_of_pic_nums_idc" => idc = field.val,
+            "abs_diff_pic_num_minus1" => ret[list].operations.push(format!("{}{}", if idc == 0 { "-" } else { "+" }, field.val + 1)),
+            "long_term_pic_num" => {
+                ret[list].operations.push(format!("LT{}", field.val));
+                ret[list].long_term = true;
+            },
+            "abs_diff_view_idx_minus1" => ret[list].operations.push(format!("view{}{}", if idc == 4 { "-" } else { "+" }, field.val + 1)),
+            _ => (),
+        }
+    }
+
+    ret
+}
+
+fn num_ref_idx_active(header: &SyntaxNode, pps: Option<&SyntaxNode>, list: usize) -> Option<i32> {
+    let override_name = format!("num_ref_idx_l{}_active_minus1", list);
+    let default_name = format!("num_ref_idx_l{}_default_active_minus1", list);
+    Some(header.get_field(&override_name).or_else(|| pps?.get_field(&default_name))? + 1)
+}
+
+// One row per P/B slice that reorders a reference list, then warnings about patterns hardware decoders are known
+// to mishandle: modifications on every slice, long-term references, and more commands than active references.
+pub fn ref_pic_list_modification_report(nalus: &[SyntaxElement]) -> String {
+    let mut parameter_sets = ParameterSets::new();
+    let mut ret = "nalu frame_num slice_type l0 l1\n".to_string();
+    let mut warnings: Vec<String> = vec![];
+    let (mut inter_slices, mut modified_slices, mut long_term_slices) = (0, 0, 0);
+    for (idx, element) in nalus.iter().enumerate() {
+        let SyntaxElement::Node(nalu) = element else {
+            continue;
+        };
+        parameter_sets.update(nalu);
+        let Some(header) = get_slice_header(nalu) else {
+            continue;
+        };
+        let slice_type = header.get_field("slice_type").unwrap_or(2) % 5;
+        if slice_type == 2 || slice_type == 4 {
+            continue;
+        }
+        inter_slices += 1;
+        let Some(modification) = header.get_node("ref_pic_list_modification").or_else(|| header.get_node("ref_pic_list_mvc_modification")) else {
+            continue;
+        };
+        let lists = list_modifications(modification);
+        if !lists[0].present && !lists[1].present {
+            continue;
+        }
+        modified_slices += 1;
+        long_term_slices += usize::from(lists[0].long_term || lists[1].long_term);
+        let describe = |list: &ListModification| if list.present { list.operations.join(",") } else { "-".to_string() };
+        ret.push_str(&format!("{} {} {} {} {}\n", idx, header.get_field("frame_num").unwrap_or(0),
+                              if slice_type == 1 { 'B' } else { 'P' }, describe(&lists[0]), describe(&lists[1])));
+        let pps = parameter_sets.for_slice(header).map(|x| x.1);
+        for (list, modification) in lists.iter().enumerate() {
+            if modification.present && modification.operations.is_empty() {
+                warnings.push(format!("NALU {} sets ref_pic_list_modification_flag_l{} without any commands", idx, list));
+            }
+            if let Some(active) = num_ref_idx_active(header, pps, list) {
+                if modification.operations.len() as i32 > active {
+                    warnings.push(format!("NALU {} has {} commands for the {} active references of list {}",
+                                          idx, modification.operations.len(), active, list));
+                }
+            }
+        }
+    }
+
+    ret.push_str(&format!("{} of {} P/B slices modify a reference list\n", modified_slices, inter_slices));
+    if modified_slices > 1 && modified_slices == inter_slices {
+        ret.push_str("Warning: every P/B slice modifies its reference lists\n");
+    }
+    if long_term_slices > 0 {
+        ret.push_str(&format!("Warning: {} slices move long-term references in their lists\n", long_term_slices));
+    }
+    for warning in warnings {
+        ret.push_str(&format!("Warning: {}\n", warning));
+    }
+
+    ret
+}
+
+// Frame number a short-term picNum refers to. Only frames map back directly, field picNums are shown as is.
+fn pic_num_to_frame_num(pic_num: i32, max_frame_num: Option<i32>) -> String {
+    match max_frame_num {
+        Some(max_frame_num) => format!("frame_num {}", pic_num.rem_euclid(max_frame_num)),
+        None => format!("picNum {}", pic_num),
+    }
+}
+
+// What the dec_ref_pic_marking of a reference picture does to the DPB, one entry per operation
+fn marking_events(marking: &SyntaxNode, curr_pic_num: i32, max_frame_num: Option<i32>) -> Vec<String> {
+    let mut ret: Vec<String> = vec![];
+    if marking.get_field("long_term_reference_flag").is_some() {
+        ret.push("IDR, all references dropped".to_string());
+        if marking.get_field("no_output_of_prior_pics_flag").unwrap_or(0) != 0 {
+            ret.push("prior pictures discarded without output".to_string());
+        }
+        if marking.get_field("long_term_reference_flag").unwrap_or(0) != 0 {
+            ret.push("current marked long-term idx 0".to_string());
+        }
+        return ret;
+    }
+    if marking.get_field("adaptive_ref_pic_marking_mode_flag").unwrap_or(0) == 0 {
+        return ret;
+    }
+    let mut operation = 0;
+    let mut pic_num = 0;
+    for element in &marking.children {
+        let SyntaxElement::Field(field) = element else {
+            continue;
+        };
+        match field.name.as_str() {
+            "memory_management_control_operation" => {
+                operation = field.val;
+                if operation == 5 {
+                    ret.push("MMCO 5: all references dropped, frame_num restarts".to_string());
+                }
+            },
+            "difference_of_pic_nums_minus1" => {
+                pic_num = curr_pic_num - (field.val + 1);
+                if operation == 1 {
+                    ret.push(format!("MMCO 1: unmark short-term {}", pic_num_to_frame_num(pic_num, max_frame_num)));
+                }
+            },
+            "long_term_pic_num" => ret.push(format!("MMCO 2: unmark long-term picNum {}", field.val)),
+            "long_term_frame_idx" if operation == 3 => {
+                ret.push(format!("MMCO 3: short-term {} to long-term idx {}", pic_num_to_frame_num(pic_num, max_frame_num), field.val));
+            },
+            "long_term_frame_idx" => ret.push(format!("MMCO 6: current marked long-term idx {}", field.val)),
+            "max_long_term_frame_idx_plus1" if field.val == 0 => ret.push("MMCO 4: all long-term references dropped".to_string()),
+            "max_long_term_frame_idx_plus1" => ret.push(format!("MMCO 4: long-term idx above {} dropped", field.val - 1)),
+            _ => (),
+        }
+    }
+
+    ret
+}
+
+// Every reference picture that changes the DPB beyond the sliding window, in decode order, so corruption can
+// be lined up with the marking that caused it.
+pub fn mmco_timeline(nalus: &[SyntaxElement]) -> String {
+    let mut parameter_sets = ParameterSets::new();
+    let mut ret = "picture nalu frame_num events\n".to_string();
+    let (mut resets, mut long_term_marks) = (0, 0);
+    for (picture, access_unit) in split_access_units(nalus).into_iter().enumerate() {
+        let mut first_slice: Option<(usize, &SyntaxNode)> = None;
+        for idx in access_unit {
+            let SyntaxElement::Node(nalu) = &nalus[idx] else {
+                continue;
+            };
+            parameter_sets.update(nalu);
+            if first_slice.is_none() && get_slice_header(nalu).is_some() {
+                first_slice = Some((idx, nalu));
+            }
+        }
+        let Some((idx, nalu)) = first_slice else {
+            continue;
+        };
+        let header = get_slice_header(nalu).unwrap();
+        let Some(marking) = header.get_node("dec_ref_pic_marking") else {
+            continue;
+        };
+        let frame_num = header.get_field("frame_num").unwrap_or(0);
+        let field_pic = header.get_field("field_pic_flag").unwrap_or(0) != 0;
+        let max_frame_num = parameter_sets.for_slice(header)
+            .and_then(|(sps, _)| sps.get_field("log2_max_frame_num_minus4"))
+            .filter(|_| !field_pic)
+            .map(|x| 1 << (x + 4));
+        let curr_pic_num = if field_pic { 2 * frame_num + 1 } else { frame_num };
+        let events = marking_events(marking, curr_pic_num, max_frame_num);
+        if events.is_empty() {
+            continue;
+        }
+        resets += events.iter().filter(|x| x.contains("all references dropped")).count();
+        long_term_marks += events.iter().filter(|x| x.contains("to long-term") || x.contains("marked long-term")).count();
+        ret.push_str(&format!("{} {} {} {}\n", picture, idx, frame_num, events.join("; ")));
+    }
+    ret.push_str(&format!("{} DPB resets, {} pictures marked long-term\n", resets, long_term_marks));
+
+    ret
+}
+
+// A frame marked as used for reference by 8.2.5. Marking is tracked per frame, so an operation on either field
+// of a frame applies to both.
+struct RefFrame {
+    frame_num: i32,
+    // None while the frame is a short-term reference
+    long_term_frame_idx: Option<i32>,
+    pic_order_cnt: i32,
+    // Inferred for a gap in frame_num (8.2.5.2) rather than decoded
+    non_existing: bool,
+}
+
+// Short-term frames by frame_num, in parentheses when they're non-existing, long-term ones by index
+fn describe_ref_frame(frame: &RefFrame) -> String {
+    match frame.long_term_frame_idx {
+        Some(idx) => format!("LT{}", idx),
+        None if frame.non_existing => format!("({})", frame.frame_num),
+        None => frame.frame_num.to_string(),
+    }
+}
+
+fn describe_ref_frames<'a>(frames: impl Iterator<Item = &'a RefFrame>) -> String {
+    let frames: Vec<String> = frames.map(describe_ref_frame).collect();
+    if frames.is_empty() { "-".to_string() } else { frames.join(",") }
+}
+
+struct Dpb {
+    frames: Vec<RefFrame>,
+    max_num_ref_frames: i32,
+    max_frame_num: i32,
+    // MaxLongTermFrameIdx, None for "no long-term frame indices"
+    max_long_term_frame_idx: Option<i32>,
+}
+
+impl Dpb {
+    // FrameNumWrap (8-27)
+    fn frame_num_wrap(&self, frame_num: i32, curr_frame_num: i32) -> i32 {
+        if frame_num > curr_frame_num { frame_num - self.max_frame_num } else { frame_num }
+    }
+
+    // 8.2.5.3: with the DPB full, the short-term frame with the smallest FrameNumWrap makes room
+    fn sliding_window(&mut self, curr_frame_num: i32, events: &mut Vec<String>) {
+        if (self.frames.len() as i32) < self.max_num_ref_frames.max(1) {
+            return;
+        }
+        let oldest = self.frames.iter()
+            .enumerate()
+            .filter(|(_, x)| x.long_term_frame_idx.is_none())
+            .min_by_key(|(_, x)| self.frame_num_wrap(x.frame_num, curr_frame_num))
+            .map(|(idx, _)| idx);
+        if let Some(idx) = oldest {
+            events.push(format!("sliding window drops {}", describe_ref_frame(&self.frames.remove(idx))));
+        }
+    }
+
+    fn short_term_position(&self, frame_num_wrap: i32, curr_frame_num: i32) -> Option<usize> {
+        self.frames.iter().position(|x| x.long_term_frame_idx.is_none() && self.frame_num_wrap(x.frame_num, curr_frame_num) == frame_num_wrap)
+    }
+
+    // Free a long-term index for another frame, as MMCO 3 and 6 do
+    fn release_long_term_frame_idx(&mut self, idx: i32, events: &mut Vec<String>) {
+        if let Some(position) = self.frames.iter().position(|x| x.long_term_frame_idx == Some(idx)) {
+            self.frames.remove(position);
+            events.push(format!("LT{} replaced", idx));
+        }
+    }
+
+    fn check_long_term_frame_idx(&self, idx: i32, warnings: &mut Vec<String>) {
+        if self.max_long_term_frame_idx.is_none_or(|x| idx > x) {
+            warnings.push(format!("long-term index {} is above MaxLongTermFrameIdx", idx));
+        }
+    }
+
+    // 8.2.5.4. Returns the long-term index MMCO 6 gives the current picture, if any.
+    fn adaptive_marking(&mut self, marking: &SyntaxNode, curr_frame_num: i32, field_pic: bool, events: &mut Vec<String>,
+                        warnings: &mut Vec<String>) -> Option<i32> {
+        let curr_pic_num = if field_pic { 2 * curr_frame_num + 1 } else { curr_frame_num };
+        // Field picNums and LongTermPicNums count both parities of a frame (8-28 to 8-31)
+        let to_frame = |pic_num: i32| if field_pic { pic_num.div_euclid(2) } else { pic_num };
+        let mut current_long_term: Option<i32> = None;
+        let mut operation = 0;
+        let mut short_term: Option<usize> = None;
+        for element in &marking.children {
+            let SyntaxElement::Field(field) = element else {
+                continue;
+            };
+            match field.name.as_str() {
+                "memory_management_control_operation" => {
+                    operation = field.val;
+                    if operation == 5 {
+                        self.frames.clear();
+                        self.max_long_term_frame_idx = None;
+                        events.push("MMCO 5 drops every reference".to_string());
+                    }
+                },
+                "difference_of_pic_nums_minus1" => {
+                    let frame_num_wrap = to_frame(curr_pic_num - (field.val + 1));
+                    short_term = self.short_term_position(frame_num_wrap, curr_frame_num);
+                    match short_term {
+                        None => warnings.push(format!("MMCO {} names frame_num {}, which isn't a short-term reference", operation,
+                                                      frame_num_wrap.rem_euclid(self.max_frame_num))),
+                        Some(position) if operation == 1 => {
+                            events.push(format!("MMCO 1 drops {}", describe_ref_frame(&self.frames.remove(position))));
+                        },
+                        _ => (),
+                    }
+                },
+                "long_term_pic_num" => {
+                    let idx = to_frame(field.val);
+                    match self.frames.iter().position(|x| x.long_term_frame_idx == Some(idx)) {
+                        Some(position) => events.push(format!("MMCO 2 drops {}", describe_ref_frame(&self.frames.remove(position)))),
+                        None => warnings.push(format!("MMCO 2 names LT{}, which isn't a long-term reference", idx)),
+                    }
+                },
+                "long_term_frame_idx" if operation == 3 => {
+                    self.check_long_term_frame_idx(field.val, warnings);
+                    if let Some(position) = short_term {
+                        let frame_num = self.frames[position].frame_num;
+                        self.release_long_term_frame_idx(field.val, events);
+                        // The release may have moved the frame
+                        let position = self.frames.iter().position(|x| x.long_term_frame_idx.is_none() && x.frame_num == frame_num).unwrap();
+                        self.frames[position].long_term_frame_idx = Some(field.val);
+                        events.push(format!("MMCO 3 turns {} into LT{}", frame_num, field.val));
+                    }
+                },
+                "long_term_frame_idx" => {
+                    self.check_long_term_frame_idx(field.val, warnings);
+                    self.release_long_term_frame_idx(field.val, events);
+                    current_long_term = Some(field.val);
+                    events.push(format!("MMCO 6 marks the current picture LT{}", field.val));
+                },
+                "max_long_term_frame_idx_plus1" => {
+                    self.max_long_term_frame_idx = if field.val == 0 { None } else { Some(field.val - 1) };
+                    let max = self.max_long_term_frame_idx;
+                    let before = self.frames.len();
+                    self.frames.retain(|x| x.long_term_frame_idx.is_none_or(|idx| max.is_some_and(|max| idx <= max)));
+                    events.push(format!("MMCO 4 drops {} long-term references", before - self.frames.len()));
+                },
+                _ => (),
+            }
+        }
+
+        current_long_term
+    }
+}
+
+// Initial RefPicList0 and RefPicList1 of a frame (8.2.4.2.1, 8.2.4.2.3), before ref_pic_list_modification: for
+// P slices short-term frames by descending FrameNumWrap, for B slices by POC distance on either side of the
+// current frame. Long-term frames follow by index. Non-existing frames have no POC to place them by in B lists.
+fn initial_ref_pic_lists(dpb: &Dpb, curr_frame_num: i32, curr_pic_order_cnt: i32, b_slice: bool) -> [Vec<&RefFrame>; 2] {
+    let mut long_term: Vec<&RefFrame> = dpb.frames.iter().filter(|x| x.long_term_frame_idx.is_some()).collect();
+    long_term.sort_by_key(|x| x.long_term_frame_idx);
+    let mut short_term: Vec<&RefFrame> = dpb.frames.iter().filter(|x| x.long_term_frame_idx.is_none()).collect();
+    if !b_slice {
+        short_term.sort_by_key(|x| -dpb.frame_num_wrap(x.frame_num, curr_frame_num));
+        short_term.extend(long_term);
+        return [short_term, vec![]];
+    }
+    short_term.retain(|x| !x.non_existing);
+    let mut before: Vec<&RefFrame> = short_term.iter().copied().filter(|x| x.pic_order_cnt < curr_pic_order_cnt).collect();
+    before.sort_by_key(|x| -x.pic_order_cnt);
+    let mut after: Vec<&RefFrame> = short_term.iter().copied().filter(|x| x.pic_order_cnt > curr_pic_order_cnt).collect();
+    after.sort_by_key(|x| x.pic_order_cnt);
+    let l0: Vec<&RefFrame> = before.iter().chain(after.iter()).chain(long_term.iter()).copied().collect();
+    let mut l1: Vec<&RefFrame> = after.iter().chain(before.iter()).chain(long_term.iter()).copied().collect();
+    // A RefPicList1 identical to RefPicList0 has its first two entries swapped
+    if l1.len() > 1 && l1.iter().zip(l0.iter()).all(|(x, y)| std::ptr::eq(*x, *y)) {
+        l1.swap(0, 1);
+    }
+    [l0, l1]
+}
+
+// Simulate reference marking (8.2.5) over the stream in decode order. Every picture is listed with the initial
+// reference lists of a frame, the marking it does, and the reference frames it leaves in the DPB, followed by
+// warnings about marking that can't be carried out as coded or overfills the DPB.
+pub fn dpb_report(nalus: &[SyntaxElement]) -> String {
+    let pic_order_cnts: HashMap<usize, i32> = compute_picture_order(nalus).iter().map(|x| (x.access_unit, x.pic_order_cnt)).collect();
+    let mut parameter_sets = ParameterSets::new();
+    let mut dpb = Dpb { frames: vec![], max_num_ref_frames: 1, max_frame_num: 16, max_long_term_frame_idx: None };
+    let mut ret = "picture nalu type frame_num POC lists DPB events\n".to_string();
+    let mut warnings: Vec<String> = vec![];
+    let mut prev_ref_frame_num = 0;
+    // frame_num and bottom_field_flag of the last picture while it's a reference field waiting for its pair
+    let mut first_field: Option<(i32, bool)> = None;
+    let mut max_occupancy = 0;
+    for (picture, access_unit) in split_access_units(nalus).into_iter().enumerate() {
+        let frame_type = access_unit_frame_type(&nalus[access_unit.clone()]);
+        let mut first_slice: Option<(usize, &SyntaxNode)> = None;
+        for idx in access_unit {
+            let SyntaxElement::Node(nalu) = &nalus[idx] else {
+                continue;
+            };
+            parameter_sets.update(nalu);
+            if first_slice.is_none() && get_slice_header(nalu).is_some() {
+                first_slice = Some((idx, nalu));
+            }
+        }
+        let (Some((idx, nalu)), Some(frame_type)) = (first_slice, frame_type) else {
+            continue;
+        };
+        let header = get_slice_header(nalu).unwrap();
+        let Some((sps, pps)) = parameter_sets.for_slice(header) else {
+            warnings.push(format!("picture {} refers to a missing parameter set, skipping it", picture));
+            continue;
+        };
+        dpb.max_num_ref_frames = sps.get_field("max_num_ref_frames").unwrap_or(1);
+        dpb.max_frame_num = 1 << (sps.get_field("log2_max_frame_num_minus4").unwrap_or(0) + 4);
+        let idr = nalu.get_field("nal_unit_type") == Some(5);
+        let reference = nalu.get_field("nal_ref_idc").unwrap_or(0) != 0;
+        let frame_num = header.get_field("frame_num").unwrap_or(0);
+        let field_pic = header.get_field("field_pic_flag").unwrap_or(0) != 0;
+        let bottom_field = header.get_field("bottom_field_flag").unwrap_or(0) != 0;
+        let pic_order_cnt = pic_order_cnts.get(&picture).copied().unwrap_or(0);
+        let mut events: Vec<String> = vec![];
+
+        if idr {
+            prev_ref_frame_num = 0;
+        } else if frame_num != prev_ref_frame_num && frame_num != (prev_ref_frame_num + 1) % dpb.max_frame_num {
+            // 8.2.5.2: the missing frame_nums become non-existing short-term frames
+            if sps.get_field("gaps_in_frame_num_value_allowed_flag").unwrap_or(0) != 0 {
+                let mut unused_frame_num = (prev_ref_frame_num + 1) % dpb.max_frame_num;
+                while unused_frame_num != frame_num {
+                    dpb.sliding_window(unused_frame_num, &mut events);
+                    dpb.frames.push(RefFrame { frame_num: unused_frame_num, long_term_frame_idx: None, pic_order_cnt: 0, non_existing: true });
+                    unused_frame_num = (unused_frame_num + 1) % dpb.max_frame_num;
+                }
+                events.push(format!("frame_num gap after {} filled with non-existing frames", prev_ref_frame_num));
+            } else {
+                warnings.push(format!("picture {} jumps from frame_num {} to {} without gaps_in_frame_num_value_allowed_flag",
+                                      picture, prev_ref_frame_num, frame_num));
+            }
+            first_field = None;
+        }
+
+        let lists = if field_pic || frame_type == 'I' {
+            "-".to_string()
+        } else {
+            let lists = initial_ref_pic_lists(&dpb, frame_num, pic_order_cnt, frame_type == 'B');
+            let mut describe: Vec<String> = vec![];
+            for (list, frames) in lists.iter().enumerate().take(if frame_type == 'B' { 2 } else { 1 }) {
+                let active = num_ref_idx_active(header, Some(pps), list).unwrap_or(1) as usize;
+                describe.push(format!("L{}={}", list, describe_ref_frames(frames.iter().take(active).copied())));
+                if frames.len() < active {
+                    events.push(format!("L{} holds {} of {} active references", list, frames.len(), active));
+                }
+            }
+            describe.join(" ")
+        };
+
+        let second_field = field_pic && first_field.is_some_and(|(x, y)| x == frame_num && y != bottom_field);
+        let marking = header.get_node("dec_ref_pic_marking");
+        let mmco5 = marking.is_some_and(|x| x.children.iter().any(|y| matches!(y, SyntaxElement::Field(field)
+            if field.name == "memory_management_control_operation" && field.val == 5)));
+        if idr {
+            if !dpb.frames.is_empty() {
+                events.push("IDR drops every reference".to_string());
+            }
+            dpb.frames.clear();
+            let long_term = marking.and_then(|x| x.get_field("long_term_reference_flag")).unwrap_or(0) != 0;
+            dpb.max_long_term_frame_idx = if long_term { Some(0) } else { None };
+            dpb.frames.push(RefFrame { frame_num, long_term_frame_idx: if long_term { Some(0) } else { None }, pic_order_cnt, non_existing: false });
+        } else if reference {
+            let long_term_frame_idx = match marking {
+                Some(marking) if marking.get_field("adaptive_ref_pic_marking_mode_flag").unwrap_or(0) != 0 => {
+                    let mut picture_warnings: Vec<String> = vec![];
+                    let ret = dpb.adaptive_marking(marking, frame_num, field_pic, &mut events, &mut picture_warnings);
+                    warnings.extend(picture_warnings.into_iter().map(|x| format!("picture {}: {}", picture, x)));
+                    ret
+                },
+                // The second field of a reference frame joins its first field (8.2.5.3)
+                _ if second_field => None,
+                _ => {
+                    dpb.sliding_window(frame_num, &mut events);
+                    None
+                },
+            };
+            // After MMCO 5 the picture counts as frame_num 0 (8.2.1)
+            let stored_frame_num = if mmco5 { 0 } else { frame_num };
+            let stored = if second_field && !mmco5 {
+                dpb.frames.iter().position(|x| x.long_term_frame_idx.is_none() && x.frame_num == frame_num && !x.non_existing)
+            } else {
+                None
+            };
+            match stored {
+                Some(position) => dpb.frames[position].long_term_frame_idx = long_term_frame_idx,
+                None => dpb.frames.push(RefFrame { frame_num: stored_frame_num, long_term_frame_idx, pic_order_cnt, non_existing: false }),
+            }
+            if dpb.frames.len() as i32 > dpb.max_num_ref_frames.max(1) {
+                warnings.push(format!("picture {} leaves {} reference frames, more than max_num_ref_frames {}", picture, dpb.frames.len(),
+                                      dpb.max_num_ref_frames));
+            }
+        }
+        if reference {
+            prev_ref_frame_num = if mmco5 { 0 } else { frame_num };
+        }
+        first_field = if reference && field_pic && !second_field { Some((frame_num, bottom_field)) } else { None };
+        max_occupancy = max_occupancy.max(dpb.frames.len());
+
+        let frame_type = if reference { frame_type } else { frame_type.to_ascii_lowercase() };
+        ret.push_str(&format!("{} {} {} {} {} {} DPB={} ({}/{})", picture, idx, frame_type, frame_num, pic_order_cnt, lists,
+                              describe_ref_frames(dpb.frames.iter()), dpb.frames.len(), dpb.max_num_ref_frames));
+        if !events.is_empty() {
+            ret.push_str(&format!(" {}", events.join("; ")));
+        }
+        ret.push('\n');
+    }
+    ret.push_str(&format!("At most {} reference frames held\n", max_occupancy));
+    for warning in warnings {
+        ret.push_str(&format!("Warning: {}\n", warning));
+    }
+
+    ret
+}
+
+// Check frame_num continuity (7.4.3): IDR pictures have frame_num 0, and every other picture has PrevRefFrameNum
+// + 1 modulo MaxFrameNum, or PrevRefFrameNum when it's the second field of a reference field pair. Anything else
+// is a gap, which is only allowed with gaps_in_frame_num_value_allowed_flag. Problems come with the byte
+// offset of the slice's start code. nalus must be the parse of bitstream.
+pub fn frame_num_report(bitstream: &[u8], nalus: &[SyntaxElement]) -> String {
+    let chunks: Vec<Range<usize>> = nalu_chunks(bitstream).into_iter()
+        .filter(|x| x.0.is_some())
+        .map(|x| x.1)
+        .collect();
+    let mut parameter_sets = ParameterSets::new();
+    let mut ret = String::new();
+    let mut prev_ref_frame_num = 0;
+    // Access unit and bottom_field_flag of the last reference picture, when it was a field
+    let mut prev_ref_field: Option<(usize, bool)> = None;
+    let (mut pictures, mut gaps, mut wraparounds, mut warnings) = (0, 0, 0, 0);
+    for (access_unit_idx, access_unit) in split_access_units(nalus).into_iter().enumerate() {
+        let mut first_slice: Option<(usize, &SyntaxNode)> = None;
+        for idx in access_unit {
+            let SyntaxElement::Node(nalu) = &nalus[idx] else {
+                continue;
+            };
+            parameter_sets.update(nalu);
+            if first_slice.is_none() && get_slice_header(nalu).is_some() {
+                first_slice = Some((idx, nalu));
+            }
+        }
+        let Some((idx, nalu)) = first_slice else {
+            continue;
+        };
+        let header = get_slice_header(nalu).unwrap();
+        let offset = chunks[idx].start;
+        let Some((sps, _)) = parameter_sets.for_slice(header) else {
+            ret.push_str(&format!("Warning: access unit {} at byte {} refers to a missing parameter set, skipping it\n", access_unit_idx, offset));
+            warnings += 1;
+            continue;
+        };
+        pictures += 1;
+        let max_frame_num = 1 << (sps.get_field("log2_max_frame_num_minus4").unwrap_or(0) + 4);
+        let idr = nalu.get_field("nal_unit_type") == Some(5);
+        let reference = nalu.get_field("nal_ref_idc").unwrap_or(0) != 0;
+        let frame_num = header.get_field("frame_num").unwrap_or(0);
+        let field = (header.get_field("field_pic_flag").unwrap_or(0) != 0).then(|| header.get_field("bottom_field_flag").unwrap_or(0) != 0);
+
+        if idr {
+            if frame_num != 0 {
+                ret.push_str(&format!("Warning: access unit {} at byte {} is an IDR picture with frame_num {}\n", access_unit_idx, offset, frame_num));
+                warnings += 1;
+            }
+            prev_ref_frame_num = 0;
+        } else if frame_num == prev_ref_frame_num {
+            let second_field = reference && field.is_some() &&
+                prev_ref_field.is_some_and(|(prev_access_unit, prev_bottom)| prev_access_unit + 1 == access_unit_idx && Some(!prev_bottom) == field);
+            if !second_field {
+                ret.push_str(&format!("Warning: access unit {} at byte {} repeats frame_num {} of the previous reference picture\n",
+                                      access_unit_idx, offset, frame_num));
+                warnings += 1;
+            }
+        } else if frame_num != (prev_ref_frame_num + 1) % max_frame_num {
+            let missing = (frame_num - prev_ref_frame_num - 1).rem_euclid(max_frame_num);
+            let wraps = if frame_num < prev_ref_frame_num { ", wrapping around MaxFrameNum" } else { "" };
+            gaps += 1;
+            if sps.get_field("gaps_in_frame_num_value_allowed_flag").unwrap_or(0) != 0 {
+                ret.push_str(&format!("access unit {} at byte {}: frame_num jumps from {} to {}{}, {} non-existing frames inferred\n",
+                                      access_unit_idx, offset, prev_ref_frame_num, frame_num, wraps, missing));
+            } else {
+                ret.push_str(&format!("Warning: access unit {} at byte {}: frame_num jumps from {} to {}{}, skipping {} values without gaps_in_frame_num_value_allowed_flag\n",
+                                      access_unit_idx, offset, prev_ref_frame_num, frame_num, wraps, missing));
+                warnings += 1;
+            }
+        } else if frame_num == 0 {
+            wraparounds += 1;
+        }
+
+        if reference {
+            prev_ref_frame_num = if has_mmco5(header) { 0 } else { frame_num };
+            prev_ref_field = field.map(|x| (access_unit_idx, x));
+        } else if frame_num != prev_ref_frame_num && !idr {
+            // With gaps, PrevRefFrameNum becomes the last inferred frame_num (8.2.5.2)
+            prev_ref_frame_num = (frame_num - 1).rem_euclid(max_frame_num);
+        }
+    }
+    ret.push_str(&format!("pictures: {}\ngaps: {}\nwraparounds: {}\nwarnings: {}\n", pictures, gaps, wraparounds, warnings));
+
+    ret
+}
@@ -0,0 +1,1283 @@
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::collections::VecDeque;
+use std::ops::Range;
+
+use crate::bitstream_util::SyntaxElement;
+use crate::bitstream_util::SyntaxField;
+use crate::bitstream_util::SyntaxNode;
+use crate::bitstream_util::TraceEntry;
+use crate::bitstream_util::ebsp_len;
+use crate::bitstream_util::rbsp_to_ebsp;
+use crate::h264_access_units::is_vcl_nalu;
+use crate::h264_access_units::split_access_units;
+use crate::h264_analysis::is_arbitrary_slice_order;
+use crate::h264_analysis::is_idr_access_unit;
+use crate::h264_analysis::parameter_set_key;
+use crate::h264_analysis::slice_starts;
+use crate::h264_corpus::stream_features;
+use crate::h264_parser::SLICE_HEADER_NODES;
+use crate::h264_parser::SerializeOptions;
+use crate::h264_parser::SliceDataStart;
+use crate::h264_parser::get_slice_header;
+use crate::h264_parser::nal_unit_header_bytes;
+use crate::h264_parser::nalu_contents;
+use crate::h264_parser::nalu_to_rbsp;
+use crate::h264_parser::pack_slice_payload;
+use crate::h264_parser::parse_h264;
+use crate::h264_parser::parse_h264_deep;
+use crate::h264_parser::parse_h264_nalu;
+use crate::h264_parser::parse_h264_slice_data_starts;
+use crate::h264_parser::serialize_changed_h264_nalus;
+use crate::h264_parser::serialize_h264_nalus;
+use crate::h264_parser::serialize_h264_nalus_separately;
+use crate::h264_parser::tokenize_h264_nalus;
+use crate::h264_parser::trace_h264;
+use crate::h264_parser::unpack_slice_payload;
+use crate::h264_poc::compute_picture_order;
+use crate::h264_profiles::consistent_constraint_flags;
+use crate::h264_profiles::is_high_profile;
+use crate::h264_profiles::profile_violations;
+use crate::nal_header::NalHeaderFormat;
+use crate::nal_header::peek_nal_unit_type;
+
+fn nal_unit_type(element: &SyntaxElement) -> Option<i32> {
+    match element {
+        SyntaxElement::Node(nalu) => nalu.get_field("nal_unit_type"),
+        _ => None,
+    }
+}
+
+// Smallest primary_pic_type (Table 7-5) whose allowed slice types cover every slice in the access unit
+fn primary_pic_type(access_unit: &[SyntaxElement]) -> i32 {
+    // Bit positions follow slice_type % 5: P, B, I, SP, SI
+    let mut used_slice_types = 0;
+    for element in access_unit {
+        let SyntaxElement::Node(nalu) = element else {
+            continue;
+        };
+        if let Some(slice_type) = get_slice_header(nalu).and_then(|x| x.get_field("slice_type")) {
+            used_slice_types |= 1 << (slice_type % 5);
+        }
+    }
+    let allowed_slice_types = [0b00100, 0b00101, 0b00111, 0b10000, 0b11000, 0b10100, 0b11101, 0b11111];
+    allowed_slice_types.iter()
+        .position(|x| used_slice_types & !x == 0)
+        .unwrap() as i32
+}
+
+fn new_aud(primary_pic_type: i32) -> SyntaxElement {
+    let mut nalu = SyntaxNode::new("nalu");
+    nalu.push_field("forbidden_zero_bit", 0);
+    nalu.push_field("nal_ref_idc", 0);
+    nalu.push_field("nal_unit_type", 9);
+    let mut aud = SyntaxNode::new("access_unit_delimiter");
+    aud.push_field("primary_pic_type", primary_pic_type);
+    // The rbsp stop bit and alignment in the 5 bits after primary_pic_type
+    aud.push_payload("trailing_bits", vec![0x10]);
+    nalu.children.push_back(SyntaxElement::Node(aud));
+    SyntaxElement::Node(nalu)
+}
+
+pub fn insert_auds(nalus: Vec<SyntaxElement>) -> Vec<SyntaxElement> {
+    let access_units = split_access_units(&nalus);
+    let mut ret: Vec<SyntaxElement> = vec![];
+    let mut nalus = nalus.into_iter();
+    for access_unit in access_units {
+        let access_unit: Vec<SyntaxElement> = nalus.by_ref().take(access_unit.len()).collect();
+        if access_unit.first().and_then(nal_unit_type) != Some(9) {
+            ret.push(new_aud(primary_pic_type(&access_unit)));
+        }
+        ret.extend(access_unit);
+    }
+
+    ret
+}
+
+pub fn strip_auds(nalus: Vec<SyntaxElement>) -> Vec<SyntaxElement> {
+    nalus.into_iter()
+        .filter(|x| nal_unit_type(x) != Some(9))
+        .collect()
+}
+
+// Sort the slices of every picture into raster order by first_mb_in_slice, for decoders that can't handle
+// arbitrary slice order. Each slice moves together with the data partitions and redundant slices that follow
+// it, and NALUs in between slices stay where they are.
+pub fn reorder_slices(nalus: Vec<SyntaxElement>) -> Vec<SyntaxElement> {
+    let access_units = split_access_units(&nalus);
+    let mut ret: Vec<SyntaxElement> = vec![];
+    let mut nalus = nalus.into_iter();
+    for access_unit in access_units {
+        let access_unit: Vec<SyntaxElement> = nalus.by_ref().take(access_unit.len()).collect();
+        let starts = slice_starts(&access_unit);
+        if !is_arbitrary_slice_order(&starts) {
+            ret.extend(access_unit);
+            continue;
+        }
+        let mut access_unit: Vec<Option<SyntaxElement>> = access_unit.into_iter().map(Some).collect();
+        // Each primary slice owns the VCL NALUs up to the next primary slice or non-VCL NALU
+        let mut groups: Vec<(i32, Vec<SyntaxElement>)> = vec![];
+        for (idx, (offset, first_mb)) in starts.iter().enumerate() {
+            let end = starts.get(idx + 1).map(|x| x.0).unwrap_or(access_unit.len());
+            let mut group: Vec<SyntaxElement> = vec![access_unit[*offset].take().unwrap()];
+            for element in &mut access_unit[(offset + 1)..end] {
+                if !element.as_ref().and_then(nal_unit_type).is_some_and(is_vcl_nalu) {
+                    break;
+                }
+                group.push(element.take().unwrap());
+            }
+            groups.push((*first_mb, group));
+        }
+        groups.sort_by_key(|x| x.0);
+        // Refill the slots the slices came from, in the new order
+        let mut groups = groups.into_iter().flat_map(|x| x.1);
+        let slots: Vec<usize> = (0..access_unit.len()).filter(|x| access_unit[*x].is_none()).collect();
+        for slot in slots {
+            access_unit[slot] = groups.next();
+        }
+        ret.extend(access_unit.into_iter().flatten());
+    }
+
+    ret
+}
+
+// Fields only High profile SPSs carry, with the values Baseline, Main and Extended imply
+const HIGH_PROFILE_SPS_DEFAULTS: [(&str, i32); 5] = [("chroma_format_idc", 1), ("bit_depth_luma_minus8", 0), ("bit_depth_chroma_minus8", 0),
+                                                     ("qpprime_y_zero_transform_bypass_flag", 0), ("seq_scaling_matrix_present_flag", 0)];
+
+fn set_profile_idc(sps: &mut SyntaxNode, profile_idc: i32) {
+    let old_profile_idc = sps.get_field("profile_idc").unwrap_or(0);
+    sps.set_field("profile_idc", profile_idc);
+    if is_high_profile(profile_idc) && !is_high_profile(old_profile_idc) {
+        let idx = sps.children.iter().position(|x| matches!(x, SyntaxElement::Field(field) if field.name == "seq_paramter_set_id")).unwrap();
+        for (offset, (name, val)) in HIGH_PROFILE_SPS_DEFAULTS.iter().enumerate() {
+            sps.children.insert(idx + 1 + offset, SyntaxElement::Field(SyntaxField { name: name.to_string(), val: *val, line: None }));
+        }
+    } else if !is_high_profile(profile_idc) && is_high_profile(old_profile_idc) {
+        for (name, val) in HIGH_PROFILE_SPS_DEFAULTS {
+            if sps.get_field(name).unwrap_or(val) != val {
+                panic!("profile_idc {} can't signal {} {}", profile_idc, name, sps.get_field(name).unwrap());
+            }
+        }
+        sps.children.retain(|x| !matches!(x, SyntaxElement::Field(field) if HIGH_PROFILE_SPS_DEFAULTS.iter().any(|y| y.0 == field.name)));
+    }
+}
+
+// Recompute the constraint_set flags of every SPS from the tools the stream actually uses, after switching it
+// to profile_idc if one is given. Panics if the stream uses something the profile itself rules out.
+pub fn fix_constraint_flags(mut nalus: Vec<SyntaxElement>, profile_idc: Option<i32>) -> Vec<SyntaxElement> {
+    let features = stream_features(&nalus);
+    for element in nalus.iter_mut() {
+        let SyntaxElement::Node(nalu) = element else {
+            continue;
+        };
+        let Some(sps) = nalu.get_node_mut("sps") else {
+            continue;
+        };
+        if let Some(profile_idc) = profile_idc {
+            set_profile_idc(sps, profile_idc);
+        }
+        let flags = consistent_constraint_flags(sps, &features);
+        for (i, flag) in flags.iter().enumerate() {
+            sps.set_field(&format!("constraint_set{}_flag", i), *flag);
+        }
+        let violations = profile_violations(sps, &features);
+        if !violations.is_empty() {
+            panic!("SPS {} can't be signaled as profile_idc {}: {}", sps.get_field("seq_paramter_set_id").unwrap_or(0),
+                   sps.get_field("profile_idc").unwrap_or(0), violations.join(", "));
+        }
+        // The SPS may have changed length, so it needs fresh trailing bits
+        let options = SerializeOptions { regenerate_trailing_bits: true, ..Default::default() };
+        let bytes = serialize_h264_nalus(VecDeque::from([element.clone()]), &options);
+        *element = parse_h264(&bytes).into_iter().next().unwrap();
+    }
+
+    nalus
+}
+
+// Drop SPS/PPS copies identical to the version already in effect. With a period, a copy is still kept
+// once at least that many access units have passed since the last kept one, preserving random access.
+pub fn dedup_parameter_sets(nalus: Vec<SyntaxElement>, period: Option<usize>) -> Vec<SyntaxElement> {
+    let mut access_unit_idx: Vec<usize> = vec![0; nalus.len()];
+    for (idx, access_unit) in split_access_units(&nalus).into_iter().enumerate() {
+        access_unit_idx[access_unit].fill(idx);
+    }
+    // Contents and access unit of the last copy kept for each parameter set
+    let mut last_kept: HashMap<(i32, i32), (String, usize)> = HashMap::new();
+    let mut ret: Vec<SyntaxElement> = vec![];
+    for (element, access_unit) in nalus.into_iter().zip(access_unit_idx) {
+        if let Some((key, nalu)) = match &element { SyntaxElement::Node(nalu) => parameter_set_key(nalu).map(|x| (x, nalu)), _ => None } {
+            let contents = nalu_contents(nalu);
+            if let Some((last_contents, last_access_unit)) = last_kept.get(&key) {
+                let due = period.is_some_and(|x| access_unit - last_access_unit >= x);
+                if *last_contents == contents && !due {
+                    continue;
+                }
+            }
+            last_kept.insert(key, (contents, access_unit));
+        }
+        ret.push(element);
+    }
+
+    ret
+}
+
+fn raw_parameter_set_key(nalu: &[u8]) -> Option<(i32, i32)> {
+    match peek_nal_unit_type(nalu, NalHeaderFormat::H264)? {
+        7 | 8 => parameter_set_key(&parse_h264_nalu(nalu)),
+        15 => Some((15, parse_h264_nalu(nalu).get_node("subset_sps")?.get_field("seq_paramter_set_id")?)),
+        _ => None,
+    }
+}
+
+// Make sure every SPS/PPS used by the stream is present before the first slice, copying in the first version
+// of each from later in the stream or from out-of-band parameter sets (e.g. a container's avcC). This works on
+// the raw NALUs since slices ahead of their parameter sets can't be parsed correctly.
+pub fn move_parameter_sets_to_front(bitstream: &[u8], out_of_band: &[u8]) -> Vec<u8> {
+    let nalus = tokenize_h264_nalus(bitstream);
+    let first_vcl = nalus.iter()
+        .position(|x| peek_nal_unit_type(x, NalHeaderFormat::H264).is_some_and(is_vcl_nalu))
+        .unwrap_or(nalus.len());
+    let mut present: Vec<(i32, i32)> = vec![];
+    let mut to_front: Vec<&[u8]> = vec![];
+    // Sets already ahead of the first slice win, then out-of-band ones, then the first later copy in the stream
+    let candidates = nalus[..first_vcl].iter().map(|x| (false, *x))
+        .chain(tokenize_h264_nalus(out_of_band).into_iter().map(|x| (true, x)))
+        .chain(nalus[first_vcl..].iter().map(|x| (true, *x)));
+    for (needs_copy, nalu) in candidates {
+        let Some(key) = raw_parameter_set_key(nalu) else {
+            continue;
+        };
+        if !present.contains(&key) {
+            present.push(key);
+            if needs_copy {
+                let kind = match key.0 { 7 => "SPS", 15 => "subset SPS", _ => "PPS" };
+                eprintln!("Copying {} {} to the start of the stream", kind, key.1);
+                to_front.push(nalu);
+            }
+        }
+    }
+
+    // An access unit delimiter has to stay the first NALU of its access unit
+    let insert_idx = if nalus.first().is_some_and(|x| peek_nal_unit_type(x, NalHeaderFormat::H264) == Some(9)) { 1 } else { 0 };
+    let mut front: Vec<&[u8]> = nalus[..insert_idx].iter().copied().chain(to_front).chain(nalus[insert_idx..first_vcl].iter().copied()).collect();
+    // SPSs and subset SPSs go ahead of every PPS, as a PPS may refer to either. The parameter sets ahead of the
+    // first slice are reordered among the places they take up, leaving any other NALU where it is.
+    let slots: Vec<usize> = (0..front.len()).filter(|x| matches!(peek_nal_unit_type(front[*x], NalHeaderFormat::H264), Some(7 | 8 | 15))).collect();
+    let mut parameter_sets: Vec<&[u8]> = slots.iter().map(|x| front[*x]).collect();
+    parameter_sets.sort_by_key(|x| peek_nal_unit_type(x, NalHeaderFormat::H264) == Some(8));
+    for (slot, nalu) in slots.into_iter().zip(parameter_sets) {
+        front[slot] = nalu;
+    }
+    let mut ret: Vec<u8> = vec![];
+    for nalu in front.iter().chain(&nalus[first_vcl..]) {
+        ret.extend([0x00, 0x00, 0x00, 0x01]);
+        ret.extend(*nalu);
+    }
+
+    ret
+}
+
+fn new_filler(filler_bytes: usize) -> SyntaxElement {
+    let mut nalu = SyntaxNode::new("nalu");
+    nalu.push_field("forbidden_zero_bit", 0);
+    nalu.push_field("nal_ref_idc", 0);
+    nalu.push_field("nal_unit_type", 12);
+    let mut filler = SyntaxNode::new("filler_nalu");
+    // ff_bytes followed by rbsp_trailing_bits
+    let mut data = vec![0xFF; filler_bytes];
+    data.push(0x80);
+    filler.push_payload("filler_data", data);
+    nalu.children.push_back(SyntaxElement::Node(filler));
+    SyntaxElement::Node(nalu)
+}
+
+// Start code, NALU header and trailing bits around the ff_bytes of a filler NALU
+const FILLER_OVERHEAD: usize = 6;
+
+// Coded size of each NALU as written, found by serializing the stream as it stands. Each NALU counts its start
+// code and trailing zero bytes, the first one also the zero bytes ahead of it.
+fn serialized_nalu_sizes(nalus: &[SyntaxElement]) -> Vec<usize> {
+    let bytes = serialize_h264_nalus(nalus.iter().cloned().collect(), &SerializeOptions::default());
+    let mut ret: Vec<usize> = vec![];
+    let mut leading_bytes = 0;
+    for (nalu, range) in nalu_chunks(&bytes) {
+        match nalu {
+            Some(_) => ret.push(std::mem::take(&mut leading_bytes) + range.len()),
+            None => leading_bytes += range.len(),
+        }
+    }
+
+    ret
+}
+
+// Append a filler NALU to every access unit, with filler_size picking the number of ff_bytes (or none) from
+// the access unit's current size on the wire.
+fn insert_fillers(nalus: Vec<SyntaxElement>, mut filler_size: impl FnMut(usize) -> Option<usize>) -> Vec<SyntaxElement> {
+    let nalu_sizes = serialized_nalu_sizes(&nalus);
+    let access_units = split_access_units(&nalus);
+    let mut ret: Vec<SyntaxElement> = vec![];
+    let mut nalus = nalus.into_iter();
+    for access_unit in access_units {
+        let access_unit_bytes = nalu_sizes[access_unit.clone()].iter().sum();
+        let mut access_unit: Vec<SyntaxElement> = nalus.by_ref().take(access_unit.len()).collect();
+        if let Some(filler_bytes) = filler_size(access_unit_bytes) {
+            // Filler data follows the picture but stays ahead of end of sequence/stream NALUs
+            let insert_idx = access_unit.iter()
+                .rposition(|x| !matches!(nal_unit_type(x), Some(10) | Some(11)))
+                .map_or(0, |x| x + 1);
+            access_unit.insert(insert_idx, new_filler(filler_bytes));
+        }
+        ret.extend(access_unit);
+    }
+
+    ret
+}
+
+pub fn insert_fixed_fillers(nalus: Vec<SyntaxElement>, filler_bytes: usize) -> Vec<SyntaxElement> {
+    insert_fillers(nalus, |_| Some(filler_bytes))
+}
+
+// Pad every access unit up to the size a constant bitrate implies at the given frame rate.
+pub fn pad_to_constant_bitrate(nalus: Vec<SyntaxElement>, kbps: f64, frame_rate: f64) -> Vec<SyntaxElement> {
+    let target_bytes = (kbps * 1000.0 / 8.0 / frame_rate) as usize;
+    let mut oversized = 0;
+    let ret = insert_fillers(nalus, |access_unit_bytes| {
+        if access_unit_bytes + FILLER_OVERHEAD > target_bytes {
+            if access_unit_bytes > target_bytes {
+                oversized += 1;
+            }
+            return None;
+        }
+        Some(target_bytes - access_unit_bytes - FILLER_OVERHEAD)
+    });
+    if oversized > 0 {
+        eprintln!("Warning: {} access units are already larger than the {} byte target", oversized, target_bytes);
+    }
+
+    ret
+}
+
+// Split a stream into byte ranges that each hold one NALU's start code, payload and whatever follows up to the
+// next start code, so that concatenating them gives back the input exactly. Bytes ahead of the first start
+// code come back as a leading range with no NALU.
+pub fn nalu_chunks(bitstream: &[u8]) -> Vec<(Option<&[u8]>, Range<usize>)> {
+    let nalus = tokenize_h264_nalus(bitstream);
+    let mut starts: Vec<usize> = vec![];
+    for nalu in &nalus {
+        let offset = nalu.as_ptr() as usize - bitstream.as_ptr() as usize;
+        let four_byte = offset >= 4 && bitstream[offset - 4..offset] == [0x00, 0x00, 0x00, 0x01];
+        starts.push(offset - if four_byte { 4 } else { 3 });
+    }
+    let mut ret: Vec<(Option<&[u8]>, Range<usize>)> = vec![];
+    if starts.first().is_some_and(|x| *x > 0) {
+        ret.push((None, 0..starts[0]));
+    }
+    for (idx, nalu) in nalus.iter().enumerate() {
+        let end = starts.get(idx + 1).copied().unwrap_or(bitstream.len());
+        ret.push((Some(*nalu), starts[idx]..end));
+    }
+
+    ret
+}
+
+// Re-emit a stream dropping every NALU whose type is listed, without re-serializing anything, then verify
+// that the output tokenizes back into exactly the kept NALUs byte for byte.
+pub fn passthrough(bitstream: &[u8], drop_types: &[u8]) -> Vec<u8> {
+    let mut ret: Vec<u8> = vec![];
+    let mut kept: Vec<&[u8]> = vec![];
+    let mut dropped = 0;
+    for (nalu, range) in nalu_chunks(bitstream) {
+        if let Some(nalu) = nalu {
+            if peek_nal_unit_type(nalu, NalHeaderFormat::H264).is_some_and(|x| drop_types.contains(&(x as u8))) {
+                dropped += 1;
+                continue;
+            }
+            kept.push(nalu);
+        }
+        ret.extend(&bitstream[range]);
+    }
+
+    let emitted = tokenize_h264_nalus(&ret);
+    if emitted.len() != kept.len() {
+        panic!("Verification failed: kept {} NALUs but the output tokenizes into {}", kept.len(), emitted.len());
+    }
+    for (idx, (expected, actual)) in kept.iter().zip(&emitted).enumerate() {
+        if expected != actual {
+            panic!("Verification failed: NALU {} of the output differs from the input", idx);
+        }
+    }
+    eprintln!("Verified {} NALUs passed through bit-exact, {} dropped", kept.len(), dropped);
+
+    ret
+}
+
+// xorshift64*, enough to scramble picture data reproducibly without pulling in a dependency
+struct Scrambler {
+    state: u64,
+}
+
+impl Scrambler {
+    fn new(seed: u64) -> Scrambler {
+        // A zero state would only ever produce zeros
+        Scrambler { state: seed ^ 0x9E37_79B9_7F4A_7C15 }
+    }
+
+    // Never below 0x04, so no run of these can form a start code or an emulation prevention pattern, not
+    // even following zero bytes at the end of the slice header
+    fn next_byte(&mut self) -> u8 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        let val = (self.state.wrapping_mul(0x2545_F491_4F6C_DD1D) >> 56) as u8;
+        0x04 + val % 0xFC
+    }
+}
+
+// Replace every slice's data past the byte holding the end of its header with pseudorandom bytes, keeping NALU
+// sizes, headers and everything between NALUs as they were, so a stream's structure can be shared without its
+// pictures. The last byte becomes a bare stop bit. The output is checked to tokenize into the same NALU sizes.
+pub fn anonymize_slice_data(bitstream: &[u8], seed: u64) -> Vec<u8> {
+    let starts = parse_h264_slice_data_starts(bitstream);
+    let mut scrambler = Scrambler::new(seed);
+    let mut ret: Vec<u8> = vec![];
+    let mut nalu_sizes: Vec<usize> = vec![];
+    let (mut scrambled_bytes, mut scrambled_slices) = (0, 0);
+    for (nalu, range) in nalu_chunks(bitstream) {
+        let Some(nalu) = nalu else {
+            ret.extend(&bitstream[range]);
+            continue;
+        };
+        nalu_sizes.push(nalu.len());
+        let nalu_start = nalu.as_ptr() as usize - bitstream.as_ptr() as usize;
+        let mut chunk = bitstream[range.clone()].to_vec();
+        if let Some(Some(start)) = starts.get(nalu_sizes.len() - 1) {
+            // The data start is known within the RBSP, so count the emulation prevention bytes in front of it
+            let data_start = nalu_start - range.start + ebsp_len(nalu, start.bit_offset.div_ceil(8));
+            let data_end = nalu_start - range.start + nalu.len();
+            if data_start < data_end {
+                for byte in &mut chunk[data_start..data_end - 1] {
+                    *byte = scrambler.next_byte();
+                }
+                chunk[data_end - 1] = 0x80;
+                scrambled_bytes += data_end - data_start;
+                scrambled_slices += 1;
+            }
+        }
+        ret.extend(chunk);
+    }
+
+    let emitted: Vec<usize> = tokenize_h264_nalus(&ret).iter().map(|x| x.len()).collect();
+    if emitted != nalu_sizes {
+        panic!("Verification failed: the anonymized stream no longer tokenizes into the original NALU sizes");
+    }
+    eprintln!("Replaced {} bytes of slice data in {} slices", scrambled_bytes, scrambled_slices);
+
+    ret
+}
+
+// Put an edited slice header in front of the original slice data. Everything from the first byte boundary
+// after the original header onwards is copied verbatim, emulation prevention bytes included, so a ciphertext
+// payload stays intact. The bits between the end of the header and that boundary are clear slice data: CABAC
+// alignment ones can be regenerated for a header of any length, but CAVLC macroblock bits only survive if
+// the header keeps its bit alignment. The edited NALU is an RBSP, the original is as coded.
+fn overlay_slice(original: &[u8], original_start: &SliceDataStart, edited: &[u8], edited_start: &SliceDataStart, idx: usize) -> Vec<u8> {
+    let original_rbsp = nalu_to_rbsp(original);
+    let original_offset = original_start.bit_offset;
+    let edited_offset = edited_start.bit_offset;
+    let mut head = edited[..edited_offset.div_ceil(8)].to_vec();
+    let same_alignment = original_offset % 8 == edited_offset % 8;
+    if !same_alignment && !original_start.cabac {
+        panic!("Slice header in NALU {} changed its bit alignment, which would shift the CAVLC slice data into the encrypted bytes", idx);
+    }
+    if !edited_offset.is_multiple_of(8) {
+        let header_mask = 0xFFu8 >> (edited_offset % 8);
+        let last = head.len() - 1;
+        head[last] &= !header_mask;
+        head[last] |= if same_alignment { original_rbsp[original_offset / 8] & header_mask } else { header_mask };
+    }
+    let tail = &original[ebsp_len(original, original_offset.div_ceil(8))..];
+
+    // Escape the header together with the first data byte, so an emulation prevention byte lands between
+    // them if the new header ends in zeros
+    let header_bytes = nal_unit_header_bytes(&head);
+    let mut ret = head[..header_bytes].to_vec();
+    let mut escaped = rbsp_to_ebsp(&[&head[header_bytes..], &tail[..tail.len().min(1)]].concat());
+    escaped.truncate(escaped.len() - tail.len().min(1));
+    ret.extend(escaped);
+    ret.extend(tail);
+    if nalu_to_rbsp(&ret)[head.len()..] != original_rbsp[original_offset.div_ceil(8)..] {
+        eprintln!("Warning: the new slice header in NALU {} changes how emulation prevention bytes in its slice data read", idx);
+    }
+
+    ret
+}
+
+// Apply an edit of a stream whose slice data is encrypted (e.g. CENC subsample encryption, where only the
+// NALU and slice headers are clear). Non-slice NALUs are re-serialized as usual, while slices keep their
+// original bytes past the header. The edit must keep the original NALUs one to one.
+pub fn overlay_slice_headers(original: &[u8], edited: Vec<SyntaxElement>) -> Vec<u8> {
+    let original_nalus = tokenize_h264_nalus(original);
+    let original_starts = parse_h264_slice_data_starts(original);
+    let edited_nalus = serialize_h264_nalus_separately(edited.into());
+    if original_nalus.len() != edited_nalus.len() {
+        panic!("The edit has {} NALUs but the original stream has {}", edited_nalus.len(), original_nalus.len());
+    }
+    let mut ret: Vec<u8> = vec![];
+    for (idx, ((original, original_start), (edited, edited_start))) in original_nalus.iter().zip(&original_starts).zip(&edited_nalus).enumerate() {
+        ret.extend([0x00, 0x00, 0x00, 0x01]);
+        match (original_start, edited_start) {
+            (Some(original_start), Some(edited_start)) => ret.extend(overlay_slice(original, original_start, edited, edited_start, idx)),
+            _ => {
+                let header_bytes = nal_unit_header_bytes(edited);
+                ret.extend(&edited[..header_bytes]);
+                ret.extend(rbsp_to_ebsp(&edited[header_bytes..]));
+            },
+        }
+    }
+
+    ret
+}
+
+// Apply an edit by re-encoding only the NALUs it changed and copying every other byte of the original
+// stream, start codes and whatever sits between NALUs included. The edit must keep the original NALUs one
+// to one, and changed NALUs keep the start code length they had.
+pub fn splice_edited_nalus(original: &[u8], edited: Vec<SyntaxElement>, options: &SerializeOptions) -> Vec<u8> {
+    // Compare against the same depth of parse the edit was made from
+    let deep = edited.iter().any(|x| match x {
+        SyntaxElement::Node(nalu) => nalu.children.iter().any(|y| matches!(y, SyntaxElement::Node(z) if z.get_node("slice_data").is_some())),
+        _ => false,
+    });
+    let original_nalus = if deep { parse_h264_deep(original) } else { parse_h264(original) };
+    let changed = serialize_changed_h264_nalus(edited.into(), &original_nalus, options);
+    let mut ret: Vec<u8> = vec![];
+    let mut changed_nalus = changed.into_iter();
+    let mut reencoded = 0;
+    for (nalu, range) in nalu_chunks(original) {
+        let Some(nalu) = nalu else {
+            ret.extend(&original[range]);
+            continue;
+        };
+        match changed_nalus.next().unwrap() {
+            Some(bytes) => {
+                let offset = nalu.as_ptr() as usize - original.as_ptr() as usize;
+                ret.extend(&original[range.start..offset]);
+                ret.extend(bytes);
+                reencoded += 1;
+            },
+            None => ret.extend(&original[range]),
+        }
+    }
+    eprintln!("Re-encoded {} of {} NALUs, copied the rest from the original", reencoded, original_nalus.len());
+
+    ret
+}
+
+// Parameter sets an access unit uses but doesn't carry itself, SPSs first
+fn missing_parameter_sets<'a>(access_unit: &[SyntaxElement], sps_map: &HashMap<i32, &'a [u8]>, pps_map: &HashMap<i32, (i32, &'a [u8])>,
+                              access_unit_idx: usize) -> Vec<&'a [u8]> {
+    let mut sps_ids: Vec<i32> = vec![];
+    let mut pps_ids: Vec<i32> = vec![];
+    let mut sps_missing: Vec<i32> = vec![];
+    let mut pps_missing: Vec<i32> = vec![];
+    for element in access_unit {
+        let SyntaxElement::Node(nalu) = element else {
+            continue;
+        };
+        if let Some(sps_id) = nalu.get_node("sps").and_then(|x| x.get_field("seq_paramter_set_id")) {
+            sps_ids.push(sps_id);
+        }
+        let mut sps_id = None;
+        if let Some(pps) = nalu.get_node("pps") {
+            pps_ids.extend(pps.get_field("pic_parameter_set_id"));
+            sps_id = pps.get_field("seq_parameter_set_id");
+        }
+        if let Some(pps_id) = get_slice_header(nalu).and_then(|x| x.get_field("pic_parameter_set_id")) {
+            if !pps_ids.contains(&pps_id) && !pps_missing.contains(&pps_id) {
+                pps_missing.push(pps_id);
+                sps_id = pps_map.get(&pps_id).map(|x| x.0);
+            }
+        }
+        if let Some(sps_id) = sps_id {
+            if !sps_ids.contains(&sps_id) && !sps_missing.contains(&sps_id) {
+                sps_missing.push(sps_id);
+            }
+        }
+    }
+
+    let mut ret: Vec<&[u8]> = vec![];
+    for sps_id in sps_missing {
+        match sps_map.get(&sps_id) {
+            Some(sps) => ret.push(sps),
+            None => eprintln!("Warning: access unit {} refers to SPS {} which hasn't been sent", access_unit_idx, sps_id),
+        }
+    }
+    for pps_id in pps_missing {
+        match pps_map.get(&pps_id) {
+            Some(pps) => ret.push(pps.1),
+            None => eprintln!("Warning: access unit {} refers to PPS {} which hasn't been sent", access_unit_idx, pps_id),
+        }
+    }
+
+    ret
+}
+
+// Cut a stream into one self-contained stream per access unit, each starting with copies of the SPS and
+// PPS it needs. NALUs are copied byte for byte.
+pub fn split_pictures(bitstream: &[u8]) -> Vec<Vec<u8>> {
+    let raw_nalus = tokenize_h264_nalus(bitstream);
+    let nalus = parse_h264(bitstream);
+    let mut sps_map: HashMap<i32, &[u8]> = HashMap::new();
+    let mut pps_map: HashMap<i32, (i32, &[u8])> = HashMap::new();
+    let mut ret: Vec<Vec<u8>> = vec![];
+    for (access_unit_idx, access_unit) in split_access_units(&nalus).into_iter().enumerate() {
+        let mut picture: Vec<u8> = vec![];
+        for nalu in missing_parameter_sets(&nalus[access_unit.clone()], &sps_map, &pps_map, access_unit_idx) {
+            picture.extend([0x00, 0x00, 0x00, 0x01]);
+            picture.extend(nalu);
+        }
+        for idx in access_unit {
+            picture.extend([0x00, 0x00, 0x00, 0x01]);
+            picture.extend(raw_nalus[idx]);
+            let SyntaxElement::Node(nalu) = &nalus[idx] else {
+                continue;
+            };
+            if let Some(sps_id) = nalu.get_node("sps").and_then(|x| x.get_field("seq_paramter_set_id")) {
+                sps_map.insert(sps_id, raw_nalus[idx]);
+            }
+            if let Some(pps) = nalu.get_node("pps") {
+                if let (Some(pps_id), Some(sps_id)) = (pps.get_field("pic_parameter_set_id"), pps.get_field("seq_parameter_set_id")) {
+                    pps_map.insert(pps_id, (sps_id, raw_nalus[idx]));
+                }
+            }
+        }
+        ret.push(picture);
+    }
+
+    ret
+}
+
+// Move slice data that started at old_start so it starts at new_offset instead. CABAC slice data begins at a
+// byte boundary after alignment ones, so only those are redone; CAVLC slice data is shifted bit by bit and
+// gets new trailing bits.
+fn realign_slice_payload(payload: &[u8], old_start: &SliceDataStart, new_offset: usize) -> Vec<u8> {
+    if old_start.cabac {
+        let skip = usize::from(!old_start.bit_offset.is_multiple_of(8)).min(payload.len());
+        let mut ret: Vec<u8> = if new_offset.is_multiple_of(8) { vec![] } else { vec![0xFF] };
+        ret.extend(&payload[skip..]);
+        return ret;
+    }
+    let mut bits = unpack_slice_payload(payload, old_start.bit_offset);
+    let stop_bit = bits.iter().rposition(|x| *x).unwrap_or(0);
+    bits.truncate(stop_bit);
+    bits.push(true);
+    while !(new_offset + bits.len()).is_multiple_of(8) {
+        bits.push(false);
+    }
+
+    pack_slice_payload(&bits, new_offset)
+}
+
+// The pictures of a POC type 2 stream are output in decode order, and non-reference pictures can't follow
+// each other since they'd end up with the same picture order count (8.2.1.3).
+fn check_poc_type_2_compatible(nalus: &[SyntaxElement]) {
+    let pictures = compute_picture_order(nalus);
+    for pair in pictures.windows(2) {
+        if !pair[1].resets_order && pair[1].pic_order_cnt < pair[0].pic_order_cnt {
+            panic!("Access unit {} is output before access unit {}, POC type 2 needs output order to follow decode order",
+                   pair[1].access_unit, pair[0].access_unit);
+        }
+    }
+    let mut last_non_reference: Option<usize> = None;
+    for (idx, access_unit) in split_access_units(nalus).into_iter().enumerate() {
+        let Some(nalu) = nalus[access_unit].iter().find_map(|x| match x {
+            SyntaxElement::Node(nalu) if get_slice_header(nalu).is_some() => Some(nalu),
+            _ => None,
+        }) else {
+            continue;
+        };
+        if get_slice_header(nalu).and_then(|x| x.get_field("field_pic_flag")).unwrap_or(0) != 0 {
+            panic!("Access unit {} is a field, only frame streams can be converted to POC type 2", idx);
+        }
+        if nalu.get_field("nal_ref_idc").unwrap_or(0) != 0 {
+            last_non_reference = None;
+            continue;
+        }
+        if let Some(last) = last_non_reference {
+            panic!("Access units {} and {} are consecutive non-reference pictures, which POC type 2 can't order", last, idx);
+        }
+        last_non_reference = Some(idx);
+    }
+}
+
+// Experimental: switch every POC type 0 SPS and subset SPS to type 2, dropping pic_order_cnt_lsb and
+// delta_pic_order_cnt_bottom from the slices and moving their slice data to where the shorter headers now end.
+pub fn convert_poc_type_0_to_2(mut nalus: Vec<SyntaxElement>) -> Vec<SyntaxElement> {
+    check_poc_type_2_compatible(&nalus);
+    let old_starts: Vec<Option<SliceDataStart>> = serialize_h264_nalus_separately(nalus.iter().cloned().collect())
+        .into_iter().map(|x| x.1).collect();
+    let mut converted_sps = 0;
+    for (idx, element) in nalus.iter_mut().enumerate() {
+        let SyntaxElement::Node(nalu) = element else {
+            continue;
+        };
+        if let Some(sps) = ["sps", "subset_sps"].into_iter().find(|x| nalu.get_node(x).is_some()).and_then(|x| nalu.get_node_mut(x)) {
+            if sps.get_field("pic_order_cnt_type") == Some(0) {
+                // The hex extension data of a subset SPS holds its trailing bits, which can't be moved
+                if sps.name == "subset_sps" && !sps.children.iter().any(|x| matches!(x, SyntaxElement::Payload(payload) if payload.name == "trailing_bits")) {
+                    panic!("The subset SPS in NALU {} ends in extension data, cannot convert it to POC type 2", idx);
+                }
+                sps.set_field("pic_order_cnt_type", 2);
+                sps.children.retain(|x| !matches!(x, SyntaxElement::Field(field) if field.name == "log2_max_pic_order_cnt_lsb_minus4"));
+                // Its fields now end earlier, so the stop bit has to move with them
+                *element = with_fresh_trailing_bits(nalu.clone());
+                converted_sps += 1;
+            }
+            continue;
+        }
+        let name = SLICE_HEADER_NODES.into_iter().find(|x| nalu.get_node(x).is_some());
+        if let Some(header) = name.and_then(|x| nalu.get_node_mut(x)).and_then(|x| x.get_node_mut("slice_header")) {
+            header.children.retain(|x| !matches!(x, SyntaxElement::Field(field)
+                                                 if field.name == "pic_order_cnt_lsb" || field.name == "delta_pic_order_cnt_bottom"));
+        }
+    }
+    eprintln!("Converted {} SPSs from POC type 0 to 2", converted_sps);
+
+    // The slice data moves along with its stop bit, which realigning writes afresh. Slices are parsed without
+    // their slice data here, so it all sits in slice_payload.
+    let new_starts: Vec<Option<SliceDataStart>> = serialize_h264_nalus_separately(nalus.iter().cloned().collect())
+        .into_iter().map(|x| x.1).collect();
+    for ((element, old_start), new_start) in nalus.iter_mut().zip(&old_starts).zip(&new_starts) {
+        let (SyntaxElement::Node(nalu), Some(old_start), Some(new_start)) = (element, old_start, new_start) else {
+            continue;
+        };
+        let name = SLICE_HEADER_NODES.into_iter().find(|x| nalu.get_node(x).is_some());
+        let Some(slice) = name.and_then(|x| nalu.get_node_mut(x)) else {
+            continue;
+        };
+        for child in slice.children.iter_mut() {
+            if let SyntaxElement::Payload(payload) = child {
+                if payload.name == "slice_payload" {
+                    payload.data = realign_slice_payload(&payload.data, old_start, new_start.bit_offset);
+                }
+            }
+        }
+    }
+
+    nalus
+}
+
+// Set every field with the given name in a node and its subnodes
+fn set_fields_named(node: &mut SyntaxNode, name: &str, val: i32) {
+    for child in node.children.iter_mut() {
+        match child {
+            SyntaxElement::Field(field) if field.name == name => field.val = val,
+            SyntaxElement::Node(subnode) => set_fields_named(subnode, name, val),
+            _ => (),
+        }
+    }
+}
+
+// Index in a NALU as coded of each byte of its RBSP
+fn rbsp_byte_positions(nalu: &[u8]) -> Vec<usize> {
+    let header_bytes = nal_unit_header_bytes(nalu);
+    let mut ret: Vec<usize> = (0..header_bytes).collect();
+    let mut zero_count = 0;
+    for (idx, byte) in nalu.iter().enumerate().skip(header_bytes) {
+        if zero_count >= 2 && *byte == 0x03 {
+            zero_count = 0;
+            continue;
+        }
+        ret.push(idx);
+        zero_count = if *byte == 0x00 { zero_count + 1 } else { 0 };
+    }
+
+    ret
+}
+
+// Change the value of fields without re-serializing the stream: only the bits of the named fields are rewritten
+// in a copy of the original bytes, in every NALU or just the one at only_nalu. Each edit has to keep its field's
+// coded length and can't call for emulation prevention bytes to be added or removed; anything else is refused.
+pub fn patch_fields(bitstream: &[u8], edits: &[(String, i32)], only_nalu: Option<usize>) -> Vec<u8> {
+    let coded = tokenize_h264_nalus(bitstream);
+    let (mut nalus, traces) = trace_h264(bitstream);
+    // Bits of each NALU's RBSP the edits are allowed to change
+    let mut patched_bits: Vec<Vec<Range<usize>>> = vec![vec![]; nalus.len()];
+    for (idx, (element, trace)) in nalus.iter_mut().zip(&traces).enumerate() {
+        let SyntaxElement::Node(nalu) = element else {
+            continue;
+        };
+        if only_nalu.is_some_and(|x| x != idx) {
+            continue;
+        }
+        for (name, val) in edits {
+            for entry in trace {
+                if let TraceEntry::Field { position, name: field_name, bits, value } = entry {
+                    if field_name == name {
+                        eprintln!("Patch: {} in NALU {} from {} to {}", name, idx, value, val);
+                        patched_bits[idx].push(*position..position + bits.len());
+                    }
+                }
+            }
+            set_fields_named(nalu, name, *val);
+        }
+    }
+    if patched_bits.iter().all(|x| x.is_empty()) {
+        panic!("None of the fields to patch are in the stream");
+    }
+
+    let serialized = serialize_h264_nalus_separately(nalus.into());
+    let mut ret = bitstream.to_vec();
+    for (idx, ranges) in patched_bits.iter().enumerate().filter(|(_, x)| !x.is_empty()) {
+        let original = coded[idx];
+        let original_rbsp = nalu_to_rbsp(original);
+        let patched_rbsp = &serialized[idx].0;
+        if patched_rbsp.len() != original_rbsp.len() {
+            panic!("Patching NALU {} changes its length, re-encode it with -e instead", idx);
+        }
+        let mut patched = original.to_vec();
+        for (rbsp_idx, coded_idx) in rbsp_byte_positions(original).into_iter().enumerate() {
+            let changed = original_rbsp[rbsp_idx] ^ patched_rbsp[rbsp_idx];
+            let outside = (0..8).any(|bit| changed & (0x80 >> bit) != 0 && !ranges.iter().any(|x| x.contains(&(rbsp_idx * 8 + bit))));
+            if outside {
+                panic!("Patching NALU {} changes bits outside the patched fields, so a field changes its coded length", idx);
+            }
+            patched[coded_idx] = patched_rbsp[rbsp_idx];
+        }
+        if nalu_to_rbsp(&patched) != *patched_rbsp || patched.windows(3).any(|x| x[0] == 0x00 && x[1] == 0x00 && x[2] <= 0x02) {
+            panic!("Patching NALU {} would add or remove emulation prevention bytes", idx);
+        }
+        let offset = original.as_ptr() as usize - bitstream.as_ptr() as usize;
+        ret[offset..offset + patched.len()].copy_from_slice(&patched);
+    }
+
+    // Values that don't fit their field are cut short when written, so read the result back
+    let (_, patched_traces) = trace_h264(&ret);
+    for (idx, ranges) in patched_bits.iter().enumerate() {
+        for entry in &patched_traces[idx] {
+            if let TraceEntry::Field { position, name, value, .. } = entry {
+                let expected = edits.iter().find(|x| x.0 == *name).map(|x| x.1);
+                if ranges.iter().any(|x| x.start == *position) && expected.is_some_and(|x| x != *value) {
+                    panic!("{} in NALU {} can't hold {}", name, idx, expected.unwrap());
+                }
+            }
+        }
+    }
+    // A patched parameter set field can change how every NALU referring to it parses, e.g. the length of
+    // frame_num, so everything but the patched fields must read back as it did before
+    if patched_traces.len() != traces.len() {
+        panic!("Patching changes how the stream splits into NALUs");
+    }
+    for (idx, (original, patched)) in traces.iter().zip(&patched_traces).enumerate() {
+        if let Some(name) = first_difference(original, patched, &patched_bits[idx]) {
+            panic!("Patching changes how NALU {} parses from {} on, re-encode the stream with -e instead", idx, name);
+        }
+    }
+
+    ret
+}
+
+// Name of the first field or payload that reads differently in two traces of the same NALU, ignoring the
+// fields starting at the given positions
+fn first_difference(a: &[TraceEntry], b: &[TraceEntry], skip: &[Range<usize>]) -> Option<String> {
+    let name = |x: &TraceEntry| match x {
+        TraceEntry::Field { name, .. } | TraceEntry::Payload { name, .. } => name.clone(),
+    };
+    for (x, y) in a.iter().zip(b) {
+        let same = match (x, y) {
+            (TraceEntry::Field { position, name: x_name, value: x_value, .. }, TraceEntry::Field { name: y_name, value: y_value, .. }) =>
+                x_name == y_name && (x_value == y_value || skip.iter().any(|r| r.start == *position)),
+            (TraceEntry::Payload { name: x_name, data: x_data, .. }, TraceEntry::Payload { name: y_name, data: y_data, .. }) =>
+                x_name == y_name && x_data == y_data,
+            _ => false,
+        };
+        if !same {
+            return Some(name(x));
+        }
+    }
+    a.get(b.len()).or(b.get(a.len())).map(name)
+}
+
+// view_id of every view an MVC subset SPS lists, base view first, with the number of inter-view references
+// each has in its anchor and non-anchor pictures (H.7.4.2.1.4)
+fn mvc_views(nalus: &[SyntaxElement]) -> Vec<(i32, i32)> {
+    let Some(extension) = nalus.iter().find_map(|x| match x {
+        SyntaxElement::Node(nalu) => nalu.get_node("subset_sps").and_then(|y| y.get_node("seq_parameter_set_mvc_extension")),
+        _ => None,
+    }) else {
+        panic!("No MVC subset SPS in stream");
+    };
+    let num_views_minus1 = extension.get_field("num_views_minus1").unwrap();
+    (0..=num_views_minus1).map(|i| {
+        let num_refs: i32 = ["num_anchor_refs_l0", "num_anchor_refs_l1", "num_non_anchor_refs_l0", "num_non_anchor_refs_l1"].iter()
+            .filter_map(|x| extension.get_field(&format!("{}[{}]", x, i)))
+            .sum();
+        (extension.get_field(&format!("view_id[{}]", i)).unwrap(), num_refs)
+    }).collect()
+}
+
+// MVC SEI messages (H.13): parallel decoding info through base view temporal HRD
+fn is_mvc_sei(payload_type: i32) -> bool {
+    (36..=44).contains(&payload_type)
+}
+
+// Whether a message in an MVC scalable nesting SEI applies to view_id on its own
+fn nesting_applies_to_view(nesting: &SyntaxNode, view_id: i32) -> bool {
+    if nesting.get_field("operation_point_flag") == Some(0) {
+        nesting.get_field("all_view_components_in_au_flag") == Some(1) ||
+            (0..=nesting.get_field("num_view_components_minus1").unwrap_or(-1))
+                .any(|i| nesting.get_field(&format!("sei_view_id[{}]", i)) == Some(view_id))
+    } else {
+        nesting.get_field("num_view_components_op_minus1") == Some(0) && nesting.get_field("sei_op_view_id[0]") == Some(view_id)
+    }
+}
+
+// Keep the SEI messages a view needs in a standalone stream: for the base view those outside any MVC SEI, and
+// for another view those an MVC scalable nesting SEI applies to it, taken out of the nesting. Messages that
+// aren't nested apply to the base view (H.13). None when no message is left.
+fn sei_for_view(mut nalu: SyntaxNode, view_id: Option<i32>) -> Option<SyntaxNode> {
+    let sei = nalu.get_node_mut("sei").unwrap();
+    let mut children: VecDeque<SyntaxElement> = VecDeque::new();
+    for child in sei.children.drain(..) {
+        let SyntaxElement::Node(message) = &child else {
+            children.push_back(child);
+            continue;
+        };
+        let payload_type = message.get_field("payloadType").unwrap_or(-1);
+        match view_id {
+            None if !is_mvc_sei(payload_type) => children.push_back(child),
+            Some(view_id) if payload_type == 37 => {
+                let nesting = message.get_node("sei_payload").unwrap();
+                if nesting_applies_to_view(nesting, view_id) {
+                    children.extend(nesting.get_node("sei_message").map(|x| SyntaxElement::Node(x.clone())));
+                }
+            },
+            _ => (),
+        }
+    }
+    sei.children = children;
+    if !sei.children.iter().any(|x| matches!(x, SyntaxElement::Node(_))) {
+        return None;
+    }
+
+    Some(nalu)
+}
+
+// Write a rebuilt NALU out and read it back, which gives it the trailing_bits for the new length of its RBSP
+fn with_fresh_trailing_bits(nalu: SyntaxNode) -> SyntaxElement {
+    let options = SerializeOptions { regenerate_trailing_bits: true, ..Default::default() };
+    parse_h264(&serialize_h264_nalus(VecDeque::from([SyntaxElement::Node(nalu)]), &options)).remove(0)
+}
+
+// Turn an MVC subset SPS into the SPS of a standalone stream, keeping its seq_parameter_set_data() under the
+// High profile the MVC profiles build on
+fn subset_sps_to_sps(nalu: &SyntaxNode) -> SyntaxElement {
+    let subset_sps = nalu.get_node("subset_sps").unwrap();
+    let mut sps = SyntaxNode::new("sps");
+    sps.children = subset_sps.children.iter()
+        .take_while(|x| !matches!(x, SyntaxElement::Field(field) if field.name == "bit_equal_to_one"))
+        .cloned()
+        .collect();
+    set_profile_idc(&mut sps, 100);
+    sps.push_payload("trailing_bits", vec![0x80]);
+    let mut ret = SyntaxNode::new("nalu");
+    ret.push_field("forbidden_zero_bit", 0);
+    ret.push_field("nal_ref_idc", nalu.get_field("nal_ref_idc").unwrap());
+    ret.push_field("nal_unit_type", 7);
+    ret.children.push_back(SyntaxElement::Node(sps));
+    with_fresh_trailing_bits(ret)
+}
+
+// Turn a slice extension of a non-base view into a plain slice, IDR when its non_idr_flag is clear
+fn slice_extension_to_slice(nalu: &SyntaxNode) -> SyntaxElement {
+    let non_idr_flag = nalu.get_node("nal_unit_header_mvc_extension").and_then(|x| x.get_field("non_idr_flag")).unwrap();
+    let mut slice = nalu.get_node("slice_extension").unwrap().clone();
+    slice.name = "slice".to_string();
+    if let Some(modification) = slice.get_node_mut("slice_header").and_then(|x| x.get_node_mut("ref_pic_list_mvc_modification")) {
+        modification.name = "ref_pic_list_modification".to_string();
+    }
+    let mut ret = SyntaxNode::new("nalu");
+    ret.push_field("forbidden_zero_bit", 0);
+    ret.push_field("nal_ref_idc", nalu.get_field("nal_ref_idc").unwrap());
+    ret.push_field("nal_unit_type", if non_idr_flag != 0 { 1 } else { 5 });
+    ret.children.push_back(SyntaxElement::Node(slice));
+    SyntaxElement::Node(ret)
+}
+
+// Extract the base view of an MVC stream as plain AVC: prefix NALUs, subset SPSs, slice extensions and MVC SEI
+// messages go, everything else stays as it is
+pub fn extract_base_view(nalus: Vec<SyntaxElement>) -> Vec<SyntaxElement> {
+    let mut ret: Vec<SyntaxElement> = vec![];
+    for element in nalus {
+        let SyntaxElement::Node(nalu) = element else {
+            continue;
+        };
+        match nalu.get_field("nal_unit_type") {
+            Some(14 | 15 | 20 | 21) => (),
+            Some(6) => ret.extend(sei_for_view(nalu, None).map(SyntaxElement::Node)),
+            _ => ret.push(SyntaxElement::Node(nalu)),
+        }
+    }
+
+    ret
+}
+
+// Extract one view of an MVC stream as plain AVC. A non-base view's subset SPS becomes its SPS and its slice
+// extensions become slices, while the base view's parameter sets, slices and auxiliary pictures go. Only views
+// without inter-view prediction can be decoded on their own.
+pub fn extract_view(nalus: Vec<SyntaxElement>, view_id: i32) -> Vec<SyntaxElement> {
+    let views = mvc_views(&nalus);
+    let Some(idx) = views.iter().position(|x| x.0 == view_id) else {
+        panic!("No view with view_id {} in stream, the subset SPS lists {:?}", view_id, views.iter().map(|x| x.0).collect::<Vec<i32>>());
+    };
+    if idx == 0 {
+        return extract_base_view(nalus);
+    }
+    if views[idx].1 != 0 {
+        panic!("View {} is predicted from other views, so it can't be decoded on its own", view_id);
+    }
+    let in_view = |nalu: &SyntaxNode| nalu.get_node("nal_unit_header_mvc_extension").and_then(|x| x.get_field("view_id")) == Some(view_id);
+    let pps_ids: HashSet<i32> = nalus.iter().filter_map(|x| match x {
+        SyntaxElement::Node(nalu) if nalu.get_field("nal_unit_type") == Some(20) && in_view(nalu) => {
+            nalu.get_node("slice_extension").and_then(|y| y.get_node("slice_header")).and_then(|y| y.get_field("pic_parameter_set_id"))
+        },
+        _ => None,
+    }).collect();
+    let mut ret: Vec<SyntaxElement> = vec![];
+    for element in nalus {
+        let SyntaxElement::Node(nalu) = element else {
+            continue;
+        };
+        match nalu.get_field("nal_unit_type") {
+            Some(1..=5 | 7 | 13 | 14 | 19 | 21) => (),
+            Some(15) if nalu.get_node("subset_sps").and_then(|x| x.get_node("seq_parameter_set_mvc_extension")).is_some() => {
+                ret.push(subset_sps_to_sps(&nalu));
+            },
+            Some(15) => (),
+            Some(8) if !nalu.get_node("pps").and_then(|x| x.get_field("pic_parameter_set_id")).is_some_and(|x| pps_ids.contains(&x)) => (),
+            Some(20) if in_view(&nalu) => ret.push(slice_extension_to_slice(&nalu)),
+            Some(20) => (),
+            Some(6) => ret.extend(sei_for_view(nalu, Some(view_id)).map(SyntaxElement::Node)),
+            _ => ret.push(SyntaxElement::Node(nalu)),
+        }
+    }
+
+    ret
+}
+
+// Drop the film grain characteristics messages of an SEI NALU. None when no message is left.
+fn without_film_grain(mut nalu: SyntaxNode) -> Option<SyntaxElement> {
+    let sei = nalu.get_node_mut("sei").unwrap();
+    let before = sei.children.len();
+    sei.children.retain(|x| !matches!(x, SyntaxElement::Node(message) if message.get_field("payloadType") == Some(19)));
+    if sei.children.len() == before {
+        return Some(SyntaxElement::Node(nalu));
+    }
+    if !sei.children.iter().any(|x| matches!(x, SyntaxElement::Node(_))) {
+        return None;
+    }
+
+    Some(with_fresh_trailing_bits(nalu))
+}
+
+// Carry the film grain characteristics SEI message in `message` in every IDR access unit, in an SEI NALU of its
+// own just before the first VCL NALU, in place of the film grain messages already in the stream. Its
+// film_grain_characteristics_repetition_period decides how long decoders keep applying it (D.2.21).
+pub fn set_film_grain(nalus: Vec<SyntaxElement>, message: &SyntaxNode) -> Vec<SyntaxElement> {
+    if message.name != "sei_message" || message.get_field("payloadType") != Some(19) {
+        panic!("Film grain must be given as a sei_message with payloadType 19");
+    }
+    let mut sei_nalu = SyntaxNode::new("nalu");
+    sei_nalu.push_field("forbidden_zero_bit", 0);
+    sei_nalu.push_field("nal_ref_idc", 0);
+    sei_nalu.push_field("nal_unit_type", 6);
+    let mut sei = SyntaxNode::new("sei");
+    sei.children.push_back(SyntaxElement::Node(message.clone()));
+    sei.push_payload("trailing_bits", vec![]);
+    sei_nalu.children.push_back(SyntaxElement::Node(sei));
+    let sei_nalu = with_fresh_trailing_bits(sei_nalu);
+
+    let access_units = split_access_units(&nalus);
+    let mut ret: Vec<SyntaxElement> = vec![];
+    let mut nalus = nalus.into_iter();
+    let mut inserted = 0;
+    for access_unit in access_units {
+        let access_unit: Vec<SyntaxElement> = nalus.by_ref().take(access_unit.len()).collect();
+        let mut pending = is_idr_access_unit(&access_unit);
+        for element in access_unit {
+            if pending && nal_unit_type(&element).is_some_and(is_vcl_nalu) {
+                ret.push(sei_nalu.clone());
+                inserted += 1;
+                pending = false;
+            }
+            match element {
+                SyntaxElement::Node(nalu) if nalu.get_field("nal_unit_type") == Some(6) => ret.extend(without_film_grain(nalu)),
+                _ => ret.push(element),
+            }
+        }
+    }
+    eprintln!("Inserted film grain characteristics in {} IDR access units", inserted);
+
+    ret
+}
+
+// Changes to make to the VUI of every SPS. Parts left as None are kept as they are.
+#[derive(Default)]
+pub struct VuiEdit {
+    // sar_width and sar_height, signaled through aspect_ratio_idc where Table E-1 has them
+    pub sample_aspect_ratio: Option<(i32, i32)>,
+    // colour_primaries, transfer_characteristics and matrix_coefficients
+    pub colour_description: Option<(i32, i32, i32)>,
+    pub video_full_range_flag: Option<i32>,
+    // Frames per second as a numerator and denominator, e.g. (30000, 1001)
+    pub frame_rate: Option<(u32, u32)>,
+}
+
+// Table E-1, aspect_ratio_idc 1 to 16
+const SAMPLE_ASPECT_RATIOS: [(i32, i32); 16] = [(1, 1), (12, 11), (10, 11), (16, 11), (40, 33), (24, 11), (20, 11), (32, 11),
+                                                (80, 33), (18, 11), (15, 11), (64, 33), (160, 99), (4, 3), (3, 2), (2, 1)];
+
+// The VUI fields of each part an edit can replace, in syntax order (E.1.1)
+const VUI_ASPECT_RATIO_FIELDS: [&str; 4] = ["aspect_ratio_info_present_flag", "aspect_ratio_idc", "sar_width", "sar_height"];
+const VUI_OVERSCAN_FIELDS: [&str; 2] = ["overscan_info_present_flag", "overscan_appropriate_flag"];
+const VUI_VIDEO_SIGNAL_FIELDS: [&str; 7] = ["video_signal_type_present_flag", "video_format", "video_full_range_flag", "colour_description_present_flag",
+                                            "colour_primaries", "transfer_characteristics", "matrix_coefficients"];
+const VUI_CHROMA_LOC_FIELDS: [&str; 3] = ["chroma_loc_info_present_flag", "chroma_sample_loc_type_top_field", "chroma_sample_loc_type_bottom_field"];
+const VUI_TIMING_FIELDS: [&str; 4] = ["timing_info_present_flag", "num_units_in_tick", "time_scale", "fixed_frame_rate_flag"];
+
+fn field(name: &str, val: i32) -> SyntaxElement {
+    SyntaxElement::Field(SyntaxField { name: name.to_string(), val, line: None })
+}
+
+// Take the elements at the front of a VUI that belong to one part of it
+fn take_vui_part(children: &mut VecDeque<SyntaxElement>, names: &[&str]) -> Vec<SyntaxElement> {
+    let mut ret: Vec<SyntaxElement> = vec![];
+    while children.front().is_some_and(|x| names.contains(&x.name())) {
+        ret.push(children.pop_front().unwrap());
+    }
+    ret
+}
+
+fn part_field(part: &[SyntaxElement], name: &str) -> Option<i32> {
+    part.iter().find_map(|x| match x {
+        SyntaxElement::Field(field) if field.name == name => Some(field.val),
+        _ => None,
+    })
+}
+
+// A VUI with the edit applied, built part by part in syntax order so every presence flag matches what follows it.
+// Parts absent from the original VUI, or with no VUI at all, are signaled as not present unless edited.
+fn edited_vui(vui: Option<&SyntaxNode>, edit: &VuiEdit) -> SyntaxNode {
+    let mut children: VecDeque<SyntaxElement> = vui.map(|x| x.children.clone()).unwrap_or_default();
+    let mut ret = SyntaxNode::new("vui_parameters");
+
+    let aspect_ratio = take_vui_part(&mut children, &VUI_ASPECT_RATIO_FIELDS);
+    match edit.sample_aspect_ratio {
+        Some((sar_width, sar_height)) => {
+            ret.children.push_back(field("aspect_ratio_info_present_flag", 1));
+            match SAMPLE_ASPECT_RATIOS.iter().position(|x| *x == (sar_width, sar_height)) {
+                Some(idx) => ret.children.push_back(field("aspect_ratio_idc", idx as i32 + 1)),
+                None => {
+                    ret.children.push_back(field("aspect_ratio_idc", 255));
+                    ret.children.push_back(field("sar_width", sar_width));
+                    ret.children.push_back(field("sar_height", sar_height));
+                },
+            }
+        },
+        None if aspect_ratio.is_empty() => ret.children.push_back(field("aspect_ratio_info_present_flag", 0)),
+        None => ret.children.extend(aspect_ratio),
+    }
+
+    let overscan = take_vui_part(&mut children, &VUI_OVERSCAN_FIELDS);
+    if overscan.is_empty() {
+        ret.children.push_back(field("overscan_info_present_flag", 0));
+    }
+    ret.children.extend(overscan);
+
+    let video_signal = take_vui_part(&mut children, &VUI_VIDEO_SIGNAL_FIELDS);
+    if edit.colour_description.is_some() || edit.video_full_range_flag.is_some() {
+        // 5 is an unspecified video format, 2 unspecified colour primaries, transfer and matrix (Tables E-2 to E-5)
+        let colour_description = edit.colour_description.or_else(|| {
+            (part_field(&video_signal, "colour_description_present_flag") == Some(1)).then(|| {
+                (part_field(&video_signal, "colour_primaries").unwrap_or(2), part_field(&video_signal, "transfer_characteristics").unwrap_or(2),
+                 part_field(&video_signal, "matrix_coefficients").unwrap_or(2))
+            })
+        });
+        ret.children.push_back(field("video_signal_type_present_flag", 1));
+        ret.children.push_back(field("video_format", part_field(&video_signal, "video_format").unwrap_or(5)));
+        ret.children.push_back(field("video_full_range_flag", edit.video_full_range_flag.or(part_field(&video_signal, "video_full_range_flag")).unwrap_or(0)));
+        ret.children.push_back(field("colour_description_present_flag", i32::from(colour_description.is_some())));
+        if let Some((colour_primaries, transfer_characteristics, matrix_coefficients)) = colour_description {
+            ret.children.push_back(field("colour_primaries", colour_primaries));
+            ret.children.push_back(field("transfer_characteristics", transfer_characteristics));
+            ret.children.push_back(field("matrix_coefficients", matrix_coefficients));
+        }
+    } else if video_signal.is_empty() {
+        ret.children.push_back(field("video_signal_type_present_flag", 0));
+    } else {
+        ret.children.extend(video_signal);
+    }
+
+    let chroma_loc = take_vui_part(&mut children, &VUI_CHROMA_LOC_FIELDS);
+    if chroma_loc.is_empty() {
+        ret.children.push_back(field("chroma_loc_info_present_flag", 0));
+    }
+    ret.children.extend(chroma_loc);
+
+    let timing = take_vui_part(&mut children, &VUI_TIMING_FIELDS);
+    match edit.frame_rate {
+        // A tick is a field period, so time_scale / num_units_in_tick is twice the frame rate (E.2.1)
+        Some((numerator, denominator)) => {
+            let time_scale = i32::try_from(u64::from(numerator) * 2).ok().filter(|_| denominator != 0);
+            let Some(time_scale) = time_scale else {
+                panic!("Frame rate {}/{} can't be signaled", numerator, denominator);
+            };
+            ret.children.push_back(field("timing_info_present_flag", 1));
+            ret.children.push_back(field("num_units_in_tick", denominator as i32));
+            ret.children.push_back(field("time_scale", time_scale));
+            ret.children.push_back(field("fixed_frame_rate_flag", part_field(&timing, "fixed_frame_rate_flag").unwrap_or(1)));
+        },
+        None if timing.is_empty() => ret.children.push_back(field("timing_info_present_flag", 0)),
+        None => ret.children.extend(timing),
+    }
+
+    // HRD parameters, pic_struct_present_flag and the bitstream restrictions are kept as they were
+    if children.is_empty() {
+        for name in ["nal_hrd_parameters_present_flag", "vcl_hrd_parameters_present_flag", "pic_struct_present_flag", "bitstream_restriction_flag"] {
+            ret.children.push_back(field(name, 0));
+        }
+    }
+    ret.children.extend(children);
+
+    ret
+}
+
+// Apply a VUI edit to every SPS and subset SPS, adding a VUI to the ones without, so sample aspect ratio, colour and
+// timing can be set without writing out the rest of the VUI.
+pub fn edit_vui(mut nalus: Vec<SyntaxElement>, edit: &VuiEdit) -> Vec<SyntaxElement> {
+    let mut edited = 0;
+    for element in nalus.iter_mut() {
+        let SyntaxElement::Node(nalu) = element else {
+            continue;
+        };
+        let Some(name) = ["sps", "subset_sps"].into_iter().find(|x| nalu.get_node(x).is_some()) else {
+            continue;
+        };
+        let sps = nalu.get_node_mut(name).unwrap();
+        let vui = edited_vui(sps.get_node("vui_parameters"), edit);
+        match sps.children.iter().position(|x| matches!(x, SyntaxElement::Node(node) if node.name == "vui_parameters")) {
+            Some(idx) => sps.children[idx] = SyntaxElement::Node(vui),
+            None => {
+                sps.set_field("vui_parameters_present_flag", 1);
+                let idx = sps.children.iter().position(|x| x.name() == "vui_parameters_present_flag").unwrap();
+                sps.children.insert(idx + 1, SyntaxElement::Node(vui));
+            },
+        }
+        // The SPS changed length, so it needs fresh trailing bits
+        *element = with_fresh_trailing_bits(nalu.clone());
+        edited += 1;
+    }
+    eprintln!("Edited the VUI of {} SPSs", edited);
+
+    nalus
+}
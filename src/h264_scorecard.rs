@@ -0,0 +1,238 @@
+use crate::bitstream_util::SyntaxElement;
+use crate::bitstream_util::SyntaxNode;
+use crate::h264_analysis::frame_stats;
+use crate::h264_analysis::peak_bitrate_report;
+use crate::h264_analysis::reserved_bits_report;
+use crate::h264_analysis::slice_qp_report;
+use crate::h264_color::check_color_description;
+use crate::h264_corpus::stream_features;
+use crate::h264_parser::nalu_contents;
+use crate::h264_profiles::cpb_br_nal_factor;
+use crate::h264_profiles::cpb_br_vcl_factor;
+use crate::h264_profiles::level_bitrate_limits;
+use crate::h264_profiles::level_max_frame_size;
+use crate::h264_profiles::profile_violations;
+use crate::h264_references::ref_pic_list_modification_report;
+
+#[derive(Clone, Copy, PartialEq, PartialOrd)]
+pub enum Severity {
+    // Worth knowing, but not a problem
+    Info,
+    // Legal, but likely to cause trouble in players or hardware decoders
+    Warning,
+    // A violation of the spec
+    Error,
+}
+
+impl Severity {
+    fn label(&self) -> &'static str {
+        match self {
+            Severity::Info => "INFO",
+            Severity::Warning => "WARNING",
+            Severity::Error => "ERROR",
+        }
+    }
+}
+
+pub struct Finding {
+    pub check: &'static str,
+    pub severity: Severity,
+    pub message: String,
+}
+
+// The checks in the order the scorecard lists them
+const CHECKS: [&str; 7] = ["profile", "level", "hrd", "references", "vui", "reserved bits", "slice qp"];
+
+// Points lost per finding, out of 100
+const ERROR_PENALTY: i32 = 10;
+const WARNING_PENALTY: i32 = 2;
+
+// The "Warning:" lines of a text report, for passes that only produce one
+fn report_warnings(report: &str) -> Vec<String> {
+    report.lines()
+        .filter_map(|x| x.trim_start().strip_prefix("Warning: "))
+        .map(|x| x.to_string())
+        .collect()
+}
+
+// Each distinct SPS once, with the index of its first NALU
+fn distinct_sps(nalus: &[SyntaxElement]) -> Vec<(usize, &SyntaxNode)> {
+    let mut ret: Vec<(usize, &SyntaxNode)> = vec![];
+    let mut seen: Vec<String> = vec![];
+    for (idx, element) in nalus.iter().enumerate() {
+        let SyntaxElement::Node(nalu) = element else {
+            continue;
+        };
+        let Some(sps) = nalu.get_node("sps") else {
+            continue;
+        };
+        let contents = nalu_contents(nalu);
+        if !seen.contains(&contents) {
+            seen.push(contents);
+            ret.push((idx, sps));
+        }
+    }
+
+    ret
+}
+
+// Frame size against MaxFS, including the limit on each side (A.3.1 item h)
+fn level_frame_size_findings(sps: &SyntaxNode, name: &str) -> Vec<Finding> {
+    let level_idc = sps.get_field("level_idc").unwrap_or(0);
+    let Some(max_fs) = level_max_frame_size(sps) else {
+        return vec![Finding { check: "level", severity: Severity::Error, message: format!("{}: unknown level_idc {}", name, level_idc) }];
+    };
+    let width_mbs = sps.get_field("pic_width_in_mbs_minus1").unwrap_or(0) + 1;
+    let height_mbs = (sps.get_field("pic_height_in_mbs_minus1").unwrap_or(0) + 1) *
+        (2 - sps.get_field("frame_mbs_only_flag").unwrap_or(1));
+    let mut ret: Vec<Finding> = vec![];
+    if width_mbs * height_mbs > max_fs {
+        ret.push(Finding { check: "level", severity: Severity::Error,
+                           message: format!("{}: {} macroblocks per frame exceed MaxFS {} of level_idc {}", name, width_mbs * height_mbs, max_fs, level_idc) });
+    } else if width_mbs.max(height_mbs) * width_mbs.max(height_mbs) > 8 * max_fs {
+        ret.push(Finding { check: "level", severity: Severity::Error,
+                           message: format!("{}: {}x{} macroblocks is too narrow for level_idc {}", name, width_mbs, height_mbs, level_idc) });
+    }
+
+    ret
+}
+
+// BitRate and CpbSize of every SchedSelIdx (E.2.2) against the level's MaxBR and MaxCPB
+fn hrd_findings(sps: &SyntaxNode, name: &str) -> Vec<Finding> {
+    let Some(vui) = sps.get_node("vui_parameters") else {
+        return vec![];
+    };
+    let profile_idc = sps.get_field("profile_idc").unwrap_or(0);
+    let mut ret: Vec<Finding> = vec![];
+    for (hrd_name, factor) in [("nal_hrd_parameters", cpb_br_nal_factor(profile_idc)), ("vcl_hrd_parameters", cpb_br_vcl_factor(profile_idc))] {
+        let Some(hrd) = vui.get_node(hrd_name) else {
+            continue;
+        };
+        let Some((max_br, max_cpb)) = level_bitrate_limits(sps) else {
+            continue;
+        };
+        // level_bitrate_limits scales by cpbBrNalFactor
+        let (max_br, max_cpb) = (max_br / cpb_br_nal_factor(profile_idc) * factor, max_cpb / cpb_br_nal_factor(profile_idc) * factor);
+        let bit_rate_scale = hrd.get_field("bit_rate_scale").unwrap_or(0);
+        let cpb_size_scale = hrd.get_field("cpb_size_scale").unwrap_or(0);
+        for i in 0..=hrd.get_field("cpb_cnt_minus1").unwrap_or(0) {
+            let bit_rate = (i64::from(hrd.get_field(&format!("bit_rate_value_minus1[{}]", i)).unwrap_or(0) as u32) + 1) << (6 + bit_rate_scale);
+            let cpb_size = (i64::from(hrd.get_field(&format!("cpb_size_value_minus1[{}]", i)).unwrap_or(0) as u32) + 1) << (4 + cpb_size_scale);
+            if bit_rate > max_br {
+                ret.push(Finding { check: "hrd", severity: Severity::Error,
+                                   message: format!("{}: {} BitRate[{}] {} exceeds the level's {}", name, hrd_name, i, bit_rate, max_br) });
+            }
+            if cpb_size > max_cpb {
+                ret.push(Finding { check: "hrd", severity: Severity::Error,
+                                   message: format!("{}: {} CpbSize[{}] {} exceeds the level's {}", name, hrd_name, i, cpb_size, max_cpb) });
+            }
+        }
+    }
+    if ret.is_empty() && vui.get_node("nal_hrd_parameters").is_none() && vui.get_node("vcl_hrd_parameters").is_none() {
+        ret.push(Finding { check: "hrd", severity: Severity::Info, message: format!("{}: no HRD parameters", name) });
+    }
+
+    ret
+}
+
+fn vui_findings(sps: &SyntaxNode, name: &str) -> Vec<Finding> {
+    let Some(vui) = sps.get_node("vui_parameters") else {
+        return vec![Finding { check: "vui", severity: Severity::Info, message: format!("{}: no VUI", name) }];
+    };
+    let mut ret: Vec<Finding> = vec![];
+    if vui.get_field("timing_info_present_flag").unwrap_or(0) != 0 &&
+        (vui.get_field("num_units_in_tick").unwrap_or(0) == 0 || vui.get_field("time_scale").unwrap_or(0) == 0) {
+        ret.push(Finding { check: "vui", severity: Severity::Error, message: format!("{}: num_units_in_tick and time_scale must be non-zero", name) });
+    }
+    let aspect_ratio_idc = vui.get_field("aspect_ratio_idc").unwrap_or(0);
+    if (17..255).contains(&aspect_ratio_idc) {
+        ret.push(Finding { check: "vui", severity: Severity::Error, message: format!("{}: aspect_ratio_idc {} is reserved", name, aspect_ratio_idc) });
+    }
+    if aspect_ratio_idc == 255 && (vui.get_field("sar_width").unwrap_or(0) == 0 || vui.get_field("sar_height").unwrap_or(0) == 0) {
+        ret.push(Finding { check: "vui", severity: Severity::Warning, message: format!("{}: sample aspect ratio is unspecified (zero)", name) });
+    }
+    if let Some(max_dec_frame_buffering) = vui.get_field("max_dec_frame_buffering") {
+        if max_dec_frame_buffering < sps.get_field("max_num_ref_frames").unwrap_or(0) {
+            ret.push(Finding { check: "vui", severity: Severity::Error,
+                               message: format!("{}: max_dec_frame_buffering {} is less than max_num_ref_frames", name, max_dec_frame_buffering) });
+        }
+    }
+    for warning in check_color_description(sps, vui) {
+        ret.push(Finding { check: "vui", severity: Severity::Warning, message: format!("{}: {}", name, warning) });
+    }
+
+    ret
+}
+
+// Every check on one stream, sorted by check. frame_rate enables the bitrate checks against the level when
+// the stream has no VUI timing.
+pub fn conformance_findings(bitstream: &[u8], nalus: &[SyntaxElement], frame_rate: Option<f64>) -> Vec<Finding> {
+    let mut ret: Vec<Finding> = vec![];
+    let features = stream_features(nalus);
+    let sps_list = distinct_sps(nalus);
+    if sps_list.is_empty() {
+        ret.push(Finding { check: "profile", severity: Severity::Error, message: "no SPS in stream".to_string() });
+    }
+    for (idx, sps) in &sps_list {
+        let name = format!("SPS {} (NALU {})", sps.get_field("seq_paramter_set_id").unwrap_or(0), idx);
+        for violation in profile_violations(sps, &features) {
+            ret.push(Finding { check: "profile", severity: Severity::Error, message: format!("{}: {}", name, violation) });
+        }
+        ret.append(&mut level_frame_size_findings(sps, &name));
+        ret.append(&mut hrd_findings(sps, &name));
+        ret.append(&mut vui_findings(sps, &name));
+    }
+
+    let frames = frame_stats(bitstream, nalus);
+    match (frame_rate, sps_list.first()) {
+        (Some(frame_rate), Some((_, sps))) if !frames.is_empty() => {
+            let window = (frame_rate.round() as usize).max(1);
+            for warning in report_warnings(&peak_bitrate_report(&frames, frame_rate, window, sps)) {
+                ret.push(Finding { check: "level", severity: Severity::Error, message: warning });
+            }
+        },
+        (None, Some(_)) => {
+            ret.push(Finding { check: "level", severity: Severity::Info, message: "no frame rate known, bitrate limits not checked".to_string() });
+        },
+        _ => (),
+    }
+    for warning in report_warnings(&ref_pic_list_modification_report(nalus)) {
+        ret.push(Finding { check: "references", severity: Severity::Warning, message: warning });
+    }
+    for warning in report_warnings(&reserved_bits_report(nalus)) {
+        ret.push(Finding { check: "reserved bits", severity: Severity::Error, message: warning });
+    }
+    for warning in report_warnings(&slice_qp_report(nalus)) {
+        ret.push(Finding { check: "slice qp", severity: Severity::Error, message: warning });
+    }
+    ret.sort_by_key(|x| CHECKS.iter().position(|y| *y == x.check));
+
+    ret
+}
+
+// One report for handing to an encoder vendor: a score, the outcome of each check, then every finding with
+// its severity.
+pub fn conformance_scorecard(bitstream: &[u8], nalus: &[SyntaxElement], frame_rate: Option<f64>) -> String {
+    let findings = conformance_findings(bitstream, nalus, frame_rate);
+    let count = |severity: Severity| findings.iter().filter(|x| x.severity == severity).count() as i32;
+    let (errors, warnings, notes) = (count(Severity::Error), count(Severity::Warning), count(Severity::Info));
+    let score = (100 - ERROR_PENALTY * errors - WARNING_PENALTY * warnings).max(0);
+    let mut ret = format!("score: {}/100 ({} errors, {} warnings, {} notes)\n", score, errors, warnings, notes);
+    for check in CHECKS {
+        let worst = findings.iter()
+            .filter(|x| x.check == check && x.severity != Severity::Info)
+            .map(|x| x.severity)
+            .fold(None, |acc: Option<Severity>, x| if acc.is_some_and(|y| y >= x) { acc } else { Some(x) });
+        let outcome = match worst {
+            Some(Severity::Error) => "FAIL",
+            Some(_) => "pass with warnings",
+            None => "pass",
+        };
+        ret.push_str(&format!("{}: {}\n", check, outcome));
+    }
+    for finding in &findings {
+        ret.push_str(&format!("{} [{}] {}\n", finding.severity.label(), finding.check, finding.message));
+    }
+
+    ret
+}
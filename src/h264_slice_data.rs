@@ -0,0 +1,669 @@
+use crate::bitstream_util::BitstreamProcessor;
+use crate::bitstream_util::FieldType;
+use crate::bitstream_util::SyntaxNode;
+use crate::bitstream_util::VlcCode;
+use crate::h264_parser::SliceType;
+
+// What slice_data() (7.3.4) needs from the parameter sets and header of its slice
+#[derive(Debug)]
+pub struct SliceDataParams {
+    pub slice_type: SliceType,
+    pub first_mb_in_slice: i32,
+    pub mbaff_frame_flag: bool,
+    pub pic_width_in_mbs: i32,
+    pub pic_size_in_mbs: i32,
+    pub chroma_array_type: i32,
+    pub bit_depth_luma: i32,
+    pub bit_depth_chroma: i32,
+    pub transform_8x8_mode_flag: bool,
+    pub direct_8x8_inference_flag: bool,
+    pub num_ref_idx_l0_active_minus1: i32,
+    pub num_ref_idx_l1_active_minus1: i32,
+}
+
+// Table 9-4, coded_block_pattern by codeNum for ChromaArrayType 1 and 2
+const CBP_INTRA: [i32; 48] = [
+    47, 31, 15, 0, 23, 27, 29, 30, 7, 11, 13, 14, 39, 43, 45, 46, 16, 3, 5, 10, 12, 19, 21, 26,
+    28, 35, 37, 42, 44, 1, 2, 4, 8, 17, 18, 20, 24, 6, 9, 22, 25, 32, 33, 34, 36, 40, 38, 41,
+];
+const CBP_INTER: [i32; 48] = [
+    0, 16, 1, 2, 4, 8, 32, 3, 5, 10, 12, 15, 47, 7, 11, 13, 14, 6, 9, 31, 35, 37, 42, 44,
+    33, 34, 36, 40, 39, 43, 45, 46, 17, 18, 20, 24, 19, 21, 26, 28, 23, 27, 29, 30, 22, 25, 38, 41,
+];
+// And for ChromaArrayType 0 and 3, which have no chroma bits
+const CBP_INTRA_NO_CHROMA: [i32; 16] = [15, 0, 7, 11, 13, 14, 3, 5, 10, 12, 1, 2, 4, 8, 6, 9];
+const CBP_INTER_NO_CHROMA: [i32; 16] = [0, 1, 2, 4, 8, 3, 5, 10, 12, 15, 7, 11, 13, 14, 6, 9];
+
+// Tables 9-5 and 9-7 to 9-10 as (length, code, value). coeff_token values are TotalCoeff * 4 + TrailingOnes.
+const COEFF_TOKEN_0_TO_2: [VlcCode; 62] = [
+    (1, 1, 0), (2, 1, 5), (3, 1, 10), (5, 3, 15), (6, 3, 19), (6, 4, 9), (6, 5, 4), (7, 4, 23), (7, 5, 14), (8, 4, 27),
+    (8, 5, 18), (8, 6, 13), (8, 7, 8), (9, 4, 31), (9, 5, 22), (9, 6, 17), (9, 7, 12), (10, 4, 35), (10, 5, 26),
+    (10, 6, 21), (10, 7, 16), (11, 4, 39), (11, 5, 30), (11, 6, 25), (11, 7, 20), (13, 8, 32), (13, 9, 38),
+    (13, 10, 33), (13, 11, 28), (13, 12, 43), (13, 13, 34), (13, 14, 29), (13, 15, 24), (14, 8, 51), (14, 9, 46),
+    (14, 10, 41), (14, 11, 40), (14, 12, 47), (14, 13, 42), (14, 14, 37), (14, 15, 36), (15, 1, 53), (15, 8, 59),
+    (15, 9, 54), (15, 10, 49), (15, 11, 48), (15, 12, 55), (15, 13, 50), (15, 14, 45), (15, 15, 44), (16, 4, 64),
+    (16, 5, 66), (16, 6, 65), (16, 7, 60), (16, 8, 67), (16, 9, 62), (16, 10, 61), (16, 11, 56), (16, 12, 63),
+    (16, 13, 58), (16, 14, 57), (16, 15, 52),
+];
+
+const COEFF_TOKEN_2_TO_4: [VlcCode; 62] = [
+    (2, 2, 5), (2, 3, 0), (3, 3, 10), (4, 4, 19), (4, 5, 15), (5, 6, 23), (5, 7, 9), (6, 4, 31), (6, 5, 18),
+    (6, 6, 17), (6, 7, 8), (6, 8, 27), (6, 9, 14), (6, 10, 13), (6, 11, 4), (7, 4, 35), (7, 5, 22), (7, 6, 21),
+    (7, 7, 12), (8, 4, 20), (8, 5, 26), (8, 6, 25), (8, 7, 16), (9, 4, 39), (9, 5, 30), (9, 6, 29), (9, 7, 24),
+    (11, 8, 47), (11, 9, 38), (11, 10, 37), (11, 11, 32), (11, 12, 43), (11, 13, 34), (11, 14, 33), (11, 15, 28),
+    (12, 8, 44), (12, 9, 46), (12, 10, 45), (12, 11, 40), (12, 12, 51), (12, 13, 42), (12, 14, 41), (12, 15, 36),
+    (13, 1, 63), (13, 6, 58), (13, 7, 56), (13, 8, 59), (13, 9, 54), (13, 10, 53), (13, 11, 52), (13, 12, 55),
+    (13, 13, 50), (13, 14, 49), (13, 15, 48), (14, 4, 67), (14, 5, 66), (14, 6, 65), (14, 7, 64), (14, 8, 61),
+    (14, 9, 60), (14, 10, 62), (14, 11, 57),
+];
+
+const COEFF_TOKEN_4_TO_8: [VlcCode; 62] = [
+    (4, 8, 31), (4, 9, 27), (4, 10, 23), (4, 11, 19), (4, 12, 15), (4, 13, 10), (4, 14, 5), (4, 15, 0), (5, 8, 21),
+    (5, 9, 22), (5, 10, 17), (5, 11, 18), (5, 12, 13), (5, 13, 35), (5, 14, 14), (5, 15, 9), (6, 8, 12), (6, 9, 30),
+    (6, 10, 29), (6, 11, 8), (6, 12, 39), (6, 13, 26), (6, 14, 25), (6, 15, 4), (7, 8, 28), (7, 9, 24), (7, 10, 38),
+    (7, 11, 20), (7, 12, 43), (7, 13, 34), (7, 14, 33), (7, 15, 16), (8, 8, 51), (8, 9, 46), (8, 10, 41), (8, 11, 36),
+    (8, 12, 47), (8, 13, 42), (8, 14, 37), (8, 15, 32), (9, 7, 53), (9, 8, 48), (9, 9, 54), (9, 10, 49), (9, 11, 44),
+    (9, 12, 55), (9, 13, 50), (9, 14, 45), (9, 15, 40), (10, 1, 64), (10, 2, 67), (10, 3, 66), (10, 4, 65),
+    (10, 5, 60), (10, 6, 63), (10, 7, 62), (10, 8, 61), (10, 9, 56), (10, 10, 59), (10, 11, 58), (10, 12, 57),
+    (10, 13, 52),
+];
+
+const COEFF_TOKEN_8_UP: [VlcCode; 62] = [
+    (6, 0, 4), (6, 1, 5), (6, 3, 0), (6, 4, 8), (6, 5, 9), (6, 6, 10), (6, 8, 12), (6, 9, 13), (6, 10, 14),
+    (6, 11, 15), (6, 12, 16), (6, 13, 17), (6, 14, 18), (6, 15, 19), (6, 16, 20), (6, 17, 21), (6, 18, 22),
+    (6, 19, 23), (6, 20, 24), (6, 21, 25), (6, 22, 26), (6, 23, 27), (6, 24, 28), (6, 25, 29), (6, 26, 30),
+    (6, 27, 31), (6, 28, 32), (6, 29, 33), (6, 30, 34), (6, 31, 35), (6, 32, 36), (6, 33, 37), (6, 34, 38),
+    (6, 35, 39), (6, 36, 40), (6, 37, 41), (6, 38, 42), (6, 39, 43), (6, 40, 44), (6, 41, 45), (6, 42, 46),
+    (6, 43, 47), (6, 44, 48), (6, 45, 49), (6, 46, 50), (6, 47, 51), (6, 48, 52), (6, 49, 53), (6, 50, 54),
+    (6, 51, 55), (6, 52, 56), (6, 53, 57), (6, 54, 58), (6, 55, 59), (6, 56, 60), (6, 57, 61), (6, 58, 62),
+    (6, 59, 63), (6, 60, 64), (6, 61, 65), (6, 62, 66), (6, 63, 67),
+];
+
+const COEFF_TOKEN_CHROMA_DC_420: [VlcCode; 14] = [
+    (1, 1, 5), (2, 1, 0), (3, 1, 10), (6, 2, 16), (6, 3, 12), (6, 4, 8), (6, 5, 15), (6, 6, 9), (6, 7, 4), (7, 0, 19),
+    (7, 2, 14), (7, 3, 13), (8, 2, 18), (8, 3, 17),
+];
+
+const COEFF_TOKEN_CHROMA_DC_422: [VlcCode; 30] = [
+    (1, 1, 0), (2, 1, 5), (3, 1, 10), (5, 1, 15), (6, 1, 19), (7, 8, 27), (7, 9, 23), (7, 10, 18), (7, 11, 14),
+    (7, 12, 13), (7, 13, 9), (7, 14, 8), (7, 15, 4), (9, 4, 22), (9, 5, 17), (9, 6, 16), (9, 7, 12), (10, 4, 31),
+    (10, 5, 26), (10, 6, 21), (10, 7, 20), (11, 4, 35), (11, 5, 30), (11, 6, 25), (11, 7, 24), (12, 4, 34),
+    (12, 5, 33), (12, 6, 29), (12, 7, 28), (13, 7, 32),
+];
+
+const TOTAL_ZEROS_4X4: [&[VlcCode]; 15] = [
+    &[
+        (1, 1, 0), (3, 2, 2), (3, 3, 1), (4, 2, 4), (4, 3, 3), (5, 2, 6), (5, 3, 5), (6, 2, 8), (6, 3, 7), (7, 2, 10),
+        (7, 3, 9), (8, 2, 12), (8, 3, 11), (9, 1, 15), (9, 2, 14), (9, 3, 13),
+    ],
+    &[
+        (3, 3, 4), (3, 4, 3), (3, 5, 2), (3, 6, 1), (3, 7, 0), (4, 2, 8), (4, 3, 7), (4, 4, 6), (4, 5, 5), (5, 2, 10),
+        (5, 3, 9), (6, 0, 14), (6, 1, 13), (6, 2, 12), (6, 3, 11),
+    ],
+    &[
+        (3, 3, 7), (3, 4, 6), (3, 5, 3), (3, 6, 2), (3, 7, 1), (4, 2, 8), (4, 3, 5), (4, 4, 4), (4, 5, 0), (5, 1, 12),
+        (5, 2, 10), (5, 3, 9), (6, 0, 13), (6, 1, 11),
+    ],
+    &[
+        (3, 3, 8), (3, 4, 6), (3, 5, 5), (3, 6, 4), (3, 7, 1), (4, 2, 9), (4, 3, 7), (4, 4, 3), (4, 5, 2), (5, 0, 12),
+        (5, 1, 11), (5, 2, 10), (5, 3, 0),
+    ],
+    &[
+        (3, 3, 7), (3, 4, 6), (3, 5, 5), (3, 6, 4), (3, 7, 3), (4, 1, 10), (4, 2, 8), (4, 3, 2), (4, 4, 1), (4, 5, 0),
+        (5, 0, 11), (5, 1, 9),
+    ],
+    &[
+        (3, 1, 9), (3, 2, 7), (3, 3, 6), (3, 4, 5), (3, 5, 4), (3, 6, 3), (3, 7, 2), (4, 1, 8), (5, 1, 1), (6, 0, 10),
+        (6, 1, 0),
+    ],
+    &[
+        (2, 3, 5), (3, 1, 8), (3, 2, 6), (3, 3, 4), (3, 4, 3), (3, 5, 2), (4, 1, 7), (5, 1, 1), (6, 0, 9), (6, 1, 0),
+    ],
+    &[
+        (2, 2, 5), (2, 3, 4), (3, 1, 7), (3, 2, 6), (3, 3, 3), (4, 1, 1), (5, 1, 2), (6, 0, 8), (6, 1, 0),
+    ],
+    &[
+        (2, 1, 6), (2, 2, 4), (2, 3, 3), (3, 1, 5), (4, 1, 2), (5, 1, 7), (6, 0, 1), (6, 1, 0),
+    ],
+    &[
+        (2, 1, 5), (2, 2, 4), (2, 3, 3), (3, 1, 2), (4, 1, 6), (5, 0, 1), (5, 1, 0),
+    ],
+    &[
+        (1, 1, 4), (3, 1, 2), (3, 2, 3), (3, 3, 5), (4, 0, 0), (4, 1, 1),
+    ],
+    &[
+        (1, 1, 3), (2, 1, 2), (3, 1, 4), (4, 0, 0), (4, 1, 1),
+    ],
+    &[
+        (1, 1, 2), (2, 1, 3), (3, 0, 0), (3, 1, 1),
+    ],
+    &[
+        (1, 1, 2), (2, 0, 0), (2, 1, 1),
+    ],
+    &[
+        (1, 0, 0), (1, 1, 1),
+    ],
+];
+
+const TOTAL_ZEROS_CHROMA_DC_420: [&[VlcCode]; 3] = [
+    &[
+        (1, 1, 0), (2, 1, 1), (3, 0, 3), (3, 1, 2),
+    ],
+    &[
+        (1, 1, 0), (2, 0, 2), (2, 1, 1),
+    ],
+    &[
+        (1, 0, 1), (1, 1, 0),
+    ],
+];
+
+const TOTAL_ZEROS_CHROMA_DC_422: [&[VlcCode]; 7] = [
+    &[
+        (1, 1, 0), (3, 2, 1), (3, 3, 2), (4, 1, 5), (4, 2, 3), (4, 3, 4), (5, 0, 7), (5, 1, 6),
+    ],
+    &[
+        (2, 1, 1), (3, 0, 0), (3, 1, 2), (3, 4, 3), (3, 5, 4), (3, 6, 5), (3, 7, 6),
+    ],
+    &[
+        (2, 1, 2), (2, 2, 3), (3, 0, 0), (3, 1, 1), (3, 6, 4), (3, 7, 5),
+    ],
+    &[
+        (2, 0, 1), (2, 1, 2), (2, 2, 3), (3, 6, 0), (3, 7, 4),
+    ],
+    &[
+        (2, 0, 0), (2, 1, 1), (2, 2, 2), (2, 3, 3),
+    ],
+    &[
+        (1, 1, 2), (2, 0, 0), (2, 1, 1),
+    ],
+    &[
+        (1, 0, 0), (1, 1, 1),
+    ],
+];
+
+const RUN_BEFORE: [&[VlcCode]; 7] = [
+    &[
+        (1, 0, 1), (1, 1, 0),
+    ],
+    &[
+        (1, 1, 0), (2, 0, 2), (2, 1, 1),
+    ],
+    &[
+        (2, 0, 3), (2, 1, 2), (2, 2, 1), (2, 3, 0),
+    ],
+    &[
+        (2, 1, 2), (2, 2, 1), (2, 3, 0), (3, 0, 4), (3, 1, 3),
+    ],
+    &[
+        (2, 2, 1), (2, 3, 0), (3, 0, 5), (3, 1, 4), (3, 2, 3), (3, 3, 2),
+    ],
+    &[
+        (2, 3, 0), (3, 0, 1), (3, 1, 2), (3, 2, 4), (3, 3, 3), (3, 4, 6), (3, 5, 5),
+    ],
+    &[
+        (3, 1, 6), (3, 2, 5), (3, 3, 4), (3, 4, 3), (3, 5, 2), (3, 6, 1), (3, 7, 0), (4, 1, 7), (5, 1, 8), (6, 1, 9),
+        (7, 1, 10), (8, 1, 11), (9, 1, 12), (10, 1, 13), (11, 1, 14),
+    ],
+];
+
+#[derive(Clone, Copy, PartialEq)]
+enum PredMode {
+    L0,
+    L1,
+    Bi,
+    Direct,
+}
+
+impl PredMode {
+    fn uses_l0(self) -> bool {
+        self == PredMode::L0 || self == PredMode::Bi
+    }
+
+    fn uses_l1(self) -> bool {
+        self == PredMode::L1 || self == PredMode::Bi
+    }
+}
+
+// The prediction modes of the two partitions of B_L0_L0_16x8 to B_Bi_Bi_8x16, two mb_types per pair
+const B_PARTITION_MODES: [(PredMode, PredMode); 9] = [
+    (PredMode::L0, PredMode::L0), (PredMode::L1, PredMode::L1), (PredMode::L0, PredMode::L1),
+    (PredMode::L1, PredMode::L0), (PredMode::L0, PredMode::Bi), (PredMode::L1, PredMode::Bi),
+    (PredMode::Bi, PredMode::L0), (PredMode::Bi, PredMode::L1), (PredMode::Bi, PredMode::Bi),
+];
+
+// What Tables 7-11, 7-13 and 7-14 say about an mb_type
+enum MbType {
+    INxN,
+    // With the CodedBlockPattern the mb_type implies
+    I16x16(i32),
+    IPcm,
+    // The prediction mode of each partition
+    Inter(Vec<PredMode>),
+    BDirect16x16,
+    // P_8x8, P_8x8ref0 and B_8x8, whose partitions are described by sub_mb_pred
+    Sub8x8 { ref0: bool },
+}
+
+fn mb_type_info(slice_type: SliceType, mb_type: i32) -> MbType {
+    let intra = |x: i32| match x {
+        0 => MbType::INxN,
+        1..=24 => MbType::I16x16((((x - 1) / 4) % 3) << 4 | if x >= 13 { 15 } else { 0 }),
+        25 => MbType::IPcm,
+        _ => panic!("Invalid mb_type {}", mb_type),
+    };
+    match slice_type {
+        SliceType::P | SliceType::SP => match mb_type {
+            0 => MbType::Inter(vec![PredMode::L0]),
+            1 | 2 => MbType::Inter(vec![PredMode::L0, PredMode::L0]),
+            3 => MbType::Sub8x8 { ref0: false },
+            4 => MbType::Sub8x8 { ref0: true },
+            _ => intra(mb_type - 5),
+        },
+        SliceType::B => match mb_type {
+            0 => MbType::BDirect16x16,
+            1 => MbType::Inter(vec![PredMode::L0]),
+            2 => MbType::Inter(vec![PredMode::L1]),
+            3 => MbType::Inter(vec![PredMode::Bi]),
+            4..=21 => {
+                let (first, second) = B_PARTITION_MODES[((mb_type - 4) / 2) as usize];
+                MbType::Inter(vec![first, second])
+            },
+            22 => MbType::Sub8x8 { ref0: false },
+            _ => intra(mb_type - 23),
+        },
+        _ => intra(mb_type),
+    }
+}
+
+// NumSubMbPart and SubMbPredMode of a sub_mb_type (Tables 7-17 and 7-18)
+fn sub_mb_type_info(slice_type: SliceType, sub_mb_type: i32) -> (i32, PredMode) {
+    if slice_type == SliceType::B {
+        match sub_mb_type {
+            0 => (4, PredMode::Direct),
+            1 => (1, PredMode::L0),
+            2 => (1, PredMode::L1),
+            3 => (1, PredMode::Bi),
+            4 | 5 => (2, PredMode::L0),
+            6 | 7 => (2, PredMode::L1),
+            8 | 9 => (2, PredMode::Bi),
+            10 => (4, PredMode::L0),
+            11 => (4, PredMode::L1),
+            12 => (4, PredMode::Bi),
+            _ => panic!("Invalid sub_mb_type {}", sub_mb_type),
+        }
+    } else {
+        match sub_mb_type {
+            0 => (1, PredMode::L0),
+            1 | 2 => (2, PredMode::L0),
+            3 => (4, PredMode::L0),
+            _ => panic!("Invalid sub_mb_type {}", sub_mb_type),
+        }
+    }
+}
+
+// Position in 4x4 blocks of a luma4x4BlkIdx within its macroblock (6.4.3)
+fn luma4x4_position(blk_idx: i32) -> (i32, i32) {
+    (2 * ((blk_idx / 4) % 2) + (blk_idx % 4) % 2, 2 * (blk_idx / 8) + (blk_idx % 4) / 2)
+}
+
+struct SliceDataContext<'a> {
+    params: &'a SliceDataParams,
+    curr_mb_addr: i32,
+    // TotalCoeff( coeff_token ) of the 4x4 blocks of every macroblock of the slice so far, by plane (luma, then
+    // Cb and Cr) and in raster order within the macroblock. The chroma planes hold the chroma AC blocks unless
+    // ChromaArrayType is 3.
+    total_coeff: Vec<[[i32; 16]; 3]>,
+}
+
+impl SliceDataContext<'_> {
+    fn next_macroblock(&mut self) {
+        if self.curr_mb_addr >= self.params.pic_size_in_mbs {
+            panic!("Slice data continues past the last macroblock of the picture");
+        }
+        self.total_coeff.push([[0; 16]; 3]);
+    }
+
+    fn set_total_coeff(&mut self, plane: usize, idx: usize, total_coeff: i32) {
+        if let Some(mb) = self.total_coeff.last_mut() {
+            mb[plane][idx] = total_coeff;
+        }
+    }
+
+    // nN of the block left of or above block (x, y) of a plane w by h blocks, None when it is in a macroblock
+    // that isn't available (6.4.11.4). Without MBAFF or slice groups the macroblocks of a slice are consecutive,
+    // and those of other slices are never available.
+    fn neighbour_total_coeff(&self, plane: usize, x: i32, y: i32, w: i32, h: i32, left: bool) -> Option<i32> {
+        let (mb_offset, x, y) = match (left, x, y) {
+            (true, 0, _) if self.curr_mb_addr % self.params.pic_width_in_mbs == 0 => return None,
+            (true, 0, _) => (1, w - 1, y),
+            (true, _, _) => (0, x - 1, y),
+            (false, _, 0) => (self.params.pic_width_in_mbs, x, h - 1),
+            (false, _, _) => (0, x, y - 1),
+        };
+        let mb_addr = self.curr_mb_addr - mb_offset;
+        if mb_addr < self.params.first_mb_in_slice {
+            return None;
+        }
+        Some(self.total_coeff[(mb_addr - self.params.first_mb_in_slice) as usize][plane][(y * w + x) as usize])
+    }
+
+    // nC for the coeff_token of a block other than chroma DC (9.2.1)
+    fn n_c(&self, plane: usize, x: i32, y: i32, w: i32, h: i32) -> i32 {
+        match (self.neighbour_total_coeff(plane, x, y, w, h, true), self.neighbour_total_coeff(plane, x, y, w, h, false)) {
+            (Some(n_a), Some(n_b)) => (n_a + n_b + 1) >> 1,
+            (Some(n_a), None) => n_a,
+            (None, Some(n_b)) => n_b,
+            (None, None) => 0,
+        }
+    }
+}
+
+// residual_block_cavlc() (7.3.5.3.2). Returns TotalCoeff( coeff_token ).
+fn process_residual_block<A>(node: &mut SyntaxNode, bitstream: &mut A, n_c: i32, max_num_coeff: i32) -> i32
+    where A: BitstreamProcessor {
+    let coeff_token_table: &'static [VlcCode] = match n_c {
+        -2 => &COEFF_TOKEN_CHROMA_DC_422,
+        -1 => &COEFF_TOKEN_CHROMA_DC_420,
+        0..=1 => &COEFF_TOKEN_0_TO_2,
+        2..=3 => &COEFF_TOKEN_2_TO_4,
+        4..=7 => &COEFF_TOKEN_4_TO_8,
+        _ => &COEFF_TOKEN_8_UP,
+    };
+    let coeff_token = bitstream.field(node, "coeff_token", FieldType::Vlc(coeff_token_table), 0);
+    let (total_coeff, trailing_ones) = (coeff_token / 4, coeff_token % 4);
+    if total_coeff == 0 {
+        return 0;
+    }
+    // The levels are decoded only as far as choosing the size of the next level_suffix
+    let mut suffix_length = if total_coeff > 10 && trailing_ones < 3 { 1 } else { 0 };
+    for i in 0..total_coeff {
+        if i < trailing_ones {
+            bitstream.field(node, &format!("trailing_ones_sign_flag[{}]", i), FieldType::Boolean, 1);
+            continue;
+        }
+        let level_prefix = bitstream.field(node, &format!("level_prefix[{}]", i), FieldType::Unary, 0);
+        let mut level_code = level_prefix.min(15) << suffix_length;
+        if suffix_length > 0 || level_prefix >= 14 {
+            let level_suffix_size = if level_prefix == 14 && suffix_length == 0 {
+                4
+            } else if level_prefix >= 15 {
+                level_prefix - 3
+            } else {
+                suffix_length
+            };
+            level_code += bitstream.field(node, &format!("level_suffix[{}]", i), FieldType::UnsignedInt, level_suffix_size as u8);
+        }
+        if level_prefix >= 15 && suffix_length == 0 {
+            level_code += 15;
+        }
+        if level_prefix >= 16 {
+            level_code += (1 << (level_prefix - 3)) - 4096;
+        }
+        if i == trailing_ones && trailing_ones < 3 {
+            level_code += 2;
+        }
+        let level_val = if level_code % 2 == 0 { (level_code + 2) >> 1 } else { (-level_code - 1) >> 1 };
+        if suffix_length == 0 {
+            suffix_length = 1;
+        }
+        if level_val.abs() > (3 << (suffix_length - 1)) && suffix_length < 6 {
+            suffix_length += 1;
+        }
+    }
+    let mut zeros_left = 0;
+    if total_coeff < max_num_coeff {
+        let total_zeros_table = match max_num_coeff {
+            4 => TOTAL_ZEROS_CHROMA_DC_420[(total_coeff - 1) as usize],
+            8 => TOTAL_ZEROS_CHROMA_DC_422[(total_coeff - 1) as usize],
+            _ => TOTAL_ZEROS_4X4[(total_coeff - 1) as usize],
+        };
+        zeros_left = bitstream.field(node, "total_zeros", FieldType::Vlc(total_zeros_table), 0);
+    }
+    for i in 0..total_coeff - 1 {
+        if zeros_left > 0 {
+            zeros_left -= bitstream.field(node, &format!("run_before[{}]", i), FieldType::Vlc(RUN_BEFORE[(zeros_left.min(7) - 1) as usize]), 0);
+        }
+    }
+
+    total_coeff
+}
+
+// residual_luma() (7.3.5.3.1) of plane 0, or of Cb and Cr, planes 1 and 2, when ChromaArrayType is 3
+fn process_residual_luma<A>(node: &mut SyntaxNode, bitstream: &mut A, ctx: &mut SliceDataContext, mb_type: &MbType, cbp: i32, plane: usize)
+    where A: BitstreamProcessor {
+    let intra16x16 = matches!(mb_type, MbType::I16x16(_));
+    let prefix = ["", "Cb", "Cr"][plane];
+    if intra16x16 {
+        let n_c = ctx.n_c(plane, 0, 0, 4, 4);
+        bitstream.subnode(node, &format!("{}Intra16x16DCLevel", prefix), |x, y| {
+            process_residual_block(x, y, n_c, 16);
+        });
+    }
+    for blk_idx in 0..16 {
+        if cbp & (1 << (blk_idx / 4)) == 0 {
+            continue;
+        }
+        let (blk_x, blk_y) = luma4x4_position(blk_idx);
+        let n_c = ctx.n_c(plane, blk_x, blk_y, 4, 4);
+        let (name, max_num_coeff) = if intra16x16 {
+            (format!("{}Intra16x16ACLevel[{}]", prefix, blk_idx), 15)
+        } else {
+            (format!("{}Level4x4[{}]", ["Luma", "Cb", "Cr"][plane], blk_idx), 16)
+        };
+        let mut total_coeff = 0;
+        bitstream.subnode(node, &name, |x, y| total_coeff = process_residual_block(x, y, n_c, max_num_coeff));
+        ctx.set_total_coeff(plane, (blk_y * 4 + blk_x) as usize, total_coeff);
+    }
+}
+
+// residual() (7.3.5.3) as CAVLC codes it, where an 8x8 transform block is sent as four interleaved 4x4 blocks
+fn process_residual<A>(node: &mut SyntaxNode, bitstream: &mut A, ctx: &mut SliceDataContext, mb_type: &MbType, cbp: i32)
+    where A: BitstreamProcessor {
+    let chroma_array_type = ctx.params.chroma_array_type;
+    process_residual_luma(node, bitstream, ctx, mb_type, cbp, 0);
+    if chroma_array_type == 1 || chroma_array_type == 2 {
+        // Chroma DC blocks of 2x2 or 2x4 coefficients, and 4 or 8 AC blocks a component
+        let num_c8x8 = chroma_array_type;
+        let cbp_chroma = cbp >> 4;
+        for i_cb_cr in 0..2 {
+            if cbp_chroma & 3 != 0 {
+                bitstream.subnode(node, &format!("ChromaDCLevel[{}]", i_cb_cr), |x, y| {
+                    process_residual_block(x, y, -num_c8x8, 4 * num_c8x8);
+                });
+            }
+        }
+        for i_cb_cr in 0..2 {
+            for blk_idx in 0..4 * num_c8x8 {
+                if cbp_chroma & 2 == 0 {
+                    continue;
+                }
+                let n_c = ctx.n_c(1 + i_cb_cr, blk_idx % 2, blk_idx / 2, 2, 2 * num_c8x8);
+                let mut total_coeff = 0;
+                bitstream.subnode(node, &format!("ChromaACLevel[{}][{}]", i_cb_cr, blk_idx), |x, y| {
+                    total_coeff = process_residual_block(x, y, n_c, 15);
+                });
+                ctx.set_total_coeff(1 + i_cb_cr, blk_idx as usize, total_coeff);
+            }
+        }
+    } else if chroma_array_type == 3 {
+        process_residual_luma(node, bitstream, ctx, mb_type, cbp, 1);
+        process_residual_luma(node, bitstream, ctx, mb_type, cbp, 2);
+    }
+}
+
+// mb_pred() (7.3.5.1)
+fn process_mb_pred<A>(node: &mut SyntaxNode, bitstream: &mut A, params: &SliceDataParams, mb_type: &MbType, transform_size_8x8_flag: bool)
+    where A: BitstreamProcessor {
+    match mb_type {
+        MbType::INxN | MbType::I16x16(_) => {
+            if matches!(mb_type, MbType::INxN) {
+                let (blocks, size) = if transform_size_8x8_flag { (4, "8x8") } else { (16, "4x4") };
+                for i in 0..blocks {
+                    let prev_intra_pred_mode_flag = bitstream.field(node, &format!("prev_intra{}_pred_mode_flag[{}]", size, i), FieldType::Boolean, 1);
+                    if prev_intra_pred_mode_flag == 0 {
+                        bitstream.field(node, &format!("rem_intra{}_pred_mode[{}]", size, i), FieldType::UnsignedInt, 3);
+                    }
+                }
+            }
+            if params.chroma_array_type == 1 || params.chroma_array_type == 2 {
+                bitstream.field(node, "intra_chroma_pred_mode", FieldType::UnsignedExpGolomb, 0);
+            }
+        },
+        MbType::Inter(modes) => {
+            for (list, num_ref_idx_active_minus1) in [params.num_ref_idx_l0_active_minus1, params.num_ref_idx_l1_active_minus1].into_iter().enumerate() {
+                for (idx, mode) in modes.iter().enumerate() {
+                    if num_ref_idx_active_minus1 > 0 && if list == 0 { mode.uses_l0() } else { mode.uses_l1() } {
+                        bitstream.field(node, &format!("ref_idx_l{}[{}]", list, idx), FieldType::TruncatedExpGolomb, num_ref_idx_active_minus1 as u8);
+                    }
+                }
+            }
+            for list in 0..2 {
+                for (idx, mode) in modes.iter().enumerate() {
+                    if if list == 0 { mode.uses_l0() } else { mode.uses_l1() } {
+                        for comp_idx in 0..2 {
+                            bitstream.field(node, &format!("mvd_l{}[{}][0][{}]", list, idx, comp_idx), FieldType::SignedExpGolomb, 0);
+                        }
+                    }
+                }
+            }
+        },
+        // B_Direct_16x16 has nothing to send
+        _ => (),
+    }
+}
+
+// sub_mb_pred() (7.3.5.2). Returns NumSubMbPart and SubMbPredMode of each sub-macroblock.
+fn process_sub_mb_pred<A>(node: &mut SyntaxNode, bitstream: &mut A, params: &SliceDataParams, ref0: bool) -> [(i32, PredMode); 4]
+    where A: BitstreamProcessor {
+    let mut sub_mbs = [(1, PredMode::L0); 4];
+    for (idx, sub_mb) in sub_mbs.iter_mut().enumerate() {
+        let sub_mb_type = bitstream.field(node, &format!("sub_mb_type[{}]", idx), FieldType::UnsignedExpGolomb, 0);
+        *sub_mb = sub_mb_type_info(params.slice_type, sub_mb_type);
+    }
+    // P_8x8ref0 infers every ref_idx_l0 to be 0
+    if params.num_ref_idx_l0_active_minus1 > 0 && !ref0 {
+        for (idx, (_, mode)) in sub_mbs.iter().enumerate() {
+            if mode.uses_l0() {
+                bitstream.field(node, &format!("ref_idx_l0[{}]", idx), FieldType::TruncatedExpGolomb, params.num_ref_idx_l0_active_minus1 as u8);
+            }
+        }
+    }
+    if params.num_ref_idx_l1_active_minus1 > 0 {
+        for (idx, (_, mode)) in sub_mbs.iter().enumerate() {
+            if mode.uses_l1() {
+                bitstream.field(node, &format!("ref_idx_l1[{}]", idx), FieldType::TruncatedExpGolomb, params.num_ref_idx_l1_active_minus1 as u8);
+            }
+        }
+    }
+    for list in 0..2 {
+        for (idx, (num_sub_mb_part, mode)) in sub_mbs.iter().enumerate() {
+            if if list == 0 { mode.uses_l0() } else { mode.uses_l1() } {
+                for sub_mb_part_idx in 0..*num_sub_mb_part {
+                    for comp_idx in 0..2 {
+                        bitstream.field(node, &format!("mvd_l{}[{}][{}][{}]", list, idx, sub_mb_part_idx, comp_idx), FieldType::SignedExpGolomb, 0);
+                    }
+                }
+            }
+        }
+    }
+
+    sub_mbs
+}
+
+// macroblock_layer() (7.3.5)
+fn process_macroblock_layer<A>(node: &mut SyntaxNode, bitstream: &mut A, ctx: &mut SliceDataContext)
+    where A: BitstreamProcessor {
+    let params = ctx.params;
+    let mb_type = mb_type_info(params.slice_type, bitstream.field(node, "mb_type", FieldType::UnsignedExpGolomb, 0));
+    if let MbType::IPcm = mb_type {
+        let alignment = (8 - bitstream.bit_position() % 8) % 8;
+        if alignment != 0 {
+            bitstream.field(node, "pcm_alignment_zero_bits", FieldType::UnsignedInt, alignment as u8);
+        }
+        for i in 0..256 {
+            bitstream.field(node, &format!("pcm_sample_luma[{}]", i), FieldType::UnsignedInt, params.bit_depth_luma as u8);
+        }
+        // 2 * MbWidthC * MbHeightC
+        let chroma_samples = match params.chroma_array_type {
+            1 => 128,
+            2 => 256,
+            3 => 512,
+            _ => 0,
+        };
+        for i in 0..chroma_samples {
+            bitstream.field(node, &format!("pcm_sample_chroma[{}]", i), FieldType::UnsignedInt, params.bit_depth_chroma as u8);
+        }
+        // Neighbours count every block of an I_PCM macroblock as having 16 coefficients
+        if let Some(mb) = ctx.total_coeff.last_mut() {
+            *mb = [[16; 16]; 3];
+        }
+        return;
+    }
+    let mut transform_size_8x8_flag = false;
+    let mut no_sub_mb_part_size_less_than_8x8_flag = true;
+    if let MbType::Sub8x8 { ref0 } = mb_type {
+        let mut sub_mbs = [(1, PredMode::L0); 4];
+        bitstream.subnode(node, "sub_mb_pred", |x, y| sub_mbs = process_sub_mb_pred(x, y, params, ref0));
+        for (num_sub_mb_part, mode) in sub_mbs {
+            if (mode != PredMode::Direct && num_sub_mb_part > 1) || (mode == PredMode::Direct && !params.direct_8x8_inference_flag) {
+                no_sub_mb_part_size_less_than_8x8_flag = false;
+            }
+        }
+    } else {
+        if params.transform_8x8_mode_flag && matches!(mb_type, MbType::INxN) {
+            transform_size_8x8_flag = bitstream.field(node, "transform_size_8x8_flag", FieldType::Boolean, 1) != 0;
+        }
+        bitstream.subnode(node, "mb_pred", |x, y| process_mb_pred(x, y, params, &mb_type, transform_size_8x8_flag));
+    }
+    let cbp = match mb_type {
+        MbType::I16x16(cbp) => cbp,
+        _ => {
+            let intra = matches!(mb_type, MbType::INxN);
+            let table: &'static [i32] = match (params.chroma_array_type == 1 || params.chroma_array_type == 2, intra) {
+                (true, true) => &CBP_INTRA,
+                (true, false) => &CBP_INTER,
+                (false, true) => &CBP_INTRA_NO_CHROMA,
+                (false, false) => &CBP_INTER_NO_CHROMA,
+            };
+            let cbp = bitstream.field(node, "coded_block_pattern", FieldType::MappedExpGolomb(table), 0);
+            if cbp & 15 != 0 && params.transform_8x8_mode_flag && !intra && no_sub_mb_part_size_less_than_8x8_flag &&
+               (!matches!(mb_type, MbType::BDirect16x16) || params.direct_8x8_inference_flag) {
+                bitstream.field(node, "transform_size_8x8_flag", FieldType::Boolean, 1);
+            }
+            cbp
+        },
+    };
+    if cbp != 0 || matches!(mb_type, MbType::I16x16(_)) {
+        bitstream.field(node, "mb_qp_delta", FieldType::SignedExpGolomb, 0);
+        bitstream.subnode(node, "residual", |x, y| process_residual(x, y, ctx, &mb_type, cbp));
+    }
+}
+
+// slice_data() (7.3.4) of a CAVLC slice without MBAFF or slice groups, macroblock by macroblock. Coefficient
+// levels, motion vectors and prediction modes are kept as coded, not reconstructed.
+pub fn process_slice_data<A>(node: &mut SyntaxNode, bitstream: &mut A, params: &SliceDataParams)
+    where A: BitstreamProcessor {
+    let mut ctx = SliceDataContext { params, curr_mb_addr: params.first_mb_in_slice, total_coeff: vec![] };
+    let mut more_data_flag = true;
+    loop {
+        if params.slice_type != SliceType::I && params.slice_type != SliceType::SI {
+            let mb_skip_run = bitstream.field(node, "mb_skip_run", FieldType::UnsignedExpGolomb, 0);
+            for _i in 0..mb_skip_run {
+                ctx.next_macroblock();
+                ctx.curr_mb_addr += 1;
+            }
+            if mb_skip_run > 0 {
+                more_data_flag = bitstream.more_data(node);
+            }
+        }
+        if more_data_flag {
+            ctx.next_macroblock();
+            bitstream.subnode(node, "macroblock_layer", |x, y| process_macroblock_layer(x, y, &mut ctx));
+            ctx.curr_mb_addr += 1;
+        }
+        more_data_flag = bitstream.more_data(node);
+        if !more_data_flag {
+            break;
+        }
+    }
+}
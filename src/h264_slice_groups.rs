@@ -0,0 +1,241 @@
+use crate::bitstream_util::SyntaxElement;
+use crate::bitstream_util::SyntaxNode;
+use crate::h264_access_units::split_access_units;
+use crate::h264_analysis::ParameterSets;
+use crate::h264_parser::get_slice_header;
+
+// mapUnitToSliceGroupMap (8.2.2.1 to 8.2.2.7)
+fn map_unit_to_slice_group_map(pps: &SyntaxNode, width: i32, height_in_map_units: i32, slice_group_change_cycle: i32) -> Vec<i32> {
+    let size = (width * height_in_map_units) as usize;
+    let num_slice_groups_minus1 = pps.get_field("num_slice_groups_minus1").unwrap_or(0);
+    let field = |name: &str| pps.get_field(name).unwrap_or(0);
+    let direction = field("slice_group_change_direction_flag");
+    let map_units_in_slice_group0 = (slice_group_change_cycle * (field("slice_group_change_rate_minus1") + 1)).min(size as i32) as usize;
+    let size_of_upper_left_group = if direction != 0 { size - map_units_in_slice_group0 } else { map_units_in_slice_group0 };
+    let mut ret = vec![0; size];
+    match field("slice_group_map_type") {
+        // Interleaved
+        0 => {
+            let mut i = 0;
+            while i < size {
+                for group in 0..=num_slice_groups_minus1 {
+                    if i >= size {
+                        break;
+                    }
+                    let run_length = field(&format!("run_length_minus1[{}]", group)) as usize + 1;
+                    ret[i..(i + run_length).min(size)].fill(group);
+                    i += run_length;
+                }
+            }
+        },
+        // Dispersed
+        1 => {
+            for (i, group) in ret.iter_mut().enumerate() {
+                let i = i as i32;
+                *group = ((i % width) + (((i / width) * (num_slice_groups_minus1 + 1)) / 2)) % (num_slice_groups_minus1 + 1);
+            }
+        },
+        // Foreground with left-over, lower groups drawn last so they win where boxes overlap
+        2 => {
+            ret.fill(num_slice_groups_minus1);
+            for group in (0..num_slice_groups_minus1).rev() {
+                let top_left = field(&format!("top_left[{}]", group));
+                let bottom_right = field(&format!("bottom_right[{}]", group));
+                for y in (top_left / width)..=(bottom_right / width) {
+                    for x in (top_left % width)..=(bottom_right % width) {
+                        if let Some(unit) = ret.get_mut((y * width + x) as usize) {
+                            *unit = group;
+                        }
+                    }
+                }
+            }
+        },
+        // Box-out, spiralling from the centre
+        3 => {
+            ret.fill(1);
+            let (mut x, mut y) = ((width - direction) / 2, (height_in_map_units - direction) / 2);
+            let (mut left_bound, mut top_bound, mut right_bound, mut bottom_bound) = (x, y, x, y);
+            let (mut x_dir, mut y_dir) = (direction - 1, direction);
+            let mut k = 0;
+            while k < map_units_in_slice_group0 {
+                let unit = (y * width + x) as usize;
+                let vacant = ret[unit] == 1;
+                if vacant {
+                    ret[unit] = 0;
+                }
+                if x_dir == -1 && x == left_bound {
+                    left_bound = (left_bound - 1).max(0);
+                    x = left_bound;
+                    x_dir = 0;
+                    y_dir = 2 * direction - 1;
+                } else if x_dir == 1 && x == right_bound {
+                    right_bound = (right_bound + 1).min(width - 1);
+                    x = right_bound;
+                    x_dir = 0;
+                    y_dir = 1 - 2 * direction;
+                } else if y_dir == -1 && y == top_bound {
+                    top_bound = (top_bound - 1).max(0);
+                    y = top_bound;
+                    x_dir = 1 - 2 * direction;
+                    y_dir = 0;
+                } else if y_dir == 1 && y == bottom_bound {
+                    bottom_bound = (bottom_bound + 1).min(height_in_map_units - 1);
+                    y = bottom_bound;
+                    x_dir = 2 * direction - 1;
+                    y_dir = 0;
+                } else {
+                    x += x_dir;
+                    y += y_dir;
+                }
+                k += usize::from(vacant);
+            }
+        },
+        // Raster scan
+        4 => {
+            for (i, group) in ret.iter_mut().enumerate() {
+                *group = if i < size_of_upper_left_group { direction } else { 1 - direction };
+            }
+        },
+        // Wipe, column by column
+        5 => {
+            let mut k = 0;
+            for x in 0..width {
+                for y in 0..height_in_map_units {
+                    ret[(y * width + x) as usize] = if k < size_of_upper_left_group { direction } else { 1 - direction };
+                    k += 1;
+                }
+            }
+        },
+        // Explicit
+        6 => {
+            for (i, group) in ret.iter_mut().enumerate() {
+                *group = field(&format!("slice_group_id[{}]", i));
+            }
+        },
+        x => panic!("Invalid slice_group_map_type {}", x),
+    }
+
+    ret
+}
+
+// mbToSliceGroupMap (8.2.2.8), with one row of macroblocks per Vec
+pub fn mb_to_slice_group_map(sps: &SyntaxNode, pps: &SyntaxNode, header: &SyntaxNode) -> Vec<Vec<i32>> {
+    let width = sps.get_field("pic_width_in_mbs_minus1").unwrap_or(0) + 1;
+    // The parser names pic_height_in_map_units_minus1 after the frame_mbs_only case
+    let height_in_map_units = sps.get_field("pic_height_in_mbs_minus1").unwrap_or(0) + 1;
+    let frame_mbs_only = sps.get_field("frame_mbs_only_flag").unwrap_or(1) != 0;
+    let mbaff = sps.get_field("mb_adaptive_frame_field_flag").unwrap_or(0) != 0;
+    let field_pic = header.get_field("field_pic_flag").unwrap_or(0) != 0;
+    let map_units = map_unit_to_slice_group_map(pps, width, height_in_map_units, header.get_field("slice_group_change_cycle").unwrap_or(0));
+    let height_in_mbs = if frame_mbs_only || field_pic { height_in_map_units } else { 2 * height_in_map_units };
+    let mut ret: Vec<Vec<i32>> = vec![];
+    for y in 0..height_in_mbs {
+        ret.push((0..width).map(|x| {
+            // Map units are macroblock pairs in MBAFF and non-MBAFF interlaced frames, but the pairs are addressed
+            // differently: MBAFF numbers macroblocks pair by pair, frames keep raster order.
+            let mb = y * width + x;
+            let unit = if frame_mbs_only || field_pic {
+                mb
+            } else if mbaff {
+                (y / 2) * width + x
+            } else {
+                (mb / (2 * width)) * width + (mb % width)
+            };
+            map_units[unit as usize]
+        }).collect());
+    }
+
+    ret
+}
+
+// How slice_group_report prints each map
+#[derive(Clone, Copy, PartialEq)]
+pub enum SliceGroupMapFormat {
+    // One row of slice group numbers per row of macroblocks
+    Grid,
+    Csv,
+    // Macroblock count of every slice group, then the map in raster order as runs of group x length
+    Runs,
+}
+
+fn map_runs(map: &[Vec<i32>]) -> String {
+    let mut counts: Vec<usize> = vec![];
+    let mut runs: Vec<(i32, usize)> = vec![];
+    for group in map.iter().flatten() {
+        if counts.len() <= *group as usize {
+            counts.resize(*group as usize + 1, 0);
+        }
+        counts[*group as usize] += 1;
+        match runs.last_mut() {
+            Some((last, len)) if last == group => *len += 1,
+            _ => runs.push((*group, 1)),
+        }
+    }
+    let mut ret = String::new();
+    for (group, count) in counts.iter().enumerate() {
+        ret.push_str(&format!("slice group {}: {} macroblocks\n", group, count));
+    }
+    let runs: Vec<String> = runs.iter().map(|(group, len)| format!("{}x{}", group, len)).collect();
+    ret.push_str(&runs.join(" "));
+    ret.push('\n');
+
+    ret
+}
+
+// The macroblock to slice group map of every picture using slice groups. Maps repeated from the previous picture
+// are only noted, since types 0, 1, 2 and 6 never change within a PPS.
+pub fn slice_group_report(nalus: &[SyntaxElement], format: SliceGroupMapFormat) -> String {
+    let mut parameter_sets = ParameterSets::new();
+    let mut ret = String::new();
+    let mut previous: Option<Vec<Vec<i32>>> = None;
+    let mut pictures = 0;
+    for (picture, access_unit) in split_access_units(nalus).into_iter().enumerate() {
+        let mut first_slice: Option<(usize, &SyntaxNode)> = None;
+        for idx in access_unit {
+            let SyntaxElement::Node(nalu) = &nalus[idx] else {
+                continue;
+            };
+            parameter_sets.update(nalu);
+            if first_slice.is_none() {
+                first_slice = get_slice_header(nalu).map(|x| (idx, x));
+            }
+        }
+        let Some((idx, header)) = first_slice else {
+            continue;
+        };
+        let Some((sps, pps)) = parameter_sets.for_slice(header) else {
+            eprintln!("Warning: NALU {} refers to a missing parameter set", idx);
+            continue;
+        };
+        if pps.get_field("num_slice_groups_minus1").unwrap_or(0) == 0 {
+            continue;
+        }
+        pictures += 1;
+        ret.push_str(&format!("picture {} nalu {} pps {} slice_group_map_type {}", picture, idx,
+                              pps.get_field("pic_parameter_set_id").unwrap_or(0), pps.get_field("slice_group_map_type").unwrap_or(0)));
+        if let Some(cycle) = header.get_field("slice_group_change_cycle") {
+            ret.push_str(&format!(" slice_group_change_cycle {}", cycle));
+        }
+        let map = mb_to_slice_group_map(sps, pps, header);
+        if previous.as_ref() == Some(&map) {
+            ret.push_str(": same map as previous picture\n");
+            continue;
+        }
+        ret.push('\n');
+        if format == SliceGroupMapFormat::Runs {
+            ret.push_str(&map_runs(&map));
+        } else {
+            for row in &map {
+                let row: Vec<String> = row.iter().map(|x| x.to_string()).collect();
+                ret.push_str(&row.join(if format == SliceGroupMapFormat::Csv { "," } else { " " }));
+                ret.push('\n');
+            }
+        }
+        previous = Some(map);
+    }
+    if pictures == 0 {
+        ret.push_str("No pictures use slice groups\n");
+    }
+
+    ret
+}
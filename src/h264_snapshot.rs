@@ -0,0 +1,52 @@
+use std::collections::VecDeque;
+use std::env;
+use std::fs;
+use std::path::Path;
+
+use crate::bitstream_util::canonicalize;
+use crate::bitstream_util::syntax_elements_from_string;
+use crate::bitstream_util::SyntaxElement;
+use crate::h264_diff::diff_streams;
+use crate::h264_diff::DiffTolerance;
+use crate::output_format::format_units;
+use crate::output_format::TextFormatter;
+
+// Setting this in the environment makes assert_snapshot write the snapshot instead of comparing against it
+pub const UPDATE_SNAPSHOTS_VAR: &str = "BITSTREAM_TOOL_UPDATE_SNAPSHOTS";
+
+// The canonical text form of a parsed stream, as stored in a snapshot
+pub fn snapshot_text(nalus: &[SyntaxElement]) -> String {
+    let mut nalus = nalus.to_vec();
+    nalus.iter_mut().for_each(canonicalize);
+    format_units(&nalus, &mut TextFormatter::default())
+}
+
+// Compare a parsed stream against a snapshot in the text form -d writes. Returns a report of every mismatch,
+// with the snapshot line each one was read from, if they aren't structurally equal under the tolerance.
+pub fn compare_snapshot(nalus: &[SyntaxElement], snapshot: &str, tolerance: &DiffTolerance) -> Result<(), String> {
+    let mut rows: VecDeque<String> = snapshot.split('\n').map(|x| x.to_string()).collect();
+    let expected: Vec<SyntaxElement> = syntax_elements_from_string(&mut rows).into();
+    let (report, equal) = diff_streams(nalus, &expected, tolerance);
+    if equal {
+        Ok(())
+    } else {
+        Err(format!("a is the parsed stream, b is the snapshot\n{}", report))
+    }
+}
+
+// Test assertion against a snapshot file. While UPDATE_SNAPSHOTS_VAR is set the snapshot is written from the
+// stream instead, so new and intentionally changed snapshots are recorded by rerunning the test with it. A
+// missing snapshot fails otherwise, so one that was never committed can't pass unnoticed.
+pub fn assert_snapshot(nalus: &[SyntaxElement], path: &Path, tolerance: &DiffTolerance) {
+    if env::var_os(UPDATE_SNAPSHOTS_VAR).is_some() {
+        fs::write(path, snapshot_text(nalus)).expect("Cannot write snapshot");
+        return;
+    }
+    if !path.exists() {
+        panic!("Snapshot {} does not exist, set {}=1 to record it", path.display(), UPDATE_SNAPSHOTS_VAR);
+    }
+    let snapshot = fs::read_to_string(path).expect("Cannot read snapshot");
+    if let Err(report) = compare_snapshot(nalus, &snapshot, tolerance) {
+        panic!("Stream does not match snapshot {}\n{}", path.display(), report);
+    }
+}
@@ -0,0 +1,114 @@
+use crate::h264_color::colour_primaries_name;
+use crate::h264_color::matrix_coefficients_name;
+use crate::h264_color::transfer_characteristics_name;
+use crate::h264_parser::nal_unit_type_name;
+use crate::h264_profiles::profile_name;
+
+// Table E-1
+fn aspect_ratio_name(val: i32) -> Option<&'static str> {
+    match val {
+        0 => Some("unspecified"),
+        1 => Some("1:1"),
+        2 => Some("12:11"),
+        3 => Some("10:11"),
+        4 => Some("16:11"),
+        5 => Some("40:33"),
+        6 => Some("24:11"),
+        7 => Some("20:11"),
+        8 => Some("32:11"),
+        9 => Some("80:33"),
+        10 => Some("18:11"),
+        11 => Some("15:11"),
+        12 => Some("64:33"),
+        13 => Some("160:99"),
+        14 => Some("4:3"),
+        15 => Some("3:2"),
+        16 => Some("2:1"),
+        255 => Some("Extended_SAR"),
+        _ => None,
+    }
+}
+
+// Table 7-6, where 5 to 9 also say every slice of the picture has that type
+fn slice_type_name(val: i32) -> Option<&'static str> {
+    match val {
+        0 | 5 => Some("P"),
+        1 | 6 => Some("B"),
+        2 | 7 => Some("I"),
+        3 | 8 => Some("SP"),
+        4 | 9 => Some("SI"),
+        _ => None,
+    }
+}
+
+// Table 7-5
+fn primary_pic_type_name(val: i32) -> Option<&'static str> {
+    match val {
+        0 => Some("I"),
+        1 => Some("I, P"),
+        2 => Some("I, P, B"),
+        3 => Some("SI"),
+        4 => Some("SI, SP"),
+        5 => Some("I, SI"),
+        6 => Some("I, SI, P, SP"),
+        7 => Some("I, SI, P, SP, B"),
+        _ => None,
+    }
+}
+
+// Table D-1
+fn pic_struct_name(val: i32) -> Option<&'static str> {
+    match val {
+        0 => Some("frame"),
+        1 => Some("top field"),
+        2 => Some("bottom field"),
+        3 => Some("top field, bottom field"),
+        4 => Some("bottom field, top field"),
+        5 => Some("top field, bottom field, top field repeated"),
+        6 => Some("bottom field, top field, bottom field repeated"),
+        7 => Some("frame doubling"),
+        8 => Some("frame tripling"),
+        _ => None,
+    }
+}
+
+// The SEI messages the parser knows, by payloadType (Annex D)
+fn payload_type_name(val: i32) -> Option<&'static str> {
+    match val {
+        0 => Some("buffering period"),
+        1 => Some("pic timing"),
+        2 => Some("pan-scan rectangle"),
+        3 => Some("filler payload"),
+        4 => Some("user data registered by ITU-T T.35"),
+        5 => Some("user data unregistered"),
+        6 => Some("recovery point"),
+        19 => Some("film grain characteristics"),
+        23 => Some("tone mapping info"),
+        30 => Some("scalable nesting"),
+        37 => Some("MVC scalable nesting"),
+        56 => Some("green metadata"),
+        137 => Some("mastering display colour volume"),
+        144 => Some("content light level info"),
+        147 => Some("alternative transfer characteristics"),
+        _ => None,
+    }
+}
+
+// What the value of an enumerated field stands for, shown next to it in the text form
+pub fn field_symbol(name: &str, val: i32) -> Option<&'static str> {
+    match name {
+        "nal_unit_type" => Some(nal_unit_type_name(val)),
+        "profile_idc" => Some(profile_name(val)).filter(|x| *x != "unknown"),
+        "slice_type" => slice_type_name(val),
+        "primary_pic_type" => primary_pic_type_name(val),
+        "payloadType" => payload_type_name(val),
+        "pic_struct" => pic_struct_name(val),
+        "chroma_format_idc" => ["monochrome", "4:2:0", "4:2:2", "4:4:4"].get(val as usize).copied(),
+        "aspect_ratio_idc" => aspect_ratio_name(val),
+        "video_format" => ["component", "PAL", "NTSC", "SECAM", "MAC", "unspecified"].get(val as usize).copied(),
+        "colour_primaries" => colour_primaries_name(val),
+        "transfer_characteristics" | "preferred_transfer_characteristics" => transfer_characteristics_name(val),
+        "matrix_coefficients" => matrix_coefficients_name(val),
+        _ => None,
+    }
+}
@@ -0,0 +1,230 @@
+use crate::bitstream_util::SyntaxElement;
+use crate::bitstream_util::SyntaxField;
+use crate::bitstream_util::SyntaxNode;
+use crate::h264_access_units::split_access_units;
+use crate::h264_parser::get_slice_header;
+use crate::h264_poc::compute_picture_order;
+
+// The first clock timestamp of a picture timing SEI, as hours, minutes, seconds and n_frames (D.2.3)
+#[derive(Clone, Copy, PartialEq)]
+pub struct Timecode {
+    pub hours: i64,
+    pub minutes: i64,
+    pub seconds: i64,
+    pub frames: i64,
+}
+
+impl Timecode {
+    // Frames counted since 00:00:00:00. With drop frame counting (counting_type 4), frame numbers 0 to
+    // drop - 1 are skipped at the start of every minute not divisible by 10.
+    fn to_count(self, nominal_rate: i64, drop: i64) -> i64 {
+        let total_minutes = self.hours * 60 + self.minutes;
+        (total_minutes * 60 + self.seconds) * nominal_rate + self.frames - drop * (total_minutes - total_minutes / 10)
+    }
+
+    fn from_count(mut count: i64, nominal_rate: i64, drop: i64) -> Timecode {
+        if drop > 0 {
+            let frames_per_minute = nominal_rate * 60 - drop;
+            let frames_per_10_minutes = frames_per_minute * 10 + drop;
+            let remainder = count % frames_per_10_minutes;
+            count += drop * 9 * (count / frames_per_10_minutes);
+            if remainder > drop {
+                count += drop * ((remainder - drop) / frames_per_minute);
+            }
+        }
+        Timecode {
+            hours: count / (nominal_rate * 3600) % 24,
+            minutes: count / (nominal_rate * 60) % 60,
+            seconds: count / nominal_rate % 60,
+            frames: count % nominal_rate,
+        }
+    }
+
+    fn format(&self, drop: i64) -> String {
+        format!("{:02}:{:02}:{:02}{}{:02}", self.hours, self.minutes, self.seconds, if drop > 0 { ';' } else { ':' }, self.frames)
+    }
+}
+
+// The picture timing SEI message of an access unit, outside of any nesting
+fn pic_timing(access_unit: &[SyntaxElement]) -> Option<&SyntaxNode> {
+    access_unit.iter().find_map(|x| match x {
+        SyntaxElement::Node(nalu) => nalu.get_node("sei")?.children.iter().find_map(|y| match y {
+            SyntaxElement::Node(message) if message.get_field("payloadType") == Some(1) => message.get_node("sei_payload"),
+            _ => None,
+        }),
+        _ => None,
+    })
+}
+
+// Clock ticks an access unit lasts: DeltaTfiDivisor of Table E-6 by pic_struct, or from field_pic_flag when
+// the picture has no pic_struct
+fn access_unit_ticks(access_unit: &[SyntaxElement]) -> i64 {
+    match pic_timing(access_unit).and_then(|x| x.get_field("pic_struct")) {
+        Some(1 | 2) => 1,
+        Some(5 | 6) => 3,
+        Some(7) => 4,
+        Some(8) => 6,
+        Some(_) => 2,
+        None => {
+            let field_pic_flag = access_unit.iter().find_map(|x| match x {
+                SyntaxElement::Node(nalu) => get_slice_header(nalu).map(|y| y.get_field("field_pic_flag").unwrap_or(0)),
+                _ => None,
+            });
+            if field_pic_flag == Some(1) { 1 } else { 2 }
+        },
+    }
+}
+
+// The first clock timestamp of a picture, with values a partial timestamp leaves out taken from the last one
+// (D.2.3), along with its nuit_field_based_flag, counting_type and discontinuity_flag
+fn clock_timestamp(pic_timing: &SyntaxNode, last: Option<Timecode>) -> Option<(Timecode, i32, i32, bool)> {
+    if pic_timing.get_field("clock_timestamp_flag[0]") != Some(1) {
+        return None;
+    }
+    let last = last.unwrap_or(Timecode { hours: 0, minutes: 0, seconds: 0, frames: 0 });
+    let field = |name: &str, default: i64| pic_timing.get_field(&format!("{}[0]", name)).map(i64::from).unwrap_or(default);
+    let timecode = Timecode {
+        hours: field("hours_value", last.hours),
+        minutes: field("minutes_value", last.minutes),
+        seconds: field("seconds_value", last.seconds),
+        frames: field("n_frames", 0),
+    };
+    Some((timecode, field("nuit_field_based_flag", 0) as i32, field("counting_type", 0) as i32, field("discontinuity_flag", 0) != 0))
+}
+
+struct TimecodeEntry {
+    access_unit: usize,
+    timecode: Timecode,
+    expected: Timecode,
+    drop: i64,
+}
+
+// Timecode of every access unit that carries one, in output order, next to the one expected from the clock
+// ticks that passed since the first timecode, or the last with discontinuity_flag set. Access units are indexed
+// in decode order.
+fn timecode_entries(nalus: &[SyntaxElement], frame_rate: f64) -> Vec<TimecodeEntry> {
+    let access_units = split_access_units(nalus);
+    let mut epoch = 0;
+    let mut order: Vec<(i32, i32, usize)> = vec![];
+    for picture in compute_picture_order(nalus) {
+        if picture.resets_order {
+            epoch += 1;
+        }
+        order.push((epoch, picture.pic_order_cnt, picture.access_unit));
+    }
+    order.sort();
+
+    let mut ret: Vec<TimecodeEntry> = vec![];
+    let mut last: Option<Timecode> = None;
+    // Frame count the run of timecodes started at, and the clock ticks since
+    let mut start: Option<(i64, i64)> = None;
+    for (_, _, idx) in order {
+        let access_unit = &nalus[access_units[idx].clone()];
+        if let Some((timecode, nuit_field_based_flag, counting_type, discontinuity_flag)) =
+            pic_timing(access_unit).and_then(|x| clock_timestamp(x, last)) {
+            last = Some(timecode);
+            // n_frames counts units of 1 + nuit_field_based_flag clock ticks, with two ticks to a frame
+            let ticks_per_unit = 1 + nuit_field_based_flag as i64;
+            let nominal_rate = (frame_rate * 2.0 / ticks_per_unit as f64 - 1e-6).ceil() as i64;
+            let drop = if counting_type == 4 { nominal_rate / 15 } else { 0 };
+            let count = timecode.to_count(nominal_rate, drop);
+            let expected = match start {
+                Some((start_count, ticks)) if !discontinuity_flag => start_count + ticks / ticks_per_unit,
+                _ => {
+                    start = Some((count, 0));
+                    count
+                },
+            };
+            ret.push(TimecodeEntry { access_unit: idx, timecode, expected: Timecode::from_count(expected, nominal_rate, drop), drop });
+        }
+        if let Some(start) = start.as_mut() {
+            start.1 += access_unit_ticks(access_unit);
+        }
+    }
+
+    ret
+}
+
+// One line per access unit carrying a timecode, in output order, flagging those that don't follow on from the
+// timecode before them
+pub fn timecode_report(nalus: &[SyntaxElement], frame_rate: f64) -> String {
+    let entries = timecode_entries(nalus, frame_rate);
+    let mut ret = String::new();
+    let mut wrong = 0;
+    for entry in &entries {
+        ret.push_str(&format!("{}\t{}", entry.access_unit, entry.timecode.format(entry.drop)));
+        if entry.timecode != entry.expected {
+            ret.push_str(&format!("\texpected {}", entry.expected.format(entry.drop)));
+            wrong += 1;
+        }
+        ret.push('\n');
+    }
+    ret.push_str(&format!("{} timecodes, {} out of sequence\n", entries.len(), wrong));
+
+    ret
+}
+
+// Rewrite the fields of the first clock timestamp from ct_type on as a full timestamp
+fn set_clock_timestamp(pic_timing: &mut SyntaxNode, timecode: Timecode, cnt_dropped_flag: bool) {
+    let start = pic_timing.children.iter()
+        .position(|x| matches!(x, SyntaxElement::Field(field) if field.name == "clock_timestamp_flag[0]"))
+        .unwrap() + 1;
+    let mut end = start;
+    while matches!(pic_timing.children.get(end), Some(SyntaxElement::Field(field)) if field.name.ends_with("[0]")) {
+        end += 1;
+    }
+    let old: Vec<SyntaxElement> = pic_timing.children.drain(start..end).collect();
+    let old_field = |name: &str| old.iter().find_map(|x| match x {
+        SyntaxElement::Field(field) if field.name == format!("{}[0]", name) => Some(field.val),
+        _ => None,
+    });
+    let mut fields: Vec<(&str, i32)> = vec![
+        ("ct_type", old_field("ct_type").unwrap_or(0)),
+        ("nuit_field_based_flag", old_field("nuit_field_based_flag").unwrap_or(0)),
+        ("counting_type", old_field("counting_type").unwrap_or(0)),
+        ("full_timestamp_flag", 1),
+        ("discontinuity_flag", old_field("discontinuity_flag").unwrap_or(0)),
+        ("cnt_dropped_flag", cnt_dropped_flag as i32),
+        ("n_frames", timecode.frames as i32),
+        ("seconds_value", timecode.seconds as i32),
+        ("minutes_value", timecode.minutes as i32),
+        ("hours_value", timecode.hours as i32),
+    ];
+    if let Some(time_offset) = old_field("time_offset") {
+        fields.push(("time_offset", time_offset));
+    }
+    for (offset, (name, val)) in fields.into_iter().enumerate() {
+        pic_timing.children.insert(start + offset, SyntaxElement::Field(SyntaxField { name: format!("{}[0]", name), val, line: None }));
+    }
+}
+
+// Replace every timecode that doesn't follow on from the one before it with the expected one, written as a
+// full timestamp. A timecode with discontinuity_flag set starts a new run instead.
+pub fn fix_timecodes(mut nalus: Vec<SyntaxElement>, frame_rate: f64) -> Vec<SyntaxElement> {
+    let access_units = split_access_units(&nalus);
+    let mut fixed = 0;
+    for entry in timecode_entries(&nalus, frame_rate) {
+        if entry.timecode == entry.expected {
+            continue;
+        }
+        let timecode = entry.expected;
+        let cnt_dropped_flag = entry.drop > 0 && timecode.seconds == 0 && timecode.frames == entry.drop && timecode.minutes % 10 != 0;
+        for element in &mut nalus[access_units[entry.access_unit].clone()] {
+            let SyntaxElement::Node(nalu) = element else {
+                continue;
+            };
+            let Some(message) = nalu.get_node_mut("sei").and_then(|x| x.children.iter_mut().find_map(|y| match y {
+                SyntaxElement::Node(message) if message.get_field("payloadType") == Some(1) => Some(message),
+                _ => None,
+            })) else {
+                continue;
+            };
+            set_clock_timestamp(message.get_node_mut("sei_payload").unwrap(), timecode, cnt_dropped_flag);
+            fixed += 1;
+            break;
+        }
+    }
+    eprintln!("Fixed {} timecodes", fixed);
+
+    nalus
+}
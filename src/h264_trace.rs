@@ -0,0 +1,87 @@
+use crate::bitstream_util::SyntaxElement;
+use crate::bitstream_util::TraceEntry;
+use crate::h264_access_units::split_access_units;
+use crate::h264_analysis::is_idr_access_unit;
+use crate::h264_parser::trace_h264;
+use crate::h264_rewrite::nalu_chunks;
+
+// av_log prefixes every line with the context; scripts comparing traces strip the address anyway
+const PREFIX: &str = "[trace_headers @ 0x0] ";
+
+// The header libavcodec's coded bitstream code prints in front of each NALU type it decomposes
+fn header_name(nal_unit_type: i32) -> Option<&'static str> {
+    match nal_unit_type {
+        1 | 5 => Some("Slice Header"),
+        6 => Some("Supplemental Enhancement Information"),
+        7 => Some("Sequence Parameter Set"),
+        8 => Some("Picture Parameter Set"),
+        9 => Some("Access Unit Delimiter"),
+        10 => Some("End of Sequence"),
+        11 => Some("End of Stream"),
+        12 => Some("Filler Data"),
+        13 => Some("Sequence Parameter Set Extension"),
+        _ => None,
+    }
+}
+
+// Field names that differ from libavcodec's
+fn ffmpeg_field_name(name: &str) -> &str {
+    match name {
+        "seq_paramter_set_id" => "seq_parameter_set_id",
+        "pic_height_in_mbs_minus1" => "pic_height_in_map_units_minus1",
+        _ => name,
+    }
+}
+
+// Same layout as ff_cbs_trace_syntax_element(): position, name, then the coded bits right aligned to column 73
+fn trace_line(position: usize, name: &str, bits: &str, value: i32) -> String {
+    let pad = if name.len() + bits.len() > 60 { bits.len() + 2 } else { 61 - name.len() };
+    format!("{}{:<10}  {}{:>pad$} = {}\n", PREFIX, position, name, bits, value, pad = pad)
+}
+
+// rbsp_trailing_bits() as individual bits, when the payload holds nothing else
+fn trailing_bits_lines(position: usize, data: &[u8]) -> String {
+    let bits = 8 - position % 8;
+    if data != [1 << (bits - 1)] {
+        return "".to_string();
+    }
+    let mut ret = trace_line(position, "rbsp_stop_one_bit", "1", 1);
+    for i in 1..bits {
+        ret.push_str(&trace_line(position + i, "rbsp_alignment_zero_bit", "0", 0));
+    }
+
+    ret
+}
+
+// Headers in the layout of ffmpeg's trace_headers bitstream filter, one packet per access unit.
+pub fn ffmpeg_trace(bitstream: &[u8]) -> String {
+    let (nalus, traces) = trace_h264(bitstream);
+    let chunk_sizes: Vec<usize> = nalu_chunks(bitstream).into_iter()
+        .filter(|x| x.0.is_some())
+        .map(|x| x.1.len())
+        .collect();
+    let mut ret = "".to_string();
+    for access_unit in split_access_units(&nalus) {
+        let bytes: usize = chunk_sizes[access_unit.clone()].iter().sum();
+        let key_frame = if is_idr_access_unit(&nalus[access_unit.clone()]) { ", key frame" } else { "" };
+        ret.push_str(&format!("{}Packet: {} bytes{}, pts NOPTS, dts NOPTS.\n", PREFIX, bytes, key_frame));
+        for idx in access_unit {
+            let SyntaxElement::Node(nalu) = &nalus[idx] else {
+                continue;
+            };
+            let Some(header) = nalu.get_field("nal_unit_type").and_then(header_name) else {
+                continue;
+            };
+            ret.push_str(&format!("{}{}\n", PREFIX, header));
+            for entry in &traces[idx] {
+                match entry {
+                    TraceEntry::Field { position, name, bits, value } => ret.push_str(&trace_line(*position, ffmpeg_field_name(name), bits, *value)),
+                    TraceEntry::Payload { position, name, data } if name == "trailing_bits" => ret.push_str(&trailing_bits_lines(*position, data)),
+                    _ => (),
+                }
+            }
+        }
+    }
+
+    ret
+}
@@ -0,0 +1,108 @@
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+
+use crate::json::JsonValue;
+
+// What running a job file takes, in order
+pub enum JobStep {
+    // One run of the command line tool, with the arguments that follow the program name
+    Run(Vec<String>),
+    // The validation reports written so far, each under its check's name, concatenated into one file
+    Report(Vec<(String, String)>, String),
+}
+
+// The directory intermediate streams go in, removed when dropped, so a step that panics doesn't leave it behind
+pub struct WorkDir {
+    pub path: PathBuf,
+}
+
+impl WorkDir {
+    pub fn create(path: PathBuf) -> WorkDir {
+        fs::create_dir_all(&path).expect("Cannot create directory");
+        WorkDir { path }
+    }
+}
+
+impl Drop for WorkDir {
+    fn drop(&mut self) {
+        // Panicking again while unwinding would abort, so a failed removal is only reported
+        if let Err(err) = fs::remove_dir_all(&self.path) {
+            eprintln!("Warning: cannot remove {}: {}", self.path.display(), err);
+        }
+    }
+}
+
+fn path_string(path: &Path) -> String {
+    path.to_string_lossy().to_string()
+}
+
+fn string_list(job: &JsonValue, table: &str, key: &str) -> Vec<String> {
+    let Some(val) = job.get(table).and_then(|x| x.get(key)) else {
+        return vec![];
+    };
+    val.as_array()
+        .unwrap_or_else(|| panic!("{}.{} must be an array of strings", table, key))
+        .iter()
+        .map(|x| x.as_str().unwrap_or_else(|| panic!("{}.{} must be an array of strings", table, key)).to_string())
+        .collect()
+}
+
+fn string_value(job: &JsonValue, table: Option<&str>, key: &str) -> Option<String> {
+    let table = match table {
+        Some(name) => job.get(name)?,
+        None => job,
+    };
+    table.get(key).map(|x| x.as_str().unwrap_or_else(|| panic!("{} must be a string", key)).to_string())
+}
+
+// Turns a parsed job file into runs of the existing modes: the [filters] rewrite options go to -r, the [edits]
+// to patch, then the [outputs] stream and dump are written and each [validations] check runs on the result.
+// Relative paths are taken from base_dir, the job file's directory, and intermediate streams go in work_dir.
+pub fn job_steps(job: &JsonValue, base_dir: &Path, work_dir: &Path) -> Vec<JobStep> {
+    let resolve = |x: String| path_string(&base_dir.join(x));
+    let mut current = resolve(string_value(job, None, "input").expect("Job has no input"));
+    let codec: Vec<String> = string_value(job, None, "codec").map(|x| format!("--codec={}", x)).into_iter().collect();
+    let mut ret: Vec<JobStep> = vec![];
+
+    let rewrite = string_list(job, "filters", "rewrite");
+    if !rewrite.is_empty() {
+        let out = path_string(&work_dir.join("filtered"));
+        ret.push(JobStep::Run([vec!["-r".to_string(), current, out.clone()], rewrite, codec.clone()].concat()));
+        current = out;
+    }
+    let edits: Vec<String> = string_list(job, "edits", "set").iter().map(|x| format!("--set={}", x)).collect();
+    if !edits.is_empty() {
+        let out = path_string(&work_dir.join("edited"));
+        let nalu = job.get("edits").and_then(|x| x.get("nalu")).map(|x| format!("--nalu={}", x.as_f64().expect("edits.nalu must be a number")));
+        ret.push(JobStep::Run([vec!["patch".to_string(), current, out.clone()], edits, nalu.into_iter().collect()].concat()));
+        current = out;
+    }
+
+    if let Some(stream) = string_value(job, Some("outputs"), "stream") {
+        // passthrough with nothing to drop copies the stream byte for byte
+        ret.push(JobStep::Run(vec!["passthrough".to_string(), current.clone(), resolve(stream)]));
+    }
+    if let Some(dump) = string_value(job, Some("outputs"), "dump") {
+        let format: Vec<String> = string_value(job, Some("outputs"), "format").map(|x| format!("--format={}", x)).into_iter().collect();
+        ret.push(JobStep::Run([vec!["-d".to_string(), current.clone(), resolve(dump)], format, codec.clone()].concat()));
+    }
+
+    // A check is a mode taking <in file> [out file], optionally followed by its options
+    let report = string_value(job, Some("outputs"), "report");
+    let mut parts: Vec<(String, String)> = vec![];
+    for (i, check) in string_list(job, "validations", "checks").iter().enumerate() {
+        let mut words = check.split_whitespace().map(|x| x.to_string());
+        let Some(mode) = words.next() else {
+            continue;
+        };
+        let out = if report.is_some() { path_string(&work_dir.join(format!("check{}", i))) } else { "-".to_string() };
+        ret.push(JobStep::Run([vec![mode, current.clone(), out.clone()], words.collect(), codec.clone()].concat()));
+        parts.push((check.clone(), out));
+    }
+    if let Some(report) = report {
+        ret.push(JobStep::Report(parts, resolve(report)));
+    }
+
+    ret
+}
@@ -0,0 +1,165 @@
+// Just enough JSON for the description files the tool reads. Malformed input panics, like the text form parser.
+pub enum JsonValue {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<JsonValue>),
+    // Members in the order they appear
+    Object(Vec<(String, JsonValue)>),
+}
+
+impl JsonValue {
+    pub fn get(&self, key: &str) -> Option<&JsonValue> {
+        match self {
+            JsonValue::Object(members) => members.iter().find(|x| x.0 == key).map(|x| &x.1),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            JsonValue::Number(val) => Some(*val),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[JsonValue]> {
+        match self {
+            JsonValue::Array(elements) => Some(elements),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            JsonValue::String(val) => Some(val),
+            _ => None,
+        }
+    }
+}
+
+fn skip_whitespace(chars: &[char], pos: &mut usize) {
+    while *pos < chars.len() && chars[*pos].is_whitespace() {
+        *pos += 1;
+    }
+}
+
+fn expect_char(chars: &[char], pos: &mut usize, expected: char) {
+    skip_whitespace(chars, pos);
+    if chars.get(*pos) != Some(&expected) {
+        panic!("Expected '{}' at offset {} in JSON", expected, pos);
+    }
+    *pos += 1;
+}
+
+fn parse_string(chars: &[char], pos: &mut usize) -> String {
+    expect_char(chars, pos, '"');
+    let mut ret = String::new();
+    loop {
+        let Some(c) = chars.get(*pos) else {
+            panic!("Unterminated string in JSON");
+        };
+        *pos += 1;
+        match c {
+            '"' => return ret,
+            '\\' => {
+                let escaped = chars.get(*pos).copied().unwrap_or_else(|| panic!("Unterminated string in JSON"));
+                *pos += 1;
+                match escaped {
+                    'n' => ret.push('\n'),
+                    't' => ret.push('\t'),
+                    'r' => ret.push('\r'),
+                    'b' => ret.push('\u{8}'),
+                    'f' => ret.push('\u{c}'),
+                    'u' => {
+                        let hex: String = chars.get(*pos..*pos + 4).unwrap_or_else(|| panic!("Invalid \\u escape in JSON")).iter().collect();
+                        let code = u32::from_str_radix(&hex, 16).unwrap_or_else(|_| panic!("Invalid \\u escape in JSON"));
+                        ret.push(char::from_u32(code).unwrap_or('\u{FFFD}'));
+                        *pos += 4;
+                    },
+                    _ => ret.push(escaped),
+                }
+            },
+            _ => ret.push(*c),
+        }
+    }
+}
+
+fn parse_value(chars: &[char], pos: &mut usize) -> JsonValue {
+    skip_whitespace(chars, pos);
+    match chars.get(*pos) {
+        Some('{') => {
+            *pos += 1;
+            let mut members: Vec<(String, JsonValue)> = vec![];
+            skip_whitespace(chars, pos);
+            if chars.get(*pos) == Some(&'}') {
+                *pos += 1;
+                return JsonValue::Object(members);
+            }
+            loop {
+                skip_whitespace(chars, pos);
+                let key = parse_string(chars, pos);
+                expect_char(chars, pos, ':');
+                members.push((key, parse_value(chars, pos)));
+                skip_whitespace(chars, pos);
+                match chars.get(*pos) {
+                    Some(',') => *pos += 1,
+                    Some('}') => {
+                        *pos += 1;
+                        return JsonValue::Object(members);
+                    },
+                    _ => panic!("Expected ',' or '}}' at offset {} in JSON", pos),
+                }
+            }
+        },
+        Some('[') => {
+            *pos += 1;
+            let mut elements: Vec<JsonValue> = vec![];
+            skip_whitespace(chars, pos);
+            if chars.get(*pos) == Some(&']') {
+                *pos += 1;
+                return JsonValue::Array(elements);
+            }
+            loop {
+                elements.push(parse_value(chars, pos));
+                skip_whitespace(chars, pos);
+                match chars.get(*pos) {
+                    Some(',') => *pos += 1,
+                    Some(']') => {
+                        *pos += 1;
+                        return JsonValue::Array(elements);
+                    },
+                    _ => panic!("Expected ',' or ']' at offset {} in JSON", pos),
+                }
+            }
+        },
+        Some('"') => JsonValue::String(parse_string(chars, pos)),
+        Some(_) => {
+            let start = *pos;
+            while *pos < chars.len() && (chars[*pos].is_alphanumeric() || "+-.".contains(chars[*pos])) {
+                *pos += 1;
+            }
+            let word: String = chars[start..*pos].iter().collect();
+            match word.as_str() {
+                "null" => JsonValue::Null,
+                "true" => JsonValue::Bool(true),
+                "false" => JsonValue::Bool(false),
+                _ => JsonValue::Number(word.parse().unwrap_or_else(|_| panic!("Invalid value {} at offset {} in JSON", word, start))),
+            }
+        },
+        None => panic!("Unexpected end of JSON"),
+    }
+}
+
+pub fn parse_json(text: &str) -> JsonValue {
+    let chars: Vec<char> = text.chars().collect();
+    let mut pos = 0;
+    let ret = parse_value(&chars, &mut pos);
+    skip_whitespace(&chars, &mut pos);
+    if pos != chars.len() {
+        panic!("Unexpected '{}' at offset {} in JSON", chars[pos], pos);
+    }
+
+    ret
+}
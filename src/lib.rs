@@ -0,0 +1,56 @@
+// Library interface, so fuzzers and other tools can drive the parser without going through the CLI.
+// Each codec and container sits behind a Cargo feature of the same name so embedded consumers only build
+// what they use.
+pub mod bitstream_util;
+#[cfg(feature = "flv")]
+pub mod flv;
+#[cfg(feature = "h264")]
+pub mod fuzz;
+#[cfg(feature = "h264")]
+pub mod h264_access_units;
+#[cfg(feature = "h264")]
+pub mod h264_analysis;
+#[cfg(feature = "h264")]
+pub mod h264_cache;
+#[cfg(feature = "h264")]
+pub mod h264_captions;
+#[cfg(feature = "h264")]
+pub mod h264_color;
+#[cfg(feature = "h264")]
+pub mod h264_corpus;
+#[cfg(feature = "h264")]
+pub mod h264_diff;
+#[cfg(feature = "h264")]
+pub mod h264_generate;
+#[cfg(feature = "h264")]
+pub mod h264_parser;
+#[cfg(feature = "h264")]
+pub mod h264_poc;
+#[cfg(feature = "h264")]
+pub mod h264_probe;
+#[cfg(feature = "h264")]
+pub mod h264_profiles;
+#[cfg(feature = "h264")]
+pub mod h264_references;
+#[cfg(feature = "h264")]
+pub mod h264_rewrite;
+#[cfg(feature = "h264")]
+pub mod h264_scorecard;
+#[cfg(feature = "h264")]
+pub mod h264_slice_data;
+#[cfg(feature = "h264")]
+pub mod h264_slice_groups;
+#[cfg(feature = "h264")]
+pub mod h264_snapshot;
+#[cfg(feature = "h264")]
+pub mod h264_symbols;
+#[cfg(feature = "h264")]
+pub mod h264_timecode;
+#[cfg(feature = "h264")]
+pub mod h264_trace;
+pub mod job;
+pub mod json;
+pub mod nal_header;
+pub mod output_format;
+pub mod stream;
+pub mod toml;
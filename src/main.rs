@@ -1,27 +1,605 @@
-mod bitstream_util;
-mod h264_parser;
-
 use std::env;
 use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::panic;
+
+use bitstream_tool::bitstream_util;
+use bitstream_tool::flv;
+use bitstream_tool::fuzz;
+use bitstream_tool::h264_analysis;
+use bitstream_tool::h264_captions;
+use bitstream_tool::h264_color;
+use bitstream_tool::h264_corpus;
+use bitstream_tool::h264_diff;
+use bitstream_tool::h264_generate;
+use bitstream_tool::h264_parser;
+use bitstream_tool::h264_poc;
+use bitstream_tool::h264_probe;
+use bitstream_tool::h264_profiles;
+use bitstream_tool::h264_references;
+use bitstream_tool::h264_rewrite;
+use bitstream_tool::h264_scorecard;
+use bitstream_tool::h264_slice_groups;
+use bitstream_tool::h264_timecode;
+use bitstream_tool::h264_trace;
+use bitstream_tool::job;
+use bitstream_tool::output_format;
+use bitstream_tool::stream;
+use bitstream_tool::toml;
+
+// Output files named "-" go to stdout
+fn write_output<C: AsRef<[u8]>>(filename: &str, contents: C) {
+    if filename == "-" {
+        std::io::stdout().write_all(contents.as_ref()).expect("Cannot write to stdout");
+    } else {
+        fs::write(filename, contents).expect("Cannot write file");
+    }
+}
+
+fn output_writer(filename: &str) -> Box<dyn Write> {
+    if filename == "-" {
+        Box::new(std::io::stdout())
+    } else {
+        Box::new(std::io::BufWriter::new(fs::File::create(filename).expect("Cannot write file")))
+    }
+}
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
+    run(env::args().collect());
+}
+
+// One invocation of the tool, args[0] being the program name. Job files run several through here.
+fn run(args: Vec<String>) {
     let mode = &args[1];
-    let in_filename = &args[2];
-    let out_filename = &args[3];
+    let codec_name = args[2..].iter().find_map(|x| x.strip_prefix("--codec=")).unwrap_or("h264");
+    let codec = stream::codec_from_name(codec_name).expect("Unsupported codec");
+    let options: Vec<&String> = args[2..].iter().filter(|x| x.starts_with("--") && !x.starts_with("--codec=")).collect();
+    let files: Vec<&String> = args[2..].iter().filter(|x| !x.starts_with("--")).collect();
+    let in_filename = files[0];
+    let out_filename = files.get(1).map(|x| x.as_str()).unwrap_or("-");
 
     if mode == "-e" {
         let human_readable = fs::read_to_string(in_filename).expect("Cannot read file");
-        let bytes = h264_parser::serialize_h264(human_readable);
-        fs::write(out_filename, bytes).expect("Cannot write file");
+        let mut serialize_options = h264_parser::SerializeOptions::default();
+        let mut original: Option<&str> = None;
+        for option in &options {
+            if let Some(("--original", val)) = option.split_once('=') {
+                original = Some(val);
+                continue;
+            }
+            match option.as_str() {
+                "--fixup" => {
+                    serialize_options.fixup_dependent_fields = true;
+                    serialize_options.regenerate_trailing_bits = true;
+                },
+                "--start-codes=4" => serialize_options.start_codes = h264_parser::StartCodePolicy::AllFour,
+                "--start-codes=3" => serialize_options.start_codes = h264_parser::StartCodePolicy::AllThree,
+                "--start-codes=au" => serialize_options.start_codes = h264_parser::StartCodePolicy::AccessUnit,
+                _ => panic!("Invalid option {}", option),
+            }
+        }
+        let bytes = match original {
+            Some(original) => {
+                if options.iter().any(|x| x.starts_with("--start-codes=")) {
+                    panic!("--start-codes can't be combined with --original, which keeps the original start codes");
+                }
+                let mut rows = human_readable.split('\n').map(|x| x.to_string()).collect();
+                let edited = bitstream_util::syntax_elements_from_string(&mut rows);
+                h264_rewrite::splice_edited_nalus(&fs::read(original).expect("Cannot read file"), edited.into(), &serialize_options)
+            },
+            None => h264_parser::serialize_h264(human_readable, &serialize_options),
+        };
+        write_output(out_filename, bytes);
     } else if mode == "-d" {
+        let bytes = fs::read(in_filename).expect("Cannot read file");
+        let mut canonical = false;
+        let mut salvage = false;
+        let mut deep = false;
+        let mut cache_dir: Option<&str> = None;
+        let mut format = "text";
+        for option in &options {
+            match option.split_once('=') {
+                Some(("--format", val)) => format = val,
+                Some(("--cache", val)) => cache_dir = Some(val),
+                _ if option.as_str() == "--canonical" => canonical = true,
+                _ if option.as_str() == "--salvage" => salvage = true,
+                _ if option.as_str() == "--deep" => deep = true,
+                _ => panic!("Invalid option {}", option),
+            }
+        }
+        if format == "ndjson" {
+            // Written NALU by NALU as the stream is parsed, with the warnings each raises as events of their own
+            let options = h264_parser::ParseOptions { deep, salvage, cache_dir: cache_dir.map(PathBuf::from) };
+            let mut formatter = output_format::NdjsonFormatter::streaming(output_writer(out_filename));
+            h264_parser::parse_h264_streaming(&bytes, &options, &mut |mut nalu, warnings| {
+                for warning in warnings {
+                    formatter.warning(&warning);
+                }
+                if canonical {
+                    bitstream_util::canonicalize(&mut nalu);
+                }
+                output_format::send_element(&nalu, &mut formatter);
+            });
+            return;
+        }
+        let mut nalus = if salvage {
+            h264_parser::parse_h264_salvaging(&bytes)
+        } else if let Some(cache_dir) = cache_dir {
+            h264_parser::parse_h264_cached(&bytes, Path::new(cache_dir))
+        } else if deep {
+            h264_parser::parse_h264_deep(&bytes)
+        } else {
+            codec.parse(&bytes)
+        };
+        // Symbols are a presentation choice that may be extended, so the canonical text form leaves them out
+        let mut formatter: Box<dyn output_format::OutputFormatter> = if canonical && format == "text" {
+            Box::new(output_format::TextFormatter::default())
+        } else {
+            output_format::formatter_from_name(format).expect("Unsupported format")
+        };
+        if canonical {
+            nalus.iter_mut().for_each(bitstream_util::canonicalize);
+        }
+        write_output(out_filename, output_format::format_units(&nalus, formatter.as_mut()));
+    } else if mode == "-r" {
+        let mut bytes = fs::read(in_filename).expect("Cannot read file");
+        for option in &options {
+            match option.split_once('=') {
+                Some(("--ps-to-front", val)) => {
+                    let out_of_band = fs::read(val).expect("Cannot read file");
+                    bytes = h264_rewrite::move_parameter_sets_to_front(&bytes, &out_of_band);
+                },
+                None if option.as_str() == "--ps-to-front" => bytes = h264_rewrite::move_parameter_sets_to_front(&bytes, &[]),
+                _ => (),
+            }
+        }
+        let mut nalus = h264_parser::parse_h264(&bytes);
+        let mut frame_rate = h264_analysis::stream_frame_rate(&nalus);
+        for option in &options {
+            nalus = match option.split_once('=') {
+                Some(("--fps", val)) => {
+                    frame_rate = Some(val.parse().expect("Invalid frame rate"));
+                    nalus
+                },
+                Some(("--filler", val)) => h264_rewrite::insert_fixed_fillers(nalus, val.parse().expect("Invalid filler size")),
+                Some(("--cbr", val)) => {
+                    let frame_rate = frame_rate.expect("No VUI timing in stream, pass --fps=<rate> before --cbr");
+                    h264_rewrite::pad_to_constant_bitrate(nalus, val.parse().expect("Invalid bitrate"), frame_rate)
+                },
+                Some(("--dedup-ps", val)) => h264_rewrite::dedup_parameter_sets(nalus, Some(val.parse().expect("Invalid period"))),
+                None if option.as_str() == "--dedup-ps" => h264_rewrite::dedup_parameter_sets(nalus, None),
+                // Applied to the input before parsing
+                Some(("--ps-to-front", _)) => nalus,
+                None if option.as_str() == "--ps-to-front" => nalus,
+                None if option.as_str() == "--insert-aud" => h264_rewrite::insert_auds(nalus),
+                None if option.as_str() == "--strip-aud" => h264_rewrite::strip_auds(nalus),
+                None if option.as_str() == "--poc-type2" => h264_rewrite::convert_poc_type_0_to_2(nalus),
+                None if option.as_str() == "--reorder-slices" => h264_rewrite::reorder_slices(nalus),
+                None if option.as_str() == "--base-view" => h264_rewrite::extract_base_view(nalus),
+                Some(("--view", val)) => h264_rewrite::extract_view(nalus, val.parse().expect("Invalid view_id")),
+                Some(("--film-grain", val)) => {
+                    let mut rows = fs::read_to_string(val).expect("Cannot read file").split('\n').map(|x| x.to_string()).collect();
+                    let message = bitstream_util::syntax_elements_from_string(&mut rows).into_iter()
+                        .find_map(|x| match x {
+                            bitstream_util::SyntaxElement::Node(node) => Some(node),
+                            _ => None,
+                        })
+                        .expect("No sei_message in film grain file");
+                    h264_rewrite::set_film_grain(nalus, &message)
+                },
+                Some(("--sar", val)) => {
+                    let (width, height) = val.split_once(':').expect("Invalid sample aspect ratio, expected <width>:<height>");
+                    let sample_aspect_ratio = (width.parse().expect("Invalid sar_width"), height.parse().expect("Invalid sar_height"));
+                    h264_rewrite::edit_vui(nalus, &h264_rewrite::VuiEdit { sample_aspect_ratio: Some(sample_aspect_ratio), ..Default::default() })
+                },
+                Some(("--colour", val)) => {
+                    let vals: Vec<i32> = val.split(',').map(|x| x.parse().expect("Invalid colour description")).collect();
+                    let [colour_primaries, transfer_characteristics, matrix_coefficients] = vals[..] else {
+                        panic!("Invalid colour description, expected <primaries>,<transfer>,<matrix>");
+                    };
+                    let colour_description = Some((colour_primaries, transfer_characteristics, matrix_coefficients));
+                    h264_rewrite::edit_vui(nalus, &h264_rewrite::VuiEdit { colour_description, ..Default::default() })
+                },
+                Some(("--full-range", val)) => {
+                    let video_full_range_flag = Some(val.parse().expect("Invalid video_full_range_flag"));
+                    h264_rewrite::edit_vui(nalus, &h264_rewrite::VuiEdit { video_full_range_flag, ..Default::default() })
+                },
+                Some(("--timing", val)) => {
+                    let (numerator, denominator) = val.split_once('/').unwrap_or((val, "1"));
+                    let frame_rate = Some((numerator.parse().expect("Invalid frame rate"), denominator.parse().expect("Invalid frame rate")));
+                    h264_rewrite::edit_vui(nalus, &h264_rewrite::VuiEdit { frame_rate, ..Default::default() })
+                },
+                Some(("--constraint-flags", val)) => h264_rewrite::fix_constraint_flags(nalus, Some(val.parse().expect("Invalid profile_idc"))),
+                None if option.as_str() == "--constraint-flags" => h264_rewrite::fix_constraint_flags(nalus, None),
+                _ => panic!("Invalid option {}", option),
+            };
+        }
+        let bytes = codec.serialize(nalus);
+        write_output(out_filename, bytes);
+    } else if mode == "escape" {
+        let bytes = fs::read(in_filename).expect("Cannot read file");
+        write_output(out_filename, bitstream_util::rbsp_to_ebsp(&bytes));
+    } else if mode == "unescape" {
+        let bytes = fs::read(in_filename).expect("Cannot read file");
+        write_output(out_filename, bitstream_util::ebsp_to_rbsp(&bytes));
+    } else if mode == "probe" {
+        let bytes = fs::read(in_filename).expect("Cannot read file");
+        let mut frame_rate: Option<f64> = None;
+        for option in &options {
+            match option.split_once('=') {
+                Some(("--fps", val)) => frame_rate = Some(val.parse().expect("Invalid frame rate")),
+                _ => panic!("Invalid option {}", option),
+            }
+        }
+        write_output(out_filename, h264_probe::probe_h264(&bytes, frame_rate));
+    } else if mode == "timeline" {
+        let bytes = fs::read(in_filename).expect("Cannot read file");
+        let nalus = h264_parser::parse_h264(&bytes);
+        write_output(out_filename, h264_analysis::frame_type_timeline(&nalus));
+    } else if mode == "bitrate" {
         let bytes = fs::read(in_filename).expect("Cannot read file");
         let nalus = h264_parser::parse_h264(&bytes);
-        let mut human_readable = "".to_string();
-        for nalu in &nalus {
-            human_readable = format!("{}{}", human_readable, nalu.to_string());
+        let mut frame_rate = h264_analysis::stream_frame_rate(&nalus);
+        let mut svg = false;
+        for option in &options {
+            match option.split_once('=') {
+                Some(("--fps", val)) => frame_rate = Some(val.parse().expect("Invalid frame rate")),
+                None if option.as_str() == "--svg" => svg = true,
+                _ => panic!("Invalid option {}", option),
+            }
+        }
+        let frame_rate = frame_rate.expect("No VUI timing in stream, pass --fps=<rate>");
+        let frames = h264_analysis::frame_stats(&bytes, &nalus);
+        if svg {
+            write_output(out_filename, h264_analysis::bitrate_svg(&frames, frame_rate));
+        } else {
+            write_output(out_filename, h264_analysis::bitrate_gnuplot(&frames, frame_rate));
+        }
+    } else if mode == "peak-bitrate" {
+        let bytes = fs::read(in_filename).expect("Cannot read file");
+        let nalus = h264_parser::parse_h264(&bytes);
+        let mut frame_rate = h264_analysis::stream_frame_rate(&nalus);
+        let mut window: Option<usize> = None;
+        for option in &options {
+            match option.split_once('=') {
+                Some(("--fps", val)) => frame_rate = Some(val.parse().expect("Invalid frame rate")),
+                Some(("--window", val)) => window = Some(val.parse().expect("Invalid window")),
+                _ => panic!("Invalid option {}", option),
+            }
+        }
+        let frame_rate = frame_rate.expect("No VUI timing in stream, pass --fps=<rate>");
+        let sps = nalus.iter().find_map(|x| match x {
+            bitstream_util::SyntaxElement::Node(nalu) => nalu.get_node("sps"),
+            _ => None,
+        }).expect("No SPS in stream");
+        let frames = h264_analysis::frame_stats(&bytes, &nalus);
+        let window = window.unwrap_or((frame_rate.round() as usize).max(1));
+        write_output(out_filename, h264_analysis::peak_bitrate_report(&frames, frame_rate, window, sps));
+    } else if mode == "complexity" {
+        let bytes = fs::read(in_filename).expect("Cannot read file");
+        let nalus = h264_parser::parse_h264(&bytes);
+        let mut frame_rate = h264_analysis::stream_frame_rate(&nalus);
+        for option in &options {
+            match option.split_once('=') {
+                Some(("--fps", val)) => frame_rate = Some(val.parse().expect("Invalid frame rate")),
+                _ => panic!("Invalid option {}", option),
+            }
+        }
+        let frame_rate = frame_rate.expect("No VUI timing in stream, pass --fps=<rate>");
+        write_output(out_filename, h264_analysis::complexity_report(&bytes, &nalus, frame_rate));
+    } else if mode == "corpus" {
+        let mut paths: Vec<_> = fs::read_dir(in_filename).expect("Cannot read directory")
+            .map(|x| x.expect("Cannot read directory").path())
+            .filter(|x| x.is_file())
+            .collect();
+        paths.sort();
+        let mut matrix = false;
+        for option in &options {
+            match option.as_str() {
+                "--matrix" => matrix = true,
+                _ => panic!("Invalid option {}", option),
+            }
+        }
+        let mut summaries: Vec<h264_corpus::StreamSummary> = vec![];
+        for path in paths {
+            let bytes = fs::read(&path).expect("Cannot read file");
+            // One unparseable stream shouldn't sink the whole report
+            match panic::catch_unwind(|| h264_parser::parse_h264(&bytes)) {
+                Ok(nalus) => summaries.push(h264_corpus::summarize_stream(&path.display().to_string(), &nalus)),
+                Err(_) => eprintln!("Warning: skipping {}, it could not be parsed", path.display()),
+            }
+        }
+        if matrix {
+            write_output(out_filename, h264_corpus::feature_matrix(&summaries));
+        } else {
+            write_output(out_filename, h264_corpus::corpus_report(&summaries));
+        }
+    } else if mode == "profile-check" {
+        let bytes = fs::read(in_filename).expect("Cannot read file");
+        let nalus = h264_parser::parse_h264(&bytes);
+        write_output(out_filename, h264_profiles::profile_compliance_report(&nalus));
+    } else if mode == "codecs" {
+        let bytes = fs::read(in_filename).expect("Cannot read file");
+        let nalus = h264_parser::parse_h264(&bytes);
+        let mut in_band = false;
+        for option in &options {
+            match option.as_str() {
+                "--in-band" => in_band = true,
+                _ => panic!("Invalid option {}", option),
+            }
+        }
+        write_output(out_filename, h264_profiles::codec_string_report(&nalus, in_band));
+    } else if mode == "scorecard" {
+        let bytes = fs::read(in_filename).expect("Cannot read file");
+        let nalus = h264_parser::parse_h264(&bytes);
+        let mut frame_rate = h264_analysis::stream_frame_rate(&nalus);
+        for option in &options {
+            match option.split_once('=') {
+                Some(("--fps", val)) => frame_rate = Some(val.parse().expect("Invalid frame rate")),
+                _ => panic!("Invalid option {}", option),
+            }
+        }
+        write_output(out_filename, h264_scorecard::conformance_scorecard(&bytes, &nalus, frame_rate));
+    } else if mode == "qp" {
+        let bytes = fs::read(in_filename).expect("Cannot read file");
+        let nalus = h264_parser::parse_h264(&bytes);
+        write_output(out_filename, h264_analysis::slice_qp_report(&nalus));
+    } else if mode == "reserved-bits" {
+        let bytes = fs::read(in_filename).expect("Cannot read file");
+        let nalus = h264_parser::parse_h264(&bytes);
+        write_output(out_filename, h264_analysis::reserved_bits_report(&nalus));
+    } else if mode == "coverage" {
+        let bytes = fs::read(in_filename).expect("Cannot read file");
+        let nalus = h264_parser::parse_h264(&bytes);
+        write_output(out_filename, h264_analysis::slice_coverage_report(&nalus));
+    } else if mode == "aso" {
+        let bytes = fs::read(in_filename).expect("Cannot read file");
+        let nalus = h264_parser::parse_h264(&bytes);
+        write_output(out_filename, h264_analysis::arbitrary_slice_order_report(&nalus));
+    } else if mode == "slice-groups" {
+        let bytes = fs::read(in_filename).expect("Cannot read file");
+        let nalus = h264_parser::parse_h264(&bytes);
+        let mut format = h264_slice_groups::SliceGroupMapFormat::Grid;
+        for option in &options {
+            match option.as_str() {
+                "--csv" => format = h264_slice_groups::SliceGroupMapFormat::Csv,
+                "--runs" => format = h264_slice_groups::SliceGroupMapFormat::Runs,
+                _ => panic!("Invalid option {}", option),
+            }
+        }
+        write_output(out_filename, h264_slice_groups::slice_group_report(&nalus, format));
+    } else if mode == "idr-interval" {
+        let bytes = fs::read(in_filename).expect("Cannot read file");
+        let nalus = h264_parser::parse_h264(&bytes);
+        let mut frame_rate = h264_analysis::stream_frame_rate(&nalus);
+        let mut max_interval: Option<f64> = None;
+        for option in &options {
+            match option.split_once('=') {
+                Some(("--fps", val)) => frame_rate = Some(val.parse().expect("Invalid frame rate")),
+                Some(("--max-interval", val)) => max_interval = Some(val.parse().expect("Invalid interval")),
+                _ => panic!("Invalid option {}", option),
+            }
+        }
+        if max_interval.is_some() && frame_rate.is_none() {
+            panic!("No VUI timing in stream, pass --fps=<rate> to check --max-interval");
+        }
+        write_output(out_filename, h264_analysis::idr_interval_report(&nalus, frame_rate, max_interval));
+    } else if mode == "sizes" {
+        let bytes = fs::read(in_filename).expect("Cannot read file");
+        write_output(out_filename, stream::unit_size_report(&stream::Stream::new(codec.as_ref(), &bytes)));
+    } else if mode == "au-ranges" {
+        let bytes = fs::read(in_filename).expect("Cannot read file");
+        let nalus = h264_parser::parse_h264(&bytes);
+        write_output(out_filename, h264_analysis::access_unit_byte_ranges(&bytes, &nalus));
+    } else if mode == "ps-dups" {
+        let bytes = fs::read(in_filename).expect("Cannot read file");
+        let nalus = h264_parser::parse_h264(&bytes);
+        write_output(out_filename, h264_analysis::duplicate_parameter_set_report(&nalus));
+    } else if mode == "weights" {
+        let bytes = fs::read(in_filename).expect("Cannot read file");
+        let nalus = h264_parser::parse_h264(&bytes);
+        write_output(out_filename, h264_analysis::weighted_prediction_report(&nalus));
+    } else if mode == "ref-mods" {
+        let bytes = fs::read(in_filename).expect("Cannot read file");
+        let nalus = h264_parser::parse_h264(&bytes);
+        write_output(out_filename, h264_references::ref_pic_list_modification_report(&nalus));
+    } else if mode == "mmco" {
+        let bytes = fs::read(in_filename).expect("Cannot read file");
+        let nalus = h264_parser::parse_h264(&bytes);
+        write_output(out_filename, h264_references::mmco_timeline(&nalus));
+    } else if mode == "frame-num" {
+        let bytes = fs::read(in_filename).expect("Cannot read file");
+        let nalus = h264_parser::parse_h264(&bytes);
+        write_output(out_filename, h264_references::frame_num_report(&bytes, &nalus));
+    } else if mode == "dpb" {
+        let bytes = fs::read(in_filename).expect("Cannot read file");
+        let nalus = h264_parser::parse_h264(&bytes);
+        write_output(out_filename, h264_references::dpb_report(&nalus));
+    } else if mode == "poc" {
+        let bytes = fs::read(in_filename).expect("Cannot read file");
+        let nalus = h264_parser::parse_h264(&bytes);
+        write_output(out_filename, h264_poc::picture_order_report(&nalus));
+    } else if mode == "reorder" {
+        let bytes = fs::read(in_filename).expect("Cannot read file");
+        let nalus = h264_parser::parse_h264(&bytes);
+        write_output(out_filename, h264_poc::reorder_report(&nalus));
+    } else if mode == "fields" {
+        let bytes = fs::read(in_filename).expect("Cannot read file");
+        let nalus = h264_parser::parse_h264(&bytes);
+        write_output(out_filename, h264_poc::field_structure_report(&nalus));
+    } else if mode == "passthrough" {
+        let bytes = fs::read(in_filename).expect("Cannot read file");
+        let mut drop_types: Vec<u8> = vec![];
+        for option in &options {
+            match option.split_once('=') {
+                Some(("--strip-type", val)) => drop_types.push(val.parse().expect("Invalid NALU type")),
+                None if option.as_str() == "--strip-sei" => drop_types.push(6),
+                None if option.as_str() == "--strip-aud" => drop_types.push(9),
+                None if option.as_str() == "--strip-filler" => drop_types.push(12),
+                _ => panic!("Invalid option {}", option),
+            }
+        }
+        write_output(out_filename, h264_rewrite::passthrough(&bytes, &drop_types));
+    } else if mode == "anonymize" {
+        let bytes = fs::read(in_filename).expect("Cannot read file");
+        let mut seed = 0;
+        for option in &options {
+            match option.split_once('=') {
+                Some(("--seed", val)) => seed = val.parse().expect("Invalid seed"),
+                _ => panic!("Invalid option {}", option),
+            }
+        }
+        write_output(out_filename, h264_rewrite::anonymize_slice_data(&bytes, seed));
+    } else if mode == "flv" {
+        let bytes = fs::read(in_filename).expect("Cannot read file");
+        let mut annexb = false;
+        let mut drop_types: Vec<u8> = vec![];
+        for option in &options {
+            match option.split_once('=') {
+                Some(("--strip-type", val)) => drop_types.push(val.parse().expect("Invalid NALU type")),
+                None if option.as_str() == "--annexb" => annexb = true,
+                None if option.as_str() == "--strip-sei" => drop_types.push(6),
+                None if option.as_str() == "--strip-aud" => drop_types.push(9),
+                None if option.as_str() == "--strip-filler" => drop_types.push(12),
+                _ => panic!("Invalid option {}", option),
+            }
+        }
+        if !drop_types.is_empty() {
+            write_output(out_filename, flv::strip_flv_nalus(&bytes, &drop_types));
+        } else if annexb {
+            write_output(out_filename, flv::flv_to_annexb(&bytes));
+        } else {
+            let mut human_readable = "".to_string();
+            for tag in flv::flv_syntax_tree(&bytes) {
+                human_readable.push_str(&tag.to_string());
+            }
+            write_output(out_filename, human_readable);
+        }
+    } else if mode == "trace" {
+        let bytes = fs::read(in_filename).expect("Cannot read file");
+        write_output(out_filename, h264_trace::ffmpeg_trace(&bytes));
+    } else if mode == "extract" {
+        let bytes = fs::read(in_filename).expect("Cannot read file");
+        let prefix = files.get(1).expect("No output prefix given");
+        let pictures = h264_rewrite::split_pictures(&bytes);
+        for (idx, picture) in pictures.iter().enumerate() {
+            write_output(&format!("{}{:05}.264", prefix, idx), picture);
+        }
+        eprintln!("Wrote {} pictures", pictures.len());
+    } else if mode == "captions" {
+        if files[0] != "extract" {
+            panic!("Invalid captions command {}", files[0]);
+        }
+        let in_filename = files.get(1).expect("No input file given");
+        let bytes = fs::read(in_filename).expect("Cannot read file");
+        let nalus = h264_parser::parse_h264(&bytes);
+        let out_filename = files.get(2).map(|x| x.to_string()).unwrap_or(format!("{}.cc", in_filename));
+        let triplets = h264_captions::extract_captions(&nalus);
+        write_output(&out_filename, triplets.concat());
+        eprintln!("Wrote {} caption triplets", triplets.len());
+    } else if mode == "timecodes" {
+        let in_filename = files.get(1).expect("No input file given");
+        let nalus = h264_parser::parse_h264(&fs::read(in_filename).expect("Cannot read file"));
+        let out_filename = files.get(2).map(|x| x.as_str()).unwrap_or("-");
+        let frame_rate = options.iter().find_map(|x| x.strip_prefix("--fps=")).map(|x| x.parse().expect("Invalid frame rate"))
+            .or(h264_analysis::stream_frame_rate(&nalus))
+            .expect("No VUI timing in stream, pass --fps=<rate>");
+        match files[0].as_str() {
+            "list" => write_output(out_filename, h264_timecode::timecode_report(&nalus, frame_rate)),
+            "fix" => {
+                let nalus = h264_timecode::fix_timecodes(nalus, frame_rate);
+                write_output(out_filename, codec.serialize(nalus));
+            },
+            _ => panic!("Invalid timecodes command {}", files[0]),
+        }
+    } else if mode == "generate" {
+        if let Some(description) = options.iter().find_map(|x| x.strip_prefix("--from=")) {
+            let description = h264_generate::parse_stream_description(&fs::read_to_string(description).expect("Cannot read file"));
+            write_output(in_filename, h264_generate::generate_from_description(&description));
+        } else {
+            let size = options.iter().find_map(|x| x.strip_prefix("--size=")).expect("No --size=WxH given");
+            let (width, height) = size.split_once('x').expect("Size must be given as WxH");
+            let profile_idc = options.iter().find_map(|x| x.strip_prefix("--profile=")).map(|x| x.parse().expect("Invalid profile")).unwrap_or(66);
+            let level_idc = options.iter().find_map(|x| x.strip_prefix("--level=")).map(|x| x.parse().expect("Invalid level"));
+            write_output(in_filename, h264_generate::generate_skeleton(width.parse().expect("Invalid width"), height.parse().expect("Invalid height"),
+                                                                       profile_idc, level_idc));
+        }
+    } else if mode == "roundtrip" {
+        let bytes = fs::read(in_filename).expect("Cannot read file");
+        match fuzz::fuzz_roundtrip(&bytes) {
+            Ok(()) => write_output(out_filename, "Round trip is stable\n"),
+            Err(err) => write_output(out_filename, format!("{:?}\n", err)),
+        }
+    } else if mode == "color" {
+        let bytes = fs::read(in_filename).expect("Cannot read file");
+        let nalus = h264_parser::parse_h264(&bytes);
+        write_output(out_filename, h264_color::color_description_report(&nalus));
+    } else if mode == "hdr" {
+        let bytes = fs::read(in_filename).expect("Cannot read file");
+        let nalus = h264_parser::parse_h264(&bytes);
+        write_output(out_filename, h264_color::hdr_metadata_report(&nalus));
+    } else if mode == "diff" {
+        let a = h264_parser::parse_h264(&fs::read(files[0]).expect("Cannot read file"));
+        let b = h264_parser::parse_h264(&fs::read(files[1]).expect("Cannot read file"));
+        let out_filename = files.get(2).map(|x| x.as_str()).unwrap_or("-");
+        let mut tolerance = h264_diff::DiffTolerance::default();
+        for option in &options {
+            match option.as_str() {
+                "--ignore-sei" => tolerance.ignore_sei = true,
+                "--ignore-filler" => tolerance.ignore_filler = true,
+                "--ignore-frame-num" => tolerance.ignore_frame_num = true,
+                "--ignore-payloads" => tolerance.ignore_payloads = true,
+                _ => match option.split_once('=') {
+                    Some(("--ignore-field", val)) => tolerance.ignore_fields.push(val.to_string()),
+                    _ => panic!("Invalid option {}", option),
+                },
+            }
+        }
+        write_output(out_filename, h264_diff::diff_report(&a, &b, &tolerance));
+    } else if mode == "patch" {
+        let bytes = fs::read(in_filename).expect("Cannot read file");
+        let mut edits: Vec<(String, i32)> = vec![];
+        let mut nalu: Option<usize> = None;
+        for option in &options {
+            match option.split_once('=') {
+                Some(("--set", edit)) => {
+                    let (name, val) = edit.split_once('=').expect("Invalid --set, expected --set=<field>=<value>");
+                    edits.push((name.to_string(), val.parse().expect("Invalid value")));
+                },
+                Some(("--nalu", val)) => nalu = Some(val.parse().expect("Invalid NALU index")),
+                _ => panic!("Invalid option {}", option),
+            }
+        }
+        write_output(out_filename, h264_rewrite::patch_fields(&bytes, &edits, nalu));
+    } else if mode == "overlay" {
+        let bytes = fs::read(in_filename).expect("Cannot read file");
+        let human_readable = fs::read_to_string(files[1]).expect("Cannot read file");
+        let mut rows = human_readable.split('\n').map(|x| x.to_string()).collect();
+        let edited = bitstream_util::syntax_elements_from_string(&mut rows);
+        let out_filename = files.get(2).map(|x| x.as_str()).unwrap_or("-");
+        write_output(out_filename, h264_rewrite::overlay_slice_headers(&bytes, edited.into()));
+    } else if mode == "run" {
+        let job = toml::parse_toml(&fs::read_to_string(in_filename).expect("Cannot read file"));
+        let base_dir = Path::new(in_filename).parent().unwrap_or(Path::new(""));
+        let work_dir = job::WorkDir::create(env::temp_dir().join(format!("bitstream_tool_job_{}", std::process::id())));
+        for step in job::job_steps(&job, base_dir, &work_dir.path) {
+            match step {
+                job::JobStep::Run(step_args) => {
+                    eprintln!("Running {}", step_args.join(" "));
+                    run([vec![args[0].clone()], step_args].concat());
+                },
+                job::JobStep::Report(parts, out) => {
+                    let mut report = String::new();
+                    for (check, path) in parts {
+                        report.push_str(&format!("== {} ==\n", check));
+                        report.push_str(&fs::read_to_string(path).expect("Cannot read file"));
+                    }
+                    write_output(&out, report);
+                },
+            }
         }
-        fs::write(out_filename, human_readable).expect("Cannot write file");
     } else {
         panic!("Invalid flag {}", mode);
     }
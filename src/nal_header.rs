@@ -0,0 +1,151 @@
+use crate::bitstream_util::BitstreamProcessor;
+use crate::bitstream_util::FieldType;
+use crate::bitstream_util::SyntaxNode;
+
+// NAL unit header layouts of the codecs built on NAL units. Fields keep the names of each spec.
+#[derive(Clone, Copy, PartialEq)]
+pub enum NalHeaderFormat {
+    // H.264 7.3.1, one byte, extended for NALU types 14, 20 and 21 by their SVC, MVC or 3D-AVC header
+    H264,
+    // H.265 7.3.1.2
+    Hevc,
+    // H.266 7.3.1.2
+    Vvc,
+}
+
+// The H.264 header extension a NALU of type 14, 20 or 21 carries, which also decides how the rest of it is
+// coded
+#[derive(Clone, Copy, PartialEq)]
+pub enum H264HeaderExtension {
+    None,
+    Svc,
+    Mvc,
+    Avc3d,
+}
+
+// The header fields tools key on, in the same terms across codecs
+pub struct NalHeader {
+    pub nal_unit_type: i32,
+    // H.264 only, zero elsewhere
+    pub nal_ref_idc: i32,
+    // nuh_layer_id, zero for H.264
+    pub layer_id: i32,
+    // TemporalId, i.e. nuh_temporal_id_plus1 - 1, or the temporal_id of an H.264 header extension
+    pub temporal_id: i32,
+    // H.264 only: the header extension, and IdrPicFlag, which the extension signals for types 20 and 21
+    pub h264_extension: H264HeaderExtension,
+    pub idr_pic_flag: bool,
+}
+
+impl NalHeaderFormat {
+    pub fn header_bytes(&self) -> usize {
+        match self {
+            NalHeaderFormat::H264 => 1,
+            NalHeaderFormat::Hevc | NalHeaderFormat::Vvc => 2,
+        }
+    }
+}
+
+// H.264 7.3.1: the extension following the first header byte of prefix, slice extension and 3D-AVC slice
+// NALUs. Returns (the extension, its temporal_id, IdrPicFlag).
+fn process_h264_header_extension<A>(node: &mut SyntaxNode, bitstream: &mut A, nal_unit_type: i32) -> (H264HeaderExtension, i32, bool)
+    where A: BitstreamProcessor {
+    let mut temporal_id = 0;
+    let mut idr_pic_flag = false;
+    let (svc_extension_flag, avc_3d_extension_flag) = if nal_unit_type == 21 {
+        (0, bitstream.field(node, "avc_3d_extension_flag", FieldType::Boolean, 1))
+    } else {
+        (bitstream.field(node, "svc_extension_flag", FieldType::Boolean, 1), 0)
+    };
+    if svc_extension_flag != 0 {
+        // G.7.3.1.1
+        bitstream.subnode(node, "nal_unit_header_svc_extension", |x, y| {
+            idr_pic_flag = y.field(x, "idr_flag", FieldType::Boolean, 1) != 0;
+            y.field(x, "priority_id", FieldType::UnsignedInt, 6);
+            y.field(x, "no_inter_layer_pred_flag", FieldType::Boolean, 1);
+            y.field(x, "dependency_id", FieldType::UnsignedInt, 3);
+            y.field(x, "quality_id", FieldType::UnsignedInt, 4);
+            temporal_id = y.field(x, "temporal_id", FieldType::UnsignedInt, 3);
+            y.field(x, "use_ref_base_pic_flag", FieldType::Boolean, 1);
+            y.field(x, "discardable_flag", FieldType::Boolean, 1);
+            y.field(x, "output_flag", FieldType::Boolean, 1);
+            y.field(x, "reserved_three_2bits", FieldType::UnsignedInt, 2);
+        });
+        (H264HeaderExtension::Svc, temporal_id, idr_pic_flag)
+    } else if avc_3d_extension_flag != 0 {
+        // J.7.3.1.1
+        bitstream.subnode(node, "nal_unit_header_3davc_extension", |x, y| {
+            y.field(x, "view_idx", FieldType::UnsignedInt, 8);
+            y.field(x, "depth_flag", FieldType::Boolean, 1);
+            idr_pic_flag = y.field(x, "non_idr_flag", FieldType::Boolean, 1) == 0;
+            temporal_id = y.field(x, "temporal_id", FieldType::UnsignedInt, 3);
+            y.field(x, "anchor_pic_flag", FieldType::Boolean, 1);
+            y.field(x, "inter_view_flag", FieldType::Boolean, 1);
+        });
+        (H264HeaderExtension::Avc3d, temporal_id, idr_pic_flag)
+    } else {
+        // H.7.3.1.1
+        bitstream.subnode(node, "nal_unit_header_mvc_extension", |x, y| {
+            idr_pic_flag = y.field(x, "non_idr_flag", FieldType::Boolean, 1) == 0;
+            y.field(x, "priority_id", FieldType::UnsignedInt, 6);
+            y.field(x, "view_id", FieldType::UnsignedInt, 10);
+            temporal_id = y.field(x, "temporal_id", FieldType::UnsignedInt, 3);
+            y.field(x, "anchor_pic_flag", FieldType::Boolean, 1);
+            y.field(x, "inter_view_flag", FieldType::Boolean, 1);
+            y.field(x, "reserved_one_bit", FieldType::Boolean, 1);
+        });
+        (H264HeaderExtension::Mvc, temporal_id, idr_pic_flag)
+    }
+}
+
+pub fn process_nal_unit_header<A>(node: &mut SyntaxNode, bitstream: &mut A, format: NalHeaderFormat) -> NalHeader
+    where A: BitstreamProcessor {
+    bitstream.field(node, "forbidden_zero_bit", FieldType::Boolean, 1);
+    match format {
+        NalHeaderFormat::H264 => {
+            let nal_ref_idc = bitstream.field(node, "nal_ref_idc", FieldType::UnsignedInt, 2);
+            let nal_unit_type = bitstream.field(node, "nal_unit_type", FieldType::UnsignedInt, 5);
+            let (h264_extension, temporal_id, idr_pic_flag) = if nal_unit_type == 14 || nal_unit_type == 20 || nal_unit_type == 21 {
+                process_h264_header_extension(node, bitstream, nal_unit_type)
+            } else {
+                (H264HeaderExtension::None, 0, nal_unit_type == 5)
+            };
+            NalHeader { nal_unit_type, nal_ref_idc, layer_id: 0, temporal_id, h264_extension, idr_pic_flag }
+        },
+        NalHeaderFormat::Hevc => {
+            let nal_unit_type = bitstream.field(node, "nal_unit_type", FieldType::UnsignedInt, 6);
+            let layer_id = bitstream.field(node, "nuh_layer_id", FieldType::UnsignedInt, 6);
+            let temporal_id_plus1 = bitstream.field(node, "nuh_temporal_id_plus1", FieldType::UnsignedInt, 3);
+            NalHeader { nal_unit_type, nal_ref_idc: 0, layer_id, temporal_id: temporal_id_plus1 - 1,
+                        h264_extension: H264HeaderExtension::None, idr_pic_flag: false }
+        },
+        NalHeaderFormat::Vvc => {
+            bitstream.field(node, "nuh_reserved_zero_bit", FieldType::Boolean, 1);
+            let layer_id = bitstream.field(node, "nuh_layer_id", FieldType::UnsignedInt, 6);
+            let nal_unit_type = bitstream.field(node, "nal_unit_type", FieldType::UnsignedInt, 5);
+            let temporal_id_plus1 = bitstream.field(node, "nuh_temporal_id_plus1", FieldType::UnsignedInt, 3);
+            NalHeader { nal_unit_type, nal_ref_idc: 0, layer_id, temporal_id: temporal_id_plus1 - 1,
+                        h264_extension: H264HeaderExtension::None, idr_pic_flag: false }
+        },
+    }
+}
+
+// nal_unit_type straight from the coded bytes, for tools that sort NALUs without parsing them
+pub fn peek_nal_unit_type(nalu: &[u8], format: NalHeaderFormat) -> Option<i32> {
+    match format {
+        NalHeaderFormat::H264 => nalu.first().map(|x| i32::from(x & 0x1F)),
+        NalHeaderFormat::Hevc => nalu.first().map(|x| i32::from((x >> 1) & 0x3F)),
+        NalHeaderFormat::Vvc => nalu.get(1).map(|x| i32::from(x >> 3)),
+    }
+}
+
+// TemporalId of a parsed NALU, zero when its header has none
+pub fn temporal_id(nalu: &SyntaxNode) -> i32 {
+    if let Some(temporal_id_plus1) = nalu.get_field("nuh_temporal_id_plus1") {
+        return temporal_id_plus1 - 1;
+    }
+    ["nal_unit_header_svc_extension", "nal_unit_header_mvc_extension", "nal_unit_header_3davc_extension"].iter()
+        .find_map(|x| nalu.get_node(x))
+        .and_then(|x| x.get_field("temporal_id"))
+        .unwrap_or(0)
+}
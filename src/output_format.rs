@@ -0,0 +1,351 @@
+use std::io::Write;
+
+use crate::bitstream_util::SyntaxElement;
+use crate::bitstream_util::SyntaxField;
+use crate::bitstream_util::SyntaxPayload;
+#[cfg(feature = "h264")]
+use crate::h264_symbols;
+
+// Receives parsed units as tree events in document order, so new output formats can be added without touching
+// the parsers. Every begin_node is matched by an end_node once the node's children have been sent.
+pub trait OutputFormatter {
+    fn begin_node(&mut self, name: &str);
+    fn end_node(&mut self, name: &str);
+    fn field(&mut self, field: &SyntaxField);
+    fn payload(&mut self, payload: &SyntaxPayload);
+    // The formatted output, once every unit has been sent
+    fn finish(&mut self) -> String;
+}
+
+pub fn send_element(element: &SyntaxElement, formatter: &mut dyn OutputFormatter) {
+    match element {
+        SyntaxElement::Field(field) => formatter.field(field),
+        SyntaxElement::Node(node) => {
+            formatter.begin_node(&node.name);
+            for child in &node.children {
+                send_element(child, formatter);
+            }
+            formatter.end_node(&node.name);
+        },
+        SyntaxElement::Payload(payload) => formatter.payload(payload),
+    }
+}
+
+pub fn format_units(units: &[SyntaxElement], formatter: &mut dyn OutputFormatter) -> String {
+    for unit in units {
+        send_element(unit, formatter);
+    }
+    formatter.finish()
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|x| format!("{:02X}", x)).collect::<Vec<String>>().join(" ")
+}
+
+// Payloads marked as text are shown as a string when every byte is printable ASCII, as in the text form
+fn printable_text(payload: &SyntaxPayload) -> Option<String> {
+    (payload.text && payload.data.iter().all(|x| (0x20..0x7F).contains(x))).then(|| String::from_utf8_lossy(&payload.data).to_string())
+}
+
+#[cfg(feature = "h264")]
+fn field_symbol(name: &str, val: i32) -> Option<&'static str> {
+    h264_symbols::field_symbol(name, val)
+}
+
+#[cfg(not(feature = "h264"))]
+fn field_symbol(_name: &str, _val: i32) -> Option<&'static str> {
+    None
+}
+
+// The text form -d writes and -e reads. With symbols, enumerated fields are followed by what their value stands
+// for in parentheses, e.g. "slice_type: 7 (I)", which reading the text back ignores.
+#[derive(Default)]
+pub struct TextFormatter {
+    out: String,
+    depth: usize,
+    pub symbols: bool,
+}
+
+impl TextFormatter {
+    fn line(&mut self, line: &str) {
+        self.out.push_str(&"\t".repeat(self.depth));
+        self.out.push_str(line);
+        self.out.push('\n');
+    }
+}
+
+impl OutputFormatter for TextFormatter {
+    fn begin_node(&mut self, name: &str) {
+        self.line(&format!("{} {{", name));
+        self.depth += 1;
+    }
+
+    fn end_node(&mut self, _name: &str) {
+        self.depth -= 1;
+        self.line("}");
+    }
+
+    fn field(&mut self, field: &SyntaxField) {
+        match field_symbol(&field.name, field.val).filter(|_| self.symbols) {
+            Some(symbol) => self.line(&format!("{}: {} ({})", field.name, field.val, symbol)),
+            None => self.line(&format!("{}: {}", field.name, field.val)),
+        }
+    }
+
+    fn payload(&mut self, payload: &SyntaxPayload) {
+        match printable_text(payload) {
+            Some(text) => self.line(&format!("{}: '{}'", payload.name, text)),
+            None => self.line(&format!("{}: \"{}\"", payload.name, hex(&payload.data))),
+        }
+    }
+
+    fn finish(&mut self) -> String {
+        std::mem::take(&mut self.out)
+    }
+}
+
+fn json_string(val: &str) -> String {
+    let mut ret = "\"".to_string();
+    for c in val.chars() {
+        match c {
+            '"' => ret.push_str("\\\""),
+            '\\' => ret.push_str("\\\\"),
+            c if (c as u32) < 0x20 => ret.push_str(&format!("\\u{:04x}", c as u32)),
+            c => ret.push(c),
+        }
+    }
+    ret.push('"');
+    ret
+}
+
+// An array of units. Nodes become {"name": ..., "children": [...]}, fields {"name": ..., "value": ...} and
+// payloads {"name": ..., "hex": ...} or {"name": ..., "text": ...}, keeping the order and repeats of the tree.
+#[derive(Default)]
+pub struct JsonFormatter {
+    out: String,
+    // Whether the array at each open level already has an element, for the separating commas
+    has_elements: Vec<bool>,
+}
+
+impl JsonFormatter {
+    fn element(&mut self, json: &str) {
+        if self.has_elements.is_empty() {
+            self.has_elements.push(false);
+        }
+        let depth = self.has_elements.len();
+        if self.has_elements[depth - 1] {
+            self.out.push(',');
+        }
+        self.has_elements[depth - 1] = true;
+        self.out.push('\n');
+        self.out.push_str(&"  ".repeat(depth));
+        self.out.push_str(json);
+    }
+}
+
+impl OutputFormatter for JsonFormatter {
+    fn begin_node(&mut self, name: &str) {
+        self.element(&format!("{{\"name\": {}, \"children\": [", json_string(name)));
+        self.has_elements.push(false);
+    }
+
+    fn end_node(&mut self, _name: &str) {
+        if self.has_elements.pop() == Some(true) {
+            self.out.push('\n');
+            self.out.push_str(&"  ".repeat(self.has_elements.len()));
+        }
+        self.out.push_str("]}");
+    }
+
+    fn field(&mut self, field: &SyntaxField) {
+        self.element(&format!("{{\"name\": {}, \"value\": {}}}", json_string(&field.name), field.val));
+    }
+
+    fn payload(&mut self, payload: &SyntaxPayload) {
+        let json = match printable_text(payload) {
+            Some(text) => format!("{{\"name\": {}, \"text\": {}}}", json_string(&payload.name), json_string(&text)),
+            None => format!("{{\"name\": {}, \"hex\": \"{}\"}}", json_string(&payload.name), hex(&payload.data)),
+        };
+        self.element(&json);
+    }
+
+    fn finish(&mut self) -> String {
+        let ret = format!("[{}\n]\n", self.out);
+        self.out.clear();
+        self.has_elements.clear();
+        ret
+    }
+}
+
+fn xml_escape(val: &str) -> String {
+    val.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;").replace('\'', "&apos;")
+}
+
+// Names like "cbr_flag[0]" aren't valid XML tags, so every element carries its name as an attribute
+#[derive(Default)]
+pub struct XmlFormatter {
+    out: String,
+    depth: usize,
+}
+
+impl XmlFormatter {
+    fn line(&mut self, line: &str) {
+        self.out.push_str(&"  ".repeat(self.depth + 1));
+        self.out.push_str(line);
+        self.out.push('\n');
+    }
+}
+
+impl OutputFormatter for XmlFormatter {
+    fn begin_node(&mut self, name: &str) {
+        self.line(&format!("<node name=\"{}\">", xml_escape(name)));
+        self.depth += 1;
+    }
+
+    fn end_node(&mut self, _name: &str) {
+        self.depth -= 1;
+        self.line("</node>");
+    }
+
+    fn field(&mut self, field: &SyntaxField) {
+        self.line(&format!("<field name=\"{}\" value=\"{}\"/>", xml_escape(&field.name), field.val));
+    }
+
+    fn payload(&mut self, payload: &SyntaxPayload) {
+        match printable_text(payload) {
+            Some(text) => self.line(&format!("<payload name=\"{}\" text=\"{}\"/>", xml_escape(&payload.name), xml_escape(&text))),
+            None => self.line(&format!("<payload name=\"{}\" hex=\"{}\"/>", xml_escape(&payload.name), hex(&payload.data))),
+        }
+    }
+
+    fn finish(&mut self) -> String {
+        format!("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<units>\n{}</units>\n", std::mem::take(&mut self.out))
+    }
+}
+
+// One row per field or payload: the index of its unit, the path of nodes down to it, its name and value
+pub struct CsvFormatter {
+    out: String,
+    path: Vec<String>,
+    unit: usize,
+}
+
+impl Default for CsvFormatter {
+    fn default() -> CsvFormatter {
+        CsvFormatter { out: "unit,path,name,value\n".to_string(), path: vec![], unit: 0 }
+    }
+}
+
+impl CsvFormatter {
+    fn row(&mut self, name: &str, val: &str) {
+        // Text payloads are the only values that can hold commas or quotes
+        let val = if val.contains([',', '"']) { format!("\"{}\"", val.replace('"', "\"\"")) } else { val.to_string() };
+        self.out.push_str(&format!("{},{},{},{}\n", self.unit, self.path.join("/"), name, val));
+    }
+}
+
+impl OutputFormatter for CsvFormatter {
+    fn begin_node(&mut self, name: &str) {
+        self.path.push(name.to_string());
+    }
+
+    fn end_node(&mut self, _name: &str) {
+        self.path.pop();
+        if self.path.is_empty() {
+            self.unit += 1;
+        }
+    }
+
+    fn field(&mut self, field: &SyntaxField) {
+        self.row(&field.name, &field.val.to_string());
+    }
+
+    fn payload(&mut self, payload: &SyntaxPayload) {
+        let val = printable_text(payload).unwrap_or_else(|| hex(&payload.data));
+        self.row(&payload.name, &val);
+    }
+
+    fn finish(&mut self) -> String {
+        std::mem::take(self).out
+    }
+}
+
+// Newline delimited JSON: one object per event, each on its own line, so a pipeline can act on every line as it
+// reads it. Every event carries the index of its unit and the path of nodes above it, like the CSV rows.
+#[derive(Default)]
+pub struct NdjsonFormatter {
+    out: String,
+    // Where lines are written as they are formatted, flushed after every unit, instead of collecting them in out
+    sink: Option<Box<dyn Write>>,
+    path: Vec<String>,
+    unit: usize,
+}
+
+impl NdjsonFormatter {
+    pub fn streaming(sink: Box<dyn Write>) -> NdjsonFormatter {
+        NdjsonFormatter { sink: Some(sink), ..NdjsonFormatter::default() }
+    }
+
+    // A warning about the unit about to be sent
+    pub fn warning(&mut self, message: &str) {
+        self.line(format!("{{\"event\": \"warning\", \"unit\": {}, \"message\": {}}}\n", self.unit, json_string(message)));
+    }
+
+    fn event(&mut self, event: &str, name: &str, rest: &str) {
+        let path = json_string(&self.path.join("/"));
+        self.line(format!("{{\"event\": \"{}\", \"unit\": {}, \"path\": {}, \"name\": {}{}}}\n",
+                          event, self.unit, path, json_string(name), rest));
+    }
+
+    fn line(&mut self, line: String) {
+        match &mut self.sink {
+            Some(sink) => sink.write_all(line.as_bytes()).expect("Cannot write output"),
+            None => self.out.push_str(&line),
+        }
+    }
+}
+
+impl OutputFormatter for NdjsonFormatter {
+    fn begin_node(&mut self, name: &str) {
+        self.event("begin_node", name, "");
+        self.path.push(name.to_string());
+    }
+
+    fn end_node(&mut self, name: &str) {
+        self.path.pop();
+        self.event("end_node", name, "");
+        if self.path.is_empty() {
+            self.unit += 1;
+            if let Some(sink) = &mut self.sink {
+                sink.flush().expect("Cannot write output");
+            }
+        }
+    }
+
+    fn field(&mut self, field: &SyntaxField) {
+        self.event("field", &field.name, &format!(", \"value\": {}", field.val));
+    }
+
+    fn payload(&mut self, payload: &SyntaxPayload) {
+        let rest = match printable_text(payload) {
+            Some(text) => format!(", \"text\": {}", json_string(&text)),
+            None => format!(", \"hex\": \"{}\"", hex(&payload.data)),
+        };
+        self.event("payload", &payload.name, &rest);
+    }
+
+    fn finish(&mut self) -> String {
+        std::mem::take(self).out
+    }
+}
+
+pub fn formatter_from_name(name: &str) -> Option<Box<dyn OutputFormatter>> {
+    match name {
+        "text" => Some(Box::new(TextFormatter { symbols: true, ..TextFormatter::default() })),
+        "json" => Some(Box::new(JsonFormatter::default())),
+        "xml" => Some(Box::new(XmlFormatter::default())),
+        "csv" => Some(Box::new(CsvFormatter::default())),
+        "ndjson" => Some(Box::new(NdjsonFormatter::default())),
+        _ => None,
+    }
+}
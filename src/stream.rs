@@ -0,0 +1,130 @@
+#[cfg(feature = "h264")]
+use std::collections::VecDeque;
+
+use crate::bitstream_util::SyntaxElement;
+#[cfg(feature = "h264")]
+use crate::h264_parser;
+#[cfg(feature = "h264")]
+use crate::nal_header::NalHeaderFormat;
+#[cfg(feature = "h264")]
+use crate::nal_header::peek_nal_unit_type;
+
+// What the codec-agnostic tooling needs from each codec module. Units are the codec's top level syntax
+// structure: NALUs for H.264/HEVC, OBUs for AV1, frames for VP9.
+pub trait Codec {
+    fn split_units<'a>(&self, bitstream: &'a [u8]) -> Vec<&'a [u8]>;
+    fn unit_type(&self, unit: &[u8]) -> Option<i32>;
+    fn unit_type_name(&self, unit_type: i32) -> &'static str;
+    fn is_picture_unit(&self, unit_type: i32) -> bool;
+    fn parse(&self, bitstream: &[u8]) -> Vec<SyntaxElement>;
+    fn serialize(&self, units: Vec<SyntaxElement>) -> Vec<u8>;
+}
+
+#[cfg(feature = "h264")]
+pub struct H264Codec;
+
+#[cfg(feature = "h264")]
+impl Codec for H264Codec {
+    fn split_units<'a>(&self, bitstream: &'a [u8]) -> Vec<&'a [u8]> {
+        h264_parser::tokenize_h264_nalus(bitstream)
+    }
+
+    fn unit_type(&self, unit: &[u8]) -> Option<i32> {
+        peek_nal_unit_type(unit, NalHeaderFormat::H264)
+    }
+
+    fn unit_type_name(&self, unit_type: i32) -> &'static str {
+        h264_parser::nal_unit_type_name(unit_type)
+    }
+
+    fn is_picture_unit(&self, unit_type: i32) -> bool {
+        (1..=5).contains(&unit_type)
+    }
+
+    fn parse(&self, bitstream: &[u8]) -> Vec<SyntaxElement> {
+        h264_parser::parse_h264(bitstream)
+    }
+
+    fn serialize(&self, units: Vec<SyntaxElement>) -> Vec<u8> {
+        h264_parser::serialize_h264_nalus(VecDeque::from(units), &h264_parser::SerializeOptions::default())
+    }
+}
+
+pub fn codec_from_name(name: &str) -> Option<Box<dyn Codec>> {
+    match name {
+        #[cfg(feature = "h264")]
+        "h264" => Some(Box::new(H264Codec)),
+        _ => None,
+    }
+}
+
+pub struct Unit<'a> {
+    pub unit_type: Option<i32>,
+    // Byte offset of the unit's payload in the stream
+    pub offset: usize,
+    pub data: &'a [u8],
+}
+
+pub struct Stream<'a> {
+    pub codec: &'a dyn Codec,
+    pub units: Vec<Unit<'a>>,
+}
+
+impl<'a> Stream<'a> {
+    pub fn new(codec: &'a dyn Codec, bitstream: &'a [u8]) -> Stream<'a> {
+        let units = codec.split_units(bitstream).into_iter()
+            .map(|data| Unit { unit_type: codec.unit_type(data),
+                               offset: data.as_ptr() as usize - bitstream.as_ptr() as usize,
+                               data })
+            .collect();
+        Stream { codec, units }
+    }
+}
+
+// Value at the given percentile of an already sorted list, by nearest rank
+fn percentile(sorted: &[usize], percent: usize) -> usize {
+    let rank = (sorted.len() * percent).div_ceil(100).max(1);
+    sorted[rank - 1]
+}
+
+// Size statistics per unit type, followed by units small enough to suggest truncation or a muxer bug.
+pub fn unit_size_report(stream: &Stream) -> String {
+    let mut sizes_by_type: Vec<(i32, Vec<usize>)> = vec![];
+    for unit in &stream.units {
+        let Some(unit_type) = unit.unit_type else {
+            continue;
+        };
+        match sizes_by_type.iter_mut().find(|x| x.0 == unit_type) {
+            Some(sizes) => sizes.1.push(unit.data.len()),
+            None => sizes_by_type.push((unit_type, vec![unit.data.len()])),
+        }
+    }
+    sizes_by_type.sort_by_key(|x| x.0);
+    let mut ret = "type name count min max mean p50 p90 p99\n".to_string();
+    for (unit_type, sizes) in sizes_by_type.iter_mut() {
+        sizes.sort();
+        ret.push_str(&format!("{} \"{}\" {} {} {} {:.1} {} {} {}\n",
+                              unit_type, stream.codec.unit_type_name(*unit_type), sizes.len(),
+                              sizes[0], sizes[sizes.len() - 1], sizes.iter().sum::<usize>() as f64 / sizes.len() as f64,
+                              percentile(sizes, 50), percentile(sizes, 90), percentile(sizes, 99)));
+    }
+
+    for (idx, unit) in stream.units.iter().enumerate() {
+        let Some(unit_type) = unit.unit_type else {
+            continue;
+        };
+        let sizes = &sizes_by_type.iter().find(|x| x.0 == unit_type).unwrap().1;
+        let median = percentile(sizes, 50);
+        let name = stream.codec.unit_type_name(unit_type);
+        // A coded picture needs at least its unit header and a few bytes of picture or slice header
+        if stream.codec.is_picture_unit(unit_type) && unit.data.len() <= 3 {
+            ret.push_str(&format!("Outlier: unit {} ({}) at byte {} is only {} bytes, likely truncated\n",
+                                  idx, name, unit.offset, unit.data.len()));
+        } else if sizes.len() >= 10 && unit.data.len() * 10 < median {
+            ret.push_str(&format!("Outlier: unit {} ({}) at byte {} is {} bytes against a median of {}\n",
+                                  idx, name, unit.offset, unit.data.len(), median));
+        }
+    }
+
+    ret
+}
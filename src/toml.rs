@@ -0,0 +1,117 @@
+use crate::json::JsonValue;
+use crate::json::parse_json;
+
+// The subset of TOML that job files need: [table] headers, key = value pairs, # comments, and values that
+// are strings, numbers, booleans or arrays of them, which may span lines. Returns an object holding the top
+// level keys followed by one object per table. Malformed input panics, like the JSON parser.
+pub fn parse_toml(text: &str) -> JsonValue {
+    let mut root: Vec<(String, JsonValue)> = vec![];
+    let mut table: Option<usize> = None;
+    let mut lines = text.lines();
+    while let Some(line) = lines.next() {
+        let line = strip_comment(line);
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        if let Some(name) = line.strip_prefix('[').and_then(|x| x.strip_suffix(']')) {
+            root.push((name.trim().to_string(), JsonValue::Object(vec![])));
+            table = Some(root.len() - 1);
+            continue;
+        }
+        let (key, value) = line.split_once('=').unwrap_or_else(|| panic!("Expected key = value in TOML, got {}", line));
+        let mut value = value.trim().to_string();
+        // Arrays may continue over several lines
+        while bracket_depth(&value) > 0 {
+            let next = lines.next().unwrap_or_else(|| panic!("Unterminated array in TOML"));
+            value.push(' ');
+            value.push_str(strip_comment(next).trim());
+        }
+        let member = (key.trim().trim_matches('"').to_string(), parse_value(&value));
+        match table {
+            Some(idx) => {
+                if let JsonValue::Object(members) = &mut root[idx].1 {
+                    members.push(member);
+                }
+            },
+            None => root.push(member),
+        }
+    }
+
+    JsonValue::Object(root)
+}
+
+// Calls cb with each character and the quote of the string it belongs to, delimiters included
+fn scan_strings(text: &str, mut cb: impl FnMut(char, Option<char>)) {
+    let mut quote: Option<char> = None;
+    let mut escaped = false;
+    for c in text.chars() {
+        match quote {
+            Some(q) => {
+                cb(c, quote);
+                if escaped {
+                    escaped = false;
+                } else if c == '\\' && q == '"' {
+                    escaped = true;
+                } else if c == q {
+                    quote = None;
+                }
+            },
+            None => {
+                if c == '"' || c == '\'' {
+                    quote = Some(c);
+                }
+                cb(c, quote);
+            },
+        }
+    }
+}
+
+fn strip_comment(line: &str) -> String {
+    let mut ret = String::new();
+    let mut comment = false;
+    scan_strings(line, |c, quote| {
+        comment |= c == '#' && quote.is_none();
+        if !comment {
+            ret.push(c);
+        }
+    });
+    ret
+}
+
+fn bracket_depth(value: &str) -> i32 {
+    let mut depth = 0;
+    scan_strings(value, |c, quote| match c {
+        '[' if quote.is_none() => depth += 1,
+        ']' if quote.is_none() => depth -= 1,
+        _ => (),
+    });
+    depth
+}
+
+// TOML values are JSON values once literal 'strings' are requoted and trailing commas and digit separators
+// are dropped
+fn parse_value(value: &str) -> JsonValue {
+    let mut json = String::new();
+    scan_strings(value, |c, quote| {
+        if quote == Some('\'') {
+            match c {
+                '\'' => json.push('"'),
+                '"' | '\\' => {
+                    json.push('\\');
+                    json.push(c);
+                },
+                _ => json.push(c),
+            }
+        } else if quote.is_some() {
+            json.push(c);
+        } else if c == ']' {
+            let trimmed = json.trim_end().strip_suffix(',').unwrap_or(json.trim_end()).len();
+            json.truncate(trimmed);
+            json.push(c);
+        } else if c != '_' {
+            json.push(c);
+        }
+    });
+    parse_json(&json)
+}
@@ -0,0 +1,48 @@
+use std::fs;
+use std::path::PathBuf;
+
+use bitstream_tool::fuzz::RoundtripError;
+use bitstream_tool::fuzz::fuzz_roundtrip;
+use bitstream_tool::h264_parser::SerializeOptions;
+use bitstream_tool::h264_parser::parse_h264;
+use bitstream_tool::h264_parser::serialize_h264;
+use bitstream_tool::h264_parser::tokenize_h264_nalus;
+
+// Inputs shorter than a four byte start code, which once underflowed the tokenizer's bounds checks
+const SHORT_INPUTS: [&[u8]; 5] = [&[], &[0x00], &[0x65], &[0x00, 0x00, 0x01], &[0x00, 0x00, 0x65]];
+
+#[test]
+fn short_inputs_tokenize() {
+    assert!(tokenize_h264_nalus(&[]).is_empty());
+    assert_eq!(tokenize_h264_nalus(&[0x00]), vec![&[0x00][..]]);
+    assert_eq!(tokenize_h264_nalus(&[0x65]), vec![&[0x65][..]]);
+    assert!(tokenize_h264_nalus(&[0x00, 0x00, 0x01]).is_empty());
+    assert_eq!(tokenize_h264_nalus(&[0x00, 0x00, 0x65]), vec![&[0x00, 0x00, 0x65][..]]);
+}
+
+#[test]
+fn short_inputs_pass_the_fuzz_round_trip() {
+    for bytes in SHORT_INPUTS {
+        match fuzz_roundtrip(bytes) {
+            Ok(()) | Err(RoundtripError::ParsePanic(_)) => (),
+            Err(err) => panic!("{:02x?}: {:?}", bytes, err),
+        }
+    }
+}
+
+// An SPS extension with bit_depth_aux_minus8 = 6, which would size the alpha values past the 12 bits the spec allows
+#[test]
+#[should_panic(expected = "bit_depth_aux_minus8 of 6 exceeds the parse limit of 4")]
+fn sps_extension_bit_depth_is_bounded() {
+    parse_h264(&[0x00, 0x00, 0x00, 0x01, 0x0d, 0xa3, 0x80]);
+}
+
+// A cropping offset near the Exp-Golomb maximum, which once overflowed working out the cropped width
+#[test]
+#[should_panic(expected = "CroppedWidth of -4294967180 leaves nothing of the 112 samples of the frame")]
+fn crop_offsets_past_the_frame_are_refused() {
+    let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests").join("golden").join("grey_100x50_high.txt");
+    let text = fs::read_to_string(path).expect("Cannot read golden file");
+    let text = text.replace("frame_crop_right_offset: 6", "frame_crop_right_offset: 2147483646");
+    parse_h264(&serialize_h264(text, &SerializeOptions::default()));
+}
@@ -0,0 +1,70 @@
+use std::fs;
+use std::path::PathBuf;
+
+use bitstream_tool::fuzz::fuzz_roundtrip;
+use bitstream_tool::h264_parser::SerializeOptions;
+use bitstream_tool::h264_parser::parse_h264;
+use bitstream_tool::h264_parser::parse_h264_deep;
+use bitstream_tool::h264_parser::serialize_h264;
+use bitstream_tool::h264_snapshot::snapshot_text;
+
+// Each sample stream in tests/golden with the text `-d --canonical` writes for it, from the .txt file of the
+// same name. The samples come from the generate mode; after an intended change to the text form, rewrite a
+// golden file with: cargo run -- -d <sample>.264 <sample>.txt --canonical
+fn samples() -> Vec<(PathBuf, Vec<u8>, String)> {
+    let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests").join("golden");
+    let mut paths: Vec<PathBuf> = fs::read_dir(&dir).expect("Cannot read golden file directory")
+        .map(|x| x.expect("Cannot read golden file directory").path())
+        .filter(|x| x.extension().is_some_and(|y| y == "264"))
+        .collect();
+    paths.sort();
+    assert!(!paths.is_empty(), "No sample streams in {}", dir.display());
+    paths.into_iter().map(|path| {
+        let bytes = fs::read(&path).expect("Cannot read sample stream");
+        let text = fs::read_to_string(path.with_extension("txt")).expect("Cannot read golden file");
+        (path, bytes, text)
+    }).collect()
+}
+
+#[test]
+fn canonical_text_matches_golden_files() {
+    for (path, bytes, text) in samples() {
+        assert_eq!(snapshot_text(&parse_h264(&bytes)), text, "canonical text of {} differs from its golden file", path.display());
+    }
+}
+
+#[test]
+fn golden_text_round_trips_through_bytes() {
+    for (path, bytes, text) in samples() {
+        let serialized = serialize_h264(text.clone(), &SerializeOptions::default());
+        assert!(serialized == bytes, "golden file of {} doesn't serialize back to the sample", path.display());
+        assert_eq!(snapshot_text(&parse_h264(&serialized)), text, "reparsing {} changes its text", path.display());
+    }
+}
+
+#[test]
+fn samples_pass_the_fuzz_round_trip() {
+    for (path, bytes, _) in samples() {
+        if let Err(err) = fuzz_roundtrip(&bytes) {
+            panic!("{}: {:?}", path.display(), err);
+        }
+    }
+}
+
+// Samples with real macroblocks, rather than the filler slice data generate writes, also have the text
+// `-d --deep --canonical` writes for them in <sample>.deep.txt
+#[test]
+fn deep_text_matches_golden_files_and_round_trips() {
+    let mut checked = 0;
+    for (path, bytes, _) in samples() {
+        let Ok(text) = fs::read_to_string(path.with_extension("deep.txt")) else {
+            continue;
+        };
+        assert_eq!(snapshot_text(&parse_h264_deep(&bytes)), text, "deep text of {} differs from its golden file", path.display());
+        assert!(!text.contains("slice_payload"), "{} has slices that aren't parsed macroblock by macroblock", path.display());
+        let serialized = serialize_h264(text, &SerializeOptions::default());
+        assert!(serialized == bytes, "deep golden file of {} doesn't serialize back to the sample", path.display());
+        checked += 1;
+    }
+    assert!(checked > 0, "No sample streams have deep golden files");
+}
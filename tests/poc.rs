@@ -0,0 +1,17 @@
+use std::fs;
+use std::path::PathBuf;
+
+use bitstream_tool::h264_parser::parse_h264;
+use bitstream_tool::h264_poc::compute_picture_order;
+
+// The POC type 1 sample in tests/golden advances by offset_for_ref_frame[0] = 2 per frame, and every slice
+// codes delta_pic_order_cnt[1] = 1, which puts each bottom field one after its top field
+#[test]
+fn poc_type_1_applies_both_delta_pic_order_cnts() {
+    let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests").join("golden").join("poc_type1.264");
+    let nalus = parse_h264(&fs::read(path).expect("Cannot read sample stream"));
+    let orders: Vec<(Option<i32>, Option<i32>)> = compute_picture_order(&nalus).iter()
+        .map(|x| (x.top_field_order_cnt, x.bottom_field_order_cnt))
+        .collect();
+    assert_eq!(orders, vec![(Some(0), Some(1)), (Some(2), Some(3)), (Some(4), Some(5)), (Some(6), Some(7))]);
+}
@@ -0,0 +1,214 @@
+use std::collections::VecDeque;
+use std::fs;
+use std::path::PathBuf;
+
+use bitstream_tool::bitstream_util::SyntaxElement;
+use bitstream_tool::bitstream_util::SyntaxNode;
+use bitstream_tool::bitstream_util::syntax_elements_from_string;
+use bitstream_tool::h264_parser::SerializeOptions;
+use bitstream_tool::h264_parser::parse_h264;
+use bitstream_tool::h264_parser::serialize_h264_nalus;
+use bitstream_tool::h264_rewrite::VuiEdit;
+use bitstream_tool::h264_rewrite::anonymize_slice_data;
+use bitstream_tool::h264_rewrite::convert_poc_type_0_to_2;
+use bitstream_tool::h264_rewrite::edit_vui;
+use bitstream_tool::h264_rewrite::extract_base_view;
+use bitstream_tool::h264_rewrite::extract_view;
+use bitstream_tool::h264_rewrite::patch_fields;
+use bitstream_tool::h264_rewrite::set_film_grain;
+use bitstream_tool::h264_snapshot::snapshot_text;
+
+// Each rewrite is applied to sample streams in tests/rewrites, and its output, parsed again, is compared to the
+// text in <sample>.<rewrite>.txt. After an intended change to a rewrite, write its output for the sample with
+// the mode or option of the same name and rewrite the text with: cargo run -- -d <output> <file> --canonical
+fn sample(name: &str) -> Vec<u8> {
+    fs::read(dir().join(name).with_extension("264")).expect("Cannot read sample stream")
+}
+
+fn dir() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests").join("rewrites")
+}
+
+// Check the reparse of a rewritten stream against the expected text, returning the reparse
+fn assert_output_matches(sample: &str, rewrite: &str, bytes: &[u8]) -> Vec<SyntaxElement> {
+    let reparsed = parse_h264(bytes);
+    let expected = fs::read_to_string(dir().join(format!("{}.{}.txt", sample, rewrite))).expect("Cannot read expected text");
+    assert_eq!(snapshot_text(&reparsed), expected, "{} of {} differs from its expected text", rewrite, sample);
+    reparsed
+}
+
+fn assert_rewrite_matches(sample: &str, rewrite: &str, nalus: Vec<SyntaxElement>) -> Vec<SyntaxElement> {
+    assert_output_matches(sample, rewrite, &serialize_h264_nalus(VecDeque::from(nalus), &SerializeOptions::default()))
+}
+
+fn nodes<'a>(nalus: &'a [SyntaxElement], name: &'a str) -> impl Iterator<Item = &'a SyntaxNode> {
+    nalus.iter().filter_map(move |x| match x {
+        SyntaxElement::Node(nalu) => nalu.get_node(name),
+        _ => None,
+    })
+}
+
+#[test]
+fn poc_type_2_conversion_rewrites_sps_and_slices() {
+    let reparsed = assert_rewrite_matches("poc_type0", "poc_type2", convert_poc_type_0_to_2(parse_h264(&sample("poc_type0"))));
+    let sps = nodes(&reparsed, "sps").next().unwrap();
+    assert_eq!(sps.get_field("pic_order_cnt_type"), Some(2));
+    assert_eq!(sps.get_field("log2_max_pic_order_cnt_lsb_minus4"), None);
+    // The SPS is one field shorter, and its stop bit has to move up with it
+    let trailing_bits = sps.children.iter().find_map(|x| match x {
+        SyntaxElement::Payload(payload) if payload.name == "trailing_bits" => Some(&payload.data),
+        _ => None,
+    });
+    assert_eq!(trailing_bits, Some(&vec![0x04]));
+    assert!(nodes(&reparsed, "slice").all(|x| x.get_node("slice_header").unwrap().get_field("pic_order_cnt_lsb").is_none()));
+}
+
+fn slice_payload(nalu: &SyntaxNode) -> Option<&Vec<u8>> {
+    nalu.children.iter().filter_map(|x| match x {
+        SyntaxElement::Node(node) => node.children.iter().find_map(|y| match y {
+            SyntaxElement::Payload(payload) if payload.name == "slice_payload" => Some(&payload.data),
+            _ => None,
+        }),
+        _ => None,
+    }).next()
+}
+
+#[test]
+fn anonymize_replaces_the_data_of_every_kind_of_slice() {
+    for (name, nal_unit_types) in [("data_partitions", [2, 3, 4]), ("auxiliary", [1, 5, 19]), ("mvc", [1, 5, 20])] {
+        let original = parse_h264(&sample(name));
+        let anonymized = assert_output_matches(name, "anonymize", &anonymize_slice_data(&sample(name), 0));
+        let mut seen: Vec<i32> = vec![];
+        for (before, after) in original.iter().zip(&anonymized) {
+            let (SyntaxElement::Node(before), SyntaxElement::Node(after)) = (before, after) else {
+                continue;
+            };
+            let nal_unit_type = after.get_field("nal_unit_type").unwrap();
+            let (Some(before), Some(after)) = (slice_payload(before), slice_payload(after)) else {
+                continue;
+            };
+            // Only the first byte can still hold bits of the header, and the last is a bare stop bit. The NALU
+            // sizes are kept, not the RBSP ones, as the original data can have emulation prevention bytes.
+            // A random byte can match by chance, two in a row hardly
+            let unchanged: Vec<bool> = before[1..before.len() - 1].iter().zip(&after[1..after.len() - 1]).map(|(x, y)| x == y).collect();
+            assert!(!unchanged.windows(2).any(|x| x[0] && x[1]), "slice data of NAL unit type {} in {} survives", nal_unit_type, name);
+            assert_eq!(after.last(), Some(&0x80));
+            seen.push(nal_unit_type);
+        }
+        assert!(nal_unit_types.iter().all(|x| seen.contains(x)), "{} lacks some of NAL unit types {:?}", name, nal_unit_types);
+    }
+}
+
+#[test]
+fn patch_changes_only_the_bits_of_the_field() {
+    let original = sample("ipp");
+    let patched = patch_fields(&original, &[("level_idc".to_string(), 40)], None);
+    assert_output_matches("ipp", "patch", &patched);
+    let changed: Vec<usize> = (0..original.len()).filter(|x| original[*x] != patched[*x]).collect();
+    assert_eq!(changed, vec![7]);
+    assert_eq!(patched[7], 40);
+}
+
+#[test]
+#[should_panic(expected = "Patching NALU 0 changes its length")]
+fn patch_refuses_a_field_changing_its_coded_length() {
+    patch_fields(&sample("ipp"), &[("log2_max_frame_num_minus4".to_string(), 3)], None);
+}
+
+fn nal_unit_types(nalus: &[SyntaxElement]) -> Vec<i32> {
+    nalus.iter().filter_map(|x| match x {
+        SyntaxElement::Node(nalu) => nalu.get_field("nal_unit_type"),
+        _ => None,
+    }).collect()
+}
+
+#[test]
+fn base_view_extraction_drops_every_mvc_nalu() {
+    let original = parse_h264(&sample("mvc"));
+    let reparsed = assert_rewrite_matches("mvc", "base_view", extract_base_view(original.clone()));
+    assert!(!nal_unit_types(&reparsed).iter().any(|x| matches!(x, 14 | 15 | 20 | 21)));
+    let base: Vec<i32> = nal_unit_types(&original).into_iter().filter(|x| !matches!(x, 14 | 15 | 20 | 21)).collect();
+    assert_eq!(nal_unit_types(&reparsed), base);
+}
+
+#[test]
+fn view_extraction_turns_the_view_into_plain_avc() {
+    let reparsed = assert_rewrite_matches("mvc_independent", "view", extract_view(parse_h264(&sample("mvc_independent")), 1));
+    assert_eq!(nal_unit_types(&reparsed), vec![7, 8, 6, 5]);
+    // The view's subset SPS becomes a High profile SPS
+    assert_eq!(nodes(&reparsed, "sps").next().unwrap().get_field("profile_idc"), Some(100));
+}
+
+#[test]
+#[should_panic(expected = "View 1 is predicted from other views")]
+fn view_extraction_refuses_an_inter_view_predicted_view() {
+    extract_view(parse_h264(&sample("mvc")), 1);
+}
+
+// A film grain characteristics message as the file --film-grain reads holds it, payloadSize left to be recomputed
+const FILM_GRAIN: &str = "sei_message {
+\tpayloadType: 19
+\tpayloadSize: 0
+\tsei_payload {
+\t\tfilm_grain_characteristics_cancel_flag: 0
+\t\tmodel_id: 0
+\t\tseparate_colour_description_present_flag: 0
+\t\tblending_mode_id: 0
+\t\tlog2_scale_factor: 3
+\t\tcomp_model_present_flag[0]: 1
+\t\tcomp_model_present_flag[1]: 0
+\t\tcomp_model_present_flag[2]: 0
+\t\tnum_intensity_intervals_minus1[0]: 0
+\t\tnum_model_values_minus1[0]: 2
+\t\tintensity_interval_lower_bound[0][0]: 0
+\t\tintensity_interval_upper_bound[0][0]: 255
+\t\tcomp_model_value[0][0][0]: 100
+\t\tcomp_model_value[0][0][1]: 7
+\t\tcomp_model_value[0][0][2]: 7
+\t\tfilm_grain_characteristics_repetition_period: 1
+\t}
+}
+";
+
+fn film_grain_message() -> SyntaxNode {
+    let mut rows = FILM_GRAIN.split('\n').map(|x| x.to_string()).collect();
+    match syntax_elements_from_string(&mut rows).pop_front() {
+        Some(SyntaxElement::Node(message)) => message,
+        _ => panic!("No sei_message in FILM_GRAIN"),
+    }
+}
+
+#[test]
+fn film_grain_goes_ahead_of_each_idr_picture_once() {
+    let nalus = set_film_grain(parse_h264(&sample("ipp")), &film_grain_message());
+    let reparsed = assert_rewrite_matches("ipp", "film_grain", nalus);
+    assert_eq!(nal_unit_types(&reparsed), vec![7, 8, 6, 5, 1, 1, 1]);
+    // Setting it again replaces the message rather than adding another
+    assert_eq!(snapshot_text(&set_film_grain(reparsed.clone(), &film_grain_message())), snapshot_text(&reparsed));
+}
+
+fn vui(nalus: &[SyntaxElement]) -> &SyntaxNode {
+    nodes(nalus, "sps").next().unwrap().get_node("vui_parameters").unwrap()
+}
+
+#[test]
+fn vui_edits_add_a_vui_and_keep_each_others_fields() {
+    let edit = VuiEdit { sample_aspect_ratio: Some((16, 11)), ..Default::default() };
+    let reparsed = assert_rewrite_matches("ipp", "sar", edit_vui(parse_h264(&sample("ipp")), &edit));
+    // 16:11 is in Table E-1, so it's coded as its aspect_ratio_idc
+    assert_eq!(vui(&reparsed).get_field("aspect_ratio_idc"), Some(4));
+    assert_eq!(vui(&reparsed).get_field("sar_width"), None);
+
+    let edit = VuiEdit { frame_rate: Some((30000, 1001)), ..Default::default() };
+    let retimed = parse_h264(&serialize_h264_nalus(VecDeque::from(edit_vui(reparsed, &edit)), &SerializeOptions::default()));
+    assert_eq!(vui(&retimed).get_field("aspect_ratio_idc"), Some(4));
+    assert_eq!(vui(&retimed).get_field("num_units_in_tick"), Some(1001));
+    assert_eq!(vui(&retimed).get_field("time_scale"), Some(60000));
+
+    // Ratios outside Table E-1 are coded as Extended_SAR
+    let edit = VuiEdit { sample_aspect_ratio: Some((5, 3)), ..Default::default() };
+    let nalus = edit_vui(parse_h264(&sample("ipp")), &edit);
+    let extended = parse_h264(&serialize_h264_nalus(VecDeque::from(nalus), &SerializeOptions::default()));
+    assert_eq!(vui(&extended).get_field("aspect_ratio_idc"), Some(255));
+    assert_eq!((vui(&extended).get_field("sar_width"), vui(&extended).get_field("sar_height")), (Some(5), Some(3)));
+}